@@ -0,0 +1,157 @@
+//! Errors for graph endpoints, mapped to HTTP responses.
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde_derive::Serialize;
+use std::fmt;
+
+/// Policy-engine error, with a canonical HTTP status and client message.
+#[derive(Debug)]
+pub enum PeError {
+    /// Invalid client request parameters.
+    InvalidQuery(String),
+    /// Query string exceeds the maximum length, in bytes.
+    QueryTooLong(usize),
+    /// Unknown endpoint, with an optional hint for clients.
+    NotFound(Option<String>),
+    /// Client version is not supported.
+    UnsupportedVersion(String),
+    /// Upstream graph could not be fetched.
+    UpstreamUnavailable(String),
+    /// Graph exceeds the maximum allowed size.
+    GraphTooLarge,
+    /// Request processing exceeded its deadline, in milliseconds.
+    ProcessingTimeout(u64),
+    /// Service is warming up; clients should retry after some seconds.
+    WarmingUp(u64),
+    /// Too many requests in flight; clients should retry after some seconds.
+    Overloaded(u64),
+    /// Unexpected internal failure.
+    Internal(String),
+}
+
+/// JSON error envelope, as returned to clients.
+#[derive(Debug, Serialize)]
+pub struct ErrorEnvelope {
+    /// Machine-readable error kind.
+    pub kind: String,
+    /// Human-readable error description.
+    pub value: String,
+}
+
+impl PeError {
+    /// Machine-readable kind of this error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PeError::InvalidQuery(_) => "invalid_query",
+            PeError::QueryTooLong(_) => "query_too_long",
+            PeError::NotFound(_) => "not_found",
+            PeError::UnsupportedVersion(_) => "unsupported_version",
+            PeError::UpstreamUnavailable(_) => "upstream_unavailable",
+            PeError::GraphTooLarge => "graph_too_large",
+            PeError::ProcessingTimeout(_) => "processing_timeout",
+            PeError::WarmingUp(_) => "warming_up",
+            PeError::Overloaded(_) => "overloaded",
+            PeError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Message for clients, not including internal details.
+    pub fn client_message(&self) -> String {
+        match self {
+            PeError::InvalidQuery(msg) => format!("invalid query: {}", msg),
+            PeError::QueryTooLong(max) => format!("query exceeds {} bytes", max),
+            PeError::NotFound(None) => "unknown endpoint".to_string(),
+            PeError::NotFound(Some(hint)) => format!("unknown endpoint; {}", hint),
+            PeError::UnsupportedVersion(msg) => format!("unsupported client version: {}", msg),
+            PeError::UpstreamUnavailable(_) => "upstream graph temporarily unavailable".to_string(),
+            PeError::GraphTooLarge => "graph too large".to_string(),
+            PeError::ProcessingTimeout(ms) => format!("processing exceeded {}ms", ms),
+            PeError::WarmingUp(_) => "service warming up, retry later".to_string(),
+            PeError::Overloaded(_) => "service overloaded, retry later".to_string(),
+            PeError::Internal(_) => "internal server error".to_string(),
+        }
+    }
+
+    /// JSON error envelope for this error.
+    pub fn envelope(&self) -> ErrorEnvelope {
+        ErrorEnvelope {
+            kind: self.kind().to_string(),
+            value: self.client_message(),
+        }
+    }
+}
+
+impl fmt::Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeError::InvalidQuery(msg) => write!(f, "invalid query: {}", msg),
+            PeError::QueryTooLong(max) => write!(f, "query exceeds {} bytes", max),
+            PeError::NotFound(_) => write!(f, "unknown endpoint"),
+            PeError::UnsupportedVersion(msg) => write!(f, "unsupported client version: {}", msg),
+            PeError::UpstreamUnavailable(msg) => write!(f, "upstream unavailable: {}", msg),
+            PeError::GraphTooLarge => write!(f, "graph too large"),
+            PeError::ProcessingTimeout(ms) => write!(f, "processing exceeded {}ms", ms),
+            PeError::WarmingUp(secs) => write!(f, "warming up, retry after {}s", secs),
+            PeError::Overloaded(secs) => write!(f, "overloaded, retry after {}s", secs),
+            PeError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PeError {}
+
+impl ResponseError for PeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PeError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            PeError::QueryTooLong(_) => StatusCode::URI_TOO_LONG,
+            PeError::NotFound(_) => StatusCode::NOT_FOUND,
+            PeError::UnsupportedVersion(_) => StatusCode::CONFLICT,
+            PeError::UpstreamUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            PeError::GraphTooLarge => StatusCode::INTERNAL_SERVER_ERROR,
+            PeError::ProcessingTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            PeError::WarmingUp(_) => StatusCode::SERVICE_UNAVAILABLE,
+            PeError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            PeError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        if let PeError::WarmingUp(secs) | PeError::Overloaded(secs) = self {
+            resp.header(header::RETRY_AFTER, secs.to_string());
+        }
+        resp.json(self.envelope())
+    }
+}
+
+impl From<failure::Error> for PeError {
+    fn from(err: failure::Error) -> Self {
+        PeError::Internal(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for PeError {
+    fn from(err: serde_json::Error) -> Self {
+        PeError::Internal(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let err = PeError::InvalidQuery("missing stream".to_string());
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.envelope().kind, "invalid_query");
+        assert_eq!(err.envelope().value, "invalid query: missing stream");
+
+        // Internal details are not exposed to clients.
+        let err = PeError::UpstreamUnavailable("connection refused".to_string());
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!err.envelope().value.contains("connection refused"));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{metadata, policy};
-use failure::Fallible;
+use failure::{bail, ensure, Fallible};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -76,6 +76,56 @@ impl Graph {
         Ok(final_graph)
     }
 
+    /// Check that all edges reference existing nodes.
+    pub fn check_edges(&self) -> Fallible<()> {
+        let nodes = self.nodes.len() as u64;
+        if let Some((from, to)) = self
+            .edges
+            .iter()
+            .find(|(from, to)| *from >= nodes || *to >= nodes)
+        {
+            bail!(
+                "edge ({}, {}) out of bounds for a graph with {} nodes",
+                from,
+                to,
+                nodes
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the graph is a DAG, i.e. has valid edges and no cycles.
+    pub fn check_acyclic(&self) -> Fallible<()> {
+        self.check_edges()?;
+
+        // Kahn's algorithm: repeatedly remove nodes without incoming edges.
+        let mut incoming = vec![0usize; self.nodes.len()];
+        let mut outgoing = vec![vec![]; self.nodes.len()];
+        for (from, to) in &self.edges {
+            incoming[*to as usize] += 1;
+            outgoing[*from as usize].push(*to as usize);
+        }
+        let mut roots: Vec<usize> = (0..self.nodes.len())
+            .filter(|index| incoming[*index] == 0)
+            .collect();
+        let mut visited = 0;
+        while let Some(index) = roots.pop() {
+            visited += 1;
+            for target in &outgoing[index] {
+                incoming[*target] -= 1;
+                if incoming[*target] == 0 {
+                    roots.push(*target);
+                }
+            }
+        }
+        ensure!(
+            visited == self.nodes.len(),
+            "graph has cycles through {} nodes",
+            self.nodes.len() - visited
+        );
+        Ok(())
+    }
+
     /// Compute edges based on graph metadata.
     fn compute_edges(nodes: &[CincinnatiPayload]) -> Fallible<Vec<(u64, u64)>> {
         use std::collections::BTreeSet;
@@ -206,8 +256,44 @@ impl Graph {
 }
 
 /// The scope of a cached graph, i.e. the specific stream and basearch that it is valid for.
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct GraphScope {
     pub basearch: String,
     pub stream: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(size: usize, edges: Vec<(u64, u64)>) -> Graph {
+        let nodes = (0..size)
+            .map(|i| CincinnatiPayload {
+                version: format!("35.{}.0", i),
+                metadata: HashMap::new(),
+                payload: String::new(),
+            })
+            .collect();
+        Graph { nodes, edges }
+    }
+
+    #[test]
+    fn test_check_edges() {
+        graph(2, vec![(0, 1), (1, 0)]).check_edges().unwrap();
+        graph(0, vec![]).check_edges().unwrap();
+        graph(2, vec![(0, 1), (1, 2)]).check_edges().unwrap_err();
+        graph(2, vec![(2, 0)]).check_edges().unwrap_err();
+    }
+
+    #[test]
+    fn test_check_acyclic() {
+        graph(4, vec![(0, 1), (0, 2), (1, 3), (2, 3)])
+            .check_acyclic()
+            .unwrap();
+        graph(3, vec![(0, 1), (1, 2), (2, 1)])
+            .check_acyclic()
+            .unwrap_err();
+        graph(1, vec![(0, 0)]).check_acyclic().unwrap_err();
+        graph(1, vec![(0, 1)]).check_acyclic().unwrap_err();
+    }
+}
@@ -1,3 +1,4 @@
+pub mod errors;
 pub mod graph;
 pub mod metadata;
 pub mod metrics;
@@ -14,6 +14,17 @@ pub static SCHEME: &str = "org.fedoraproject.coreos.scheme";
 pub static AGE_INDEX: &str = "org.fedoraproject.coreos.releases.age_index";
 pub static ARCH_PREFIX: &str = "org.fedoraproject.coreos.releases.arch";
 
+/// Base architecture of an arch-specific metadata key, i.e. a key of the
+/// form `<ARCH_PREFIX>.<basearch>.<name>`.
+pub fn key_basearch(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(ARCH_PREFIX)?.strip_prefix('.')?;
+    let mut parts = rest.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(basearch), Some(name)) if !basearch.is_empty() && !name.is_empty() => Some(basearch),
+        _ => None,
+    }
+}
+
 pub static BARRIER: &str = "org.fedoraproject.coreos.updates.barrier";
 pub static BARRIER_REASON: &str = "org.fedoraproject.coreos.updates.barrier_reason";
 pub static DEADEND: &str = "org.fedoraproject.coreos.updates.deadend";
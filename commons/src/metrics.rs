@@ -1,18 +1,339 @@
 //! Metrics endpoint.
 
-use actix_web::HttpResponse;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::fmt::Write;
+use std::io::Write as _;
 
-/// Serve metrics requests (Prometheus textual format).
-pub async fn serve_metrics() -> Result<HttpResponse, failure::Error> {
+/// Content type of the OpenMetrics text format.
+pub static OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Serve metrics requests.
+///
+/// The OpenMetrics text format is served to clients accepting it, the
+/// classic Prometheus textual format otherwise. Metrics are gzip-compressed
+/// for clients accepting it.
+pub async fn serve_metrics(req: HttpRequest) -> Result<HttpResponse, failure::Error> {
+    let header_str = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (content_type, content) = encode_metrics(header_str(header::ACCEPT))?;
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(content_type);
+    resp.header(header::VARY, "Accept, Accept-Encoding");
+    if header_str(header::ACCEPT_ENCODING)
+        .map(accepts_gzip)
+        .unwrap_or(false)
+    {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        encoder.write_all(&content)?;
+        resp.header(header::CONTENT_ENCODING, "gzip");
+        return Ok(resp.body(encoder.finish()?));
+    }
+    Ok(resp.body(content))
+}
+
+/// Encode the metrics of the default registry, as served to clients with
+/// the given `Accept` header value, returning the content type and the
+/// encoded metrics.
+pub fn encode_metrics(accept: Option<&str>) -> Result<(&'static str, Vec<u8>), failure::Error> {
+    let metrics = prometheus::default_registry().gather();
+    render(&metrics, accept)
+}
+
+/// Encode metrics in the format negotiated from an `Accept` header value,
+/// returning the content type and the encoded metrics.
+fn render(
+    metrics: &[MetricFamily],
+    accept: Option<&str>,
+) -> Result<(&'static str, Vec<u8>), failure::Error> {
     use prometheus::Encoder;
 
-    let content = {
-        let metrics = prometheus::default_registry().gather();
-        let txt_enc = prometheus::TextEncoder::new();
-        let mut buf = vec![];
-        txt_enc.encode(&metrics, &mut buf)?;
-        buf
-    };
+    if accept.map(accepts_openmetrics).unwrap_or(false) {
+        return Ok((OPENMETRICS_FORMAT, encode_openmetrics(metrics).into_bytes()));
+    }
+    let txt_enc = prometheus::TextEncoder::new();
+    let mut buf = vec![];
+    txt_enc.encode(metrics, &mut buf)?;
+    Ok((prometheus::TEXT_FORMAT, buf))
+}
+
+/// Whether an `Accept` header value lists OpenMetrics (with non-zero quality).
+fn accepts_openmetrics(accept: &str) -> bool {
+    accepts(accept, "application/openmetrics-text")
+}
+
+/// Whether an `Accept-Encoding` header value lists gzip (with non-zero quality).
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accepts(accept_encoding, "gzip")
+}
+
+/// Whether a list of values with optional quality parameters, as in
+/// `Accept` headers, lists the given value with non-zero quality.
+fn accepts(list: &str, value: &str) -> bool {
+    list.split(',').any(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        let listed = params.next().unwrap_or_default();
+        if !listed.eq_ignore_ascii_case(value) {
+            return false;
+        }
+        let quality = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .filter_map(|q| q.parse::<f64>().ok())
+            .next()
+            .unwrap_or(1.0);
+        quality > 0.0
+    })
+}
+
+/// Encode metrics in the OpenMetrics text format.
+///
+/// Exemplars are not supported by the metrics library, so none are emitted.
+fn encode_openmetrics(metrics: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in metrics {
+        let metric_type = family.get_field_type();
+        // Counter families are named without their `_total` sample suffix.
+        let name = match metric_type {
+            MetricType::COUNTER => family
+                .get_name()
+                .strip_suffix("_total")
+                .unwrap_or_else(|| family.get_name()),
+            _ => family.get_name(),
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        if !family.get_help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", name, escape(family.get_help()));
+        }
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        for metric in family.get_metric() {
+            match metric_type {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    write_sample(&mut out, name, "_total", metric, None, value);
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    write_sample(&mut out, name, "", metric, None, value);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut inf_seen = false;
+                    for bucket in histogram.get_bucket() {
+                        let bound = format_value(bucket.get_upper_bound());
+                        inf_seen |= bucket.get_upper_bound() == f64::INFINITY;
+                        let count = bucket.get_cumulative_count() as f64;
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            metric,
+                            Some(("le", &bound)),
+                            count,
+                        );
+                    }
+                    let count = histogram.get_sample_count() as f64;
+                    if !inf_seen {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            metric,
+                            Some(("le", "+Inf")),
+                            count,
+                        );
+                    }
+                    let sum = histogram.get_sample_sum();
+                    write_sample(&mut out, name, "_sum", metric, None, sum);
+                    write_sample(&mut out, name, "_count", metric, None, count);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let label = format_value(quantile.get_quantile());
+                        let value = quantile.get_value();
+                        write_sample(
+                            &mut out,
+                            name,
+                            "",
+                            metric,
+                            Some(("quantile", &label)),
+                            value,
+                        );
+                    }
+                    let sum = summary.get_sample_sum();
+                    write_sample(&mut out, name, "_sum", metric, None, sum);
+                    let count = summary.get_sample_count() as f64;
+                    write_sample(&mut out, name, "_count", metric, None, count);
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    write_sample(&mut out, name, "", metric, None, value);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Write a single OpenMetrics sample line.
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    out.push_str(suffix);
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|pair| (pair.get_name(), pair.get_value()))
+        .chain(extra_label);
+    let mut separator = "{";
+    for (label, label_value) in labels {
+        let _ = write!(out, "{}{}=\"{}\"", separator, label, escape(label_value));
+        separator = ",";
+    }
+    if separator == "," {
+        out.push('}');
+    }
+    let _ = write!(out, " {}", format_value(value));
+    // OpenMetrics timestamps are in seconds.
+    let timestamp_ms = metric.get_timestamp_ms();
+    if timestamp_ms != 0 {
+        let _ = write!(out, " {}", timestamp_ms as f64 / 1000.0);
+    }
+    out.push('\n');
+}
+
+/// Format a sample value, with OpenMetrics spelling of special values.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escape backslashes, double quotes and line feeds.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+    use prometheus::{HistogramOpts, HistogramVec, IntCounter, Opts, Registry};
+    use std::io::Read;
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_requests_total", "Total requests").unwrap();
+        counter.inc_by(3);
+        registry.register(Box::new(counter)).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::from(Opts::new("test_duration_seconds", "Request \"duration\""))
+                .buckets(vec![0.5]),
+            &["stream"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["stable"]).observe(0.25);
+        registry.register(Box::new(histogram)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_render_negotiation() {
+        let metrics = registry().gather();
+
+        for accept in &[
+            None,
+            Some("text/plain"),
+            Some("application/openmetrics-text;q=0"),
+        ] {
+            let (content_type, body) = render(&metrics, *accept).unwrap();
+            assert_eq!(content_type, prometheus::TEXT_FORMAT);
+            let body = String::from_utf8(body).unwrap();
+            assert!(body.contains("# TYPE test_requests_total counter\n"));
+            assert!(!body.contains("# EOF"));
+        }
+
+        let scraper = "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5";
+        let (content_type, body) = render(&metrics, Some(scraper)).unwrap();
+        assert_eq!(content_type, OPENMETRICS_FORMAT);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "# HELP test_duration_seconds Request \\\"duration\\\"\n\
+             # TYPE test_duration_seconds histogram\n\
+             test_duration_seconds_bucket{stream=\"stable\",le=\"0.5\"} 1\n\
+             test_duration_seconds_bucket{stream=\"stable\",le=\"+Inf\"} 1\n\
+             test_duration_seconds_sum{stream=\"stable\"} 0.25\n\
+             test_duration_seconds_count{stream=\"stable\"} 1\n\
+             # HELP test_requests Total requests\n\
+             # TYPE test_requests counter\n\
+             test_requests_total 3\n\
+             # EOF\n"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_metrics_gzip() {
+        let counter = IntCounter::new("test_scrapes_total", "Total scrapes").unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let mut app =
+            test::init_service(App::new().route("/metrics", web::get().to(serve_metrics))).await;
+
+        for accept_encoding in &[None, Some("gzip;q=0"), Some("br")] {
+            let mut req = test::TestRequest::get().uri("/metrics");
+            if let Some(value) = accept_encoding {
+                req = req.header(header::ACCEPT_ENCODING, *value);
+            }
+            let resp = test::call_service(&mut app, req.to_request()).await;
+            assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+            let body = test::read_body(resp).await;
+            assert!(std::str::from_utf8(&body)
+                .unwrap()
+                .contains("test_scrapes_total 1\n"));
+        }
 
-    Ok(HttpResponse::Ok().body(content))
+        let req = test::TestRequest::get()
+            .uri("/metrics")
+            .header(header::ACCEPT_ENCODING, "gzip, deflate")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            prometheus::TEXT_FORMAT
+        );
+        let body = test::read_body(resp).await;
+        let mut exposition = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut exposition)
+            .unwrap();
+        assert!(exposition.contains("# TYPE test_scrapes_total counter\n"));
+        assert!(exposition.contains("test_scrapes_total 1\n"));
+    }
 }
@@ -1,8 +1,14 @@
-use crate::graph::Graph;
+use crate::graph::{CincinnatiPayload, Graph};
 use crate::metadata;
-use std::collections::HashSet;
+use serde_derive::Serialize;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 /// Prune outgoing edges from "deadend" nodes.
+///
+/// Like most graph policies, this only drops edges: nodes are never
+/// removed nor reordered, so that edge indices keep referencing the
+/// same releases.
 pub fn filter_deadends(input: Graph) -> Graph {
     let mut graph = input;
     let mut deadends = HashSet::new();
@@ -23,6 +29,11 @@ pub fn filter_deadends(input: Graph) -> Graph {
 }
 
 /// Conditionally prune incoming edges towards throttled rollouts.
+///
+/// Each release in progress is throttled on its own rollout parameters, so
+/// concurrent rollouts on the same graph progress independently: a client
+/// may be offered one release but not another, depending on its wariness
+/// and the current progress of each rollout.
 pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
     let mut graph = input;
     let mut hidden = HashSet::new();
@@ -30,8 +41,42 @@ pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
 
     for (index, release) in graph.nodes.iter().enumerate() {
         // Skip if this release is not being rolled out.
-        if release.metadata.get(metadata::ROLLOUT).is_none() {
-            continue;
+        let throttling = match rollout_progress(release, now) {
+            Some(throttling) => throttling,
+            None => continue,
+        };
+
+        if client_wariness > throttling {
+            hidden.insert(index);
+        }
+    }
+
+    graph.edges.retain(|(_from, to)| {
+        let index = *to as usize;
+        !hidden.contains(&index)
+    });
+    graph.edges.shrink_to_fit();
+
+    graph
+}
+
+/// Rollout parameters of a release, as set in its metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Rollout {
+    /// Start of the rollout, as a Unix timestamp.
+    pub start_epoch: i64,
+    /// Fraction of clients (by wariness) offered the release at start.
+    pub start_value: f64,
+    /// Time for the rollout to reach all clients, in minutes, if any.
+    pub duration_minutes: Option<u64>,
+}
+
+impl Rollout {
+    /// Rollout parameters of a release, or `None` if the release is not
+    /// being rolled out.
+    pub fn of_release(release: &CincinnatiPayload) -> Option<Self> {
+        if !release.metadata.contains_key(metadata::ROLLOUT) {
+            return None;
         };
 
         // Start epoch defaults to 0.
@@ -47,43 +92,733 @@ pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
         };
 
         // Duration has no default (i.e. no progress).
-        let mut minutes: Option<u64> = None;
+        let mut duration_minutes: Option<u64> = None;
         if let Some(mins) = release.metadata.get(metadata::DURATION) {
             if let Ok(m) = mins.parse::<u64>() {
-                minutes = Some(m.max(1));
+                duration_minutes = Some(m.max(1));
             }
         }
 
-        let throttling: f64;
-        if let Some(mins) = minutes {
-            let end = start_epoch + (mins.saturating_mul(60)) as i64;
-            let rate = (1.0 - start_value) / (end.saturating_sub(start_epoch)) as f64;
-            if now < start_epoch {
-                throttling = 0.0;
-            } else if now > end {
-                throttling = 1.0;
-            } else {
-                throttling = start_value + rate * (now - start_epoch) as f64;
+        Some(Self {
+            start_epoch,
+            start_value,
+            duration_minutes,
+        })
+    }
+
+    /// Progress of the rollout at the given time, as the fraction of
+    /// clients (by wariness) being offered the release.
+    pub fn progress(&self, now: i64) -> f64 {
+        let start_epoch = self.start_epoch;
+        let start_value = self.start_value;
+        if now < start_epoch {
+            return 0.0;
+        }
+        match self.duration_minutes {
+            Some(mins) => {
+                let end = start_epoch + (mins.saturating_mul(60)) as i64;
+                let rate = (1.0 - start_value) / (end.saturating_sub(start_epoch)) as f64;
+                if now > end {
+                    1.0
+                } else {
+                    start_value + rate * (now - start_epoch) as f64
+                }
             }
-        } else {
             // Without duration, rollout does not progress past initial value.
-            if now < start_epoch {
-                throttling = 0.0;
-            } else {
-                throttling = start_value
-            }
+            None => start_value,
         }
+    }
+}
 
-        if client_wariness > throttling {
-            hidden.insert(index);
+/// Progress of a release rollout at the given time, as the fraction of
+/// clients (by wariness) being offered the release, or `None` if the
+/// release is not being rolled out.
+pub fn rollout_progress(release: &CincinnatiPayload, now: i64) -> Option<f64> {
+    Rollout::of_release(release).map(|rollout| rollout.progress(now))
+}
+
+/// Trim outgoing edges of nodes exceeding the given limit.
+///
+/// Edges beyond the limit are dropped, in graph order. The indices of
+/// trimmed nodes are returned alongside the resulting graph.
+pub fn limit_outgoing_edges(input: Graph, max_edges: usize) -> (Graph, Vec<u64>) {
+    let mut graph = input;
+    let mut outgoing: HashMap<u64, usize> = HashMap::new();
+    let mut trimmed = HashSet::new();
+
+    graph.edges.retain(|(from, _to)| {
+        let count = outgoing.entry(*from).or_insert(0);
+        *count += 1;
+        if *count > max_edges {
+            trimmed.insert(*from);
+            return false;
+        }
+        true
+    });
+
+    let mut trimmed: Vec<u64> = trimmed.into_iter().collect();
+    trimmed.sort_unstable();
+    (graph, trimmed)
+}
+
+/// Trim edges so that no update path from `current_version` is longer than
+/// `max_hops`, forcing clients to update in steps.
+///
+/// Only shortest-path edges are kept on paths from `current_version`, so that
+/// every release within `max_hops` hops stays reachable in as few hops as
+/// before. Edges not reachable from `current_version` are left untouched, as
+/// is the whole graph if no release has this version.
+pub fn limit_hops(input: Graph, current_version: &str, max_hops: usize) -> Graph {
+    let mut graph = input;
+    let start = match graph
+        .nodes
+        .iter()
+        .position(|release| release.version == current_version)
+    {
+        Some(start) => start as u64,
+        None => return graph,
+    };
+
+    let mut outgoing: HashMap<u64, Vec<u64>> = HashMap::new();
+    for (from, to) in &graph.edges {
+        outgoing.entry(*from).or_default().push(*to);
+    }
+    // Breadth-first hop distances from the current release.
+    let mut distances = HashMap::new();
+    distances.insert(start, 0usize);
+    let mut queue = std::collections::VecDeque::new();
+    queue.push_back(start);
+    while let Some(from) = queue.pop_front() {
+        let next = distances[&from] + 1;
+        for to in outgoing.get(&from).into_iter().flatten() {
+            if !distances.contains_key(to) {
+                distances.insert(*to, next);
+                queue.push_back(*to);
+            }
         }
     }
 
-    graph.edges.retain(|(_from, to)| {
-        let index = *to as usize;
-        !hidden.contains(&index)
-    });
+    graph.edges.retain(
+        |(from, to)| match (distances.get(from), distances.get(to)) {
+            (Some(from), Some(to)) => *from < max_hops && *to == *from + 1,
+            _ => true,
+        },
+    );
     graph.edges.shrink_to_fit();
 
     graph
 }
+
+/// Keep only the outgoing edges of each release towards its newest target,
+/// so that clients update straight to the latest release offered to them.
+///
+/// Edges towards releases missing from the graph are left untouched.
+pub fn prefer_latest_edges(input: Graph) -> Graph {
+    let mut graph = input;
+    let version = |index: u64| graph.nodes.get(index as usize).map(|n| n.version.as_str());
+
+    let mut latest: HashMap<u64, &str> = HashMap::new();
+    for (from, to) in &graph.edges {
+        if let Some(target) = version(*to) {
+            let newest = latest.entry(*from).or_insert(target);
+            if compare_versions(target, newest) == Ordering::Greater {
+                *newest = target;
+            }
+        }
+    }
+    let kept: Vec<(u64, u64)> = graph
+        .edges
+        .iter()
+        .filter(|(from, to)| match (version(*to), latest.get(from)) {
+            (Some(target), Some(newest)) => compare_versions(target, newest) == Ordering::Equal,
+            _ => true,
+        })
+        .cloned()
+        .collect();
+    graph.edges = kept;
+
+    graph
+}
+
+/// Remove releases with blocked versions, with their edges, e.g. to stop
+/// offering a faulty release as soon as it is discovered.
+///
+/// Unlike other graph policies, this removes nodes, so the remaining edges
+/// are re-indexed (edges towards releases missing from the graph are dropped
+/// too). The release of `current_version` is kept even if blocked, without
+/// inbound edges, so that clients stuck on it are still offered updates away.
+pub fn block_versions(
+    input: Graph,
+    blocked: &HashSet<String>,
+    current_version: Option<&str>,
+) -> Graph {
+    let mut graph = input;
+    let is_current = |version: &str| current_version == Some(version);
+    let keep = |version: &str| is_current(version) || !blocked.contains(version);
+    if !graph
+        .nodes
+        .iter()
+        .any(|release| blocked.contains(&release.version))
+    {
+        return graph;
+    }
+
+    // New indices of kept releases, and of the blocked current one, if any.
+    let mut indices = HashMap::new();
+    let mut current = None;
+    for (index, release) in graph.nodes.iter().enumerate() {
+        if keep(&release.version) {
+            let new_index = indices.len() as u64;
+            if is_current(&release.version) && blocked.contains(&release.version) {
+                current = Some(new_index);
+            }
+            indices.insert(index as u64, new_index);
+        }
+    }
+    graph.nodes.retain(|release| keep(&release.version));
+    let edges = graph
+        .edges
+        .iter()
+        .filter_map(|(from, to)| Some((*indices.get(from)?, *indices.get(to)?)))
+        .filter(|(_from, to)| Some(*to) != current)
+        .collect();
+    graph.edges = edges;
+
+    graph
+}
+
+/// Rewrite the given prefix of node payloads, e.g. to point to a mirror.
+///
+/// Payloads not starting with `from_prefix` are left untouched.
+pub fn rewrite_payload_prefix(input: Graph, from_prefix: &str, to_prefix: &str) -> Graph {
+    let mut graph = input;
+    for release in graph.nodes.iter_mut() {
+        if let Some(rest) = release.payload.strip_prefix(from_prefix) {
+            release.payload = format!("{}{}", to_prefix, rest);
+        }
+    }
+    graph
+}
+
+/// Strip node metadata entries whose key is not kept by `keep`.
+pub fn filter_metadata<F>(input: Graph, keep: F) -> Graph
+where
+    F: Fn(&str) -> bool,
+{
+    let mut graph = input;
+    for release in graph.nodes.iter_mut() {
+        release.metadata.retain(|key, _| keep(key));
+    }
+    graph
+}
+
+/// Drop arch-specific node metadata of other base architectures than the
+/// given one (see `metadata::key_basearch`). Other metadata is kept.
+pub fn select_arch_metadata(input: Graph, basearch: &str) -> Graph {
+    filter_metadata(input, |key| match metadata::key_basearch(key) {
+        Some(key_basearch) => key_basearch == basearch,
+        None => true,
+    })
+}
+
+/// Compare two dotted release versions, component by component.
+///
+/// Numeric components are compared as numbers, other components
+/// lexicographically. Missing trailing components count as zero.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut left = a.split('.');
+    let mut right = b.split('.');
+    loop {
+        let ord = match (left.next(), right.next()) {
+            (None, None) => return Ordering::Equal,
+            (l, r) => {
+                let (l, r) = (l.unwrap_or("0"), r.unwrap_or("0"));
+                match (l.parse::<u64>(), r.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    _ => l.cmp(r),
+                }
+            }
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// Whether a client version is below the minimum supported version floor.
+pub fn below_version_floor(current_version: &str, min_version: &str) -> bool {
+    compare_versions(current_version, min_version) == Ordering::Less
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_outgoing_edges() {
+        let nodes = (0..5)
+            .map(|i| CincinnatiPayload {
+                version: format!("35.{}.0", i),
+                metadata: Default::default(),
+                payload: String::new(),
+            })
+            .collect();
+        let input = Graph {
+            nodes,
+            edges: vec![(0, 1), (0, 2), (0, 3), (0, 4), (1, 2), (1, 3), (2, 3)],
+        };
+
+        let (graph, trimmed) = limit_outgoing_edges(input, 2);
+        assert_eq!(trimmed, vec![0]);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2), (1, 2), (1, 3), (2, 3)]);
+
+        let (graph, trimmed) = limit_outgoing_edges(graph, 2);
+        assert!(trimmed.is_empty());
+        assert_eq!(graph.edges.len(), 5);
+    }
+
+    #[test]
+    fn test_rollout_progress() {
+        let release = |entries: &[(&str, &str)]| CincinnatiPayload {
+            version: "35.1.0".to_string(),
+            metadata: entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            payload: String::new(),
+        };
+        assert_eq!(rollout_progress(&release(&[]), 0), None);
+
+        let rollout = release(&[
+            (metadata::ROLLOUT, "true"),
+            (metadata::START_EPOCH, "6000"),
+            (metadata::START_VALUE, "0.2"),
+            (metadata::DURATION, "100"),
+        ]);
+        assert_eq!(rollout_progress(&rollout, 5999), Some(0.0));
+        assert_eq!(rollout_progress(&rollout, 6000), Some(0.2));
+        let halfway = rollout_progress(&rollout, 6000 + 50 * 60).unwrap();
+        assert!((halfway - 0.6).abs() < 1e-9);
+        assert_eq!(rollout_progress(&rollout, 6000 + 101 * 60), Some(1.0));
+
+        // Without duration, rollouts stay at their start value.
+        let stalled = release(&[(metadata::ROLLOUT, "true"), (metadata::START_VALUE, "0.3")]);
+        assert_eq!(rollout_progress(&stalled, 1_000_000), Some(0.3));
+    }
+
+    #[test]
+    fn test_rollout_of_release() {
+        let release = |entries: &[(&str, &str)]| CincinnatiPayload {
+            version: "35.1.0".to_string(),
+            metadata: entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            payload: String::new(),
+        };
+        assert_eq!(Rollout::of_release(&release(&[])), None);
+        assert_eq!(
+            Rollout::of_release(&release(&[
+                (metadata::ROLLOUT, "true"),
+                (metadata::START_EPOCH, "6000"),
+                (metadata::START_VALUE, "0.2"),
+                (metadata::DURATION, "0"),
+            ])),
+            Some(Rollout {
+                start_epoch: 6000,
+                start_value: 0.2,
+                duration_minutes: Some(1),
+            })
+        );
+        // Malformed parameters fall back to their defaults.
+        assert_eq!(
+            Rollout::of_release(&release(&[
+                (metadata::ROLLOUT, "true"),
+                (metadata::START_EPOCH, "soon"),
+                (metadata::DURATION, "forever"),
+            ])),
+            Some(Rollout {
+                start_epoch: 0,
+                start_value: 0.0,
+                duration_minutes: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_throttle_concurrent_rollouts() {
+        let node = |version: &str, rollout: Option<(i64, f64, u64)>| {
+            let mut metadata = HashMap::new();
+            if let Some((start_epoch, start_value, minutes)) = rollout {
+                metadata.insert(metadata::ROLLOUT.to_string(), "true".to_string());
+                metadata.insert(metadata::START_EPOCH.to_string(), start_epoch.to_string());
+                metadata.insert(metadata::START_VALUE.to_string(), start_value.to_string());
+                metadata.insert(metadata::DURATION.to_string(), minutes.to_string());
+            }
+            CincinnatiPayload {
+                version: version.to_string(),
+                metadata,
+                payload: String::new(),
+            }
+        };
+        // Two releases rolling out, currently at about 20% and 70%.
+        let now = chrono::Utc::now().timestamp();
+        let day = 24 * 60;
+        let input = Graph {
+            nodes: vec![
+                node("35.1.0", None),
+                node("35.2.0", Some((now - 1200, 0.2, day * 365))),
+                node("35.3.0", Some((now - 600, 0.7, day * 365))),
+            ],
+            edges: vec![(0, 1), (0, 2), (1, 2)],
+        };
+
+        let graph = throttle_rollouts(input.clone(), 0.1);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2), (1, 2)]);
+        let graph = throttle_rollouts(input.clone(), 0.5);
+        assert_eq!(graph.edges, vec![(0, 2), (1, 2)]);
+        let graph = throttle_rollouts(input.clone(), 0.9);
+        assert!(graph.edges.is_empty());
+    }
+
+    #[test]
+    fn test_limit_hops() {
+        let nodes = (0..6)
+            .map(|i| CincinnatiPayload {
+                version: format!("35.{}.0", i),
+                metadata: Default::default(),
+                payload: String::new(),
+            })
+            .collect();
+        let input = Graph {
+            nodes,
+            edges: vec![(0, 1), (0, 2), (1, 2), (2, 3), (3, 4), (1, 5), (5, 4)],
+        };
+
+        let graph = limit_hops(input.clone(), "35.0.0", 1);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2)]);
+        let graph = limit_hops(input.clone(), "35.0.0", 2);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2), (2, 3), (1, 5)]);
+        // `35.4.0` is three hops away, via either `35.3.0` or `35.5.0`.
+        let graph = limit_hops(input.clone(), "35.0.0", 3);
+        assert_eq!(
+            graph.edges,
+            vec![(0, 1), (0, 2), (2, 3), (3, 4), (1, 5), (5, 4)]
+        );
+
+        // Edges not on paths from the current release are kept.
+        let graph = limit_hops(input.clone(), "35.3.0", 1);
+        assert_eq!(graph.edges, input.edges);
+        let graph = limit_hops(input.clone(), "35.9.0", 1);
+        assert_eq!(graph.edges, input.edges);
+    }
+
+    #[test]
+    fn test_prefer_latest_edges() {
+        let nodes = ["35.1.0", "35.2.0", "35.10.0", "35.3.0", "36.1.0"]
+            .iter()
+            .map(|version| CincinnatiPayload {
+                version: version.to_string(),
+                metadata: Default::default(),
+                payload: String::new(),
+            })
+            .collect();
+        // `35.1.0` branches towards `35.2.0`, `35.10.0` and `35.3.0`, then
+        // `35.2.0` towards `35.10.0` and `36.1.0`.
+        let input = Graph {
+            nodes,
+            edges: vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 4), (3, 2), (0, 9)],
+        };
+
+        let graph = prefer_latest_edges(input.clone());
+        assert_eq!(graph.nodes.len(), input.nodes.len());
+        assert_eq!(graph.edges, vec![(0, 2), (1, 4), (3, 2), (0, 9)]);
+        // Idempotent.
+        assert_eq!(prefer_latest_edges(graph.clone()).edges, graph.edges);
+    }
+
+    #[test]
+    fn test_select_arch_metadata() {
+        let arch_key =
+            |basearch: &str| format!("{}.{}.ostree-commit", metadata::ARCH_PREFIX, basearch);
+        let mut entries = HashMap::new();
+        entries.insert(arch_key("x86_64"), "abcd".to_string());
+        entries.insert(arch_key("aarch64"), "ef01".to_string());
+        entries.insert(metadata::ARCH_PREFIX.to_string(), "multi".to_string());
+        entries.insert(
+            format!("{}.aarch64", metadata::ARCH_PREFIX),
+            "bare".to_string(),
+        );
+        entries.insert(metadata::AGE_INDEX.to_string(), "0".to_string());
+        let input = Graph {
+            nodes: vec![CincinnatiPayload {
+                version: "35.1.0".to_string(),
+                metadata: entries,
+                payload: "sha256:abcd".to_string(),
+            }],
+            edges: vec![],
+        };
+
+        let graph = select_arch_metadata(input, "x86_64");
+        let mut kept: Vec<&str> = graph.nodes[0].metadata.keys().map(String::as_str).collect();
+        kept.sort_unstable();
+        let x86_64_key = arch_key("x86_64");
+        let bare_key = format!("{}.aarch64", metadata::ARCH_PREFIX);
+        let mut expected = vec![
+            metadata::AGE_INDEX,
+            metadata::ARCH_PREFIX,
+            bare_key.as_str(),
+            x86_64_key.as_str(),
+        ];
+        expected.sort_unstable();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn test_block_versions() {
+        let nodes = ["35.1.0", "35.2.0", "35.3.0", "35.4.0"]
+            .iter()
+            .map(|version| CincinnatiPayload {
+                version: version.to_string(),
+                metadata: Default::default(),
+                payload: String::new(),
+            })
+            .collect();
+        let input = Graph {
+            nodes,
+            edges: vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)],
+        };
+        let blocked: HashSet<String> = vec!["35.2.0".to_string(), "36.1.0".to_string()]
+            .into_iter()
+            .collect();
+
+        let graph = block_versions(input.clone(), &blocked, Some("35.1.0"));
+        let versions: Vec<&str> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["35.1.0", "35.3.0", "35.4.0"]);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2), (1, 2)]);
+
+        // Clients on a blocked release still get updates away from it.
+        let graph = block_versions(input.clone(), &blocked, Some("35.2.0"));
+        assert_eq!(graph.nodes.len(), input.nodes.len());
+        assert_eq!(graph.edges, vec![(0, 2), (0, 3), (1, 2), (1, 3), (2, 3)]);
+
+        let graph = block_versions(input.clone(), &HashSet::new(), None);
+        assert_eq!(graph.edges, input.edges);
+    }
+
+    #[test]
+    fn test_filter_metadata() {
+        let mut entries = HashMap::new();
+        entries.insert(metadata::AGE_INDEX.to_string(), "0".to_string());
+        entries.insert("org.example.build-host".to_string(), "b1".to_string());
+        let input = Graph {
+            nodes: vec![CincinnatiPayload {
+                version: "35.1.0".to_string(),
+                metadata: entries,
+                payload: "sha256:abcd".to_string(),
+            }],
+            edges: vec![],
+        };
+
+        let graph = filter_metadata(input, |key| key != "org.example.build-host");
+        let kept = &graph.nodes[0].metadata;
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept.get(metadata::AGE_INDEX), Some(&"0".to_string()));
+    }
+
+    #[test]
+    fn test_rewrite_payload_prefix() {
+        let node = |payload: &str| CincinnatiPayload {
+            version: "35.1.0".to_string(),
+            metadata: Default::default(),
+            payload: payload.to_string(),
+        };
+        let input = Graph {
+            nodes: vec![
+                node("https://builds.example.com/prod/a.ociarchive"),
+                node("https://other.example.com/prod/b.ociarchive"),
+                node("sha256:abcd"),
+            ],
+            edges: vec![(0, 1)],
+        };
+
+        let graph = rewrite_payload_prefix(
+            input,
+            "https://builds.example.com/",
+            "https://mirror.example.org/fcos/",
+        );
+        assert_eq!(
+            graph.nodes[0].payload,
+            "https://mirror.example.org/fcos/prod/a.ociarchive"
+        );
+        assert_eq!(
+            graph.nodes[1].payload,
+            "https://other.example.com/prod/b.ociarchive"
+        );
+        assert_eq!(graph.nodes[2].payload, "sha256:abcd");
+        assert_eq!(graph.edges, vec![(0, 1)]);
+    }
+
+    /// Minimal xorshift PRNG, for reproducible randomized tests.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+    }
+
+    /// Random valid graph, with deadends and (non-progressing) rollouts.
+    fn random_graph(rng: &mut XorShift) -> Graph {
+        let size = rng.below(30) + 1;
+        let nodes = (0..size)
+            .map(|i| {
+                let mut metadata = HashMap::new();
+                if rng.below(5) == 0 {
+                    metadata.insert(metadata::DEADEND.to_string(), "true".to_string());
+                }
+                if rng.below(3) == 0 {
+                    let start_value = rng.below(11) as f64 / 10.0;
+                    metadata.insert(metadata::ROLLOUT.to_string(), "true".to_string());
+                    metadata.insert(metadata::START_VALUE.to_string(), start_value.to_string());
+                }
+                CincinnatiPayload {
+                    version: format!("35.{}.0", i),
+                    metadata,
+                    payload: format!("payload-{}", i),
+                }
+            })
+            .collect();
+        let edges = (0..rng.below(size * 3))
+            .map(|_| (rng.below(size), rng.below(size)))
+            .collect();
+        Graph { nodes, edges }
+    }
+
+    #[test]
+    fn test_policies_preserve_nodes() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let input = random_graph(&mut rng);
+            let wariness = rng.below(11) as f64 / 10.0;
+            let max_edges = rng.below(4) as usize + 1;
+            let throttled = throttle_rollouts(input.clone(), wariness);
+            let filtered = filter_deadends(throttled);
+            let (output, _) = limit_outgoing_edges(filtered, max_edges);
+
+            // Nodes are untouched, in upstream order.
+            let versions =
+                |g: &Graph| -> Vec<String> { g.nodes.iter().map(|n| n.version.clone()).collect() };
+            assert_eq!(versions(&output), versions(&input));
+
+            // Edges are an ordered subset of the input edges, still
+            // referencing the intended releases.
+            let mut remaining = input.edges.iter();
+            for (from, to) in &output.edges {
+                assert!(remaining.any(|edge| edge == &(*from, *to)));
+                let (from, to) = (&output.nodes[*from as usize], &output.nodes[*to as usize]);
+                assert_ne!(from.metadata.get(metadata::DEADEND), Some(&"true".into()));
+                if let Some(start) = to.metadata.get(metadata::START_VALUE) {
+                    assert!(wariness <= start.parse::<f64>().unwrap());
+                }
+            }
+
+            // Edges are only dropped by the policies themselves.
+            let kept = input
+                .edges
+                .iter()
+                .filter(|(from, to)| {
+                    let (from, to) = (&input.nodes[*from as usize], &input.nodes[*to as usize]);
+                    let deadend = from.metadata.contains_key(metadata::DEADEND);
+                    let throttled = to
+                        .metadata
+                        .get(metadata::START_VALUE)
+                        .map(|start| wariness > start.parse::<f64>().unwrap())
+                        .unwrap_or(false);
+                    !deadend && !throttled
+                })
+                .count();
+            assert!(output.edges.len() <= kept);
+            assert!(output.edges.len() >= kept.min(max_edges));
+        }
+    }
+
+    #[test]
+    fn test_limit_hops_paths() {
+        // Hop distances from a release, over the given edges.
+        let hops = |edges: &[(u64, u64)], start: u64| {
+            let mut distances = HashMap::new();
+            distances.insert(start, 0usize);
+            let mut frontier = vec![start];
+            while !frontier.is_empty() {
+                let mut next = vec![];
+                for (from, to) in edges {
+                    if frontier.contains(from) && !distances.contains_key(to) {
+                        distances.insert(*to, distances[from] + 1);
+                        next.push(*to);
+                    }
+                }
+                frontier = next;
+            }
+            distances
+        };
+
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let input = random_graph(&mut rng);
+            let start = rng.below(input.nodes.len() as u64);
+            let max_hops = rng.below(4) as usize + 1;
+            let version = input.nodes[start as usize].version.clone();
+            let output = limit_hops(input.clone(), &version, max_hops);
+
+            // No path from the current release is longer than the limit.
+            let mut paths = vec![(start, 0usize)];
+            while let Some((from, length)) = paths.pop() {
+                assert!(length <= max_hops);
+                for (_, to) in output.edges.iter().filter(|(f, _)| *f == from) {
+                    paths.push((*to, length + 1));
+                }
+            }
+
+            // Releases within the limit are still reachable, in as few hops.
+            let before = hops(&input.edges, start);
+            let after = hops(&output.edges, start);
+            for (release, distance) in before.iter().filter(|(_, d)| **d <= max_hops) {
+                assert_eq!(after.get(release), Some(distance));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(
+            compare_versions("35.20220327.3.0", "35.20220327.3.0"),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_versions("35.20220327.3.0", "35.20220410.3.1"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("36.20220505.3.2", "35.20220410.3.1"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_versions("35.1", "35.1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("35.10", "35.9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_below_version_floor() {
+        let floor = "35.20220327.3.0";
+        assert!(below_version_floor("34.20220101.3.0", floor));
+        assert!(!below_version_floor("35.20220327.3.0", floor));
+        assert!(!below_version_floor("35.20220410.3.1", floor));
+    }
+}
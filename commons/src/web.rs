@@ -1,13 +1,59 @@
 use crate::graph::GraphScope;
 use actix_cors::CorsFactory;
-use failure::{bail, ensure, err_msg};
+use actix_web::http::{HeaderName, Method};
+use failure::{bail, ensure, err_msg, format_err};
 use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// CORS policy, besides allowed origins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsPolicy {
+    /// Methods allowed in CORS requests.
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in CORS requests, `None` allowing any.
+    pub allowed_headers: Option<Vec<String>>,
+    /// Response headers exposed to CORS clients.
+    pub exposed_headers: Vec<String>,
+}
+
+impl Default for CorsPolicy {
+    /// Read-only requests, including conditional ones (`If-None-Match`,
+    /// `If-Modified-Since`), with the `ETag` of responses exposed.
+    fn default() -> Self {
+        Self {
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+            allowed_headers: None,
+            exposed_headers: vec!["ETag".to_string()],
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Check that methods and header names are valid.
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        ensure!(!self.allowed_methods.is_empty(), "no CORS allowed methods");
+        for method in &self.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| format_err!("invalid CORS allowed method '{}'", method))?;
+        }
+        let headers = self.allowed_headers.iter().flatten();
+        for header in headers.chain(&self.exposed_headers) {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format_err!("invalid CORS header name '{}'", header))?;
+        }
+        Ok(())
+    }
+}
 
 /// Build a CORS middleware.
 ///
 /// By default, this allows all CORS requests from all origins.
 /// If an allowlist is provided, only those origins are allowed instead.
-pub fn build_cors_middleware(origin_allowlist: &Option<Vec<String>>) -> CorsFactory {
+/// Methods and headers follow the given policy, which must be valid.
+pub fn build_cors_middleware(
+    origin_allowlist: &Option<Vec<String>>,
+    policy: &CorsPolicy,
+) -> CorsFactory {
     let mut builder = actix_cors::Cors::new();
     match origin_allowlist {
         Some(allowed) => {
@@ -19,6 +65,13 @@ pub fn build_cors_middleware(origin_allowlist: &Option<Vec<String>>) -> CorsFact
             builder = builder.send_wildcard();
         }
     };
+    builder = builder.allowed_methods(policy.allowed_methods.iter().map(String::as_str));
+    if let Some(headers) = &policy.allowed_headers {
+        builder = builder.allowed_headers(headers.iter().map(String::as_str));
+    }
+    if !policy.exposed_headers.is_empty() {
+        builder = builder.expose_headers(policy.exposed_headers.iter().map(String::as_str));
+    }
     builder.finish()
 }
 
@@ -50,9 +103,115 @@ pub fn validate_scope(
     Ok(scope)
 }
 
+/// Ensure the main service and status servers listen on distinct ports.
+pub fn ensure_distinct_ports(
+    service: SocketAddr,
+    status: SocketAddr,
+) -> Result<(), failure::Error> {
+    ensure!(
+        service.port() != status.port(),
+        "main service and status service cannot share port {}",
+        service.port()
+    );
+    Ok(())
+}
+
+/// Turn a failure to bind a server socket into an actionable error.
+pub fn bind_error(err: std::io::Error, name: &str, addr: SocketAddr) -> failure::Error {
+    let hint = match err.kind() {
+        std::io::ErrorKind::PermissionDenied if addr.port() < 1024 => {
+            " (binding ports below 1024 requires the CAP_NET_BIND_SERVICE capability, \
+             e.g. via `AmbientCapabilities=CAP_NET_BIND_SERVICE` in a systemd unit)"
+        }
+        std::io::ErrorKind::AddrInUse => " (is another instance already running?)",
+        _ => "",
+    };
+    format_err!("failed to bind {} to {}: {}{}", name, addr, err, hint)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn test_cors_conditional_get() {
+        let origins = Some(vec!["https://example.com".to_string()]);
+        let policy = CorsPolicy::default();
+        policy.validate().unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(build_cors_middleware(&origins, &policy))
+                .route(
+                    "/v1/graph",
+                    web::get().to(|| HttpResponse::Ok().header(header::ETAG, "\"x\"").finish()),
+                ),
+        )
+        .await;
+
+        // Preflight for a conditional GET.
+        let req = test::TestRequest::with_uri("/v1/graph")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "if-none-match, if-modified-since",
+            )
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://example.com"
+        );
+        let allowed = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_ascii_lowercase();
+        assert!(allowed.contains("if-none-match") && allowed.contains("if-modified-since"));
+
+        // Preflight for a method not allowed.
+        let req = test::TestRequest::with_uri("/v1/graph")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // The ETag is exposed on actual requests.
+        let req = test::TestRequest::get()
+            .uri("/v1/graph")
+            .header(header::ORIGIN, "https://example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+                .unwrap(),
+            "etag"
+        );
+    }
+
+    #[test]
+    fn test_cors_policy_validate() {
+        let policy = |methods: &[&str], headers: &[&str]| CorsPolicy {
+            allowed_methods: methods.iter().map(|m| m.to_string()).collect(),
+            allowed_headers: Some(headers.iter().map(|h| h.to_string()).collect()),
+            exposed_headers: vec![],
+        };
+        policy(&["GET"], &["If-None-Match"]).validate().unwrap();
+        assert!(policy(&[], &[]).validate().is_err());
+        assert!(policy(&["G ET"], &[]).validate().is_err());
+        assert!(policy(&["GET"], &["If None Match"]).validate().is_err());
+    }
 
     #[test]
     fn test_validate_scope() {
@@ -91,4 +250,23 @@ mod tests {
             assert!(r.is_ok());
         }
     }
+
+    #[test]
+    fn test_ensure_distinct_ports() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        ensure_distinct_ports(addr("0.0.0.0:8081"), addr("0.0.0.0:9081")).unwrap();
+        let err = ensure_distinct_ports(addr("0.0.0.0:8081"), addr("127.0.0.1:8081")).unwrap_err();
+        assert!(err.to_string().contains("port 8081"));
+    }
+
+    #[test]
+    fn test_bind_error() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let denied = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let msg = bind_error(denied(), "main service", addr("0.0.0.0:443")).to_string();
+        assert!(msg.starts_with("failed to bind main service to 0.0.0.0:443: "));
+        assert!(msg.contains("CAP_NET_BIND_SERVICE"));
+        let msg = bind_error(denied(), "main service", addr("0.0.0.0:8081")).to_string();
+        assert!(!msg.contains("CAP_NET_BIND_SERVICE"));
+    }
 }
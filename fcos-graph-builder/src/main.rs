@@ -13,7 +13,7 @@ use actix_web::{web, App, HttpResponse};
 use commons::{graph, metrics};
 use failure::{Fallible, ResultExt};
 use prometheus::{IntCounterVec, IntGauge, IntGaugeVec};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use structopt::clap::{crate_name, crate_version};
 use structopt::StructOpt;
@@ -101,11 +101,14 @@ fn main() -> Fallible<()> {
         App::new()
             .wrap(commons::web::build_cors_middleware(
                 &service_settings.origin_allowlist,
+                &commons::web::CorsPolicy::default(),
             ))
             .data(gb_service.clone())
             .route("/v1/graph", web::get().to(gb_serve_graph))
+            .route("/v1/scopes", web::get().to(gb_serve_scopes))
     })
-    .bind(service_socket)?
+    .bind(service_socket)
+    .map_err(|e| commons::web::bind_error(e, "main service", service_socket))?
     .run();
 
     // Graph-builder status service.
@@ -117,7 +120,8 @@ fn main() -> Fallible<()> {
             .data(gb_status.clone())
             .route("/metrics", web::get().to(metrics::serve_metrics))
     })
-    .bind(status_socket)?
+    .bind(status_socket)
+    .map_err(|e| commons::web::bind_error(e, "status service", status_socket))?
     .run();
 
     sys.run()?;
@@ -137,6 +141,19 @@ struct GraphQuery {
     stream: Option<String>,
 }
 
+/// Graph scopes served by this graph-builder.
+#[derive(Serialize)]
+struct Scopes<'a> {
+    scopes: Vec<&'a graph::GraphScope>,
+}
+
+/// Serve the list of available graph scopes, for discovery by policy-engine.
+pub(crate) async fn gb_serve_scopes(data: web::Data<AppState>) -> HttpResponse {
+    let mut scopes: Vec<&graph::GraphScope> = data.scrapers.keys().collect();
+    scopes.sort();
+    HttpResponse::Ok().json(Scopes { scopes })
+}
+
 pub(crate) async fn gb_serve_graph(
     data: web::Data<AppState>,
     web::Query(query): web::Query<GraphQuery>,
@@ -15,6 +15,10 @@ impl GraphBuilderSettings {
     pub fn validate_config(_cfg: FileConfig) -> Fallible<Self> {
         // TODO(lucab): translate config entries.
         let settings = GraphBuilderSettings::default();
+        commons::web::ensure_distinct_ports(
+            settings.service.socket_addr(),
+            settings.status.socket_addr(),
+        )?;
         Ok(settings)
     }
 }
@@ -0,0 +1,185 @@
+//! Admin endpoints, served on the status service.
+//!
+//! Admin requests must carry the configured token as a bearer token
+//! (`Authorization: Bearer <token>`). The token file is re-read on each
+//! request, so that it can be rotated without a restart.
+//!
+//! Every admin request, authorized or not, is recorded as a JSON entry in
+//! the audit log, a dedicated log target enabled regardless of verbosity.
+
+use crate::settings::AdminSettings;
+use actix_web::http::{header, HeaderMap};
+use actix_web::HttpRequest;
+use commons::graph::GraphScope;
+use serde::{Deserialize, Serialize};
+
+/// Log target of admin actions audit entries.
+pub(crate) static AUDIT_LOG_TARGET: &str = "fcos_policy_engine::audit";
+
+/// Identity of requests authenticated with the admin token.
+pub(crate) static TOKEN_IDENTITY: &str = "admin-token";
+
+/// Whether a request carries the admin token.
+pub(crate) fn authorized(settings: &AdminSettings, headers: &HeaderMap) -> bool {
+    let token = match std::fs::read_to_string(&settings.token_path) {
+        Ok(token) => token,
+        Err(e) => {
+            log::error!(
+                "failed to read admin token file '{}': {}",
+                settings.token_path.display(),
+                e
+            );
+            return false;
+        }
+    };
+    let token = token.trim();
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if !token.is_empty() => {
+            ring::constant_time::verify_slices_are_equal(provided.as_bytes(), token.as_bytes())
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Audit log entry of an admin action.
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditEntry<'a> {
+    /// Admin action, e.g. `flush-cache`.
+    pub(crate) action: &'a str,
+    /// Action parameters, as requested.
+    pub(crate) params: serde_json::Value,
+    /// IP address of the requesting peer, if known.
+    pub(crate) source_ip: Option<String>,
+    /// Authenticated identity, if the request was authorized.
+    pub(crate) identity: Option<&'a str>,
+    /// Outcome of the request: `success`, `unauthorized` or `invalid`.
+    pub(crate) outcome: &'a str,
+}
+
+impl<'a> AuditEntry<'a> {
+    /// Audit entry of an admin request, without parameters.
+    pub(crate) fn new(req: &HttpRequest, action: &'a str, identity: Option<&'a str>) -> Self {
+        Self {
+            action,
+            params: serde_json::Value::Null,
+            source_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            identity,
+            outcome: "success",
+        }
+    }
+
+    /// Write the entry to the audit log.
+    pub(crate) fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(entry) => log::info!(target: AUDIT_LOG_TARGET, "audit: {}", entry),
+            Err(e) => log::error!("failed to serialize audit log entry: {}", e),
+        }
+    }
+}
+
+/// Scopes selected by a cache flush request, all of them by default.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FlushQuery {
+    pub(crate) stream: Option<String>,
+    pub(crate) basearch: Option<String>,
+}
+
+impl FlushQuery {
+    /// Whether a scope is selected for flushing.
+    pub(crate) fn matches(&self, scope: &GraphScope) -> bool {
+        let stream = match &self.stream {
+            Some(stream) => *stream == scope.stream,
+            None => true,
+        };
+        let basearch = match &self.basearch {
+            Some(basearch) => *basearch == scope.basearch,
+            None => true,
+        };
+        stream && basearch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+    use std::io::Write;
+
+    #[test]
+    fn test_authorized() {
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        let settings = AdminSettings {
+            token_path: token_file.path().to_path_buf(),
+        };
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        // An empty token never matches.
+        assert!(!authorized(&settings, &headers("Bearer ")));
+
+        token_file.write_all(b"s3cret\n").unwrap();
+        assert!(authorized(&settings, &headers("Bearer s3cret")));
+        assert!(!authorized(&settings, &headers("Bearer s3cre")));
+        assert!(!authorized(&settings, &headers("Basic s3cret")));
+        assert!(!authorized(&settings, &HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_audit_entry() {
+        let req = actix_web::test::TestRequest::post()
+            .uri("/admin/flush-cache?stream=stable")
+            .peer_addr("192.0.2.7:41234".parse().unwrap())
+            .to_http_request();
+        let mut entry = AuditEntry::new(&req, "flush-cache", Some(TOKEN_IDENTITY));
+        entry.params = serde_json::to_value(FlushQuery {
+            stream: Some("stable".to_string()),
+            basearch: None,
+        })
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            serde_json::json!({
+                "action": "flush-cache",
+                "params": {"stream": "stable", "basearch": null},
+                "source_ip": "192.0.2.7",
+                "identity": "admin-token",
+                "outcome": "success",
+            })
+        );
+
+        let req = actix_web::test::TestRequest::post().to_http_request();
+        let entry = AuditEntry::new(&req, "flush-cache", None);
+        assert!(entry.source_ip.is_none());
+        assert!(entry.identity.is_none());
+    }
+
+    #[test]
+    fn test_flush_query_matches() {
+        let scope = |basearch: &str, stream: &str| GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        assert!(FlushQuery::default().matches(&scope("x86_64", "stable")));
+        let stable = FlushQuery {
+            stream: Some("stable".to_string()),
+            basearch: None,
+        };
+        assert!(stable.matches(&scope("aarch64", "stable")));
+        assert!(!stable.matches(&scope("x86_64", "testing")));
+        let stable_x86 = FlushQuery {
+            basearch: Some("x86_64".to_string()),
+            ..stable
+        };
+        assert!(stable_x86.matches(&scope("x86_64", "stable")));
+        assert!(!stable_x86.matches(&scope("aarch64", "stable")));
+    }
+}
@@ -0,0 +1,213 @@
+//! Allowlist of graph scopes clients can request.
+//!
+//! Allowed scopes are either statically configured, or discovered from the
+//! graph-builder `/v1/scopes` endpoint and periodically refreshed. The last
+//! discovered list is kept when a refresh fails; until a discovery succeeds,
+//! the static allowlist (if any) applies.
+//!
+//! Allowed scopes are also published on `/v1/streams`, grouped by stream.
+
+use crate::settings::{AllowlistSettings, DiscoverySettings, UpstreamSettings};
+use commons::graph::GraphScope;
+use failure::{ensure, Fallible};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Timeout of requests to the scopes discovery endpoint.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Current allowlist of graph scopes.
+#[derive(Debug)]
+pub(crate) struct ScopeAllowlist {
+    static_scopes: Arc<Option<HashSet<GraphScope>>>,
+    discovered: RwLock<Option<Arc<Option<HashSet<GraphScope>>>>>,
+    discovery: Option<DiscoverySettings>,
+}
+
+/// Response of the graph-builder scopes endpoint.
+#[derive(Debug, Deserialize)]
+struct DiscoveredScopes {
+    scopes: Vec<GraphScope>,
+}
+
+impl ScopeAllowlist {
+    pub(crate) fn new(settings: &AllowlistSettings) -> Self {
+        Self {
+            static_scopes: Arc::new(settings.scopes.clone()),
+            discovered: RwLock::new(None),
+            discovery: settings.discovery.clone(),
+        }
+    }
+
+    /// Currently allowed scopes, `None` allowing all scopes.
+    pub(crate) fn current(&self) -> Arc<Option<HashSet<GraphScope>>> {
+        let discovered = self.discovered.read().unwrap_or_else(|e| e.into_inner());
+        match &*discovered {
+            Some(scopes) => Arc::clone(scopes),
+            None => Arc::clone(&self.static_scopes),
+        }
+    }
+
+    /// Settings for scopes discovery, if enabled.
+    pub(crate) fn discovery(&self) -> Option<&DiscoverySettings> {
+        self.discovery.as_ref()
+    }
+
+    /// Refresh discovered scopes, keeping the previous ones on failure.
+    pub(crate) async fn refresh(&self, client: &reqwest::Client, upstream: &UpstreamSettings) {
+        let discovery = match &self.discovery {
+            Some(discovery) => discovery,
+            None => return,
+        };
+        match discover_scopes(client, upstream, &discovery.url).await {
+            Ok(scopes) => {
+                crate::metrics::SCOPE_DISCOVERY_REFRESHES
+                    .with_label_values(&["success"])
+                    .inc();
+                log::debug!("discovered {} allowed scopes", scopes.len());
+                let mut discovered = self.discovered.write().unwrap_or_else(|e| e.into_inner());
+                *discovered = Some(Arc::new(Some(scopes)));
+            }
+            Err(e) => {
+                crate::metrics::SCOPE_DISCOVERY_REFRESHES
+                    .with_label_values(&["failure"])
+                    .inc();
+                log::warn!("failed to discover allowed scopes: {}", e);
+            }
+        }
+    }
+}
+
+/// Group scopes by stream, in stable (sorted) order.
+pub(crate) fn streams(scopes: &HashSet<GraphScope>) -> BTreeMap<String, BTreeSet<String>> {
+    let mut streams = BTreeMap::<String, BTreeSet<String>>::new();
+    for scope in scopes {
+        streams
+            .entry(scope.stream.clone())
+            .or_default()
+            .insert(scope.basearch.clone());
+    }
+    streams
+}
+
+/// Fetch the scopes available on the graph-builder.
+async fn discover_scopes(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    url: &reqwest::Url,
+) -> Fallible<HashSet<GraphScope>> {
+    let req = crate::utils::new_request(client, reqwest::Method::GET, url.clone(), upstream)?;
+    let resp = req.timeout(DISCOVERY_TIMEOUT).send().await?;
+    let discovered = resp.error_for_status()?.json::<DiscoveredScopes>().await?;
+    ensure!(!discovered.scopes.is_empty(), "no scopes discovered");
+    Ok(discovered.scopes.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn scope(basearch: &str, stream: &str) -> GraphScope {
+        GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_discovery() {
+        let available = Arc::new(AtomicBool::new(false));
+        let srv_available = Arc::clone(&available);
+        let srv = actix_web::test::start(move || {
+            let available = Arc::clone(&srv_available);
+            App::new().route(
+                "/v1/scopes",
+                web::get().to(move || {
+                    let available = available.load(Ordering::SeqCst);
+                    async move {
+                        let resp = if available {
+                            HttpResponse::Ok().json(serde_json::json!({
+                                "scopes": [
+                                    {"basearch": "x86_64", "stream": "stable"},
+                                    {"basearch": "aarch64", "stream": "stable"},
+                                ]
+                            }))
+                        } else {
+                            HttpResponse::ServiceUnavailable().finish()
+                        };
+                        Ok::<_, actix_web::Error>(resp)
+                    }
+                }),
+            )
+        });
+
+        let settings = AllowlistSettings {
+            scopes: Some(vec![scope("x86_64", "testing")].into_iter().collect()),
+            discovery: Some(DiscoverySettings {
+                url: reqwest::Url::parse(&srv.url("/v1/scopes")).unwrap(),
+                refresh: Duration::from_secs(60),
+            }),
+        };
+        let upstream = UpstreamSettings::default();
+        let allowlist = ScopeAllowlist::new(&settings);
+        let allowed = |allowlist: &ScopeAllowlist, s: &GraphScope| {
+            allowlist.current().as_ref().as_ref().unwrap().contains(s)
+        };
+
+        // Static allowlist as a fallback, until discovery succeeds.
+        allowlist
+            .refresh(&crate::utils::new_client(&upstream).unwrap(), &upstream)
+            .await;
+        assert!(allowed(&allowlist, &scope("x86_64", "testing")));
+        assert!(!allowed(&allowlist, &scope("x86_64", "stable")));
+
+        available.store(true, Ordering::SeqCst);
+        allowlist
+            .refresh(&crate::utils::new_client(&upstream).unwrap(), &upstream)
+            .await;
+        assert!(allowed(&allowlist, &scope("x86_64", "stable")));
+        assert!(allowed(&allowlist, &scope("aarch64", "stable")));
+        assert!(!allowed(&allowlist, &scope("x86_64", "testing")));
+
+        // Discovered scopes are kept on failures.
+        available.store(false, Ordering::SeqCst);
+        allowlist
+            .refresh(&crate::utils::new_client(&upstream).unwrap(), &upstream)
+            .await;
+        assert!(allowed(&allowlist, &scope("x86_64", "stable")));
+    }
+
+    #[test]
+    fn test_streams() {
+        let scopes = vec![
+            scope("x86_64", "testing"),
+            scope("x86_64", "stable"),
+            scope("aarch64", "stable"),
+        ]
+        .into_iter()
+        .collect();
+        let streams = streams(&scopes);
+        let listed: Vec<(&str, Vec<&str>)> = streams
+            .iter()
+            .map(|(stream, arches)| (stream.as_str(), arches.iter().map(String::as_str).collect()))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("stable", vec!["aarch64", "x86_64"]),
+                ("testing", vec!["x86_64"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_allowlist() {
+        let allowlist = ScopeAllowlist::new(&AllowlistSettings::default());
+        assert!(allowlist.current().is_none());
+        assert!(allowlist.discovery().is_none());
+    }
+}
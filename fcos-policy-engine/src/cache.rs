@@ -0,0 +1,555 @@
+//! In-process cache of upstream graphs.
+
+use crate::clock::Clock;
+use chrono::{DateTime, TimeZone, Utc};
+use commons::graph::{Graph, GraphScope};
+use failure::{bail, format_err, Fallible};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Cache of upstream graphs, keyed by scope.
+///
+/// Entries older than the TTL are refreshed from upstream on access, but
+/// are retained so that they can still be served if the upstream fails,
+/// until reaped for being idle.
+#[derive(Debug)]
+pub(crate) struct GraphCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    entries: RwLock<HashMap<GraphScope, CacheEntry>>,
+}
+
+/// Cache slot, tracking the last access (milliseconds since epoch) to its
+/// graph and the serialized size of the latter.
+#[derive(Debug)]
+struct CacheEntry {
+    cached: CachedGraph,
+    accessed: AtomicI64,
+    size: usize,
+}
+
+/// Cache entry, as persisted to disk.
+#[derive(Debug, Deserialize, Serialize)]
+struct PersistedEntry {
+    scope: GraphScope,
+    graph: Graph,
+    /// Fetch time, in milliseconds since epoch.
+    fetched_ms: i64,
+}
+
+/// How a graph was obtained, with respect to the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CacheStatus {
+    /// Served from a fresh cache entry (or a pinned snapshot).
+    Hit,
+    /// Freshly fetched from upstream.
+    Miss,
+    /// Served from an expired entry, after an upstream failure (or with
+    /// the upstream fetch suppressed by the minimum fetch interval, or a
+    /// shrunk upstream graph refused), or while it is refreshed in the
+    /// background.
+    Stale,
+}
+
+impl CacheStatus {
+    /// Value for the `X-Cache` response header.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+        }
+    }
+}
+
+/// Cached graph entry.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedGraph {
+    pub(crate) graph: Graph,
+    pub(crate) fetched: DateTime<Utc>,
+}
+
+impl CachedGraph {
+    /// Age of this entry, at the given time.
+    pub(crate) fn age(&self, now: DateTime<Utc>) -> Duration {
+        now.signed_duration_since(self.fetched)
+            .to_std()
+            .unwrap_or_default()
+    }
+}
+
+impl GraphCache {
+    pub(crate) fn new(ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ttl,
+            clock,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Return the cached entry for a scope, if any, regardless of its age.
+    pub(crate) fn get(&self, scope: &GraphScope) -> Option<CachedGraph> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get(scope)?;
+        let now = self.clock.now().timestamp_millis();
+        entry.accessed.store(now, Ordering::Relaxed);
+        Some(entry.cached.clone())
+    }
+
+    /// Whether the given entry is still within its TTL.
+    pub(crate) fn is_fresh(&self, entry: &CachedGraph) -> bool {
+        entry.age(self.clock.now()) < self.ttl
+    }
+
+    /// Store a freshly fetched graph for a scope, returning the new entry.
+    pub(crate) fn insert(&self, scope: GraphScope, graph: Graph) -> CachedGraph {
+        let entry = CachedGraph {
+            graph,
+            fetched: self.clock.now(),
+        };
+        let slot = CacheEntry {
+            size: serialized_size(&entry.graph),
+            cached: entry.clone(),
+            accessed: AtomicI64::new(entry.fetched.timestamp_millis()),
+        };
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        entries.insert(scope, slot);
+        update_size_gauge(&entries);
+        entry
+    }
+
+    /// Restamp the entry of a scope as fetched now, returning it, so that
+    /// it is kept as fresh instead of being refreshed.
+    pub(crate) fn renew(&self, scope: &GraphScope) -> Option<CachedGraph> {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get_mut(scope)?;
+        entry.cached.fetched = self.clock.now();
+        Some(entry.cached.clone())
+    }
+
+    /// Remove the entries of selected scopes, returning these scopes.
+    pub(crate) fn flush(&self, selected: impl Fn(&GraphScope) -> bool) -> Vec<GraphScope> {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let flushed: Vec<GraphScope> = entries.keys().filter(|s| selected(s)).cloned().collect();
+        for scope in &flushed {
+            entries.remove(scope);
+        }
+        update_size_gauge(&entries);
+        flushed
+    }
+
+    /// Remove the entries not accessed for at least `max_idle`, returning
+    /// how many were removed.
+    pub(crate) fn reap_idle(&self, max_idle: Duration) -> usize {
+        let cutoff = self.clock.now().timestamp_millis() - max_idle.as_millis() as i64;
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let before = entries.len();
+        entries.retain(|_, entry| entry.accessed.load(Ordering::Relaxed) > cutoff);
+        update_size_gauge(&entries);
+        before - entries.len()
+    }
+
+    /// Total serialized size of the cached graphs, in bytes.
+    pub(crate) fn size_bytes(&self) -> usize {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        total_size(&entries)
+    }
+
+    /// Age of the oldest cached entry, if any.
+    pub(crate) fn oldest_age(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries.values().map(|entry| entry.cached.age(now)).max()
+    }
+
+    /// Persist all entries to a file, returning how many were written.
+    ///
+    /// Entries are written to a temporary file renamed over the previous
+    /// one, so that a crash while flushing never leaves a truncated file.
+    pub(crate) fn save(&self, path: &Path) -> Fallible<usize> {
+        let persisted: Vec<PersistedEntry> = {
+            let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+            entries
+                .iter()
+                .map(|(scope, entry)| PersistedEntry {
+                    scope: scope.clone(),
+                    graph: entry.cached.graph.clone(),
+                    fetched_ms: entry.cached.fetched.timestamp_millis(),
+                })
+                .collect()
+        };
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&persisted)?)
+            .map_err(|e| format_err!("failed to write cache file '{}': {}", path.display(), e))?;
+        std::fs::rename(&tmp_path, path)
+            .map_err(|e| format_err!("failed to replace cache file '{}': {}", path.display(), e))?;
+        Ok(persisted.len())
+    }
+
+    /// Load the entries persisted to a file, returning how many were loaded.
+    ///
+    /// Expired entries are discarded, as are entries for scopes already
+    /// cached. A missing file is not an error, and loads nothing.
+    pub(crate) fn load(&self, path: &Path) -> Fallible<usize> {
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => {
+                bail!("failed to read cache file '{}': {}", path.display(), e)
+            }
+        };
+        let persisted: Vec<PersistedEntry> = serde_json::from_slice(&content)
+            .map_err(|e| format_err!("failed to parse cache file '{}': {}", path.display(), e))?;
+
+        let now = self.clock.now().timestamp_millis();
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let mut loaded = 0;
+        for entry in persisted {
+            let fetched = match Utc.timestamp_millis_opt(entry.fetched_ms).single() {
+                Some(fetched) => fetched,
+                None => continue,
+            };
+            let cached = CachedGraph {
+                graph: entry.graph,
+                fetched,
+            };
+            if !self.is_fresh(&cached) || entries.contains_key(&entry.scope) {
+                continue;
+            }
+            let slot = CacheEntry {
+                size: serialized_size(&cached.graph),
+                cached,
+                accessed: AtomicI64::new(now),
+            };
+            entries.insert(entry.scope, slot);
+            loaded += 1;
+        }
+        update_size_gauge(&entries);
+        Ok(loaded)
+    }
+}
+
+/// Limiter of upstream fetches, allowing at most one fetch per scope within
+/// each interval, regardless of the cache TTL.
+///
+/// Fetch attempts count whatever their outcome, so that a failing upstream
+/// is not queried more often either.
+#[derive(Debug)]
+pub(crate) struct FetchLimiter {
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    last_fetch: Mutex<HashMap<GraphScope, DateTime<Utc>>>,
+}
+
+impl FetchLimiter {
+    pub(crate) fn new(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            interval,
+            clock,
+            last_fetch: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an upstream fetch for a scope, unless one already happened
+    /// within the interval, in which case the fetch must be suppressed.
+    pub(crate) fn try_fetch(&self, scope: &GraphScope) -> bool {
+        let now = self.clock.now();
+        // Times in the future (after clock adjustments) count as recent.
+        let recent = |at: &DateTime<Utc>| match now.signed_duration_since(*at).to_std() {
+            Ok(elapsed) => elapsed < self.interval,
+            Err(_) => true,
+        };
+        let mut last_fetch = self.last_fetch.lock().unwrap_or_else(|e| e.into_inner());
+        match last_fetch.get(scope) {
+            Some(at) if recent(at) => return false,
+            Some(_) => {}
+            // Forget expired scopes, bounding memory to the recently fetched ones.
+            None => last_fetch.retain(|_, at| recent(at)),
+        }
+        last_fetch.insert(scope.clone(), now);
+        true
+    }
+}
+
+/// Tracker of stale-while-revalidate refreshes.
+///
+/// Within the window past the TTL, expired entries are served as is while
+/// a single background refresh per scope is in flight.
+#[derive(Debug)]
+pub(crate) struct Revalidator {
+    /// Maximum age of entries served while revalidating (TTL plus window).
+    max_age: Duration,
+    clock: Arc<dyn Clock>,
+    in_flight: Mutex<HashSet<GraphScope>>,
+}
+
+impl Revalidator {
+    pub(crate) fn new(ttl: Duration, window: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            max_age: ttl + window,
+            clock,
+            in_flight: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether an expired entry is recent enough to be served while it is
+    /// refreshed in the background.
+    pub(crate) fn is_revalidatable(&self, entry: &CachedGraph) -> bool {
+        entry.age(self.clock.now()) < self.max_age
+    }
+
+    /// Record a background refresh for a scope, unless one is already in
+    /// flight, in which case it must not be started.
+    pub(crate) fn try_start(&self, scope: &GraphScope) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.insert(scope.clone())
+    }
+
+    /// Record the end of the background refresh of a scope.
+    pub(crate) fn finish(&self, scope: &GraphScope) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(scope);
+    }
+}
+
+/// Serialized (JSON) size of a graph, as an estimate of its memory footprint.
+fn serialized_size(graph: &Graph) -> usize {
+    serde_json::to_vec(graph)
+        .map(|bytes| bytes.len())
+        .unwrap_or(0)
+}
+
+/// Total serialized size of cache entries, in bytes.
+fn total_size(entries: &HashMap<GraphScope, CacheEntry>) -> usize {
+    entries.values().map(|entry| entry.size).sum()
+}
+
+/// Update the cache memory gauge, after inserting or evicting entries.
+fn update_size_gauge(entries: &HashMap<GraphScope, CacheEntry>) {
+    crate::metrics::CACHE_GRAPHS_BYTES.set(total_size(entries) as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{MockClock, SystemClock};
+
+    #[test]
+    fn test_graph_cache() {
+        let scope = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+
+        let cache = GraphCache::new(Duration::from_secs(3600), Arc::new(SystemClock));
+        assert!(cache.get(&scope).is_none());
+        assert!(cache.oldest_age().is_none());
+        cache.insert(scope.clone(), Graph::default());
+        let entry = cache.get(&scope).unwrap();
+        assert!(cache.is_fresh(&entry));
+        assert!(cache.oldest_age().is_some());
+
+        let expired = GraphCache::new(Duration::from_secs(0), Arc::new(SystemClock));
+        expired.insert(scope.clone(), Graph::default());
+        let entry = expired.get(&scope).unwrap();
+        assert!(!expired.is_fresh(&entry));
+    }
+
+    #[test]
+    fn test_graph_cache_renew() {
+        let scope = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let cache = GraphCache::new(Duration::from_secs(30), clock.clone());
+        assert!(cache.renew(&scope).is_none());
+        cache.insert(scope.clone(), Graph::default());
+        clock.advance(Duration::from_secs(31));
+        assert!(!cache.is_fresh(&cache.get(&scope).unwrap()));
+        let renewed = cache.renew(&scope).unwrap();
+        assert!(cache.is_fresh(&renewed));
+        assert!(cache.is_fresh(&cache.get(&scope).unwrap()));
+    }
+
+    #[test]
+    fn test_graph_cache_expiry() {
+        let scope = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let cache = GraphCache::new(Duration::from_secs(60), clock.clone());
+        cache.insert(scope.clone(), Graph::default());
+
+        clock.advance(Duration::from_secs(59));
+        let entry = cache.get(&scope).unwrap();
+        assert!(cache.is_fresh(&entry));
+        assert_eq!(cache.oldest_age(), Some(Duration::from_secs(59)));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!cache.is_fresh(&entry));
+        assert_eq!(cache.oldest_age(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_graph_cache_reap_idle() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let cache = GraphCache::new(Duration::from_secs(60), clock.clone());
+        cache.insert(scope("stable"), Graph::default());
+        cache.insert(scope("testing"), Graph::default());
+
+        // Accesses keep entries alive, regardless of their age.
+        clock.advance(Duration::from_secs(200));
+        cache.get(&scope("stable")).unwrap();
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(cache.reap_idle(Duration::from_secs(300)), 1);
+        assert!(cache.get(&scope("stable")).is_some());
+        assert!(cache.get(&scope("testing")).is_none());
+
+        clock.advance(Duration::from_secs(299));
+        assert_eq!(cache.reap_idle(Duration::from_secs(300)), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.reap_idle(Duration::from_secs(300)), 1);
+        assert!(cache.oldest_age().is_none());
+    }
+
+    #[test]
+    fn test_fetch_limiter() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let limiter = FetchLimiter::new(Duration::from_secs(10), clock.clone());
+        assert!(limiter.try_fetch(&scope("stable")));
+        assert!(!limiter.try_fetch(&scope("stable")));
+        // Scopes are limited independently.
+        assert!(limiter.try_fetch(&scope("testing")));
+
+        clock.advance(Duration::from_secs(9));
+        assert!(!limiter.try_fetch(&scope("stable")));
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.try_fetch(&scope("stable")));
+        assert!(!limiter.try_fetch(&scope("stable")));
+
+        // Expired scopes are forgotten.
+        clock.advance(Duration::from_secs(10));
+        assert!(limiter.try_fetch(&scope("next")));
+        let last_fetch = limiter.last_fetch.lock().unwrap();
+        assert_eq!(last_fetch.len(), 1);
+    }
+
+    #[test]
+    fn test_revalidator() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let cache = GraphCache::new(Duration::from_secs(30), clock.clone());
+        let revalidator = Revalidator::new(
+            Duration::from_secs(30),
+            Duration::from_secs(60),
+            clock.clone(),
+        );
+        let entry = cache.insert(scope("stable"), Graph::default());
+
+        clock.advance(Duration::from_secs(89));
+        assert!(!cache.is_fresh(&entry));
+        assert!(revalidator.is_revalidatable(&entry));
+        clock.advance(Duration::from_secs(1));
+        assert!(!revalidator.is_revalidatable(&entry));
+
+        // A single refresh is in flight per scope.
+        assert!(revalidator.try_start(&scope("stable")));
+        assert!(!revalidator.try_start(&scope("stable")));
+        assert!(revalidator.try_start(&scope("testing")));
+        revalidator.finish(&scope("stable"));
+        assert!(revalidator.try_start(&scope("stable")));
+    }
+
+    #[test]
+    fn test_graph_cache_persistence() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let graph = Graph {
+            nodes: vec![],
+            edges: vec![(0, 1)],
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        // Fetch times are persisted with millisecond precision.
+        let clock = Arc::new(MockClock::at(Utc.timestamp(1_600_000_000, 0)));
+        let cache = GraphCache::new(Duration::from_secs(60), clock.clone());
+        assert_eq!(cache.load(&path).unwrap(), 0);
+
+        cache.insert(scope("stable"), graph.clone());
+        clock.advance(Duration::from_secs(30));
+        cache.insert(scope("testing"), graph.clone());
+        assert_eq!(cache.save(&path).unwrap(), 2);
+        assert_eq!(cache.save(&path).unwrap(), 2);
+
+        // Entries keep their age across restarts, and expire on load.
+        clock.advance(Duration::from_secs(40));
+        let restarted = GraphCache::new(Duration::from_secs(60), clock.clone());
+        assert_eq!(restarted.load(&path).unwrap(), 1);
+        assert!(restarted.get(&scope("stable")).is_none());
+        let entry = restarted.get(&scope("testing")).unwrap();
+        assert_eq!(entry.graph.edges, graph.edges);
+        assert_eq!(entry.age(clock.now()), Duration::from_secs(40));
+        assert!(restarted.is_fresh(&entry));
+        assert_eq!(restarted.size_bytes(), cache.size_bytes() / 2);
+
+        // Entries fetched since startup are not overwritten.
+        restarted.insert(scope("testing"), Graph::default());
+        assert_eq!(restarted.load(&path).unwrap(), 0);
+        assert!(restarted
+            .get(&scope("testing"))
+            .unwrap()
+            .graph
+            .edges
+            .is_empty());
+
+        std::fs::write(&path, "{not a cache").unwrap();
+        restarted.load(&path).unwrap_err();
+    }
+
+    #[test]
+    fn test_graph_cache_size() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let graph = Graph {
+            nodes: vec![],
+            edges: vec![(0, 1)],
+        };
+        let size = serde_json::to_vec(&graph).unwrap().len();
+        let cache = GraphCache::new(Duration::from_secs(60), Arc::new(SystemClock));
+        assert_eq!(cache.size_bytes(), 0);
+
+        cache.insert(scope("stable"), graph.clone());
+        cache.insert(scope("testing"), graph.clone());
+        assert_eq!(cache.size_bytes(), 2 * size);
+        // Replaced entries are not counted twice.
+        cache.insert(scope("testing"), graph);
+        assert_eq!(cache.size_bytes(), 2 * size);
+
+        cache.flush(|s| s.stream == "testing");
+        assert_eq!(cache.size_bytes(), size);
+        cache.reap_idle(Duration::from_secs(0));
+        assert_eq!(cache.size_bytes(), 0);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::ConfigFormat;
 use log::LevelFilter;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -9,9 +10,95 @@ pub(crate) struct CliOptions {
     #[structopt(short = "v", parse(from_occurrences))]
     verbosity: u8,
 
-    /// Path to configuration file.
+    /// Path to configuration file, required unless generating one.
     #[structopt(short = "c")]
-    pub config_path: PathBuf,
+    pub config_path: Option<PathBuf>,
+
+    /// Configuration file format (toml, json or yaml), detected from the
+    /// file extension by default.
+    #[structopt(long = "config-format")]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Directory of TOML configuration fragments, merged over the
+    /// configuration file in lexical order of file names.
+    #[structopt(long = "config-dir")]
+    pub config_dir: Option<PathBuf>,
+
+    /// Only run the status service (metrics, readiness), without serving
+    /// graphs.
+    #[structopt(long = "metrics-only")]
+    pub metrics_only: bool,
+
+    /// Alternative one-shot command, instead of running the server.
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+/// One-shot commands.
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Process a graph as the server would, and print it to stdout.
+    DumpGraph(DumpGraphOptions),
+    /// Fetch and validate upstream graphs, as a deployment smoke test.
+    SelfTest(SelfTestOptions),
+    /// Render metrics once, as served by the status service, to stdout.
+    ExportMetrics(ExportMetricsOptions),
+    /// Write a fully-commented default configuration file.
+    GenerateConfig(GenerateConfigOptions),
+}
+
+/// Options for the `generate-config` command.
+#[derive(Debug, StructOpt)]
+pub(crate) struct GenerateConfigOptions {
+    /// Path of the configuration file to write (default: stdout).
+    #[structopt(short = "o", long = "output")]
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `export-metrics` command.
+#[derive(Debug, StructOpt)]
+pub(crate) struct ExportMetricsOptions {
+    /// Use the OpenMetrics text format, instead of the Prometheus one.
+    #[structopt(long)]
+    pub openmetrics: bool,
+}
+
+/// Options for the `self-test` command.
+#[derive(Debug, StructOpt)]
+pub(crate) struct SelfTestOptions {
+    /// Update streams to check (default: allowed streams, else those with
+    /// an upstream endpoint, else next, stable and testing).
+    #[structopt(long = "stream")]
+    pub streams: Vec<String>,
+
+    /// Base architectures to check (default: allowed basearches, else
+    /// aarch64 and x86_64).
+    #[structopt(long = "basearch")]
+    pub basearches: Vec<String>,
+}
+
+/// Options for the `dump-graph` command.
+#[derive(Debug, StructOpt)]
+pub(crate) struct DumpGraphOptions {
+    /// Update stream.
+    #[structopt(long)]
+    pub stream: String,
+
+    /// Base architecture.
+    #[structopt(long)]
+    pub basearch: String,
+
+    /// Client rollout wariness (0.0 to 1.0).
+    #[structopt(long)]
+    pub rollout_wariness: Option<f64>,
+
+    /// Client current version.
+    #[structopt(long)]
+    pub current_version: Option<String>,
+
+    /// Pretty-print the JSON graph.
+    #[structopt(long)]
+    pub pretty: bool,
 }
 
 impl CliOptions {
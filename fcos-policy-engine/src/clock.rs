@@ -0,0 +1,50 @@
+//! Source of the current time, for time-dependent logic.
+
+use chrono::{DateTime, Utc};
+use std::fmt::Debug;
+
+/// Source of the current (wall-clock) time.
+pub(crate) trait Clock: Debug + Send + Sync {
+    /// Current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// System wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Manually driven clock, for tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Create a clock stopped at the given time.
+    pub(crate) fn at(now: DateTime<Utc>) -> Self {
+        Self {
+            now: std::sync::Mutex::new(now),
+        }
+    }
+
+    /// Move the clock forward.
+    pub(crate) fn advance(&self, by: std::time::Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + chrono::Duration::from_std(by).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,118 @@
+//! zstd encoding of graph responses.
+//!
+//! gzip and brotli are negotiated by the actix-web `Compress` middleware,
+//! which does not support zstd; zstd is thus negotiated and applied here,
+//! and the middleware leaves responses with a `Content-Encoding` alone.
+
+use failure::Fallible;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// Encodings negotiated by the `Compress` middleware, competing with zstd.
+const OTHER_ENCODINGS: [&str; 3] = ["br", "gzip", "deflate"];
+
+/// Whether a client prefers zstd, given its `Accept-Encoding` header.
+///
+/// zstd is picked when accepted with a quality at least as high as any
+/// other supported encoding.
+pub(crate) fn prefers_zstd(accept_encoding: &str) -> bool {
+    let mut zstd_quality = None;
+    let mut other_quality: f32 = 0.0;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        if coding == "zstd" {
+            zstd_quality = Some(quality);
+        } else if OTHER_ENCODINGS.contains(&coding.as_str()) {
+            other_quality = other_quality.max(quality);
+        }
+    }
+    match zstd_quality {
+        Some(q) => q > 0.0 && q >= other_quality,
+        None => false,
+    }
+}
+
+/// Encoder of graph bodies, memoizing compressed bodies.
+///
+/// Processed graphs differ by client wariness, but only a few distinct
+/// bodies exist for each cached upstream graph; each of them is compressed
+/// once and then reused for all clients getting the same graph.
+#[derive(Debug)]
+pub(crate) struct ZstdEncoder {
+    level: i32,
+    max_entries: usize,
+    bodies: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+}
+
+impl ZstdEncoder {
+    pub(crate) fn new(level: i32, max_entries: usize) -> Self {
+        Self {
+            level,
+            max_entries,
+            bodies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Compress a body, reusing a previous result for identical bodies.
+    pub(crate) fn encode(&self, body: &[u8]) -> Fallible<Arc<Vec<u8>>> {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let key = hasher.finish();
+
+        if let Some(encoded) = self.lock().get(&key) {
+            return Ok(Arc::clone(encoded));
+        }
+        let encoded = Arc::new(zstd::encode_all(body, self.level)?);
+        let mut bodies = self.lock();
+        if bodies.len() >= self.max_entries {
+            bodies.clear();
+        }
+        bodies.insert(key, Arc::clone(&encoded));
+        Ok(encoded)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<Vec<u8>>>> {
+        self.bodies.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefers_zstd() {
+        assert!(prefers_zstd("zstd"));
+        assert!(prefers_zstd("gzip, br, zstd"));
+        assert!(prefers_zstd("gzip;q=0.5, ZSTD;q=0.8"));
+        assert!(!prefers_zstd("gzip, br"));
+        assert!(!prefers_zstd("gzip, zstd;q=0.5"));
+        assert!(!prefers_zstd("zstd;q=0"));
+        assert!(!prefers_zstd(""));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let encoder = ZstdEncoder::new(3, 1);
+        let body = br#"{"nodes":[],"edges":[]}"#;
+        let encoded = encoder.encode(body).unwrap();
+        assert_eq!(zstd::decode_all(encoded.as_slice()).unwrap(), body);
+
+        // Identical bodies reuse the memoized result.
+        let again = encoder.encode(body).unwrap();
+        assert!(Arc::ptr_eq(&encoded, &again));
+
+        // Memoized bodies are bounded.
+        encoder.encode(b"other").unwrap();
+        let fresh = encoder.encode(body).unwrap();
+        assert!(!Arc::ptr_eq(&encoded, &fresh));
+    }
+}
@@ -0,0 +1,444 @@
+//! Limits on concurrent requests to upstream endpoints, and on concurrent
+//! graph requests from clients.
+//!
+//! Each upstream endpoint gets a fixed number of in-flight request slots.
+//! Requests beyond that wait in a bounded queue; once the queue is full,
+//! further requests fail fast instead of piling up on the upstream.
+//!
+//! Client graph requests share a single, global pool of slots. Requests
+//! beyond it are rejected right away: this is a last-resort guard against
+//! request floods, not a per-client rate limit. Optionally, requests beyond
+//! it are queued per stream instead, and freed slots are shared across
+//! streams in (weighted) round-robin order, so that a flood of requests for
+//! one stream cannot starve the others.
+
+use crate::scopes::OVERFLOW_LABEL;
+use crate::settings::{ConcurrencySettings, FairQueueSettings};
+use failure::{bail, Fallible};
+use prometheus::IntGauge;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+
+/// Per-endpoint limiter of concurrent upstream requests.
+#[derive(Debug)]
+pub(crate) struct UpstreamLimiter {
+    max_in_flight: usize,
+    max_queued: usize,
+    endpoints: Mutex<HashMap<String, Arc<EndpointSlots>>>,
+}
+
+/// Request slots for a single upstream endpoint.
+#[derive(Debug)]
+struct EndpointSlots {
+    semaphore: Semaphore,
+    queued: AtomicUsize,
+    in_flight_gauge: IntGauge,
+    queued_gauge: IntGauge,
+}
+
+/// Decrements a gauge (and an optional counter) when dropped, so that
+/// accounting stays correct when a request is cancelled.
+struct Tracked<'a> {
+    gauge: &'a IntGauge,
+    counter: Option<&'a AtomicUsize>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+        if let Some(counter) = self.counter {
+            counter.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl UpstreamLimiter {
+    pub(crate) fn new(settings: &ConcurrencySettings) -> Self {
+        Self {
+            max_in_flight: settings.max_in_flight,
+            max_queued: settings.max_queued,
+            endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run a request to the given upstream endpoint within its limits,
+    /// failing fast if too many requests are already waiting.
+    pub(crate) async fn run<F, T>(&self, endpoint: &str, request: F) -> Fallible<T>
+    where
+        F: Future<Output = Fallible<T>>,
+    {
+        let slots = self.slots(endpoint);
+        let _permit = match slots.semaphore.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if slots.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+                    slots.queued.fetch_sub(1, Ordering::SeqCst);
+                    bail!("too many queued requests to upstream '{}'", endpoint);
+                }
+                slots.queued_gauge.inc();
+                let _queued = Tracked {
+                    gauge: &slots.queued_gauge,
+                    counter: Some(&slots.queued),
+                };
+                slots.semaphore.acquire().await
+            }
+        };
+        slots.in_flight_gauge.inc();
+        let _in_flight = Tracked {
+            gauge: &slots.in_flight_gauge,
+            counter: None,
+        };
+        request.await
+    }
+
+    /// Return the request slots for an endpoint, creating them on first use.
+    fn slots(&self, endpoint: &str) -> Arc<EndpointSlots> {
+        let mut endpoints = self.endpoints.lock().unwrap_or_else(|e| e.into_inner());
+        let slots = endpoints.entry(endpoint.to_string()).or_insert_with(|| {
+            Arc::new(EndpointSlots {
+                semaphore: Semaphore::new(self.max_in_flight),
+                queued: AtomicUsize::new(0),
+                in_flight_gauge: crate::metrics::UPSTREAM_IN_FLIGHT.with_label_values(&[endpoint]),
+                queued_gauge: crate::metrics::UPSTREAM_QUEUED.with_label_values(&[endpoint]),
+            })
+        });
+        slots.clone()
+    }
+}
+
+/// Delay suggested to clients turned away by the graph requests limiter.
+pub(crate) const OVERLOAD_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Global limiter of concurrent graph requests.
+#[derive(Debug)]
+pub(crate) struct GraphRequestLimiter {
+    max_in_flight: usize,
+    fair_queue: Option<FairQueueSettings>,
+    max_streams: usize,
+    slots: Mutex<GraphSlots>,
+}
+
+/// Accounting of graph request slots.
+#[derive(Debug, Default)]
+struct GraphSlots {
+    in_flight: usize,
+    /// Requests waiting for a slot, per stream.
+    queues: BTreeMap<String, VecDeque<oneshot::Sender<()>>>,
+    /// Stream which got the last freed slot, and how many in a row.
+    turn: Option<(String, usize)>,
+}
+
+impl GraphSlots {
+    /// Drop the queue of a stream once empty, so that only streams with
+    /// queued requests count towards the limit of queues.
+    fn remove_if_empty(&mut self, stream: &str) {
+        if matches!(self.queues.get(stream), Some(queue) if queue.is_empty()) {
+            self.queues.remove(stream);
+        }
+    }
+
+    /// Pick the stream getting the next freed slot, if any request is queued.
+    ///
+    /// Streams take turns in round-robin order, each getting up to its
+    /// weight in slots before passing the turn on.
+    fn next_turn(&mut self, fair_queue: &FairQueueSettings) -> Option<String> {
+        if let Some((stream, served)) = &mut self.turn {
+            let queued = matches!(self.queues.get(stream), Some(queue) if !queue.is_empty());
+            if queued && *served < fair_queue.weight(stream) {
+                *served += 1;
+                return Some(stream.clone());
+            }
+        }
+        let last = self.turn.as_ref().map(|(stream, _)| stream.as_str());
+        let mut queued = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(stream, _)| stream);
+        let first = queued.clone().next()?;
+        let next = queued
+            .find(|stream| Some(stream.as_str()) > last)
+            .unwrap_or(first)
+            .clone();
+        self.turn = Some((next.clone(), 1));
+        Some(next)
+    }
+}
+
+/// Slot of an in-flight graph request, released on drop.
+pub(crate) struct GraphRequestSlot<'a> {
+    limiter: &'a GraphRequestLimiter,
+}
+
+impl Drop for GraphRequestSlot<'_> {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// Graph request waiting in a fair queue.
+///
+/// A cancelled request leaves the queue, passing on a slot it may have
+/// been handed in the meantime.
+struct QueuedRequest<'a> {
+    limiter: &'a GraphRequestLimiter,
+    stream: String,
+    ready: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for QueuedRequest<'_> {
+    fn drop(&mut self) {
+        let mut ready = match self.ready.take() {
+            Some(ready) => ready,
+            None => return,
+        };
+        ready.close();
+        if ready.try_recv().is_ok() {
+            self.limiter.release();
+            return;
+        }
+        let mut slots = self.limiter.lock();
+        if let Some(queue) = slots.queues.get_mut(&self.stream) {
+            queue.retain(|waiter| !waiter.is_closed());
+            crate::metrics::V1_GRAPH_QUEUED
+                .with_label_values(&[&self.stream])
+                .set(queue.len() as i64);
+        }
+        slots.remove_if_empty(&self.stream);
+    }
+}
+
+impl GraphRequestLimiter {
+    /// Limiter with `max_in_flight` slots, optionally queuing requests over
+    /// the limit per stream. At most `max_streams` distinct streams with
+    /// queued requests get their own queue, further ones share the same one.
+    pub(crate) fn new(
+        max_in_flight: usize,
+        fair_queue: Option<FairQueueSettings>,
+        max_streams: usize,
+    ) -> Self {
+        Self {
+            max_in_flight,
+            fair_queue,
+            max_streams,
+            slots: Mutex::new(GraphSlots::default()),
+        }
+    }
+
+    /// Admit a graph request for a stream.
+    ///
+    /// Without fair queuing, requests are rejected right away when all
+    /// slots are taken. Otherwise they wait for a slot, unless the queue of
+    /// their stream is full.
+    pub(crate) async fn admit(&self, stream: &str) -> Option<GraphRequestSlot<'_>> {
+        let mut queued = {
+            let mut slots = self.lock();
+            if slots.in_flight < self.max_in_flight {
+                slots.in_flight += 1;
+                crate::metrics::V1_GRAPH_IN_FLIGHT.inc();
+                return Some(GraphRequestSlot { limiter: self });
+            }
+            let depth = match &self.fair_queue {
+                Some(fair_queue) => fair_queue.depth,
+                None => return Self::reject(),
+            };
+            let stream =
+                if slots.queues.contains_key(stream) || slots.queues.len() < self.max_streams {
+                    stream
+                } else {
+                    OVERFLOW_LABEL
+                };
+            let queue = slots.queues.entry(stream.to_string()).or_default();
+            if queue.len() >= depth {
+                return Self::reject();
+            }
+            let (ready_tx, ready_rx) = oneshot::channel();
+            queue.push_back(ready_tx);
+            crate::metrics::V1_GRAPH_QUEUED
+                .with_label_values(&[stream])
+                .inc();
+            QueuedRequest {
+                limiter: self,
+                stream: stream.to_string(),
+                ready: Some(ready_rx),
+            }
+        };
+
+        let ready = queued.ready.as_mut()?;
+        if ready.await.is_err() {
+            return Self::reject();
+        }
+        // The slot was handed over by a finished request.
+        queued.ready = None;
+        Some(GraphRequestSlot { limiter: self })
+    }
+
+    /// Release a slot, handing it over to the next queued request if any.
+    fn release(&self) {
+        let mut slots = self.lock();
+        if let Some(fair_queue) = &self.fair_queue {
+            while let Some(stream) = slots.next_turn(fair_queue) {
+                let waiter = slots.queues.get_mut(&stream).and_then(VecDeque::pop_front);
+                crate::metrics::V1_GRAPH_QUEUED
+                    .with_label_values(&[&stream])
+                    .dec();
+                slots.remove_if_empty(&stream);
+                if let Some(waiter) = waiter {
+                    if waiter.send(()).is_ok() {
+                        return;
+                    }
+                }
+            }
+        }
+        slots.in_flight -= 1;
+        crate::metrics::V1_GRAPH_IN_FLIGHT.dec();
+    }
+
+    fn reject<T>() -> Option<T> {
+        crate::metrics::V1_GRAPH_OVERLOAD_REJECTIONS.inc();
+        None
+    }
+
+    fn lock(&self) -> MutexGuard<'_, GraphSlots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{join_all, FutureExt};
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_upstream_limiter() {
+        let settings = ConcurrencySettings {
+            max_in_flight: 2,
+            max_queued: 1,
+        };
+        let limiter = UpstreamLimiter::new(&settings);
+        let endpoint = "http://limited.example.com/v1/graph";
+        let peak = AtomicUsize::new(0);
+        let current = AtomicUsize::new(0);
+
+        let request = || async {
+            let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            actix_rt::time::delay_for(Duration::from_millis(50)).await;
+            current.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        };
+
+        // Two requests in flight, one queued, one rejected.
+        let results = join_all((0..4).map(|_| limiter.run(endpoint, request()))).await;
+        let failed = results.iter().filter(|res| res.is_err()).count();
+        assert_eq!(failed, 1);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        // Other endpoints have their own slots.
+        let other = limiter.run("http://other.example.com/v1/graph", request());
+        assert!(other.await.is_ok());
+
+        let slots = limiter.slots(endpoint);
+        assert_eq!(slots.in_flight_gauge.get(), 0);
+        assert_eq!(slots.queued_gauge.get(), 0);
+        assert_eq!(slots.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_request_limiter() {
+        let limiter = GraphRequestLimiter::new(2, None, 8);
+        let rejections = crate::metrics::V1_GRAPH_OVERLOAD_REJECTIONS.get();
+        let first = limiter.admit("stable").await;
+        let second = limiter.admit("stable").await;
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limiter.admit("stable").await.is_none());
+        assert!(crate::metrics::V1_GRAPH_OVERLOAD_REJECTIONS.get() > rejections);
+
+        // Slots are released on drop.
+        drop(first);
+        assert!(limiter.admit("stable").await.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_request_fair_queue() {
+        let fair_queue = FairQueueSettings {
+            depth: 10,
+            weights: vec![("next".to_string(), 2)].into_iter().collect(),
+        };
+        let limiter = GraphRequestLimiter::new(1, Some(fair_queue), 3);
+        let served = Mutex::new(Vec::new());
+        let request = |stream: &'static str| {
+            let limiter = &limiter;
+            let served = &served;
+            async move {
+                let slot = limiter.admit(stream).await;
+                if slot.is_some() {
+                    served.lock().unwrap().push(stream);
+                    actix_rt::time::delay_for(Duration::from_millis(5)).await;
+                }
+                slot.is_some()
+            }
+        };
+
+        // A flood of `testing` requests, queued before two `stable` and
+        // three `next` ones: freed slots alternate across streams.
+        let mut requests: Vec<_> = (0..6).map(|_| request("testing")).collect();
+        requests.extend((0..2).map(|_| request("stable")));
+        requests.extend((0..3).map(|_| request("next")));
+        let results = join_all(requests).await;
+        assert!(results.iter().all(|admitted| *admitted));
+        assert_eq!(
+            *served.lock().unwrap(),
+            vec![
+                "testing", "next", "next", "stable", "testing", "next", "stable", "testing",
+                "testing", "testing", "testing",
+            ]
+        );
+        assert_eq!(
+            crate::metrics::V1_GRAPH_QUEUED
+                .with_label_values(&["testing"])
+                .get(),
+            0
+        );
+        // Drained queues are dropped.
+        assert!(limiter.lock().queues.is_empty());
+
+        // Queues are bounded per stream; streams beyond the limit share one.
+        let _slot = limiter.admit("stable").await.unwrap();
+        let mut queued: Vec<_> = (0..10)
+            .map(|_| limiter.admit("testing").boxed_local())
+            .collect();
+        queued.push(limiter.admit("stable").boxed_local());
+        queued.push(limiter.admit("next").boxed_local());
+        for waiter in &mut queued {
+            assert!(futures::poll!(waiter).is_pending());
+        }
+        assert!(limiter.admit("testing").await.is_none());
+        let mut other = limiter.admit("unknown").boxed_local();
+        assert!(futures::poll!(&mut other).is_pending());
+        assert_eq!(
+            crate::metrics::V1_GRAPH_QUEUED
+                .with_label_values(&[OVERFLOW_LABEL])
+                .get(),
+            1
+        );
+
+        // Cancelled requests leave the queue, freeing it for other streams.
+        drop(queued);
+        assert_eq!(
+            crate::metrics::V1_GRAPH_QUEUED
+                .with_label_values(&["testing"])
+                .get(),
+            0
+        );
+        let mut own = limiter.admit("own").boxed_local();
+        assert!(futures::poll!(&mut own).is_pending());
+        assert!(limiter.lock().queues.contains_key("own"));
+    }
+}
@@ -1,14 +1,1086 @@
-use failure::Fallible;
-use std::path::Path;
+use failure::{bail, format_err, Fallible, ResultExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Fully-commented default configuration file, documenting every option
+/// and its default value.
+pub static DEFAULT_CONFIG_TOML: &str = include_str!("../../dist/fcos-policy-engine.toml.sample");
 
 /// Configuration file.
-#[derive(Debug, Default)]
-pub struct FileConfig {}
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Main service configuration.
+    pub service: ServiceConfig,
+    /// Status service configuration.
+    pub status: StatusConfig,
+    /// Upstream (graph-builder) configuration.
+    pub upstream: UpstreamConfig,
+    /// Graph cache configuration.
+    pub cache: CacheConfig,
+    /// Graph policies configuration.
+    pub policy: PolicyConfig,
+    /// Shadow (candidate) policies comparison configuration.
+    pub shadow: ShadowConfig,
+    /// Metrics configuration.
+    pub metrics: MetricsConfig,
+    /// Response compression configuration.
+    pub compression: CompressionConfig,
+    /// CORS configuration.
+    pub cors: CorsConfig,
+    /// Graph responses signing configuration.
+    pub signing: SigningConfig,
+    /// Debugging aids configuration.
+    pub debug: DebugConfig,
+    /// Graphs pinned to local snapshots, bypassing upstream.
+    pub pinned_graphs: Vec<PinnedGraphConfig>,
+    /// Allowlist of graph scopes clients can request.
+    pub scope_allowlist: ScopeAllowlistConfig,
+    /// Admin endpoints configuration.
+    pub admin: AdminConfig,
+    /// Tiered graphs for anonymous and authenticated clients.
+    pub tiers: TiersConfig,
+}
 
 impl FileConfig {
-    pub fn parse_file(_path: impl AsRef<Path>) -> Fallible<Self> {
-        // TODO(lucab): translate config entries.
-        let cfg = FileConfig::default();
+    /// Parse a configuration file, in the given format or else in the
+    /// format matching its extension.
+    pub fn parse_file(path: impl AsRef<Path>, format: Option<ConfigFormat>) -> Fallible<Self> {
+        let path = path.as_ref();
+        let (content, format) = Self::read_file(path, format)?;
+        let cfg = Self::parse_str(&content, format)
+            .with_context(|_| format!("failed to parse config file '{}'", path.display()))?;
+        Ok(cfg)
+    }
+
+    /// Parse a configuration file as with `parse_file`, then merge the
+    /// `*.toml` fragments of a directory over it, in lexical order of file
+    /// names.
+    ///
+    /// Tables are merged key by key, recursively; any other value (including
+    /// arrays) set by a later fragment replaces the earlier one.
+    pub fn parse_with_fragments(
+        path: impl AsRef<Path>,
+        format: Option<ConfigFormat>,
+        fragments_dir: impl AsRef<Path>,
+    ) -> Fallible<Self> {
+        let path = path.as_ref();
+        let (content, format) = Self::read_file(path, format)?;
+        let mut merged = Self::parse_value(&content, format)
+            .with_context(|_| format!("failed to parse config file '{}'", path.display()))?;
+        for fragment in Self::fragment_paths(fragments_dir.as_ref())? {
+            let (content, _) = Self::read_file(&fragment, Some(ConfigFormat::Toml))?;
+            // Check each fragment on its own, for errors to point at it.
+            let value = Self::parse_str(&content, ConfigFormat::Toml)
+                .and_then(|_| Self::parse_value(&content, ConfigFormat::Toml))
+                .with_context(|_| {
+                    format!("failed to parse config fragment '{}'", fragment.display())
+                })?;
+            merge_value(&mut merged, value);
+        }
+        let cfg = serde_json::from_value(merged).context("invalid merged configuration")?;
+        Ok(cfg)
+    }
+
+    /// Parse configuration content in the given format.
+    pub fn parse_str(content: &str, format: ConfigFormat) -> Fallible<Self> {
+        let cfg = match format {
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+        };
         Ok(cfg)
     }
+
+    /// Parse configuration content in the given format into a generic value,
+    /// for merging.
+    fn parse_value(content: &str, format: ConfigFormat) -> Fallible<serde_json::Value> {
+        let value = match format {
+            ConfigFormat::Toml => serde_json::to_value(toml::from_str::<toml::Value>(content)?)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+            ConfigFormat::Yaml => {
+                serde_json::to_value(serde_yaml::from_str::<serde_yaml::Value>(content)?)?
+            }
+        };
+        Ok(value)
+    }
+
+    /// Read a configuration file, in the given format or else in the format
+    /// matching its extension.
+    fn read_file(path: &Path, format: Option<ConfigFormat>) -> Fallible<(String, ConfigFormat)> {
+        let format = match (format, ConfigFormat::from_path(path)) {
+            (Some(format), Some(detected)) if format != detected => bail!(
+                "config file '{}' extension does not match {} format",
+                path.display(),
+                format
+            ),
+            (Some(format), _) | (None, Some(format)) => format,
+            (None, None) => bail!(
+                "unrecognized extension of config file '{}', expected one of \
+                 .toml, .json, .yaml or .yml",
+                path.display()
+            ),
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|_| format!("failed to read config file '{}'", path.display()))?;
+        Ok((content, format))
+    }
+
+    /// Paths of the `*.toml` files in a fragments directory, sorted.
+    fn fragment_paths(dir: &Path) -> Fallible<Vec<PathBuf>> {
+        let entries = std::fs::read_dir(dir).with_context(|_| {
+            format!(
+                "failed to read config fragments directory '{}'",
+                dir.display()
+            )
+        })?;
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() && ConfigFormat::from_path(&path) == Some(ConfigFormat::Toml) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// Merge a configuration value over another one: objects are merged key by
+/// key, any other value replaces the previous one.
+fn merge_value(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Configuration file format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detect the format of a configuration file from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" => Ok(ConfigFormat::Yaml),
+            _ => Err(format_err!(
+                "unknown config format '{}', expected one of toml, json or yaml",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Yaml => "YAML",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Admin endpoints configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// File containing the token required on admin endpoints.
+    pub token_path: Option<PathBuf>,
+}
+
+/// Tiered graphs configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TiersConfig {
+    /// File listing the tokens unlocking complete graphs, one per line.
+    pub token_path: Option<PathBuf>,
+    /// Streams served to anonymous clients, all of them if unset.
+    pub anonymous_streams: Option<Vec<String>>,
+}
+
+/// Main service configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// TCP port of the main service.
+    pub port: Option<u16>,
+    /// Maximum processing time of a graph request, in milliseconds.
+    pub max_processing_ms: Option<u64>,
+    /// Whether to list public endpoints in responses to unknown routes.
+    pub not_found_hint: Option<bool>,
+    /// Whether to serve service information at the root path.
+    pub root_info: Option<bool>,
+    /// Whether to add applied policies metadata to graph responses.
+    pub policy_metadata: Option<bool>,
+    /// Whether to expose the effective rollout wariness in a response header.
+    pub wariness_header: Option<bool>,
+    /// Maximum length of graph request query strings, in bytes.
+    pub max_query_length: Option<usize>,
+    /// Handling of unknown graph request query parameters.
+    pub unknown_query_params: Option<UnknownQueryParams>,
+    /// Handling of graph requests with a malformed `node_uuid`.
+    pub node_uuid_validation: Option<NodeUuidValidation>,
+    /// Base architecture of graph requests not specifying one.
+    pub default_basearch: Option<String>,
+    /// Response to clients whose current version has no updates.
+    pub up_to_date_response: Option<UpToDateResponse>,
+    /// Whether to reject graph requests without a `User-Agent` header.
+    pub require_user_agent: Option<bool>,
+    /// Maximum number of concurrent graph requests.
+    pub max_graph_requests: Option<usize>,
+    /// Maximum size of combined multi-stream graph responses, in bytes.
+    pub max_combined_graph_bytes: Option<usize>,
+    /// Fair queuing of graph requests over the concurrency limit.
+    pub fair_queue: Option<FairQueueConfig>,
+    /// Latency threshold for logging slow graph requests, in milliseconds.
+    pub slow_request_ms: Option<u64>,
+    /// TLS termination, enabling HTTP/2.
+    pub tls: Option<TlsConfig>,
+    /// Warm-up period after startup.
+    pub warmup: WarmupConfig,
+    /// Static headers added to all responses.
+    pub headers: BTreeMap<String, String>,
+    /// Streams still served, but flagged to clients as deprecated.
+    pub deprecated_streams: Vec<String>,
+    /// Whether to log graph requests for deprecated streams.
+    pub log_deprecated_streams: Option<bool>,
+    /// Stream served instead of each stream, when the latter has no graph.
+    pub stream_fallbacks: BTreeMap<String, String>,
+}
+
+/// Status service (metrics, readiness) configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusConfig {
+    /// TCP port of the status service.
+    pub port: Option<u16>,
+}
+
+/// Warm-up configuration for the main service.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    /// Maximum duration of the warm-up period, in seconds.
+    pub timeout_secs: Option<u64>,
+    /// Whether to reply 503 to graph requests while warming up.
+    pub reject_requests: Option<bool>,
+    /// Delay suggested to clients via `Retry-After`, in seconds.
+    pub retry_after_secs: Option<u64>,
+    /// Scopes which must have been served for the service to be ready.
+    pub criterion: Option<ReadinessCriterion>,
+}
+
+/// Readiness criterion at the end of warm-up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadinessCriterion {
+    /// Ready once a graph has been served for any scope.
+    Any,
+    /// Ready once a graph has been served for all allowed scopes.
+    All,
+}
+
+impl Default for ReadinessCriterion {
+    fn default() -> Self {
+        ReadinessCriterion::Any
+    }
+}
+
+/// Handling of unknown graph request query parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownQueryParams {
+    /// Ignore them, only counting them.
+    Lenient,
+    /// Reject the request.
+    Strict,
+}
+
+impl Default for UnknownQueryParams {
+    fn default() -> Self {
+        UnknownQueryParams::Lenient
+    }
+}
+
+/// Handling of graph requests whose `node_uuid` is not a well-formed UUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeUuidValidation {
+    /// Accept them, only counting them.
+    Lenient,
+    /// Reject the request.
+    Strict,
+}
+
+impl Default for NodeUuidValidation {
+    fn default() -> Self {
+        NodeUuidValidation::Lenient
+    }
+}
+
+/// Response to graph requests from clients already on a version without
+/// outgoing update paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpToDateResponse {
+    /// Serve the full graph.
+    Graph,
+    /// Reply `204 No Content`.
+    NoContent,
+    /// Serve a graph with only the client's current version node.
+    Minimal,
+}
+
+impl Default for UpToDateResponse {
+    fn default() -> Self {
+        UpToDateResponse::Graph
+    }
+}
+
+/// Fair queuing configuration for graph requests.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FairQueueConfig {
+    /// Maximum number of queued requests per stream.
+    pub depth: Option<usize>,
+    /// Relative share of capacity per stream (1 if unset).
+    pub weights: BTreeMap<String, usize>,
+}
+
+/// TLS termination configuration for the main service.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Path to a PEM certificate chain.
+    pub cert_path: PathBuf,
+    /// Path to a PEM (PKCS#8 or RSA) private key.
+    pub key_path: PathBuf,
+    /// Minimum accepted TLS protocol version.
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// Accepted cipher suites, by name.
+    #[serde(default)]
+    pub cipher_suites: Option<Vec<String>>,
+    /// Path to PEM CA certificates client certificates must be signed by.
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// TLS protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl Default for TlsVersion {
+    fn default() -> Self {
+        TlsVersion::Tls12
+    }
+}
+
+/// Graph cache configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Time-to-live of cached upstream graphs, in seconds.
+    pub ttl_secs: Option<u64>,
+    /// Whether to expose the cache status in an `X-Cache` response header.
+    pub status_header: Option<bool>,
+    /// Idle time after which in-process entries are reaped, in seconds.
+    pub max_idle_secs: Option<u64>,
+    /// Minimum interval between upstream fetches of each scope, in seconds.
+    pub min_fetch_interval_secs: Option<u64>,
+    /// Window past the TTL during which expired graphs are served while
+    /// refreshed in the background, in seconds.
+    pub stale_while_revalidate_secs: Option<u64>,
+    /// Shared cache across replicas.
+    pub redis: Option<RedisCacheConfig>,
+    /// On-disk persistence of the in-process cache across restarts.
+    pub persistence: Option<CachePersistenceConfig>,
+}
+
+/// Cache persistence configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachePersistenceConfig {
+    /// Path of the file the cache is persisted to.
+    pub path: PathBuf,
+    /// Interval between cache flushes to disk, in seconds.
+    pub flush_interval_secs: Option<u64>,
+}
+
+/// Shared (Redis) cache configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisCacheConfig {
+    /// Redis server URL (`redis://host:port/db`).
+    pub url: String,
+    /// Prefix for cache keys.
+    pub key_prefix: Option<String>,
+    /// Timeout of each Redis operation, in milliseconds.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Response compression configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Whether to compress responses (gzip, brotli), as negotiated with clients.
+    pub enabled: Option<bool>,
+    /// Whether to also offer zstd.
+    pub zstd: Option<bool>,
+    /// zstd compression level.
+    pub zstd_level: Option<i32>,
+}
+
+/// CORS configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed in CORS requests (any by default).
+    pub allowed_origins: Option<Vec<String>>,
+    /// Methods allowed in CORS requests.
+    pub allowed_methods: Option<Vec<String>>,
+    /// Request headers allowed in CORS requests (any by default).
+    pub allowed_headers: Option<Vec<String>>,
+    /// Response headers exposed to CORS clients.
+    pub exposed_headers: Option<Vec<String>>,
+}
+
+/// Graph responses signing configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Path to a PKCS#8 PEM Ed25519 private key.
+    pub private_key_path: Option<PathBuf>,
+}
+
+/// Debugging aids configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// Whether debugging aids are enabled.
+    pub enabled: Option<bool>,
+    /// Request headers to echo back in responses.
+    pub echo_headers: Vec<String>,
+    /// Artificial delay of graph responses, in milliseconds.
+    pub response_delay_ms: Option<u64>,
+    /// Fraction of requests logged in full detail.
+    pub log_sample_rate: Option<f64>,
+    /// Versions marked as dead-ends in served graphs.
+    pub inject_deadends: Vec<String>,
+}
+
+/// Metrics configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Upper bounds of the rollout wariness histogram buckets.
+    pub wariness_buckets: Option<Vec<f64>>,
+    /// Upper bounds of the graph response size histogram buckets, in bytes.
+    pub response_size_buckets: Option<Vec<f64>>,
+    /// Maximum number of distinct requested scopes tracked in metrics.
+    pub max_tracked_scopes: Option<usize>,
+    /// Whether to track unique node UUIDs.
+    pub unique_ids: Option<bool>,
+    /// Size of the unique node UUIDs Bloom filter, in bytes.
+    pub bloom_size: Option<usize>,
+    /// Expected maximum number of unique node UUIDs.
+    pub bloom_max_population: Option<usize>,
+    /// Number of shards the unique node UUIDs Bloom filter is split into.
+    pub bloom_shards: Option<usize>,
+    /// Hash algorithm of node UUIDs for unique IDs tracking.
+    pub unique_ids_hash: Option<UniqueIdsHash>,
+    /// Share of node UUIDs sampled for unique IDs tracking, in (0, 1].
+    pub unique_ids_sample_rate: Option<f64>,
+    /// Fleet-wide unique IDs estimation, shared across replicas.
+    pub shared_unique_ids: Option<SharedUniqueIdsConfig>,
+}
+
+/// Shared (Redis HyperLogLog) unique IDs estimation configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedUniqueIdsConfig {
+    /// Redis server URL (`redis://host:port/db`).
+    pub url: String,
+    /// Key of the HyperLogLog.
+    pub key: Option<String>,
+    /// Timeout of each Redis operation, in milliseconds.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Graph policies configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Minimum supported client version.
+    pub version_floor: Option<VersionFloorConfig>,
+    /// Rewriting of payload locations.
+    pub payload_rewrite: Option<PayloadRewriteConfig>,
+    /// Node metadata keys passed through to clients.
+    pub metadata_filter: Option<MetadataFilterConfig>,
+    /// Maximum number of outgoing edges per node in upstream graphs.
+    pub max_edges_per_node: Option<usize>,
+    /// Maximum number of hops in update paths from the client version.
+    pub max_hops: Option<usize>,
+    /// Selection of outgoing edges offered from each release.
+    pub edge_selection: Option<EdgeSelection>,
+    /// Release versions never offered as update targets.
+    pub blocked_versions: Vec<String>,
+    /// Arch-specific node metadata passed through to clients.
+    pub arch_metadata: Option<ArchMetadata>,
+    /// Handling of releases being rolled out.
+    pub rollouts: Option<RolloutHandling>,
+    /// Daily windows during which rollouts are allowed to advance.
+    pub rollout_windows: Vec<RolloutWindowConfig>,
+    /// Number of decimal places rollout wariness is rounded to.
+    pub wariness_precision: Option<u32>,
+    /// Secret salt mixed into node UUIDs when deriving wariness.
+    pub wariness_salt: Option<String>,
+    /// Handling of client-provided wariness out of `[0.0, 1.0]`.
+    pub wariness_parsing: Option<WarinessParsing>,
+    /// Precomputation of throttled graphs per wariness bucket.
+    pub precompute: Option<PrecomputeConfig>,
+    /// Graph post-processing webhook.
+    pub webhook: Option<WebhookConfig>,
+    /// Maximum time spent evaluating policies per request, in milliseconds.
+    pub evaluation_timeout_ms: Option<u64>,
+    /// Order in which reorderable built-in policies are applied.
+    pub chain: Option<Vec<String>>,
+    /// Policy chain of each stream, overriding the default one.
+    pub stream_chains: BTreeMap<String, Vec<String>>,
+}
+
+/// Shadow policies comparison configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// Whether graph queries are also processed with the candidate policies.
+    pub enabled: Option<bool>,
+    /// Fraction of divergent responses logged in detail.
+    pub log_sample_rate: Option<f64>,
+    /// Candidate graph policies.
+    pub policy: PolicyConfig,
+}
+
+/// Selection of the outgoing edges offered from each release.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EdgeSelection {
+    /// Keep all edges.
+    All,
+    /// Keep only edges towards the newest target release.
+    Latest,
+}
+
+impl Default for EdgeSelection {
+    fn default() -> Self {
+        EdgeSelection::All
+    }
+}
+
+/// Handling of releases being rolled out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RolloutHandling {
+    /// Hide rollouts from clients which are too wary for their progress.
+    Throttle,
+    /// Offer rollouts to all clients, along with their per-edge rollout
+    /// details, so that clients throttle themselves.
+    Passthrough,
+}
+
+impl Default for RolloutHandling {
+    fn default() -> Self {
+        RolloutHandling::Throttle
+    }
+}
+
+/// Arch-specific node metadata passed through to clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchMetadata {
+    /// Pass through metadata of all base architectures.
+    All,
+    /// Only pass through metadata of the requested base architecture.
+    Requested,
+}
+
+impl Default for ArchMetadata {
+    fn default() -> Self {
+        ArchMetadata::All
+    }
+}
+
+/// Hash algorithm of node UUIDs for unique IDs tracking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UniqueIdsHash {
+    /// XXH64, stable across builds and platforms.
+    Xxhash64,
+    /// The Rust standard library hasher, whose output may change between
+    /// toolchain releases.
+    Siphash,
+}
+
+impl Default for UniqueIdsHash {
+    fn default() -> Self {
+        UniqueIdsHash::Xxhash64
+    }
+}
+
+/// Handling of client-provided rollout wariness out of `[0.0, 1.0]`, or of
+/// any client-provided rollout wariness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WarinessParsing {
+    /// Clamp to `[0.0, 1.0]`.
+    Clamp,
+    /// Interpret values in `(1.0, 100.0]` as percentages, clamp others.
+    Lenient,
+    /// Reject the request.
+    Strict,
+    /// Ignore any client-provided value, always using the node UUID.
+    Ignore,
+}
+
+impl Default for WarinessParsing {
+    fn default() -> Self {
+        WarinessParsing::Clamp
+    }
+}
+
+/// Graph post-processing webhook configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Webhook URL, receiving graphs via `POST`.
+    pub url: String,
+    /// Timeout of each webhook request, in milliseconds.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Throttled graphs precomputation configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrecomputeConfig {
+    /// Lifetime of precomputed graphs, in milliseconds.
+    pub ttl_ms: Option<u64>,
+    /// Maximum number of precomputed graphs.
+    pub max_entries: Option<usize>,
+}
+
+/// Rollout window entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolloutWindowConfig {
+    /// Window start time (`HH:MM`).
+    pub start: String,
+    /// Window end time (`HH:MM`), exclusive.
+    pub end: String,
+    /// Timezone of start and end times, as a `±HH:MM` UTC offset.
+    pub utc_offset: Option<String>,
+}
+
+/// Payload rewriting configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadRewriteConfig {
+    /// Payload prefix to be replaced.
+    pub from_prefix: String,
+    /// Replacement prefix (e.g. a mirror location).
+    pub to_prefix: String,
+}
+
+/// Node metadata filtering configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataFilterConfig {
+    /// Metadata keys passed through, stripping all others.
+    pub allow: Option<Vec<String>>,
+    /// Metadata keys stripped, passing through all others.
+    pub deny: Option<Vec<String>>,
+}
+
+/// Minimum supported version configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VersionFloorConfig {
+    /// Minimum supported client version.
+    pub min_version: String,
+    /// Response for clients below the floor.
+    #[serde(default)]
+    pub action: VersionFloorAction,
+}
+
+/// Response for clients below the minimum supported version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VersionFloorAction {
+    /// Serve an empty graph.
+    EmptyGraph,
+    /// Reject the request with a 409 Conflict.
+    Conflict,
+}
+
+impl Default for VersionFloorAction {
+    fn default() -> Self {
+        VersionFloorAction::EmptyGraph
+    }
+}
+
+/// Pinned graph entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinnedGraphConfig {
+    /// Stream of the pinned graph.
+    pub stream: String,
+    /// Architecture of the pinned graph.
+    pub basearch: String,
+    /// Path to the JSON graph snapshot.
+    pub path: PathBuf,
+}
+
+/// Graph scopes allowlist configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScopeAllowlistConfig {
+    /// Statically allowed scopes.
+    pub scopes: Vec<ScopeConfig>,
+    /// Discovery of allowed scopes from the upstream.
+    pub discovery: Option<ScopeDiscoveryConfig>,
+}
+
+/// Allowed scope entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeConfig {
+    /// Allowed stream.
+    pub stream: String,
+    /// Allowed architecture.
+    pub basearch: String,
+}
+
+/// Scopes discovery configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeDiscoveryConfig {
+    /// URL of the graph-builder scopes endpoint.
+    pub url: String,
+    /// Interval between refreshes of discovered scopes, in seconds.
+    pub refresh_secs: Option<u64>,
+}
+
+/// Upstream configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Source of upstream graphs.
+    pub source: Option<UpstreamSource>,
+    /// Upstream graph endpoints, with optional weights.
+    pub endpoints: Vec<UpstreamEndpointConfig>,
+    /// Per-stream upstream graph endpoint URLs, overriding `endpoints`.
+    pub streams: BTreeMap<String, String>,
+    /// Additional headers to send on requests to the upstream.
+    pub headers: BTreeMap<String, String>,
+    /// Authentication towards the upstream.
+    pub auth: UpstreamAuthConfig,
+    /// Retries of failed upstream requests.
+    pub retry: UpstreamRetryConfig,
+    /// Limits on concurrent requests to each upstream endpoint.
+    pub concurrency: UpstreamConcurrencyConfig,
+    /// Following of upstream redirects.
+    pub redirects: UpstreamRedirectsConfig,
+    /// Refusal of upstream requests to internal addresses.
+    pub ssrf_guard: UpstreamSsrfGuardConfig,
+    /// Detection of upstream graphs shrinking unexpectedly.
+    pub shrink_guard: UpstreamShrinkGuardConfig,
+}
+
+/// Source of upstream graphs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamSource {
+    /// Fetch graphs from the graph-builder.
+    GraphBuilder,
+    /// Serve the graphs embedded in the binary, without any upstream.
+    Embedded,
+}
+
+impl Default for UpstreamSource {
+    fn default() -> Self {
+        UpstreamSource::GraphBuilder
+    }
+}
+
+/// Upstream endpoint entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamEndpointConfig {
+    /// URL of the upstream graph endpoint.
+    pub url: String,
+    /// Relative weight for traffic selection (zero means failover only).
+    pub weight: Option<u32>,
+}
+
+/// Upstream authentication configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamAuthConfig {
+    /// Path to a file containing a bearer token.
+    pub bearer_token_path: Option<PathBuf>,
+    /// Path to a PEM client certificate (chain), for mTLS.
+    pub client_cert_path: Option<PathBuf>,
+    /// Path to a PEM private key for the client certificate, for mTLS.
+    pub client_key_path: Option<PathBuf>,
+}
+
+/// Upstream retry configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamRetryConfig {
+    /// Maximum number of retries for a single upstream request.
+    pub max_retries: Option<u32>,
+    /// Ratio of retries to requests allowed within a budget window.
+    pub budget_ratio: Option<f64>,
+    /// Minimum number of retries always allowed within a budget window.
+    pub budget_min_retries: Option<u64>,
+    /// Length of a budget window, in seconds.
+    pub budget_window_secs: Option<u64>,
+    /// Grace delay before retrying a single transient failure, in milliseconds.
+    pub soft_failure_ms: Option<u64>,
+    /// Backoff delay before the first retry, in milliseconds.
+    pub backoff_initial_ms: Option<u64>,
+    /// Maximum backoff delay between retries, in milliseconds.
+    pub backoff_max_ms: Option<u64>,
+}
+
+/// Upstream concurrency configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConcurrencyConfig {
+    /// Maximum number of in-flight requests to each upstream endpoint.
+    pub max_in_flight: Option<usize>,
+    /// Maximum number of requests waiting for each upstream endpoint.
+    pub max_queued: Option<usize>,
+}
+
+/// Upstream redirects configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamRedirectsConfig {
+    /// Maximum number of redirects followed for a single upstream request.
+    pub max_redirects: Option<usize>,
+    /// Whether to only follow redirects to the same host and port.
+    pub same_host_only: Option<bool>,
+}
+
+/// Upstream SSRF guard configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamSsrfGuardConfig {
+    /// Whether to refuse upstream requests to internal addresses.
+    pub enabled: Option<bool>,
+    /// Hostnames and IP addresses allowed despite being internal.
+    pub allowed_hosts: Vec<String>,
+}
+
+/// Upstream shrink guard configuration section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamShrinkGuardConfig {
+    /// Maximum drop in node count from the cached graph, in percent.
+    pub max_shrink_percent: Option<u32>,
+    /// Whether to keep the cached graph instead of a shrunk one.
+    pub keep_cached: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    static TOML_CONFIG: &str = r#"
+        [service]
+        max_graph_requests = 100
+        [service.headers]
+        "X-Content-Type-Options" = "nosniff"
+
+        [service.warmup]
+        criterion = "all"
+
+        [[upstream.endpoints]]
+        url = "http://localhost:8080/v1/graph"
+        weight = 2
+
+        [policy]
+        wariness_salt = "salt"
+
+        [scope_allowlist]
+        scopes = [{ basearch = "x86_64", stream = "stable" }]
+    "#;
+
+    #[test]
+    fn test_default_config_options() {
+        // The default configuration is all comments, and valid as such.
+        FileConfig::parse_str(DEFAULT_CONFIG_TOML, ConfigFormat::Toml).unwrap();
+
+        // Each table of commented-out options must parse once uncommented,
+        // so that documented options stay in sync with the configuration.
+        let mut blocks: Vec<(String, Vec<String>)> = vec![];
+        for line in DEFAULT_CONFIG_TOML.lines() {
+            let uncommented = line.trim_start_matches("# ");
+            if uncommented.starts_with('[') {
+                blocks.push((uncommented.to_string(), vec![]));
+                continue;
+            }
+            let mut assignment = uncommented.splitn(2, " = ");
+            let key = assignment.next().unwrap_or_default();
+            let is_option = line.starts_with("# ")
+                && assignment.next().is_some()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-\"".contains(c));
+            if let (true, Some((_, options))) = (is_option, blocks.last_mut()) {
+                options.push(uncommented.to_string());
+            }
+        }
+        assert!(!blocks.is_empty());
+        for (header, options) in blocks {
+            let table = format!("{}\n{}\n", header, options.join("\n"));
+            if let Err(e) = FileConfig::parse_str(&table, ConfigFormat::Toml) {
+                panic!("invalid documented options:\n{}\n{}", table, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_formats_roundtrip() {
+        let value: toml::Value = toml::from_str(TOML_CONFIG).unwrap();
+        let toml_cfg = FileConfig::parse_str(TOML_CONFIG, ConfigFormat::Toml).unwrap();
+        let json = serde_json::to_string(&value).unwrap();
+        let json_cfg = FileConfig::parse_str(&json, ConfigFormat::Json).unwrap();
+        let yaml = serde_yaml::to_string(&value).unwrap();
+        let yaml_cfg = FileConfig::parse_str(&yaml, ConfigFormat::Yaml).unwrap();
+
+        assert_eq!(toml_cfg.service.max_graph_requests, Some(100));
+        assert_eq!(
+            toml_cfg.service.warmup.criterion,
+            Some(ReadinessCriterion::All)
+        );
+        assert_eq!(format!("{:?}", json_cfg), format!("{:?}", toml_cfg));
+        assert_eq!(format!("{:?}", yaml_cfg), format!("{:?}", toml_cfg));
+
+        // Unknown fields are rejected in all formats.
+        let unknown = r#"{"service": {"unknown": true}}"#;
+        assert!(FileConfig::parse_str(unknown, ConfigFormat::Json).is_err());
+        assert!(FileConfig::parse_str("service:\n  unknown: true\n", ConfigFormat::Yaml).is_err());
+        assert!(FileConfig::parse_str("[service]\nunknown = true\n", ConfigFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn test_parse_file_format() {
+        let config_file = |suffix: &str, content: &str| {
+            let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+            file.write_all(content.as_bytes()).unwrap();
+            file
+        };
+        let toml_file = config_file(".toml", TOML_CONFIG);
+        assert!(FileConfig::parse_file(toml_file.path(), None).is_ok());
+        let yml_file = config_file(".yml", "service:\n  max_graph_requests: 100\n");
+        let cfg = FileConfig::parse_file(yml_file.path(), None).unwrap();
+        assert_eq!(cfg.service.max_graph_requests, Some(100));
+
+        // An explicit format must match a known extension, and is required
+        // for unknown ones.
+        assert!(FileConfig::parse_file(toml_file.path(), Some(ConfigFormat::Json)).is_err());
+        let conf_file = config_file(".conf", r#"{"service": {}}"#);
+        assert!(FileConfig::parse_file(conf_file.path(), None).is_err());
+        assert!(FileConfig::parse_file(conf_file.path(), Some(ConfigFormat::Json)).is_ok());
+
+        assert_eq!("yaml".parse::<ConfigFormat>().unwrap(), ConfigFormat::Yaml);
+        assert!("ini".parse::<ConfigFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_with_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        };
+        let base = dir.path().join("base.yaml");
+        std::fs::write(
+            &base,
+            "service:\n  max_graph_requests: 100\n  max_query_length: 512\n",
+        )
+        .unwrap();
+        let fragments = dir.path().join("conf.d");
+        std::fs::create_dir(&fragments).unwrap();
+        let write_fragment =
+            |name: &str, content: &str| write(&format!("conf.d/{}", name), content);
+        write_fragment(
+            "20-env.toml",
+            "[service]\nmax_graph_requests = 300\n[[upstream.endpoints]]\nurl = \"http://b/\"\n",
+        );
+        write_fragment(
+            "10-base.toml",
+            "[service]\nmax_graph_requests = 200\nrequire_user_agent = true\n\
+             [[upstream.endpoints]]\nurl = \"http://a/\"\n",
+        );
+        // Non-TOML files are ignored.
+        write_fragment("README", "not a config fragment");
+
+        let cfg = FileConfig::parse_with_fragments(&base, None, &fragments).unwrap();
+        // Later fragments override earlier ones and the base file, while
+        // other keys of merged tables are kept.
+        assert_eq!(cfg.service.max_graph_requests, Some(300));
+        assert_eq!(cfg.service.max_query_length, Some(512));
+        assert_eq!(cfg.service.require_user_agent, Some(true));
+        // Arrays are replaced, not appended.
+        assert_eq!(cfg.upstream.endpoints.len(), 1);
+        assert_eq!(cfg.upstream.endpoints[0].url, "http://b/");
+
+        // Invalid fragments are reported by name.
+        write_fragment("30-typo.toml", "[service]\nmax_graph_request = 1\n");
+        let err = FileConfig::parse_with_fragments(&base, None, &fragments).unwrap_err();
+        assert!(format!("{}", err).contains("30-typo.toml"));
+        assert!(FileConfig::parse_with_fragments(&base, None, dir.path().join("missing")).is_err());
+    }
 }
@@ -0,0 +1,373 @@
+//! Debugging aids for the main service, disabled by default.
+//!
+//! Sampled request logs are the exception: they are meant for production,
+//! and never include client identifiers nor credentials.
+
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use commons::graph::Graph;
+use commons::metadata;
+use serde::Serialize;
+
+/// Maximum number of request headers that can be configured for echoing.
+pub(crate) const MAX_ECHO_HEADERS: usize = 16;
+/// Maximum number of echoed values per request header.
+const MAX_ECHO_VALUES: usize = 4;
+/// Maximum length of an echoed value, in bytes; longer values are truncated.
+const MAX_ECHO_VALUE_LEN: usize = 256;
+
+/// Maximum size of graphs posted for validation, in bytes.
+pub(crate) const MAX_VALIDATE_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Prefix of response headers carrying echoed request headers.
+pub(crate) static ECHO_HEADER_PREFIX: &str = "X-Echo-";
+
+/// Log target of sampled request logs, enabled regardless of verbosity.
+pub(crate) static SAMPLED_LOG_TARGET: &str = "fcos_policy_engine::sampled";
+
+/// Query parameters whose values are redacted in sampled logs.
+const REDACTED_PARAMS: &[&str] = &["node_uuid"];
+/// Request headers whose values are redacted in sampled logs.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// Response headers echoing the configured request headers.
+///
+/// Each entry maps a request header to its echo response header.
+pub(crate) fn echoed_headers(
+    echo: &[(HeaderName, HeaderName)],
+    request: &HeaderMap,
+) -> Vec<(HeaderName, HeaderValue)> {
+    let mut echoed = vec![];
+    for (name, echo_name) in echo {
+        for value in request.get_all(name).take(MAX_ECHO_VALUES) {
+            let bytes = value.as_bytes();
+            let truncated = &bytes[..bytes.len().min(MAX_ECHO_VALUE_LEN)];
+            if let Ok(value) = HeaderValue::from_bytes(truncated) {
+                echoed.push((echo_name.clone(), value));
+            }
+        }
+    }
+    echoed
+}
+
+/// Validation report of a candidate graph.
+#[derive(Debug, Serialize)]
+pub(crate) struct ValidationReport {
+    pub(crate) valid: bool,
+    pub(crate) nodes: usize,
+    pub(crate) edges: usize,
+    /// Problems making the graph unusable by the policy-engine.
+    pub(crate) errors: Vec<String>,
+    /// Versions of dead-end releases.
+    pub(crate) deadends: Vec<String>,
+}
+
+/// Validate a candidate graph JSON, with the same checks applied to
+/// upstream graphs by the self-test.
+pub(crate) fn validate_graph(body: &[u8]) -> ValidationReport {
+    let graph = match serde_json::from_slice::<Graph>(body) {
+        Ok(graph) => graph,
+        Err(e) => {
+            return ValidationReport {
+                valid: false,
+                nodes: 0,
+                edges: 0,
+                errors: vec![format!("invalid graph JSON: {}", e)],
+                deadends: vec![],
+            }
+        }
+    };
+
+    let mut errors = vec![];
+    if graph.nodes.is_empty() {
+        errors.push("empty graph".to_string());
+    }
+    if let Err(e) = graph.check_acyclic() {
+        errors.push(e.to_string());
+    }
+    let deadends = graph
+        .nodes
+        .iter()
+        .filter(|node| node.metadata.get(metadata::DEADEND).map(String::as_str) == Some("true"))
+        .map(|node| node.version.clone())
+        .collect();
+    ValidationReport {
+        valid: errors.is_empty(),
+        nodes: graph.nodes.len(),
+        edges: graph.edges.len(),
+        errors,
+        deadends,
+    }
+}
+
+/// Content type of Graphviz DOT documents.
+pub(crate) static DOT_CONTENT_TYPE: &str = "text/vnd.graphviz; charset=utf-8";
+
+/// Render a graph in Graphviz DOT format, for eyeballing its topology.
+///
+/// Nodes are labeled with release versions; dead-ends are drawn in red and
+/// releases being rolled out with a dashed outline.
+pub(crate) fn graph_to_dot(graph: &Graph, name: &str) -> String {
+    let mut dot = format!("digraph \"{}\" {{\n", escape_dot(name));
+    for (index, release) in graph.nodes.iter().enumerate() {
+        let mut attrs = vec![format!("label=\"{}\"", escape_dot(&release.version))];
+        if release.metadata.get(metadata::DEADEND).map(String::as_str) == Some("true") {
+            attrs.push("color=red".to_string());
+        }
+        if release.metadata.contains_key(metadata::ROLLOUT) {
+            attrs.push("style=dashed".to_string());
+        }
+        dot.push_str(&format!("  n{} [{}];\n", index, attrs.join(", ")));
+    }
+    for (from, to) in &graph.edges {
+        dot.push_str(&format!("  n{} -> n{};\n", from, to));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Escape a DOT quoted string.
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Reason set on synthetic dead-end releases.
+const INJECTED_DEADEND_REASON: &str = "synthetic dead-end injected by policy-engine debugging aids";
+
+/// Mark releases with the given versions as dead-ends, for testing how
+/// clients handle them. Their outgoing edges are then pruned by the
+/// dead-end filtering policy.
+pub(crate) fn inject_deadends(input: Graph, versions: &[String]) -> Graph {
+    let mut graph = input;
+    for release in graph.nodes.iter_mut() {
+        if versions.contains(&release.version) {
+            release
+                .metadata
+                .insert(metadata::DEADEND.to_string(), "true".to_string());
+            release.metadata.insert(
+                metadata::DEADEND_REASON.to_string(),
+                INJECTED_DEADEND_REASON.to_string(),
+            );
+        }
+    }
+    graph
+}
+
+/// Whether to log the current request in full detail.
+pub(crate) fn sample_request(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+/// Format a query string for sampled logs, redacting client identifiers.
+pub(crate) fn redacted_query(query: &str) -> String {
+    let params: Vec<String> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let key = parts.next().unwrap_or_default();
+            // Keys may be percent-encoded, e.g. `node%5Fuuid`.
+            let normalized = key.to_ascii_lowercase().replace("%5f", "_");
+            if REDACTED_PARAMS.contains(&normalized.as_str()) {
+                format!("{}=<redacted>", key)
+            } else {
+                param.to_string()
+            }
+        })
+        .collect();
+    params.join("&")
+}
+
+/// Format request or response headers for sampled logs, redacting
+/// credentials.
+pub(crate) fn redacted_headers(headers: &HeaderMap) -> String {
+    let entries: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            let val = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<non-ascii>")
+            };
+            format!("{}={}", name, val)
+        })
+        .collect();
+    entries.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graph_to_dot() {
+        let release = |version: &str, key: Option<&str>| {
+            let mut entries = std::collections::HashMap::new();
+            if let Some(key) = key {
+                entries.insert(key.to_string(), "true".to_string());
+            }
+            commons::graph::CincinnatiPayload {
+                version: version.to_string(),
+                metadata: entries,
+                payload: String::new(),
+            }
+        };
+        let graph = Graph {
+            nodes: vec![
+                release("35.1.0", None),
+                release("35.2.0", Some(metadata::DEADEND)),
+                release("35.3.0\"", Some(metadata::ROLLOUT)),
+            ],
+            edges: vec![(0, 1), (0, 2)],
+        };
+        assert_eq!(
+            graph_to_dot(&graph, "stable/x86_64"),
+            "digraph \"stable/x86_64\" {\n\
+             \x20 n0 [label=\"35.1.0\"];\n\
+             \x20 n1 [label=\"35.2.0\", color=red];\n\
+             \x20 n2 [label=\"35.3.0\\\"\", style=dashed];\n\
+             \x20 n0 -> n1;\n\
+             \x20 n0 -> n2;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_echoed_headers() {
+        let echo = vec![
+            (
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderName::from_static("x-echo-x-forwarded-for"),
+            ),
+            (
+                HeaderName::from_static("via"),
+                HeaderName::from_static("x-echo-via"),
+            ),
+        ];
+        let mut request = HeaderMap::new();
+        for addr in &[
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+            "192.0.2.4",
+            "192.0.2.5",
+        ] {
+            request.append(
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderValue::from_static(addr),
+            );
+        }
+        let long = "a".repeat(1000);
+        request.insert(
+            HeaderName::from_static("via"),
+            HeaderValue::from_str(&long).unwrap(),
+        );
+        request.insert(
+            HeaderName::from_static("cookie"),
+            HeaderValue::from_static("secret"),
+        );
+
+        let echoed = echoed_headers(&echo, &request);
+        let mut forwarded: Vec<_> = echoed
+            .iter()
+            .filter(|(name, _)| name == "x-echo-x-forwarded-for")
+            .map(|(_, value)| value.to_str().unwrap())
+            .collect();
+        forwarded.sort_unstable();
+        assert_eq!(
+            forwarded,
+            vec!["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"]
+        );
+        let via: Vec<_> = echoed
+            .iter()
+            .filter(|(name, _)| name == "x-echo-via")
+            .collect();
+        assert_eq!(via.len(), 1);
+        assert_eq!(via[0].1.len(), MAX_ECHO_VALUE_LEN);
+        assert_eq!(echoed.len(), 5);
+    }
+
+    #[test]
+    fn test_sampled_log_redaction() {
+        assert_eq!(
+            redacted_query("stream=stable&node_uuid=abcd&basearch=x86_64"),
+            "stream=stable&node_uuid=<redacted>&basearch=x86_64"
+        );
+        assert_eq!(
+            redacted_query("node%5Fuuid=abcd&&rollout_wariness=0.5"),
+            "node%5Fuuid=<redacted>&rollout_wariness=0.5"
+        );
+        assert_eq!(redacted_query(""), "");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_static("Bearer secret"),
+        );
+        headers.insert(
+            HeaderName::from_static("user-agent"),
+            HeaderValue::from_static("zincati"),
+        );
+        let out = redacted_headers(&headers);
+        assert!(out.contains("authorization=<redacted>"));
+        assert!(out.contains("user-agent=zincati"));
+        assert!(!out.contains("secret"));
+
+        assert!(!sample_request(0.0));
+        assert!(sample_request(1.0));
+    }
+
+    #[test]
+    fn test_validate_graph() {
+        let report = validate_graph(b"{\"nodes\": []}");
+        assert!(!report.valid);
+        assert!(report.errors[0].starts_with("invalid graph JSON"));
+
+        let graph = serde_json::json!({
+            "nodes": [
+                {"version": "35.1.0", "metadata": {}, "payload": ""},
+                {"version": "35.2.0", "metadata": {metadata::DEADEND: "true"}, "payload": ""},
+            ],
+            "edges": [[0, 1]],
+        });
+        let report = validate_graph(graph.to_string().as_bytes());
+        assert!(report.valid);
+        assert_eq!((report.nodes, report.edges), (2, 1));
+        assert!(report.errors.is_empty());
+        assert_eq!(report.deadends, vec!["35.2.0"]);
+
+        let mut cyclic = graph.clone();
+        cyclic["edges"] = serde_json::json!([[0, 1], [1, 0]]);
+        let report = validate_graph(cyclic.to_string().as_bytes());
+        assert!(!report.valid);
+        assert!(report.errors[0].contains("cycles"));
+
+        let mut out_of_bounds = graph;
+        out_of_bounds["edges"] = serde_json::json!([[0, 2]]);
+        let report = validate_graph(out_of_bounds.to_string().as_bytes());
+        assert!(!report.valid);
+        assert!(report.errors[0].contains("out of bounds"));
+    }
+
+    #[test]
+    fn test_inject_deadends() {
+        let graph: Graph = serde_json::from_value(serde_json::json!({
+            "nodes": [
+                {"version": "35.1.0", "metadata": {}, "payload": ""},
+                {"version": "35.2.0", "metadata": {}, "payload": ""},
+                {"version": "35.3.0", "metadata": {}, "payload": ""},
+            ],
+            "edges": [[0, 1], [0, 2], [1, 2]],
+        }))
+        .unwrap();
+
+        let injected = inject_deadends(graph, &["35.2.0".to_string(), "36.1.0".to_string()]);
+        let deadend = &injected.nodes[1].metadata;
+        assert_eq!(deadend.get(metadata::DEADEND).unwrap(), "true");
+        assert!(deadend.contains_key(metadata::DEADEND_REASON));
+        assert!(injected.nodes[0].metadata.is_empty());
+
+        let filtered = commons::policy::filter_deadends(injected);
+        assert!(filtered.edges.iter().all(|(from, _)| *from != 1));
+        assert_eq!(filtered.edges, vec![(0, 1), (0, 2)]);
+    }
+}
@@ -0,0 +1,214 @@
+//! End-to-end tests, running the actual main and status servers on
+//! ephemeral ports in front of a mock upstream, and querying them over real
+//! sockets. These cover the wiring of `main()` (middlewares, routes, error
+//! mapping) that handler tests bypass.
+
+use super::tests::{canned_upstream, settings_for};
+use super::*;
+use actix_web::http::StatusCode;
+use std::net::Ipv4Addr;
+
+/// Running policy-engine servers, with their upstream.
+struct Harness {
+    /// Mock upstream graph-builder, kept alive for the servers.
+    _upstream: actix_web::test::TestServer,
+    service: SocketAddr,
+    status: SocketAddr,
+    client: reqwest::Client,
+}
+
+impl Harness {
+    /// Start the servers, with the given settings tweaks, on ephemeral
+    /// loopback ports so that tests may run in parallel.
+    fn start(tweak: impl FnOnce(&mut settings::ServiceSettings)) -> Self {
+        let upstream = canned_upstream();
+        let mut settings = settings::ServiceSettings {
+            ip_addr: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            ..settings_for(&upstream)
+        };
+        settings.upstream.req_timeout = Duration::from_millis(500);
+        tweak(&mut settings);
+        let status_settings = settings::StatusSettings {
+            ip_addr: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+        };
+
+        let state = AppState::new(&settings).unwrap();
+        let status = start_status_service(&status_settings, state.clone()).unwrap();
+        let service = start_main_service(settings, state).unwrap();
+        Self {
+            _upstream: upstream,
+            service: service[0],
+            status: status[0],
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Build a request to the main service.
+    fn get(&self, path_and_query: &str) -> reqwest::RequestBuilder {
+        let url = format!("http://{}{}", self.service, path_and_query);
+        self.client.get(&url)
+    }
+}
+
+static GRAPH_QUERY: &str = "/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.1";
+
+#[actix_rt::test]
+async fn test_e2e_graph_conditional_get() {
+    let harness = Harness::start(|_| {});
+
+    let resp = harness.get(GRAPH_QUERY).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+    let graph: graph::Graph = resp.json().await.unwrap();
+    assert_eq!(graph.nodes.len(), 3);
+    assert_eq!(graph.edges, vec![(0, 1), (0, 2)]);
+
+    // Trailing slashes are normalized before routing.
+    let resp = harness
+        .get("/v1/graph/?basearch=x86_64&stream=stable&rollout_wariness=0.1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = harness
+        .get(GRAPH_QUERY)
+        .header(header::IF_NONE_MATCH, etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get(header::ETAG).unwrap(), &etag);
+    assert!(resp.bytes().await.unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn test_e2e_compression() {
+    let harness = Harness::start(|settings| {
+        settings.compression.enabled = true;
+        settings.compression.zstd_level = Some(3);
+    });
+
+    let resp = harness
+        .get(GRAPH_QUERY)
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+        "gzip"
+    );
+
+    let resp = harness
+        .get(GRAPH_QUERY)
+        .header(header::ACCEPT_ENCODING, "zstd")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+        "zstd"
+    );
+    let body = resp.bytes().await.unwrap();
+    let graph: graph::Graph =
+        serde_json::from_slice(&zstd::decode_all(&body[..]).unwrap()).unwrap();
+    assert_eq!(graph.nodes.len(), 3);
+
+    // Identity is served otherwise.
+    let resp = harness.get(GRAPH_QUERY).send().await.unwrap();
+    assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+}
+
+#[actix_rt::test]
+async fn test_e2e_error_mapping() {
+    let harness = Harness::start(|settings| {
+        let broken = reqwest::Url::parse(&format!(
+            "{}/broken",
+            settings.upstream.endpoints[0]
+                .url
+                .origin()
+                .ascii_serialization()
+        ))
+        .unwrap();
+        settings.upstream.stream_endpoints.insert(
+            "broken".to_string(),
+            settings::UpstreamEndpoint::new(broken),
+        );
+    });
+    let error = |resp: reqwest::Response| async move {
+        let status = resp.status();
+        let envelope: serde_json::Value = resp.json().await.unwrap();
+        (status, envelope["kind"].as_str().unwrap().to_string())
+    };
+
+    let resp = harness
+        .get("/v1/graph?basearch=x86_64")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        error(resp).await,
+        (StatusCode::BAD_REQUEST, "invalid_query".to_string())
+    );
+
+    let resp = harness
+        .get("/v1/graph?basearch=x86_64&stream=broken")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        error(resp).await,
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "upstream_unavailable".to_string()
+        )
+    );
+
+    let resp = harness.get("/v1/unknown").send().await.unwrap();
+    assert_eq!(
+        error(resp).await,
+        (StatusCode::NOT_FOUND, "not_found".to_string())
+    );
+}
+
+#[actix_rt::test]
+async fn test_e2e_status_service() {
+    let harness = Harness::start(|_| {});
+    let status = |path: &str| format!("http://{}{}", harness.status, path);
+
+    let resp = harness.get(GRAPH_QUERY).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = harness.client.get(&status("/readyz")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = harness
+        .client
+        .get(&status("/metrics"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let exposition = resp.text().await.unwrap();
+    assert!(exposition.contains("fcos_cincinnati_pe_v1_graph_incoming_requests_total "));
+    assert!(exposition.contains("fcos_cincinnati_pe_v1_graph_responses_total{"));
+    assert!(exposition.contains("fcos_cincinnati_pe_v1_graph_request_duration_seconds_bucket{"));
+
+    // The status service does not serve graphs.
+    let resp = harness
+        .client
+        .get(&status(GRAPH_QUERY))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
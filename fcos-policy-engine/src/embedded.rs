@@ -0,0 +1,62 @@
+//! Graphs embedded in the binary, for demos, CI and offline evaluation.
+//!
+//! In embedded mode, no upstream graph-builder is queried at all: only the
+//! scopes below are served, from small fixture graphs. This is not meant for
+//! production, where graphs always come from the graph-builder.
+
+use commons::graph::{Graph, GraphScope};
+use failure::{Fallible, ResultExt};
+use std::collections::HashMap;
+
+/// Embedded graphs, as `(stream, basearch, JSON graph)`.
+static EMBEDDED_GRAPHS: &[(&str, &str, &str)] = &[
+    (
+        "stable",
+        "x86_64",
+        include_str!("../fixtures/embedded/stable-x86_64.json"),
+    ),
+    (
+        "stable",
+        "aarch64",
+        include_str!("../fixtures/embedded/stable-aarch64.json"),
+    ),
+    (
+        "testing",
+        "x86_64",
+        include_str!("../fixtures/embedded/testing-x86_64.json"),
+    ),
+];
+
+/// Parse the embedded graphs, keyed by scope.
+pub(crate) fn graphs() -> Fallible<HashMap<GraphScope, Graph>> {
+    let mut graphs = HashMap::with_capacity(EMBEDDED_GRAPHS.len());
+    for (stream, basearch, json) in EMBEDDED_GRAPHS {
+        let graph: Graph = serde_json::from_str(json).with_context(|_| {
+            format!(
+                "invalid embedded graph for basearch='{}', stream='{}'",
+                basearch, stream
+            )
+        })?;
+        let scope = GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        graphs.insert(scope, graph);
+    }
+    Ok(graphs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_graphs() {
+        let graphs = graphs().unwrap();
+        assert_eq!(graphs.len(), EMBEDDED_GRAPHS.len());
+        for graph in graphs.values() {
+            assert!(!graph.nodes.is_empty());
+            graph.check_acyclic().unwrap();
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Entity tags of responses, for conditional requests.
+//!
+//! Tags are hashes of the (uncompressed) response body, so that they only
+//! change along with the content. Clients revalidate with `If-None-Match`
+//! and get a `304 Not Modified` while their copy is current.
+//!
+//! Graph tags also cover the scope and effective rollout wariness of the
+//! request, so that a tag is never valid for another stream or basearch,
+//! even when both happen to serve the same graph.
+//!
+//! Strong tags must differ across content-codings: zstd-encoded responses
+//! get a suffixed tag, while responses which the `Compress` middleware may
+//! encode get a weak tag, shared by all their codings.
+
+use actix_web::http::{header, HeaderMap};
+use commons::graph::GraphScope;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Strong entity tag of a response body.
+pub(crate) fn body_etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Strong entity tag of a graph response body, scoped to the inputs it
+/// was computed from.
+pub(crate) fn graph_etag(scope: &GraphScope, wariness: f64, body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    scope.stream.hash(&mut hasher);
+    scope.basearch.hash(&mut hasher);
+    wariness.to_bits().hash(&mut hasher);
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Strong entity tag of a response in a content-coding, from the tag of
+/// its unencoded body.
+pub(crate) fn encoded_etag(etag: &str, coding: &str) -> String {
+    format!("\"{}-{}\"", etag.trim_matches('"'), coding)
+}
+
+/// Weak entity tag, from a strong one.
+pub(crate) fn weak_etag(etag: &str) -> String {
+    format!("W/{}", etag)
+}
+
+/// Whether the `If-None-Match` request header matches an entity tag, i.e.
+/// the client copy is current.
+///
+/// Tags are compared weakly, as for `GET` and `HEAD` requests.
+pub(crate) fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|tags| tags.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = body_etag(b"{}");
+        assert_eq!(etag, body_etag(b"{}"));
+        assert_ne!(etag, body_etag(b"[]"));
+
+        let mut headers = HeaderMap::new();
+        assert!(!if_none_match(&headers, &etag));
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"stale\""));
+        assert!(!if_none_match(&headers, &etag));
+        headers.append(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", W/{}", etag)).unwrap(),
+        );
+        assert!(if_none_match(&headers, &etag));
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(if_none_match(&headers, &etag));
+
+        // Weak tags match their strong counterparts, unlike encoded ones.
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap());
+        assert!(if_none_match(&headers, &weak_etag(&etag)));
+        assert!(!if_none_match(&headers, &encoded_etag(&etag, "zstd")));
+    }
+
+    #[test]
+    fn test_encoded_etag() {
+        assert_eq!(encoded_etag("\"0123\"", "zstd"), "\"0123-zstd\"");
+        assert_eq!(weak_etag("\"0123\""), "W/\"0123\"");
+    }
+
+    #[test]
+    fn test_graph_etag() {
+        let scope = |basearch: &str, stream: &str| GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        let etag = graph_etag(&scope("x86_64", "stable"), 0.5, b"{}");
+        assert_eq!(etag, graph_etag(&scope("x86_64", "stable"), 0.5, b"{}"));
+        assert_ne!(etag, graph_etag(&scope("x86_64", "testing"), 0.5, b"{}"));
+        assert_ne!(etag, graph_etag(&scope("aarch64", "stable"), 0.5, b"{}"));
+        assert_ne!(etag, graph_etag(&scope("x86_64", "stable"), 0.25, b"{}"));
+        assert_ne!(etag, graph_etag(&scope("x86_64", "stable"), 0.5, b"[]"));
+        // Fields are not simply concatenated.
+        assert_ne!(
+            graph_etag(&scope("x86_64", "stable"), 0.5, b"{}"),
+            graph_etag(&scope("86_64", "stablex"), 0.5, b"{}")
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Formats of graph responses.
+//!
+//! By default, graphs are served in the Cincinnati graph format (schema v1):
+//! an object with a `nodes` array of releases, and an `edges` array of
+//! `[from, to]` pairs of node indices:
+//!
+//! ```json
+//! {
+//!   "nodes": [
+//!     {"version": "35.1.0", "metadata": {...}, "payload": "..."},
+//!     {"version": "35.2.0", "metadata": {...}, "payload": "..."}
+//!   ],
+//!   "edges": [[0, 1]]
+//! }
+//! ```
+//!
+//! Schema v2 carries its `schema_version`, and references releases by
+//! version in edges, so that they do not depend on nodes order:
+//!
+//! ```json
+//! {
+//!   "schema_version": 2,
+//!   "nodes": [...],
+//!   "edges": [{"from": "35.1.0", "to": "35.2.0"}]
+//! }
+//! ```
+//!
+//! Clients select graph schemas by listing `graph-v1` and/or `graph-v2`
+//! profiles in their `Accept` header (e.g. `Accept: application/json;
+//! profile=graph-v1, application/json; profile=graph-v2`): the highest one
+//! supported is served, and v1 if none is listed. Schema v2 responses are
+//! labeled as such in their `Content-Type` profile.
+//!
+//! For older clients, the legacy array format is a flat array of releases
+//! in graph order, each listing the versions it can directly update to:
+//!
+//! ```json
+//! [
+//!   {"version": "35.1.0", "metadata": {...}, "payload": "...", "next": ["35.2.0"]},
+//!   {"version": "35.2.0", "metadata": {...}, "payload": "...", "next": []}
+//! ]
+//! ```
+//!
+//! The legacy format is selected by a `format=legacy` query parameter, or
+//! by an `Accept: application/json; profile=legacy` request header, unless
+//! a graph schema profile is listed too; the query parameter takes
+//! precedence, `format=graph` selecting schema v1. Legacy responses carry
+//! neither policy metadata, rollout details nor pagination details.
+//!
+//! When rollouts are passed through to clients (`rollouts = "passthrough"`
+//! policy), releases being rolled out are not throttled: graph responses
+//! instead carry an `edge_rollouts` array, with an entry for each edge
+//! towards such a release, in both schemas:
+//!
+//! ```json
+//! "edge_rollouts": [
+//!   {
+//!     "from": "35.1.0",
+//!     "to": "35.2.0",
+//!     "progress": 0.5,
+//!     "start_epoch": 1600000000,
+//!     "start_value": 0.2,
+//!     "duration_minutes": 1440
+//!   }
+//! ]
+//! ```
+//!
+//! `progress` is the fraction of clients being offered the target release
+//! as of the response, and the other fields are the rollout parameters
+//! (`duration_minutes` is `null` for rollouts which do not progress on
+//! their own), so that clients can follow the rollout progress over time.
+//! Clients must throttle themselves: an edge listed there is only to be
+//! followed while the client rollout wariness is at most the rollout
+//! progress, as computed from its parameters at the time of the update.
+//! Edges not listed there are not throttled.
+
+use commons::errors::PeError;
+use commons::graph::{CincinnatiPayload, Graph};
+use commons::policy::Rollout;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Content type of graph responses in schema v2.
+pub(crate) static GRAPH_V2_CONTENT_TYPE: &str = "application/json; profile=graph-v2";
+
+/// Format of a graph response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum GraphFormat {
+    /// Cincinnati graph, with nodes and edges.
+    Graph,
+    /// Cincinnati graph in schema v2, with edges by version.
+    GraphV2,
+    /// Legacy flat array of releases.
+    Legacy,
+}
+
+/// Graph in schema v2.
+#[derive(Debug, Serialize)]
+pub(crate) struct GraphV2<'a> {
+    schema_version: u32,
+    nodes: &'a [CincinnatiPayload],
+    edges: Vec<EdgeV2<'a>>,
+}
+
+/// Edge of a schema v2 graph, by release versions.
+#[derive(Debug, Serialize)]
+struct EdgeV2<'a> {
+    from: &'a str,
+    to: &'a str,
+}
+
+/// Rollout details of an edge towards a release being rolled out.
+#[derive(Debug, Serialize)]
+pub(crate) struct EdgeRollout<'a> {
+    from: &'a str,
+    to: &'a str,
+    /// Fraction of clients (by wariness) currently offered the edge.
+    progress: f64,
+    #[serde(flatten)]
+    rollout: Rollout,
+}
+
+/// Release entry of the legacy array format.
+#[derive(Debug, Serialize)]
+pub(crate) struct LegacyRelease<'a> {
+    version: &'a str,
+    metadata: &'a HashMap<String, String>,
+    payload: &'a str,
+    /// Versions this release can directly update to, in edges order.
+    next: Vec<&'a str>,
+}
+
+/// Pick the response format, from the `format` query parameter or else the
+/// `Accept` header profiles.
+pub(crate) fn negotiate(
+    format: Option<&str>,
+    accept: Option<&str>,
+) -> Result<GraphFormat, PeError> {
+    if let Some(format) = format {
+        return match format {
+            "graph" => Ok(GraphFormat::Graph),
+            "legacy" => Ok(GraphFormat::Legacy),
+            _ => Err(PeError::InvalidQuery(format!(
+                "unknown graph format '{}'",
+                format
+            ))),
+        };
+    }
+    let profiles: Vec<&str> = accept
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(json_profile)
+        .collect();
+    if profiles.contains(&"graph-v2") {
+        Ok(GraphFormat::GraphV2)
+    } else if profiles.contains(&"graph-v1") || !profiles.contains(&"legacy") {
+        Ok(GraphFormat::Graph)
+    } else {
+        Ok(GraphFormat::Legacy)
+    }
+}
+
+/// Profile of an `Accept` media range for JSON, if any.
+fn json_profile(media_range: &str) -> Option<&str> {
+    let mut parts = media_range.split(';').map(str::trim);
+    let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+    if !["application/json", "application/*", "*/*"].contains(&media_type.as_str()) {
+        return None;
+    }
+    parts.find_map(|param| {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next().unwrap_or_default().trim();
+        let value = kv.next().unwrap_or_default().trim().trim_matches('"');
+        Some(value).filter(|_| key.eq_ignore_ascii_case("profile"))
+    })
+}
+
+/// A graph, in schema v2.
+pub(crate) fn graph_v2(graph: &Graph) -> GraphV2<'_> {
+    let version = |index: u64| graph.nodes.get(index as usize).map(|n| n.version.as_str());
+    let edges = graph
+        .edges
+        .iter()
+        .filter_map(|(from, to)| match (version(*from), version(*to)) {
+            (Some(from), Some(to)) => Some(EdgeV2 { from, to }),
+            _ => None,
+        })
+        .collect();
+    GraphV2 {
+        schema_version: 2,
+        nodes: &graph.nodes,
+        edges,
+    }
+}
+
+/// Rollout details of the edges of a graph towards releases being rolled
+/// out, as of `now`.
+pub(crate) fn edge_rollouts(graph: &Graph, now: i64) -> Vec<EdgeRollout<'_>> {
+    let rollouts: Vec<Option<Rollout>> = graph.nodes.iter().map(Rollout::of_release).collect();
+    graph
+        .edges
+        .iter()
+        .filter_map(|(from, to)| {
+            let from = graph.nodes.get(*from as usize)?;
+            let to_index = *to as usize;
+            let rollout = (*rollouts.get(to_index)?)?;
+            Some(EdgeRollout {
+                from: &from.version,
+                to: &graph.nodes[to_index].version,
+                progress: rollout.progress(now),
+                rollout,
+            })
+        })
+        .collect()
+}
+
+/// Releases of a graph, in the legacy array format.
+pub(crate) fn legacy_releases(graph: &Graph) -> Vec<LegacyRelease<'_>> {
+    let mut next: Vec<Vec<&str>> = vec![vec![]; graph.nodes.len()];
+    for (from, to) in &graph.edges {
+        let target = graph.nodes.get(*to as usize);
+        if let (Some(targets), Some(target)) = (next.get_mut(*from as usize), target) {
+            targets.push(target.version.as_str());
+        }
+    }
+    graph
+        .nodes
+        .iter()
+        .zip(next)
+        .map(|(node, next)| LegacyRelease {
+            version: &node.version,
+            metadata: &node.metadata,
+            payload: &node.payload,
+            next,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commons::metadata;
+
+    #[test]
+    fn test_negotiate() {
+        let legacy = Some("application/json; profile=legacy");
+        assert_eq!(negotiate(None, None).unwrap(), GraphFormat::Graph);
+        assert_eq!(
+            negotiate(Some("legacy"), None).unwrap(),
+            GraphFormat::Legacy
+        );
+        assert_eq!(
+            negotiate(Some("graph"), legacy).unwrap(),
+            GraphFormat::Graph
+        );
+        assert!(negotiate(Some("xml"), None).is_err());
+
+        assert_eq!(negotiate(None, legacy).unwrap(), GraphFormat::Legacy);
+        let accept = Some("text/html, application/json;q=0.9;profile=\"legacy\"");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Legacy);
+        let accept = Some("application/json, text/plain; profile=legacy");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+        let accept = Some("application/json; profile=other");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+    }
+
+    #[test]
+    fn test_negotiate_schema() {
+        // Unspecified, or v1-only clients get schema v1.
+        assert_eq!(negotiate(None, None).unwrap(), GraphFormat::Graph);
+        let accept = Some("application/json");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+        let accept = Some("application/json; profile=graph-v1");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+        let accept = Some("application/json; profile=legacy, application/json; profile=graph-v1");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+
+        // v2-capable clients get the highest supported schema.
+        let accept = Some("application/json; profile=graph-v1, application/json; profile=graph-v2");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::GraphV2);
+        let accept =
+            Some("application/json; profile=\"graph-v2\", application/json; profile=graph-v9");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::GraphV2);
+        let accept = Some("application/json; profile=graph-v9");
+        assert_eq!(negotiate(None, accept).unwrap(), GraphFormat::Graph);
+        // The query parameter takes precedence.
+        let accept = Some("application/json; profile=graph-v2");
+        assert_eq!(
+            negotiate(Some("graph"), accept).unwrap(),
+            GraphFormat::Graph
+        );
+    }
+
+    #[test]
+    fn test_graph_v2() {
+        let node = |version: &str| CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: format!("sha256:{}", version),
+        };
+        let graph = Graph {
+            nodes: vec![node("35.1.0"), node("35.2.0")],
+            edges: vec![(0, 1), (0, 5)],
+        };
+        let v2 = serde_json::to_value(graph_v2(&graph)).unwrap();
+        assert_eq!(
+            v2,
+            serde_json::json!({
+                "schema_version": 2,
+                "nodes": [
+                    {"version": "35.1.0", "metadata": {}, "payload": "sha256:35.1.0"},
+                    {"version": "35.2.0", "metadata": {}, "payload": "sha256:35.2.0"},
+                ],
+                "edges": [{"from": "35.1.0", "to": "35.2.0"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_legacy_releases() {
+        let node = |version: &str| CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: format!("sha256:{}", version),
+        };
+        let graph = Graph {
+            nodes: vec![node("35.1.0"), node("35.2.0"), node("35.3.0")],
+            edges: vec![(0, 2), (0, 1), (1, 2)],
+        };
+        let releases = serde_json::to_value(legacy_releases(&graph)).unwrap();
+        assert_eq!(
+            releases,
+            serde_json::json!([
+                {
+                    "version": "35.1.0",
+                    "metadata": {},
+                    "payload": "sha256:35.1.0",
+                    "next": ["35.3.0", "35.2.0"],
+                },
+                {
+                    "version": "35.2.0",
+                    "metadata": {},
+                    "payload": "sha256:35.2.0",
+                    "next": ["35.3.0"],
+                },
+                {
+                    "version": "35.3.0",
+                    "metadata": {},
+                    "payload": "sha256:35.3.0",
+                    "next": [],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_edge_rollouts() {
+        let node = |version: &str, rollout: &[(&str, &str)]| CincinnatiPayload {
+            version: version.to_string(),
+            metadata: rollout
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            payload: String::new(),
+        };
+        let graph = Graph {
+            nodes: vec![
+                node("35.1.0", &[]),
+                node(
+                    "35.2.0",
+                    &[
+                        (metadata::ROLLOUT, "true"),
+                        (metadata::START_EPOCH, "6000"),
+                        (metadata::START_VALUE, "0.2"),
+                        (metadata::DURATION, "100"),
+                    ],
+                ),
+                node("35.3.0", &[(metadata::ROLLOUT, "true")]),
+            ],
+            edges: vec![(0, 1), (0, 2), (1, 2), (2, 0), (0, 7)],
+        };
+        let rollouts = serde_json::to_value(edge_rollouts(&graph, 6000 + 50 * 60)).unwrap();
+        assert_eq!(
+            rollouts,
+            serde_json::json!([
+                {
+                    "from": "35.1.0",
+                    "to": "35.2.0",
+                    "progress": 0.6000000000000001,
+                    "start_epoch": 6000,
+                    "start_value": 0.2,
+                    "duration_minutes": 100,
+                },
+                {
+                    "from": "35.1.0",
+                    "to": "35.3.0",
+                    "progress": 0.0,
+                    "start_epoch": 0,
+                    "start_value": 0.0,
+                    "duration_minutes": null,
+                },
+                {
+                    "from": "35.2.0",
+                    "to": "35.3.0",
+                    "progress": 0.0,
+                    "start_epoch": 0,
+                    "start_value": 0.0,
+                    "duration_minutes": null,
+                },
+            ])
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Load tracking of the main service workers, for saturation metrics.
+//!
+//! actix-web does not expose connection or worker statistics, so load is
+//! measured per request instead: in-flight requests, overall and per
+//! worker, from which the number of busy workers is derived. Each worker
+//! runs its own single-threaded `App`, thus owns one `WorkerLoad`.
+
+use prometheus::IntGauge;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Load of a single service worker.
+#[derive(Debug)]
+pub(crate) struct WorkerLoad {
+    in_flight: Rc<Cell<u64>>,
+    metrics: LoadMetrics,
+}
+
+/// Gauges updated by worker load tracking.
+#[derive(Clone, Debug)]
+pub(crate) struct LoadMetrics {
+    pub(crate) workers: IntGauge,
+    pub(crate) busy_workers: IntGauge,
+    pub(crate) in_flight: IntGauge,
+}
+
+/// Marker of an in-flight request, released on drop (also when the
+/// request is cancelled, e.g. on client disconnection).
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    in_flight: Rc<Cell<u64>>,
+    metrics: LoadMetrics,
+}
+
+impl WorkerLoad {
+    pub(crate) fn new(metrics: LoadMetrics) -> Self {
+        metrics.workers.inc();
+        Self {
+            in_flight: Rc::new(Cell::new(0)),
+            metrics,
+        }
+    }
+
+    /// Track a request until the returned marker is dropped.
+    pub(crate) fn track(&self) -> InFlight {
+        let count = self.in_flight.get();
+        if count == 0 {
+            self.metrics.busy_workers.inc();
+        }
+        self.in_flight.set(count + 1);
+        self.metrics.in_flight.inc();
+        InFlight {
+            in_flight: Rc::clone(&self.in_flight),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl Drop for WorkerLoad {
+    fn drop(&mut self) {
+        self.metrics.workers.dec();
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let count = self.in_flight.get().saturating_sub(1);
+        self.in_flight.set(count);
+        if count == 0 {
+            self.metrics.busy_workers.dec();
+        }
+        self.metrics.in_flight.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge(name: &str) -> IntGauge {
+        IntGauge::new(name, name).unwrap()
+    }
+
+    #[test]
+    fn test_worker_load() {
+        let metrics = LoadMetrics {
+            workers: gauge("workers"),
+            busy_workers: gauge("busy_workers"),
+            in_flight: gauge("in_flight"),
+        };
+        let first = WorkerLoad::new(metrics.clone());
+        let second = WorkerLoad::new(metrics.clone());
+        assert_eq!(metrics.workers.get(), 2);
+        assert_eq!(metrics.busy_workers.get(), 0);
+
+        let a = first.track();
+        let b = first.track();
+        assert_eq!(metrics.busy_workers.get(), 1);
+        assert_eq!(metrics.in_flight.get(), 2);
+        let c = second.track();
+        assert_eq!(metrics.busy_workers.get(), 2);
+        assert_eq!(metrics.in_flight.get(), 3);
+
+        drop(a);
+        assert_eq!(metrics.busy_workers.get(), 2);
+        drop(b);
+        drop(c);
+        assert_eq!(metrics.busy_workers.get(), 0);
+        assert_eq!(metrics.in_flight.get(), 0);
+
+        drop(second);
+        assert_eq!(metrics.workers.get(), 1);
+        drop(first);
+    }
+}
@@ -3,17 +3,57 @@ extern crate log;
 #[macro_use]
 extern crate prometheus;
 
+mod admin;
+mod allowlist;
+mod cache;
 mod cli;
+mod clock;
+mod compression;
+mod concurrency;
 mod config;
+mod debug;
+#[cfg(test)]
+mod e2e;
+mod embedded;
+mod etag;
+mod format;
+mod load;
+mod metrics;
+mod normalize;
+mod pagination;
+mod pipeline;
+mod precompute;
+mod readiness;
+mod redis_conn;
+mod retry;
+mod rollout_window;
+mod rollouts;
+mod scopes;
 mod settings;
+mod shadow;
+mod shared_cache;
+mod signing;
+mod ssrf;
+mod tiers;
+mod tls;
+mod unique_ids;
 mod utils;
+mod wariness;
+mod webhook;
 
-use actix_web::{web, App, HttpResponse};
-use commons::{graph, metrics, policy};
-use failure::{Error, Fallible, ResultExt};
-use prometheus::{Histogram, IntCounter, IntGauge};
+use actix_web::dev::Service;
+use actix_web::error::QueryPayloadError;
+use actix_web::http::header;
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse};
+use commons::errors::PeError;
+use commons::{graph, policy};
+use config::UpstreamSource;
+use failure::{Fallible, ResultExt};
+use prometheus::{Histogram, HistogramVec};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 use structopt::clap::{crate_name, crate_version};
@@ -22,31 +62,6 @@ use structopt::StructOpt;
 /// Top-level log target for this application.
 static APP_LOG_TARGET: &str = "fcos_policy_engine";
 
-lazy_static::lazy_static! {
-    static ref V1_GRAPH_INCOMING_REQS: IntCounter = register_int_counter!(opts!(
-        "fcos_cincinnati_pe_v1_graph_incoming_requests_total",
-        "Total number of incoming HTTP client request to /v1/graph"
-    ))
-    .unwrap();
-    static ref UNIQUE_IDS: IntCounter = register_int_counter!(opts!(
-        "fcos_cincinnati_pe_v1_graph_unique_uuids_total",
-        "Total number of unique node UUIDs (per-instance Bloom filter)."
-    ))
-    .unwrap();
-    static ref ROLLOUT_WARINESS: Histogram = register_histogram!(
-        "fcos_cincinnati_pe_v1_graph_rollout_wariness",
-        "Per-request rollout wariness.",
-        prometheus::linear_buckets(0.0, 0.1, 11).unwrap()
-    )
-    .unwrap();
-    // NOTE(lucab): alternatively this could come from the runtime library, see
-    // https://prometheus.io/docs/instrumenting/writing_clientlibs/#process-metrics
-    static ref PROCESS_START_TIME: IntGauge = register_int_gauge!(opts!(
-        "process_start_time_seconds",
-        "Start time of the process since unix epoch in seconds."
-    )).unwrap();
-}
-
 fn main() -> Fallible<()> {
     // Parse command-line options.
     let cli_opts = cli::CliOptions::from_args();
@@ -56,176 +71,3677 @@ fn main() -> Fallible<()> {
         .format_timestamp_secs()
         .format_module_path(false)
         .filter(Some(APP_LOG_TARGET), cli_opts.loglevel())
+        .filter(Some(debug::SAMPLED_LOG_TARGET), log::LevelFilter::Info)
+        .filter(Some(admin::AUDIT_LOG_TARGET), log::LevelFilter::Info)
         .try_init()
         .context("failed to initialize logging")?;
 
+    // Generating a configuration file needs none.
+    if let Some(cli::Command::GenerateConfig(opts)) = &cli_opts.cmd {
+        return generate_config(opts);
+    }
+
     // Parse config file and validate settings.
-    let (service_settings, status_settings) = {
-        debug!("config file location: {}", cli_opts.config_path.display());
-        let cfg = config::FileConfig::parse_file(cli_opts.config_path)?;
+    let (mut service_settings, status_settings) = {
+        let config_path = cli_opts
+            .config_path
+            .as_ref()
+            .ok_or_else(|| failure::format_err!("missing configuration file path (-c)"))?;
+        debug!("config file location: {}", config_path.display());
+        let cfg = match &cli_opts.config_dir {
+            Some(dir) => {
+                debug!("config fragments directory: {}", dir.display());
+                config::FileConfig::parse_with_fragments(config_path, cli_opts.config_format, dir)?
+            }
+            None => config::FileConfig::parse_file(config_path, cli_opts.config_format)?,
+        };
         let settings = settings::PolicyEngineSettings::validate_config(cfg)?;
         (settings.service, settings.status)
     };
 
+    match cli_opts.cmd {
+        Some(cli::Command::DumpGraph(opts)) => return dump_graph(&service_settings, opts),
+        Some(cli::Command::SelfTest(opts)) => return self_test(&service_settings, opts),
+        Some(cli::Command::ExportMetrics(opts)) => return export_metrics(&service_settings, opts),
+        Some(cli::Command::GenerateConfig(_)) | None => {}
+    }
+    unique_ids::fit_bloom_filter(&mut service_settings);
+
     let sys = actix::System::new("fcos_cincinnati_pe");
 
-    let node_population = Arc::new(cbloom::Filter::new(
-        service_settings.bloom_size,
-        service_settings.bloom_max_population,
-    ));
-    let service_state = AppState {
-        // TODO(lucab): get allowed scopes from config file.
-        scope_filter: None,
-        population: Arc::clone(&node_population),
-        upstream_endpoint: service_settings.upstream_base.clone(),
-        upstream_req_timeout: service_settings.upstream_req_timeout,
-    };
+    let service_state = AppState::new(&service_settings)?;
+    service_state.register_metrics()?;
+    if service_settings.upstream.source == UpstreamSource::Embedded {
+        warn!("serving embedded demo graphs, no upstream is queried");
+    } else {
+        for scope in service_settings.pinned_graphs.keys() {
+            warn!(
+                "graph pinned to local snapshot: basearch='{}', stream='{}'",
+                scope.basearch, scope.stream
+            );
+        }
+    }
+    for endpoint in &service_settings.upstream.endpoints {
+        debug!(
+            "upstream graph endpoint: {} (weight {})",
+            endpoint.url, endpoint.weight
+        );
+    }
+    for (stream, endpoint) in &service_settings.upstream.stream_endpoints {
+        debug!(
+            "upstream graph endpoint for stream '{}': {}",
+            stream, endpoint.url
+        );
+    }
+    debug!(
+        "upstream extra headers: [{}]",
+        utils::redacted_headers(&service_settings.upstream.headers)
+    );
     debug!(
-        "upstream graph endpoint: {}",
-        service_settings.upstream_base
+        "upstream authentication: {:?}",
+        service_settings.upstream.auth
     );
+    if service_settings.policy.wariness_salt.is_empty() {
+        warn!("no wariness salt configured, clients can predict UUID-derived wariness");
+    }
+    if service_settings.debug.enabled {
+        warn!("debugging aids enabled, not meant for production traffic");
+        if let Some(delay) = service_settings.debug.response_delay {
+            warn!(
+                "CHAOS TESTING: all graph responses artificially delayed by {}ms",
+                delay.as_millis()
+            );
+        }
+        if !service_settings.debug.inject_deadends.is_empty() {
+            warn!(
+                "CHAOS TESTING: releases marked as dead-ends in all graphs: {}",
+                service_settings.debug.inject_deadends.join(", ")
+            );
+        }
+    }
 
-    let start_timestamp = chrono::Utc::now();
-    PROCESS_START_TIME.set(start_timestamp.timestamp());
+    let start_timestamp = service_state.clock.now();
+    metrics::PROCESS_START_TIME.set(start_timestamp.timestamp());
+    if service_settings.track_unique_ids {
+        metrics::UNIQUE_IDS_BLOOM_BYTES.set(service_settings.bloom_size as i64);
+    }
     info!("starting server ({} {})", crate_name!(), crate_version!());
+    log_settings_summary(&service_settings, &status_settings);
+
+    // Periodic discovery of allowed scopes.
+    if let Some(discovery) = service_state.scope_allowlist.discovery() {
+        let refresh = discovery.refresh;
+        let state = service_state.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(refresh);
+            loop {
+                interval.tick().await;
+                state
+                    .scope_allowlist
+                    .refresh(&state.upstream_client, &state.upstream)
+                    .await;
+            }
+        });
+    }
 
-    // Policy-engine main service.
+    // Periodic reaping of idle in-process cache entries.
+    if let Some(max_idle) = service_settings.cache.max_idle {
+        let state = service_state.clone();
+        actix_rt::spawn(async move {
+            let period = std::cmp::max(max_idle / 2, Duration::from_secs(1));
+            let mut interval = actix_rt::time::interval(period);
+            loop {
+                interval.tick().await;
+                reap_idle_entries(&state, max_idle);
+            }
+        });
+    }
+
+    // On-disk persistence of the in-process cache, avoiding a cold cache
+    // after restarts.
+    if let Some(persistence) = service_settings.cache.persistence.clone() {
+        let (path, flush_interval) = (persistence.path, persistence.flush_interval);
+        match service_state.cache.load(&path) {
+            Ok(loaded) => info!("loaded {} cached graphs from '{}'", loaded, path.display()),
+            Err(e) => warn!("ignoring persisted cache: {}", e),
+        }
+        let cache = service_state.cache.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(flush_interval);
+            // The first tick completes immediately, right after loading.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (cache, path) = (cache.clone(), path.clone());
+                match web::block(move || cache.save(&path)).await {
+                    Ok(saved) => debug!("persisted {} cached graphs", saved),
+                    Err(e) => warn!("failed to persist cache: {}", e),
+                }
+            }
+        });
+    }
+
+    // Policy-engine main service, unless only serving metrics.
+    if cli_opts.metrics_only {
+        warn!("metrics-only mode, not serving graphs");
+        service_state.readiness.set_ready();
+    } else {
+        start_main_service(service_settings, service_state.clone())?;
+    }
+
+    // Policy-engine status service.
+    start_status_service(&status_settings, service_state)?;
+
+    sys.run()?;
+    Ok(())
+}
+
+/// Start the status service (metrics, readiness, admin) server, returning
+/// its bound addresses.
+fn start_status_service(
+    status_settings: &settings::StatusSettings,
+    service_state: AppState,
+) -> Fallible<Vec<SocketAddr>> {
+    let status_socket = status_settings.socket_addr();
+    debug!("status service address: {}", status_socket);
+    let admin_enabled = service_state.admin.is_some();
+    let pe_status = service_state;
+    let status_server = actix_web::HttpServer::new(move || {
+        App::new()
+            .data(pe_status.clone())
+            .app_data(query_config())
+            .route("/metrics", web::get().to(pe_serve_metrics))
+            .route("/readyz", web::get().to(pe_serve_readyz))
+            .configure(|cfg| {
+                if admin_enabled {
+                    cfg.route("/admin/flush-cache", web::post().to(pe_admin_flush_cache));
+                }
+            })
+    })
+    .bind(status_socket)
+    .map_err(|e| commons::web::bind_error(e, "status service", status_socket))?;
+    let addrs = status_server.addrs();
+    status_server.run();
+    Ok(addrs)
+}
+
+/// Start the main service (graph endpoint) server, returning its bound
+/// addresses.
+fn start_main_service(
+    service_settings: settings::ServiceSettings,
+    service_state: AppState,
+) -> Fallible<Vec<SocketAddr>> {
     let service_socket = service_settings.socket_addr();
     debug!("main service address: {}", service_socket);
-    actix_web::HttpServer::new(move || {
+    let pe_service = service_state;
+    let service_tls = service_settings.tls.clone();
+    let response_headers = Arc::new(service_settings.response_headers.clone());
+    let echo_headers = Arc::new(match &service_settings.debug {
+        debug if debug.enabled => debug.echo_headers.clone(),
+        _ => vec![],
+    });
+    let log_sample_rate = service_settings.debug.log_sample_rate;
+    let debug_enabled = service_settings.debug.enabled;
+    let service_server = actix_web::HttpServer::new(move || {
+        let echo_headers = echo_headers.clone();
+        let response_headers = response_headers.clone();
+        let worker_load = Rc::new(load::WorkerLoad::new(load::LoadMetrics {
+            workers: metrics::SERVICE_WORKERS.clone(),
+            busy_workers: metrics::SERVICE_BUSY_WORKERS.clone(),
+            in_flight: metrics::SERVICE_IN_FLIGHT.clone(),
+        }));
         App::new()
+            .wrap_fn(|mut req, srv| {
+                normalize::strip_trailing_slash(&mut req);
+                srv.call(req)
+            })
+            .wrap_fn(move |req, srv| {
+                let echoed = debug::echoed_headers(&echo_headers, req.headers());
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    for (name, value) in echoed {
+                        res.headers_mut().append(name, value);
+                    }
+                    Ok(res)
+                }
+            })
             .wrap(commons::web::build_cors_middleware(
                 &service_settings.origin_allowlist,
+                &service_settings.cors,
             ))
-            .data(service_state.clone())
+            .wrap(middleware::Compress::new(
+                service_settings.compression.content_encoding(),
+            ))
+            .wrap_fn(move |req, srv| {
+                let response_headers = response_headers.clone();
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    for (name, value) in response_headers.iter() {
+                        res.headers_mut().insert(name.clone(), value.clone());
+                    }
+                    Ok(res)
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let in_flight = worker_load.track();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    drop(in_flight);
+                    res
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let sampled = if debug::sample_request(log_sample_rate) {
+                    Some(format!(
+                        "{} {}?{} [{}]",
+                        req.method(),
+                        req.path(),
+                        debug::redacted_query(req.query_string()),
+                        debug::redacted_headers(req.headers()),
+                    ))
+                } else {
+                    None
+                };
+                let start = std::time::Instant::now();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    if let Some(request) = sampled {
+                        info!(
+                            target: debug::SAMPLED_LOG_TARGET,
+                            "sampled request: {}; response: {} [{}] in {}ms",
+                            request,
+                            res.status(),
+                            debug::redacted_headers(res.headers()),
+                            start.elapsed().as_millis(),
+                        );
+                    }
+                    Ok(res)
+                }
+            })
+            .data(pe_service.clone())
+            .app_data(query_config())
+            .route("/", web::get().to(pe_serve_root))
             .route("/v1/graph", web::get().to(pe_serve_graph))
-    })
-    .bind(service_socket)?
-    .run();
+            .route("/v1/graph", web::head().to(pe_serve_graph))
+            .route("/v1/signing-key", web::get().to(pe_serve_signing_key))
+            .route("/v1/streams", web::get().to(pe_serve_streams))
+            .route("/v1/streams/graph", web::get().to(pe_serve_streams_graph))
+            .configure(|cfg| {
+                if debug_enabled {
+                    cfg.service(debug_validate_resource())
+                        .route(
+                            "/debug/blocked-versions",
+                            web::get().to(pe_serve_debug_blocked_versions),
+                        )
+                        .route("/debug/graph.dot", web::get().to(pe_serve_debug_graph_dot));
+                }
+            })
+            .default_service(web::route().to(pe_serve_not_found))
+    });
+    let service_server = match service_tls {
+        Some(tls) => service_server.bind_rustls(service_socket, tls.server_config()),
+        None => service_server.bind(service_socket),
+    }
+    .map_err(|e| commons::web::bind_error(e, "main service", service_socket))?;
+    let addrs = service_server.addrs();
+    service_server.run();
+    Ok(addrs)
+}
 
-    // Policy-engine status service.
-    let status_socket = status_settings.socket_addr();
-    debug!("status service address: {}", status_socket);
-    actix_web::HttpServer::new(move || {
-        App::new().route("/metrics", web::get().to(metrics::serve_metrics))
-    })
-    .bind(status_socket)?
-    .run();
+/// Process a single graph query as the server would, printing the result to stdout.
+fn dump_graph(settings: &settings::ServiceSettings, opts: cli::DumpGraphOptions) -> Fallible<()> {
+    let state = AppState::new(settings)?;
+    let query = GraphQuery {
+        basearch: Some(opts.basearch),
+        stream: Some(opts.stream),
+        rollout_wariness: opts.rollout_wariness.map(|w| w.to_string()),
+        current_version: opts.current_version,
+        ..Default::default()
+    };
 
-    sys.run()?;
+    let mut sys = actix::System::new("fcos_cincinnati_pe_dump");
+    let processed = sys.block_on(async move { pipeline::pe_process_graph(&state, &query).await });
+    let final_graph = processed
+        .map_err(|e| failure::format_err!("failed to process graph: {}", e))?
+        .graph;
+    let json = if opts.pretty {
+        serde_json::to_string_pretty(&final_graph)?
+    } else {
+        serde_json::to_string(&final_graph)?
+    };
+    println!("{}", json);
+    Ok(())
+}
+
+/// Streams checked by the self-test, absent other configuration.
+static SELF_TEST_STREAMS: &[&str] = &["next", "stable", "testing"];
+
+/// Basearches checked by the self-test, absent other configuration.
+static SELF_TEST_BASEARCHES: &[&str] = &["aarch64", "x86_64"];
+
+/// Fetch the upstream graph of each requested scope and validate it,
+/// reporting results on stdout and failing if any scope fails.
+fn self_test(settings: &settings::ServiceSettings, opts: cli::SelfTestOptions) -> Fallible<()> {
+    let state = AppState::new(settings)?;
+    let mut sys = actix::System::new("fcos_cincinnati_pe_selftest");
+    sys.block_on(async move { pe_self_test(&state, &opts, &mut std::io::stdout()).await })
+}
+
+/// Run the self-test, writing a `PASS` or `FAIL` line per checked scope.
+async fn pe_self_test(
+    state: &AppState,
+    opts: &cli::SelfTestOptions,
+    out: &mut impl std::io::Write,
+) -> Fallible<()> {
+    if state.scope_allowlist.discovery().is_some() {
+        state
+            .scope_allowlist
+            .refresh(&state.upstream_client, &state.upstream)
+            .await;
+    }
+    let scopes = self_test_scopes(&state.scope_allowlist.current(), &state.upstream, opts);
+
+    let mut failures = 0;
+    for scope in &scopes {
+        let fetched = utils::fetch_graph_with_retries(
+            &state.upstream_client,
+            &state.upstream,
+            &state.retry_budget,
+            &state.upstream_limiter,
+            state.clock.as_ref(),
+            scope.stream.clone(),
+            scope.basearch.clone(),
+        )
+        .await;
+        let checked = fetched.and_then(|graph| {
+            failure::ensure!(!graph.nodes.is_empty(), "empty graph");
+            graph.check_acyclic()?;
+            Ok(graph)
+        });
+        match checked {
+            Ok(graph) => writeln!(
+                out,
+                "PASS basearch='{}', stream='{}': {} nodes, {} edges",
+                scope.basearch,
+                scope.stream,
+                graph.nodes.len(),
+                graph.edges.len()
+            )?,
+            Err(e) => {
+                failures += 1;
+                writeln!(
+                    out,
+                    "FAIL basearch='{}', stream='{}': {}",
+                    scope.basearch, scope.stream, e
+                )?;
+            }
+        }
+    }
+
+    failure::ensure!(
+        failures == 0,
+        "self-test failed for {} of {} scopes",
+        failures,
+        scopes.len()
+    );
+    Ok(())
+}
+
+/// Scopes checked by the self-test, sorted by basearch, then stream.
+///
+/// With a scope allowlist, these are the allowed scopes. Otherwise, they are
+/// the cross product of streams (those with a dedicated upstream endpoint,
+/// else the usual ones) and basearches. Explicitly requested streams and
+/// basearches restrict allowed scopes, and replace the defaults otherwise.
+fn self_test_scopes(
+    allowed: &Option<HashSet<graph::GraphScope>>,
+    upstream: &settings::UpstreamSettings,
+    opts: &cli::SelfTestOptions,
+) -> Vec<graph::GraphScope> {
+    let requested =
+        |values: &[String], value: &str| values.is_empty() || values.iter().any(|v| v == value);
+    let mut scopes: Vec<_> = match allowed {
+        Some(allowed) => allowed
+            .iter()
+            .filter(|scope| {
+                requested(&opts.streams, &scope.stream)
+                    && requested(&opts.basearches, &scope.basearch)
+            })
+            .cloned()
+            .collect(),
+        None => {
+            let streams: Vec<String> = if !opts.streams.is_empty() {
+                opts.streams.clone()
+            } else if !upstream.stream_endpoints.is_empty() {
+                upstream.stream_endpoints.keys().cloned().collect()
+            } else {
+                SELF_TEST_STREAMS.iter().map(|s| s.to_string()).collect()
+            };
+            let basearches: Vec<String> = if !opts.basearches.is_empty() {
+                opts.basearches.clone()
+            } else {
+                SELF_TEST_BASEARCHES.iter().map(|s| s.to_string()).collect()
+            };
+            streams
+                .iter()
+                .flat_map(|stream| {
+                    basearches.iter().map(move |basearch| graph::GraphScope {
+                        basearch: basearch.clone(),
+                        stream: stream.clone(),
+                    })
+                })
+                .collect()
+        }
+    };
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+/// Render the metrics of a freshly started service once, exactly as served
+/// by the status service, and print them to stdout.
+///
+/// Per-process counters start from zero, while metrics derived from shared
+/// state (e.g. the fleet-wide unique IDs estimate) are current.
+fn export_metrics(
+    settings: &settings::ServiceSettings,
+    opts: cli::ExportMetricsOptions,
+) -> Fallible<()> {
+    use std::io::Write;
+
+    let state = AppState::new(settings)?;
+    state.register_metrics()?;
+    metrics::PROCESS_START_TIME.set(state.clock.now().timestamp());
+
+    let mut sys = actix::System::new("fcos_cincinnati_pe_metrics");
+    sys.block_on(async move { refresh_lazy_metrics(&state).await });
+    let accept = if opts.openmetrics {
+        Some(commons::metrics::OPENMETRICS_FORMAT)
+    } else {
+        None
+    };
+    let (_, content) = commons::metrics::encode_metrics(accept)?;
+    std::io::stdout()
+        .write_all(&content)
+        .context("failed to write metrics")?;
     Ok(())
 }
 
+/// Write the fully-commented default configuration file, to stdout or to
+/// the given path.
+fn generate_config(opts: &cli::GenerateConfigOptions) -> Fallible<()> {
+    use std::io::Write;
+
+    match &opts.output {
+        Some(path) => std::fs::write(path, config::DEFAULT_CONFIG_TOML)
+            .with_context(|_| format!("failed to write config file '{}'", path.display()))?,
+        None => std::io::stdout()
+            .write_all(config::DEFAULT_CONFIG_TOML.as_bytes())
+            .context("failed to write config file")?,
+    }
+    Ok(())
+}
+
+/// Log a concise summary of the effective settings, with secrets redacted.
+fn log_settings_summary(service: &settings::ServiceSettings, status: &settings::StatusSettings) {
+    let upstream = &service.upstream;
+    let scheme = match &service.tls {
+        Some(tls) if tls.requires_client_certs() => "https, client certificates required",
+        Some(_) => "https",
+        None => "http",
+    };
+    info!(
+        "listening: main service on {} ({}), status service on {}",
+        service.socket_addr(),
+        scheme,
+        status.socket_addr()
+    );
+    let endpoints: Vec<String> = upstream
+        .endpoints
+        .iter()
+        .map(|e| format!("{}@{}", e.url, e.weight))
+        .collect();
+    info!(
+        "upstream: {} (timeout {}s, max retries {}, auth {}, {} extra headers), cache TTL {}s",
+        endpoints.join(", "),
+        upstream.req_timeout.as_secs(),
+        upstream.retry.max_retries,
+        upstream.auth.scheme(),
+        upstream.headers.len(),
+        service.cache.ttl.as_secs()
+    );
+    let origins = match &service.origin_allowlist {
+        Some(allowed) => format!("{} allowed", allowed.len()),
+        None => "any".to_string(),
+    };
+    info!(
+        "CORS origins: {}; pinned graphs: {}",
+        origins,
+        service.pinned_graphs.len()
+    );
+    if service.track_unique_ids {
+        info!(
+            "unique IDs Bloom filter: {} bytes in {} shard(s), max population {}, sample rate {}",
+            service.bloom_size,
+            service.bloom_shards,
+            service.bloom_max_population,
+            service.unique_ids_sample_rate
+        );
+    } else {
+        info!("unique IDs tracking disabled");
+    }
+    if let Some(webhook) = &service.policy.webhook {
+        info!(
+            "graph webhook: {} (timeout {}ms)",
+            webhook.url,
+            webhook.timeout.as_millis()
+        );
+    }
+    if service.shadow.is_some() {
+        info!("shadow policies enabled, comparing graph responses");
+    }
+    if !service.deprecated_streams.is_empty() {
+        let mut streams: Vec<&str> = service
+            .deprecated_streams
+            .iter()
+            .map(String::as_str)
+            .collect();
+        streams.sort_unstable();
+        info!("deprecated streams: {}", streams.join(", "));
+    }
+    if !service.stream_fallbacks.is_empty() {
+        let mut fallbacks: Vec<String> = service
+            .stream_fallbacks
+            .iter()
+            .map(|(stream, fallback)| format!("{} -> {}", stream, fallback))
+            .collect();
+        fallbacks.sort_unstable();
+        info!("stream fallbacks: {}", fallbacks.join(", "));
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct AppState {
-    scope_filter: Option<HashSet<graph::GraphScope>>,
-    population: Arc<cbloom::Filter>,
-    upstream_endpoint: reqwest::Url,
-    upstream_req_timeout: Duration,
+    admin: Option<settings::AdminSettings>,
+    tiers: Option<Arc<tiers::Tiers>>,
+    scope_allowlist: Arc<allowlist::ScopeAllowlist>,
+    population: Option<Arc<unique_ids::ShardedFilter>>,
+    unique_ids_hash: config::UniqueIdsHash,
+    unique_ids_sampler: Arc<unique_ids::IdSampler>,
+    shared_unique_ids: Option<Arc<unique_ids::SharedUniqueIds>>,
+    upstream: settings::UpstreamSettings,
+    upstream_client: reqwest::Client,
+    retry_budget: Arc<retry::RetryBudget>,
+    upstream_limiter: Arc<concurrency::UpstreamLimiter>,
+    graph_limiter: Arc<concurrency::GraphRequestLimiter>,
+    readiness: Arc<readiness::Readiness>,
+    pinned_graphs: Arc<HashMap<graph::GraphScope, cache::CachedGraph>>,
+    cache: Arc<cache::GraphCache>,
+    fetch_limiter: Option<Arc<cache::FetchLimiter>>,
+    revalidator: Option<Arc<cache::Revalidator>>,
+    shared_cache: Option<Arc<shared_cache::SharedCache>>,
+    cache_status_header: bool,
+    cache_bypass: bool,
+    max_processing: Duration,
+    max_query_length: usize,
+    max_combined_graph_bytes: usize,
+    unknown_query_params: config::UnknownQueryParams,
+    node_uuid_validation: config::NodeUuidValidation,
+    default_basearch: Option<String>,
+    up_to_date_response: config::UpToDateResponse,
+    require_user_agent: bool,
+    deprecated_streams: Arc<HashSet<String>>,
+    log_deprecated_streams: bool,
+    stream_fallbacks: Arc<HashMap<String, String>>,
+    policy: settings::PolicySettings,
+    shadow: Option<settings::ShadowSettings>,
+    throttled: Option<Arc<precompute::ThrottledGraphs>>,
+    webhook: Option<Arc<webhook::GraphWebhook>>,
+    scopes: Arc<scopes::ScopeTracker>,
+    rollouts: Arc<rollouts::RolloutTracker>,
+    signer: Option<Arc<signing::GraphSigner>>,
+    not_found_hint: bool,
+    root_info: bool,
+    policy_metadata: bool,
+    wariness_header: bool,
+    response_delay: Option<Duration>,
+    injected_deadends: Vec<String>,
+    slow_request: Option<Duration>,
+    zstd: Option<Arc<compression::ZstdEncoder>>,
+    compress_responses: bool,
+    rollout_wariness: Histogram,
+    computed_rollout_wariness: Histogram,
+    response_sizes: HistogramVec,
+    clock: Arc<dyn clock::Clock>,
+}
+
+impl AppState {
+    /// Build the shared service state from validated settings.
+    fn new(settings: &settings::ServiceSettings) -> Fallible<Self> {
+        Self::with_clock(settings, Arc::new(clock::SystemClock))
+    }
+
+    /// Build the application state, with the given source of time.
+    fn with_clock(
+        settings: &settings::ServiceSettings,
+        clock: Arc<dyn clock::Clock>,
+    ) -> Fallible<Self> {
+        let node_population = if settings.track_unique_ids {
+            Some(Arc::new(unique_ids::ShardedFilter::new(
+                settings.bloom_size,
+                settings.bloom_max_population,
+                settings.bloom_shards,
+            )))
+        } else {
+            None
+        };
+        let shared_cache = match &settings.cache.shared {
+            Some(shared) => Some(Arc::new(shared_cache::SharedCache::new(
+                shared,
+                settings.cache.ttl,
+            )?)),
+            None => None,
+        };
+        let webhook = match &settings.policy.webhook {
+            Some(webhook) => Some(Arc::new(webhook::GraphWebhook::new(webhook)?)),
+            None => None,
+        };
+        let shared_unique_ids = match &settings.shared_unique_ids {
+            Some(shared) => Some(Arc::new(unique_ids::SharedUniqueIds::new(shared)?)),
+            None => None,
+        };
+        let tiers = match &settings.tiers {
+            Some(tiers) => Some(Arc::new(tiers::Tiers::new(tiers)?)),
+            None => None,
+        };
+        let state = Self {
+            admin: settings.admin.clone(),
+            tiers,
+            scope_allowlist: Arc::new(allowlist::ScopeAllowlist::new(&settings.scope_allowlist)),
+            population: node_population,
+            unique_ids_hash: settings.unique_ids_hash,
+            unique_ids_sampler: Arc::new(unique_ids::IdSampler::new(
+                settings.unique_ids_sample_rate,
+            )),
+            shared_unique_ids,
+            upstream: settings.upstream.clone(),
+            upstream_client: utils::new_client(&settings.upstream)?,
+            retry_budget: Arc::new(retry::RetryBudget::new(&settings.upstream.retry)),
+            graph_limiter: Arc::new(concurrency::GraphRequestLimiter::new(
+                settings.max_graph_requests,
+                settings.fair_queue.clone(),
+                settings.max_tracked_scopes,
+            )),
+            upstream_limiter: Arc::new(concurrency::UpstreamLimiter::new(
+                &settings.upstream.concurrency,
+            )),
+            readiness: Arc::new(readiness::Readiness::new(&settings.warmup, clock.clone())),
+            pinned_graphs: Arc::new(
+                settings
+                    .pinned_graphs
+                    .iter()
+                    .map(|(scope, graph)| {
+                        let entry = cache::CachedGraph {
+                            graph: graph.clone(),
+                            fetched: clock.now(),
+                        };
+                        (scope.clone(), entry)
+                    })
+                    .collect(),
+            ),
+            cache: Arc::new(cache::GraphCache::new(settings.cache.ttl, clock.clone())),
+            fetch_limiter: settings
+                .cache
+                .min_fetch_interval
+                .map(|interval| Arc::new(cache::FetchLimiter::new(interval, clock.clone()))),
+            revalidator: settings.cache.stale_while_revalidate.map(|window| {
+                Arc::new(cache::Revalidator::new(
+                    settings.cache.ttl,
+                    window,
+                    clock.clone(),
+                ))
+            }),
+            shared_cache,
+            cache_status_header: settings.cache.status_header,
+            cache_bypass: settings.debug.enabled,
+            max_processing: settings.max_processing,
+            max_query_length: settings.max_query_length,
+            max_combined_graph_bytes: settings.max_combined_graph_bytes,
+            unknown_query_params: settings.unknown_query_params,
+            node_uuid_validation: settings.node_uuid_validation,
+            default_basearch: settings.default_basearch.clone(),
+            up_to_date_response: settings.up_to_date_response,
+            require_user_agent: settings.require_user_agent,
+            deprecated_streams: Arc::new(settings.deprecated_streams.clone()),
+            log_deprecated_streams: settings.log_deprecated_streams,
+            stream_fallbacks: Arc::new(settings.stream_fallbacks.clone()),
+            policy: settings.policy.clone(),
+            shadow: settings.shadow.clone(),
+            throttled: settings.policy.precompute.as_ref().map(|precompute| {
+                Arc::new(precompute::ThrottledGraphs::new(
+                    settings.policy.wariness_precision,
+                    precompute.ttl,
+                    precompute.max_entries,
+                    clock.clone(),
+                ))
+            }),
+            webhook,
+            scopes: Arc::new(scopes::ScopeTracker::new(settings.max_tracked_scopes)),
+            rollouts: Arc::new(rollouts::RolloutTracker::new(
+                metrics::ROLLOUT_PROGRESS.clone(),
+            )),
+            signer: settings.signer.clone(),
+            not_found_hint: settings.not_found_hint,
+            root_info: settings.root_info,
+            policy_metadata: settings.policy_metadata,
+            wariness_header: settings.wariness_header,
+            slow_request: settings.slow_request,
+            response_delay: settings
+                .debug
+                .response_delay
+                .filter(|_| settings.debug.enabled),
+            injected_deadends: if settings.debug.enabled {
+                settings.debug.inject_deadends.clone()
+            } else {
+                vec![]
+            },
+            zstd: settings
+                .compression
+                .zstd_level
+                .map(|level| Arc::new(compression::ZstdEncoder::new(level, ZSTD_MAX_BODIES))),
+            compress_responses: settings.compression.enabled,
+            rollout_wariness: Histogram::with_opts(histogram_opts!(
+                "fcos_cincinnati_pe_v1_graph_rollout_wariness",
+                "Per-request rollout wariness, as requested by clients.",
+                settings.wariness_buckets.clone()
+            ))
+            .expect("valid wariness histogram"),
+            computed_rollout_wariness: Histogram::with_opts(histogram_opts!(
+                "fcos_cincinnati_pe_v1_graph_computed_rollout_wariness",
+                "Per-request rollout wariness, derived from node UUIDs.",
+                settings.wariness_buckets.clone()
+            ))
+            .expect("valid wariness histogram"),
+            response_sizes: HistogramVec::new(
+                histogram_opts!(
+                    "fcos_cincinnati_pe_v1_graph_response_size_bytes",
+                    "Size of /v1/graph response bodies, before HTTP-level compression.",
+                    settings.response_size_buckets.clone()
+                ),
+                &["stream", "compressed"],
+            )
+            .expect("valid response size histogram"),
+            clock,
+        };
+        Ok(state)
+    }
+
+    /// Register the metrics owned by the service state.
+    fn register_metrics(&self) -> Fallible<()> {
+        prometheus::register(Box::new(self.rollout_wariness.clone()))
+            .context("failed to register rollout wariness histogram")?;
+        prometheus::register(Box::new(self.computed_rollout_wariness.clone()))
+            .context("failed to register computed rollout wariness histogram")?;
+        prometheus::register(Box::new(self.response_sizes.clone()))
+            .context("failed to register response size histogram")?;
+        Ok(())
+    }
 }
 
 /// Mandatory parameters for querying a graph from policy-engine.
-#[derive(Serialize, Deserialize)]
+///
+/// `arch` is accepted as an alias for `basearch`; sending both is rejected.
+/// Repeated parameters (even with the same value) are rejected as well,
+/// rather than picking one of the occurrences.
+///
+/// `offset` and `limit` optionally select a page of the graph, for tooling
+/// and inspection; clients computing update paths need the full graph (see
+/// the `pagination` module).
+///
+/// `format` selects the response format (see the `format` module).
+#[derive(Default, Serialize, Deserialize)]
 pub struct GraphQuery {
+    #[serde(alias = "arch")]
     basearch: Option<String>,
     stream: Option<String>,
     rollout_wariness: Option<String>,
     node_uuid: Option<String>,
+    current_version: Option<String>,
+    /// Index of the first node of a graph page.
+    offset: Option<u64>,
+    /// Maximum number of nodes of a graph page.
+    limit: Option<u64>,
+    /// Response format, `graph` (default) or `legacy`.
+    format: Option<String>,
+    /// Whether to bypass caches, only honored with debugging aids enabled.
+    fresh: Option<bool>,
 }
 
+/// Known graph query parameters, including aliases.
+static GRAPH_QUERY_PARAMS: &[&str] = &[
+    "basearch",
+    "arch",
+    "stream",
+    "rollout_wariness",
+    "node_uuid",
+    "current_version",
+    "offset",
+    "limit",
+    "format",
+    "fresh",
+];
+
+/// Maximum number of distinct zstd-encoded bodies kept for reuse.
+const ZSTD_MAX_BODIES: usize = 64;
+
+/// Header carrying the base64 Ed25519 signature of the graph response body.
+static SIGNATURE_HEADER: &str = "X-Graph-Signature";
+
+/// Caching directive of root path responses, which only change on
+/// configuration changes or upgrades.
+static ROOT_INFO_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Header exposing the generation time of the upstream graph.
+static GENERATED_HEADER: &str = "X-Graph-Generated";
+
+/// Header exposing the effective rollout wariness of a graph request.
+static WARINESS_HEADER: &str = "X-Rollout-Wariness";
+
+/// Header for clients to bound the processing time of their request.
+static MAX_PROCESSING_HEADER: &str = "X-Max-Processing-Ms";
+
 pub(crate) async fn pe_serve_graph(
+    req: HttpRequest,
     data: web::Data<AppState>,
-    web::Query(query): web::Query<GraphQuery>,
-) -> Result<HttpResponse, Error> {
-    pe_record_metrics(&data, &query);
-
-    let scope = match commons::web::validate_scope(
-        query.basearch.clone(),
-        query.stream.clone(),
-        &data.scope_filter,
-    ) {
+) -> Result<HttpResponse, PeError> {
+    pe_serve_graph_endpoint(pipeline::pe_graph_response(&req, &data)).await
+}
+
+/// Wrap a graph endpoint handler, recording its response status.
+async fn pe_serve_graph_endpoint(
+    res: impl futures::Future<Output = Result<HttpResponse, PeError>>,
+) -> Result<HttpResponse, PeError> {
+    let start = std::time::Instant::now();
+    let res = res.await;
+    let status = match &res {
+        Ok(resp) => resp.status(),
+        Err(e) => actix_web::ResponseError::status_code(e),
+    };
+    pe_record_response_status(status, start.elapsed());
+    res
+}
+
+/// Admit a graph request: check its headers and query, take a graph
+/// request slot and record request metrics.
+///
+/// The returned slot must be held while the request is processed.
+async fn pe_admit_graph_request<'a>(
+    req: &HttpRequest,
+    data: &'a web::Data<AppState>,
+) -> Result<(GraphQuery, concurrency::GraphRequestSlot<'a>), PeError> {
+    if data.require_user_agent && !has_user_agent(req) {
+        metrics::V1_GRAPH_MISSING_USER_AGENT.inc();
+        return Err(PeError::InvalidQuery(
+            "missing User-Agent header".to_string(),
+        ));
+    }
+    let mut query = parse_graph_query(req, data)?;
+    query.fresh = Some(data.cache_bypass && wants_fresh_graph(req, &query));
+    if query.fresh == Some(true) {
+        metrics::V1_GRAPH_CACHE_BYPASS.inc();
+    }
+    // Queued requests wait at most as long as they may be processed.
+    let allowed = data.scope_allowlist.current();
+    let stream = fair_queue_stream(&allowed, query.stream.as_deref().unwrap_or_default());
+    let admitted = actix_rt::time::timeout(data.max_processing, data.graph_limiter.admit(stream));
+    let overloaded = PeError::Overloaded(concurrency::OVERLOAD_RETRY_AFTER.as_secs());
+    let slot = match admitted.await {
+        Ok(Some(slot)) => slot,
+        Ok(None) => return Err(overloaded),
+        Err(_) => {
+            metrics::V1_GRAPH_OVERLOAD_REJECTIONS.inc();
+            return Err(overloaded);
+        }
+    };
+    // HEAD requests transfer no body, track them apart from full requests.
+    if req.method() == actix_web::http::Method::HEAD {
+        metrics::V1_GRAPH_INCOMING_HEAD_REQS.inc();
+    } else {
+        pe_record_metrics(data, &query);
+    }
+    Ok((query, slot))
+}
+
+/// Fair queue of a requested stream: streams not allowed by the scope
+/// allowlist, if any, share the overflow queue.
+fn fair_queue_stream<'a>(allowed: &Option<HashSet<graph::GraphScope>>, stream: &'a str) -> &'a str {
+    match allowed {
+        Some(scopes) if !scopes.iter().any(|scope| scope.stream == stream) => {
+            scopes::OVERFLOW_LABEL
+        }
+        _ => stream,
+    }
+}
+
+/// Reply to requests for unknown routes, optionally listing public endpoints.
+///
+/// Only documented public endpoints are listed; internal routes are not.
+pub(crate) async fn pe_serve_not_found(data: web::Data<AppState>) -> Result<HttpResponse, PeError> {
+    if !data.not_found_hint {
+        return Err(PeError::NotFound(None));
+    }
+    let hint = format!("known endpoints: {}", public_endpoints(&data).join(", "));
+    Err(PeError::NotFound(Some(hint)))
+}
+
+/// Documented public endpoints currently served.
+fn public_endpoints(data: &AppState) -> Vec<&'static str> {
+    let mut endpoints = vec!["/v1/graph"];
+    if data.signer.is_some() {
+        endpoints.push("/v1/signing-key");
+    }
+    if data.scope_allowlist.current().is_some() {
+        endpoints.push("/v1/streams");
+        endpoints.push("/v1/streams/graph");
+    }
+    endpoints
+}
+
+/// Service information served at the root path.
+#[derive(Serialize)]
+struct RootInfo {
+    service: &'static str,
+    version: &'static str,
+    endpoints: Vec<&'static str>,
+}
+
+/// Identify the service at the root path, if enabled.
+pub(crate) async fn pe_serve_root(data: web::Data<AppState>) -> Result<HttpResponse, PeError> {
+    if !data.root_info {
+        return pe_serve_not_found(data).await;
+    }
+    let info = RootInfo {
+        service: crate_name!(),
+        version: crate_version!(),
+        endpoints: public_endpoints(&data),
+    };
+    Ok(HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, ROOT_INFO_CACHE_CONTROL)
+        .json(info))
+}
+
+/// Public key for verifying graph signatures.
+#[derive(Serialize)]
+struct SigningKey {
+    algorithm: &'static str,
+    public_key: String,
+    public_key_pem: String,
+}
+
+/// Serve the public key for verifying graph signatures, if signing is enabled.
+pub(crate) async fn pe_serve_signing_key(data: web::Data<AppState>) -> HttpResponse {
+    match &data.signer {
+        Some(signer) => HttpResponse::Ok().json(SigningKey {
+            algorithm: "ed25519",
+            public_key: signer.public_key_base64(),
+            public_key_pem: signer.public_key_pem(),
+        }),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Allowed streams, with their basearches.
+#[derive(Serialize)]
+struct StreamsListing {
+    streams: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Client caching lifetime of the streams listing.
+const STREAMS_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Serve the allowed streams and basearches, if an allowlist is in effect.
+///
+/// The listing is sorted, so that its `ETag` only changes along with the
+/// allowlist.
+pub(crate) async fn pe_serve_streams(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, PeError> {
+    let allowed = data.scope_allowlist.current();
+    let scopes = match allowed.as_ref() {
+        Some(scopes) => scopes,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let json = serde_json::to_string_pretty(&StreamsListing {
+        streams: allowlist::streams(scopes),
+    })?;
+    let etag = pipeline::pe_middleware_etag(&data, etag::body_etag(json.as_bytes()));
+
+    let cache_control = format!("public, max-age={}", STREAMS_MAX_AGE.as_secs());
+    let not_modified = etag::if_none_match(req.headers(), &etag);
+    let mut resp = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    resp.header(header::ETAG, etag.as_str())
+        .header(header::CACHE_CONTROL, cache_control);
+    if not_modified {
+        return Ok(resp.finish());
+    }
+    Ok(resp.content_type("application/json").body(json))
+}
+
+/// Serve the graphs of all allowed streams of a basearch, if an allowlist
+/// is in effect, as an object mapping each stream to its graph.
+///
+/// Each graph is processed as for a single-stream graph request with the
+/// same parameters, reusing caches. Pagination and response formats are
+/// not supported, and responses over the size cap are refused.
+pub(crate) async fn pe_serve_streams_graph(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, PeError> {
+    pe_serve_graph_endpoint(pipeline::pe_streams_graph_response(&req, &data)).await
+}
+
+/// Blocked release versions of the served policies, as a debug endpoint.
+#[derive(Debug, Serialize)]
+struct BlockedVersions<'a> {
+    blocked_versions: Vec<&'a str>,
+}
+
+/// Serve the active blocklist of release versions, in version order.
+pub(crate) async fn pe_serve_debug_blocked_versions(data: web::Data<AppState>) -> HttpResponse {
+    let mut blocked_versions: Vec<&str> = data
+        .policy
+        .blocked_versions
+        .iter()
+        .map(String::as_str)
+        .collect();
+    blocked_versions.sort_unstable_by(|a, b| policy::compare_versions(a, b));
+    HttpResponse::Ok().json(BlockedVersions { blocked_versions })
+}
+
+/// Serve the processed graph of a query in Graphviz DOT format, for
+/// debugging its topology. Only the graph itself is rendered: response
+/// headers and formats of the graph API do not apply.
+pub(crate) async fn pe_serve_debug_graph_dot(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, PeError> {
+    let query = parse_graph_query(&req, &data)?;
+    let processed = pipeline::pe_process_graph(&data, &query).await?;
+    let name = format!(
+        "{}/{}",
+        query.stream.unwrap_or_default(),
+        query.basearch.unwrap_or_default()
+    );
+    let dot = debug::graph_to_dot(&processed.graph, &name);
+    Ok(HttpResponse::Ok()
+        .content_type(debug::DOT_CONTENT_TYPE)
+        .body(dot))
+}
+
+/// Debug endpoint validating a posted candidate graph, with a bounded body.
+fn debug_validate_resource() -> actix_web::Resource {
+    web::resource("/debug/validate")
+        .data(web::PayloadConfig::new(debug::MAX_VALIDATE_BODY_SIZE))
+        .route(web::post().to(pe_serve_debug_validate))
+}
+
+/// Validate a candidate graph, replying with a validation report.
+pub(crate) async fn pe_serve_debug_validate(body: web::Bytes) -> HttpResponse {
+    let report = debug::validate_graph(&body);
+    if report.valid {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::UnprocessableEntity().json(report)
+    }
+}
+
+/// Parse the graph query of a request, rejecting oversize query strings
+/// before deserialization. Malformed node UUIDs are counted, and rejected
+/// in strict mode. An omitted basearch defaults to the configured one, if
+/// any.
+fn parse_graph_query(req: &HttpRequest, data: &AppState) -> Result<GraphQuery, PeError> {
+    let query_string = req.query_string();
+    if query_string.len() > data.max_query_length {
+        metrics::V1_GRAPH_OVERSIZE_QUERIES.inc();
+        return Err(PeError::QueryTooLong(data.max_query_length));
+    }
+    let params = web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| malformed_query::<Vec<(String, String)>>(query_string, e))?;
+    let unknown: Vec<&str> = params
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !GRAPH_QUERY_PARAMS.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        metrics::V1_GRAPH_UNKNOWN_PARAMS.inc_by(unknown.len() as u64);
+        if data.unknown_query_params == config::UnknownQueryParams::Strict {
+            return Err(PeError::InvalidQuery(format!(
+                "unknown query parameter '{}'",
+                unknown[0]
+            )));
+        }
+    }
+    let mut query = web::Query::<GraphQuery>::from_query(query_string)
+        .map(web::Query::into_inner)
+        .map_err(|e| malformed_query::<GraphQuery>(query_string, e))?;
+    if let Some(uuid) = query.node_uuid.as_deref().filter(|uuid| !uuid.is_empty()) {
+        if !unique_ids::is_well_formed_uuid(uuid) {
+            metrics::V1_GRAPH_MALFORMED_NODE_UUIDS.inc();
+            if data.node_uuid_validation == config::NodeUuidValidation::Strict {
+                return Err(PeError::InvalidQuery(
+                    "malformed node_uuid, expected a UUID".to_string(),
+                ));
+            }
+        }
+    }
+    if query.basearch.is_none() {
+        query.basearch = data.default_basearch.clone();
+    }
+    Ok(query)
+}
+
+/// Error of a query string failing deserialization into `T`, naming the
+/// malformed parameter when it can be singled out. Errors about the whole
+/// query (e.g. repeated parameters) already name the offending parameter.
+fn malformed_query<T: serde::de::DeserializeOwned>(
+    query_string: &str,
+    err: QueryPayloadError,
+) -> PeError {
+    for pair in query_string.split('&').filter(|pair| !pair.is_empty()) {
+        let name = match web::Query::<Vec<(String, String)>>::from_query(pair) {
+            Ok(mut params) => match params.pop() {
+                Some((name, _)) => name,
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+        if let Err(QueryPayloadError::Deserialize(e)) = web::Query::<T>::from_query(pair) {
+            return PeError::InvalidQuery(format!("malformed query parameter '{}': {}", name, e));
+        }
+    }
+    PeError::InvalidQuery(err.to_string())
+}
+
+/// Configuration of `web::Query` extractors, failing with the JSON error
+/// envelope instead of a bare 400 response.
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err, _| PeError::InvalidQuery(err.to_string()).into())
+}
+
+/// Whether a request carries a non-blank `User-Agent` header.
+fn has_user_agent(req: &HttpRequest) -> bool {
+    match req.headers().get(header::USER_AGENT) {
+        Some(value) => !value.as_bytes().iter().all(u8::is_ascii_whitespace),
+        None => false,
+    }
+}
+
+/// Whether a request asks for a fresh graph, via `?fresh=true` or a
+/// `Cache-Control: no-cache` header.
+fn wants_fresh_graph(req: &HttpRequest, query: &GraphQuery) -> bool {
+    if query.fresh == Some(true) {
+        return true;
+    }
+    req.headers()
+        .get_all(header::CACHE_CONTROL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+}
+
+/// Processing deadline for a request, from the client hint capped by the
+/// server-side maximum.
+fn processing_deadline(req: &HttpRequest, max: Duration) -> Result<Duration, PeError> {
+    let hint = match req.headers().get(MAX_PROCESSING_HEADER) {
+        Some(value) => value,
+        None => return Ok(max),
+    };
+    let millis = hint
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            PeError::InvalidQuery(format!("invalid {} header", MAX_PROCESSING_HEADER))
+        })?;
+    Ok(Duration::from_millis(millis).min(max))
+}
+
+/// Report readiness, i.e. whether the warm-up period is over, followed by
+/// the readiness of each allowed scope (if there is an allowlist).
+pub(crate) async fn pe_serve_readyz(data: web::Data<AppState>) -> HttpResponse {
+    let (mut resp, mut body) = if data.readiness.is_ready() {
+        (HttpResponse::Ok(), "ready".to_string())
+    } else {
+        (HttpResponse::ServiceUnavailable(), "warming up".to_string())
+    };
+    if let Some(allowed) = data.scope_allowlist.current().as_ref() {
+        for (scope, ready) in data.readiness.scopes_status(allowed) {
+            let status = if ready { "ready" } else { "warming up" };
+            body.push_str(&format!("\n{}: {}", scope, status));
+        }
+    }
+    resp.body(body)
+}
+
+/// Flush cached graphs, optionally only for the given stream and/or basearch.
+///
+/// Graphs are dropped from the in-process cache, the precomputed throttled
+/// graphs and the shared cache (for the scopes cached by this replica).
+pub(crate) async fn pe_admin_flush_cache(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let authorized = match &data.admin {
+        Some(admin) => admin::authorized(admin, req.headers()),
+        None => false,
+    };
+    let identity = Some(admin::TOKEN_IDENTITY).filter(|_| authorized);
+    let mut audit = admin::AuditEntry::new(&req, "flush-cache", identity);
+    audit.params = serde_json::Value::String(req.query_string().to_string());
+    if !authorized {
+        audit.outcome = "unauthorized";
+        audit.log();
+        return HttpResponse::Unauthorized()
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .finish();
+    }
+    let query = match web::Query::<admin::FlushQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
         Err(e) => {
-            log::error!("graph request with invalid scope: {}", e);
-            return Ok(HttpResponse::BadRequest().finish());
-        }
-        Ok(s) => {
-            log::trace!("graph query stream: {:#?}", s);
-            s
-        }
-    };
-
-    let wariness = compute_wariness(&query);
-    ROLLOUT_WARINESS.observe(wariness);
-
-    let cached_graph = utils::fetch_graph_from_gb(
-        data.upstream_endpoint.clone(),
-        scope.stream,
-        scope.basearch,
-        data.upstream_req_timeout,
-    )
-    .await?;
-
-    let throttled_graph = policy::throttle_rollouts(cached_graph, wariness);
-    let final_graph = policy::filter_deadends(throttled_graph);
-
-    let json =
-        serde_json::to_string_pretty(&final_graph).map_err(|e| failure::format_err!("{}", e))?;
-    let resp = HttpResponse::Ok()
-        .content_type("application/json")
-        .body(json);
-    Ok(resp)
-}
-
-#[allow(clippy::let_and_return)]
-fn compute_wariness(params: &GraphQuery) -> f64 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-
-    if let Ok(input) = params
-        .rollout_wariness
-        .as_ref()
-        .map(String::from)
-        .unwrap_or_default()
-        .parse::<f64>()
-    {
-        let wariness = input.max(0.0).min(1.0);
-        return wariness;
-    }
-
-    let uuid = params
-        .node_uuid
-        .as_ref()
-        .map(String::from)
-        .unwrap_or_default();
-    let wariness = {
-        // Left limit not included in range.
-        const COMPUTED_MIN: f64 = 0.0 + 0.000_001;
-        const COMPUTED_MAX: f64 = 1.0;
-        let mut hasher = DefaultHasher::new();
-        uuid.hash(&mut hasher);
-        let digest = hasher.finish();
-        // Scale down.
-        let scaled = (digest as f64) / (std::u64::MAX as f64);
-        // Clamp within limits.
-        scaled.max(COMPUTED_MIN).min(COMPUTED_MAX)
-    };
-
-    wariness
+            audit.outcome = "invalid";
+            audit.log();
+            let err = malformed_query::<admin::FlushQuery>(req.query_string(), e);
+            return actix_web::ResponseError::error_response(&err);
+        }
+    };
+    audit.params = serde_json::to_value(&query).unwrap_or_default();
+    audit.log();
+
+    let flushed = data.cache.flush(|scope| query.matches(scope));
+    if let Some(throttled) = &data.throttled {
+        throttled.flush(|scope| query.matches(scope));
+    }
+    if let Some(shared) = &data.shared_cache {
+        for scope in &flushed {
+            shared.remove(scope).await;
+        }
+    }
+    metrics::ADMIN_CACHE_FLUSHES.inc();
+    log::warn!(
+        "flushed {} cached graphs on admin request: basearch='{}', stream='{}'",
+        flushed.len(),
+        query.basearch.as_deref().unwrap_or("*"),
+        query.stream.as_deref().unwrap_or("*"),
+    );
+    HttpResponse::Ok().json(serde_json::json!({ "flushed": flushed.len() }))
 }
 
-pub(crate) fn pe_record_metrics(data: &AppState, query: &GraphQuery) {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
+/// Reap in-process cache entries not accessed for at least `max_idle`.
+fn reap_idle_entries(data: &AppState, max_idle: Duration) {
+    let reaped = data.cache.reap_idle(max_idle);
+    metrics::CACHE_REAPED_ENTRIES
+        .with_label_values(&["upstream"])
+        .inc_by(reaped as u64);
+    let throttled_reaped = match &data.throttled {
+        Some(throttled) => throttled.reap_idle(max_idle),
+        None => 0,
+    };
+    metrics::CACHE_REAPED_ENTRIES
+        .with_label_values(&["throttled"])
+        .inc_by(throttled_reaped as u64);
+    if reaped + throttled_reaped > 0 {
+        debug!(
+            "reaped {} idle cached graphs and {} idle throttled graphs, {} bytes left cached",
+            reaped,
+            throttled_reaped,
+            data.cache.size_bytes()
+        );
+    }
+}
+
+/// Serve metrics requests, refreshing lazily-computed metrics first.
+pub(crate) async fn pe_serve_metrics(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, failure::Error> {
+    refresh_lazy_metrics(&data).await;
+    commons::metrics::serve_metrics(req).await
+}
+
+/// Refresh the metrics computed on demand, before rendering them.
+async fn refresh_lazy_metrics(data: &AppState) {
+    let allowed = rollout_window::rollouts_allowed(&data.policy.rollout_windows, data.clock.now());
+    metrics::ROLLOUT_WINDOW_OPEN.set(allowed as i64);
+    let oldest_age = data.cache.oldest_age().unwrap_or_default();
+    metrics::CACHE_OLDEST_ENTRY_AGE.set(oldest_age.as_secs() as i64);
+    if let Some(shared) = &data.shared_unique_ids {
+        match shared.estimate().await {
+            Ok(estimate) => {
+                metrics::FLEET_UNIQUE_IDS.set(data.unique_ids_sampler.scale(estimate) as i64)
+            }
+            Err(e) => log::warn!("failed to query fleet-wide unique IDs estimate: {}", e),
+        }
+    }
+}
+
+/// Record the status of a graph response.
+fn pe_record_response_status(status: actix_web::http::StatusCode, duration: Duration) {
+    let class = match status.as_u16() / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        _ => "5xx",
+    };
+    metrics::V1_GRAPH_RESPONSES
+        .with_label_values(&[class])
+        .inc();
+    metrics::V1_GRAPH_REQUEST_DURATION
+        .with_label_values(&[class])
+        .observe(duration.as_secs_f64());
+    if status == actix_web::http::StatusCode::NOT_MODIFIED {
+        metrics::V1_GRAPH_NOT_MODIFIED.inc();
+    }
+}
 
-    V1_GRAPH_INCOMING_REQS.inc();
+pub(crate) fn pe_record_metrics(data: &AppState, query: &GraphQuery) {
+    metrics::V1_GRAPH_INCOMING_REQS.inc();
+    if wariness::has_conflicting_wariness(query) {
+        metrics::V1_GRAPH_CONFLICTING_WARINESS.inc();
+    }
 
+    let population = match &data.population {
+        Some(population) => population,
+        None => return,
+    };
     if let Some(uuid) = &query.node_uuid {
-        let mut hasher = DefaultHasher::default();
-        uuid.hash(&mut hasher);
-        let client_uuid = hasher.finish();
-        if !data.population.maybe_contains(client_uuid) {
-            data.population.insert(client_uuid);
-            UNIQUE_IDS.inc();
+        let client_uuid = unique_ids::hash_uuid(uuid, data.unique_ids_hash);
+        if !data.unique_ids_sampler.is_sampled(client_uuid) {
+            return;
+        }
+        if population.insert(client_uuid) {
+            metrics::UNIQUE_IDS.inc_by(data.unique_ids_sampler.record_unique());
+            metrics::UNIQUE_IDS_BLOOM_FILL.set(population.fill_ratio());
+            // Only IDs new to this replica are sent to the fleet-wide estimator.
+            if let Some(shared) = &data.shared_unique_ids {
+                let shared = Arc::clone(shared);
+                actix_rt::spawn(async move { shared.record(client_uuid).await });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wariness::{compute_wariness, WarinessSource};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use commons::metadata;
+    use config::WarinessParsing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Canned upstream graph: a dead-end release and a rollout at 50%.
+    pub(crate) fn canned_graph() -> graph::Graph {
+        let node = |version: &str, metadata: Vec<(&str, &str)>| graph::CincinnatiPayload {
+            version: version.to_string(),
+            payload: format!("sha256:{}", version),
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        graph::Graph {
+            nodes: vec![
+                node("35.1.0", vec![]),
+                node("35.2.0", vec![(metadata::DEADEND, "true")]),
+                node(
+                    "35.3.0",
+                    vec![(metadata::ROLLOUT, "true"), (metadata::START_VALUE, "0.5")],
+                ),
+            ],
+            edges: vec![(0, 1), (0, 2), (1, 2)],
+        }
+    }
+
+    /// Start a mock graph-builder, replying with the canned graph.
+    pub(crate) fn canned_upstream() -> test::TestServer {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        mock_upstream(StatusCode::OK, body, Duration::from_secs(0))
+    }
+
+    /// Start a mock graph-builder, replying with the given status and body
+    /// after the given delay.
+    pub(crate) fn mock_upstream(
+        status: StatusCode,
+        body: String,
+        delay: Duration,
+    ) -> test::TestServer {
+        test::start(move || {
+            let body = body.clone();
+            App::new().route(
+                "/v1/graph",
+                web::get().to(move || {
+                    let body = body.clone();
+                    async move {
+                        actix_rt::time::delay_for(delay).await;
+                        let resp = HttpResponse::build(status)
+                            .content_type("application/json")
+                            .body(body);
+                        Ok::<_, actix_web::Error>(resp)
+                    }
+                }),
+            )
+        })
+    }
+
+    /// Start a mock graph-builder, replying with the given statuses and
+    /// bodies in turn, then repeating the last one.
+    ///
+    /// The returned counter tracks served requests; resetting it restarts
+    /// the sequence.
+    pub(crate) fn mock_upstream_sequence(
+        responses: Vec<(StatusCode, String)>,
+    ) -> (test::TestServer, Arc<AtomicUsize>) {
+        assert!(!responses.is_empty());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let requests = Arc::clone(&requests);
+            test::start(move || {
+                let (responses, requests) = (responses.clone(), Arc::clone(&requests));
+                App::new().route(
+                    "/v1/graph",
+                    web::get().to(move || {
+                        let count = requests.fetch_add(1, Ordering::SeqCst);
+                        let (status, body) = responses[count.min(responses.len() - 1)].clone();
+                        async move {
+                            let resp = HttpResponse::build(status)
+                                .content_type("application/json")
+                                .body(body);
+                            Ok::<_, actix_web::Error>(resp)
+                        }
+                    }),
+                )
+            })
+        };
+        (server, requests)
+    }
+
+    /// Service settings, with the given mock graph-builder as the only
+    /// upstream endpoint.
+    pub(crate) fn settings_for(upstream: &test::TestServer) -> settings::ServiceSettings {
+        let mut settings = settings::ServiceSettings::default();
+        settings.upstream.endpoints = vec![settings::UpstreamEndpoint::new(
+            reqwest::Url::parse(&upstream.url("/v1/graph")).unwrap(),
+        )];
+        settings
+    }
+
+    /// Graph query for the given scope, with an optional client wariness.
+    pub(crate) fn scoped_query(stream: &str, basearch: &str, wariness: Option<&str>) -> GraphQuery {
+        GraphQuery {
+            basearch: Some(basearch.to_string()),
+            stream: Some(stream.to_string()),
+            rollout_wariness: wariness.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    /// Query the policy-engine graph endpoint, with the given upstream.
+    async fn query_graph(upstream: &test::TestServer, query: &str) -> (StatusCode, web::Bytes) {
+        let mut settings = settings_for(upstream);
+        settings.upstream.req_timeout = Duration::from_millis(500);
+
+        let state = AppState::new(&settings).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/graph?{}", query))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        (status, body)
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_success() {
+        let upstream = canned_upstream();
+
+        // Dead-end edges are always pruned, the rollout is throttled for wary clients.
+        let (status, body) = query_graph(
+            &upstream,
+            "basearch=x86_64&stream=stable&rollout_wariness=0.9",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let graph: graph::Graph = serde_json::from_slice(&body).unwrap();
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.edges, vec![(0, 1)]);
+
+        let (status, body) = query_graph(
+            &upstream,
+            "basearch=x86_64&stream=stable&rollout_wariness=0.1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let graph: graph::Graph = serde_json::from_slice(&body).unwrap();
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn test_record_metrics_unique_ids() {
+        let query = GraphQuery {
+            node_uuid: Some("test-record-uuid".to_string()),
+            ..scoped_query("stable", "x86_64", None)
+        };
+        let mut settings = settings::ServiceSettings {
+            bloom_size: 1024,
+            bloom_max_population: 100,
+            ..Default::default()
+        };
+
+        let before = metrics::UNIQUE_IDS.get();
+        let state = AppState::new(&settings).unwrap();
+        pe_record_metrics(&state, &query);
+        pe_record_metrics(&state, &query);
+        assert_eq!(metrics::UNIQUE_IDS.get(), before + 1);
+
+        settings.track_unique_ids = false;
+        let state = AppState::new(&settings).unwrap();
+        assert!(state.population.is_none());
+        pe_record_metrics(&state, &query);
+        assert_eq!(metrics::UNIQUE_IDS.get(), before + 1);
+    }
+
+    #[test]
+    fn test_record_metrics_conflicting_wariness() {
+        let query = |wariness: Option<&str>, uuid: &str| GraphQuery {
+            node_uuid: Some(uuid.to_string()),
+            ..scoped_query("stable", "x86_64", wariness)
+        };
+        let settings = settings::ServiceSettings {
+            bloom_size: 1024,
+            bloom_max_population: 100,
+            ..Default::default()
+        };
+        let state = AppState::new(&settings).unwrap();
+
+        // Both supplied: counted, the UUID still feeds unique IDs tracking,
+        // and the explicit wariness takes precedence.
+        let (before, unique_before) = (
+            metrics::V1_GRAPH_CONFLICTING_WARINESS.get(),
+            metrics::UNIQUE_IDS.get(),
+        );
+        let both = query(Some("0.2"), "test-conflicting-uuid");
+        pe_record_metrics(&state, &both);
+        assert!(metrics::V1_GRAPH_CONFLICTING_WARINESS.get() > before);
+        assert!(metrics::UNIQUE_IDS.get() > unique_before);
+        let (wariness, source) =
+            compute_wariness(&both, 3, "salt", WarinessParsing::Clamp).unwrap();
+        assert_eq!(wariness, 0.2);
+        assert_eq!(source, WarinessSource::Requested);
+
+        // Only the UUID (or an empty wariness): no conflict.
+        let uuid_only = query(Some(""), "test-conflicting-uuid");
+        let (_, source) = compute_wariness(&uuid_only, 3, "salt", WarinessParsing::Clamp).unwrap();
+        assert_eq!(source, WarinessSource::Computed);
+        assert!(wariness::has_conflicting_wariness(&both));
+        assert!(!wariness::has_conflicting_wariness(&uuid_only));
+        assert!(!wariness::has_conflicting_wariness(&query(
+            None,
+            "test-conflicting-uuid"
+        )));
+        assert!(!wariness::has_conflicting_wariness(&query(Some("0.2"), "")));
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_cache_header() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.status_header = true;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        for expected in &["MISS", "HIT"] {
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_serialization_duration() {
+        let upstream = canned_upstream();
+        let serialized = metrics::V1_GRAPH_SERIALIZATION_DURATION.with_label_values(&["success"]);
+        let before = serialized.get_sample_count();
+        let (status, _) = query_graph(&upstream, "basearch=x86_64&stream=stable").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(serialized.get_sample_count() > before);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_request_duration() {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_millis(50));
+        let ok = metrics::V1_GRAPH_REQUEST_DURATION.with_label_values(&["2xx"]);
+        let (ok_count, ok_sum) = (ok.get_sample_count(), ok.get_sample_sum());
+        let (status, _) = query_graph(&upstream, "basearch=x86_64&stream=stable").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(ok.get_sample_count() > ok_count);
+        // The upstream fetch is part of the request handling.
+        assert!(ok.get_sample_sum() - ok_sum >= 0.05);
+
+        let client_errors = metrics::V1_GRAPH_REQUEST_DURATION.with_label_values(&["4xx"]);
+        let before = client_errors.get_sample_count();
+        let (status, _) = query_graph(&upstream, "basearch=x86_64").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(client_errors.get_sample_count() > before);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_head_metrics() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph))
+                .route("/v1/graph", web::head().to(pe_serve_graph)),
+        )
+        .await;
+
+        let head_before = metrics::V1_GRAPH_INCOMING_HEAD_REQS.get();
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/v1/graph?basearch=x86_64&stream=stable")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(metrics::V1_GRAPH_INCOMING_HEAD_REQS.get(), head_before + 1);
+
+        let get_before = metrics::V1_GRAPH_INCOMING_REQS.get();
+        let req = test::TestRequest::get()
+            .uri("/v1/graph?basearch=x86_64&stream=stable")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(metrics::V1_GRAPH_INCOMING_REQS.get() > get_before);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_policy_metadata() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+
+        for enabled in &[false, true] {
+            settings.policy_metadata = *enabled;
+            let mut app = test::init_service(
+                App::new()
+                    .data(AppState::new(&settings).unwrap())
+                    .route("/v1/graph", web::get().to(pe_serve_graph)),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.25")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            let fields: Vec<&String> = body.as_object().unwrap().keys().collect();
+            if !enabled {
+                assert_eq!(fields, vec!["edges", "nodes"]);
+                continue;
+            }
+            assert_eq!(fields, vec!["edges", "nodes", "policy_metadata"]);
+            // The generation time depends on the clock, see `test_serve_graph_generated`.
+            let mut metadata = body["policy_metadata"].clone();
+            let generated = metadata.as_object_mut().unwrap().remove("graph_generated");
+            assert!(generated.is_some());
+            assert_eq!(
+                metadata,
+                serde_json::json!({
+                    "applied_policies": ["throttle_rollouts", "filter_deadends"],
+                    "rollout_wariness": 0.25,
+                })
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_tiers() {
+        use std::io::Write;
+
+        let upstream = canned_upstream();
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        token_file.write_all(b"s3cret\n").unwrap();
+        let mut settings = settings_for(&upstream);
+        settings.tiers = Some(settings::TiersSettings {
+            token_path: token_file.path().to_path_buf(),
+            anonymous_streams: Some(vec!["stable".to_string()].into_iter().collect()),
+        });
+        settings.policy_metadata = true;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let graph_request = |stream: &str, token: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!(
+                "/v1/graph?basearch=x86_64&stream={}&rollout_wariness=0.25",
+                stream
+            ));
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            req.to_request()
+        };
+
+        let cases = vec![
+            // Authenticated clients get the complete graph.
+            (
+                "stable",
+                Some("s3cret"),
+                serde_json::json!([[0, 1], [0, 2]]),
+            ),
+            (
+                "testing",
+                Some("s3cret"),
+                serde_json::json!([[0, 1], [0, 2]]),
+            ),
+            // Anonymous clients are not offered releases being rolled out,
+            // nor other streams than the configured ones.
+            ("stable", None, serde_json::json!([[0, 1]])),
+            ("stable", Some("guess"), serde_json::json!([[0, 1]])),
+            ("testing", None, serde_json::json!([])),
+        ];
+        for (stream, token, edges) in cases {
+            let resp = test::call_service(&mut app, graph_request(stream, token)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let vary: Vec<_> = resp.headers().get_all(header::VARY).collect();
+            assert!(vary.iter().any(|value| *value == "Authorization"));
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            assert_eq!(body["edges"], edges, "{} {:?}", stream, token);
+            let anonymous = body["policy_metadata"]["applied_policies"]
+                .as_array()
+                .unwrap()
+                .contains(&serde_json::json!("anonymous_tier"));
+            assert_eq!(anonymous, token != Some("s3cret"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_rollouts_passthrough() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.policy.rollouts = config::RolloutHandling::Passthrough;
+        settings.policy_metadata = true;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let uri = "/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.9";
+        let expected_rollouts = serde_json::json!([{
+            "from": "35.1.0",
+            "to": "35.3.0",
+            "progress": 0.5,
+            "start_epoch": 0,
+            "start_value": 0.5,
+            "duration_minutes": null,
+        }]);
+
+        // Too wary clients are still offered the rollout, with its details.
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["edges"], serde_json::json!([[0, 1], [0, 2]]));
+        assert_eq!(body["edge_rollouts"], expected_rollouts);
+        assert_eq!(
+            body["policy_metadata"]["applied_policies"],
+            serde_json::json!(["filter_deadends"])
+        );
+
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .header(header::ACCEPT, format::GRAPH_V2_CONTENT_TYPE)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["schema_version"], 2);
+        assert_eq!(body["edge_rollouts"], expected_rollouts);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_wariness_header() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let query = GraphQuery {
+            node_uuid: Some("e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e".to_string()),
+            ..scoped_query("stable", "x86_64", None)
+        };
+        let policy = &settings.policy;
+        let (expected, _) = compute_wariness(
+            &query,
+            policy.wariness_precision,
+            &policy.wariness_salt,
+            policy.wariness_parsing,
+        )
+        .unwrap();
+
+        for enabled in &[false, true] {
+            settings.wariness_header = *enabled;
+            let mut app = test::init_service(
+                App::new()
+                    .data(AppState::new(&settings).unwrap())
+                    .route("/v1/graph", web::get().to(pe_serve_graph)),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable&node_uuid=e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let header = resp.headers().get(WARINESS_HEADER);
+            if !enabled {
+                assert!(header.is_none());
+                continue;
+            }
+            let wariness: f64 = header.unwrap().to_str().unwrap().parse().unwrap();
+            assert_eq!(wariness, expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_etag_scoped() {
+        // The same graph is served for all streams.
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let request = |stream: &str, etag: Option<&header::HeaderValue>| {
+            let uri = format!(
+                "/v1/graph?basearch=x86_64&stream={}&rollout_wariness=0.25",
+                stream
+            );
+            let req = test::TestRequest::get().uri(&uri);
+            match etag {
+                Some(etag) => req.header(header::IF_NONE_MATCH, etag.clone()),
+                None => req,
+            }
+            .to_request()
+        };
+
+        let resp = test::call_service(&mut app, request("stable", None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stable_etag = resp.headers().get(header::ETAG).unwrap().clone();
+
+        // A client switching streams gets the graph of its new stream.
+        let resp = test::call_service(&mut app, request("testing", Some(&stable_etag))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers().get(header::ETAG).unwrap(), &stable_etag);
+        let graph: graph::Graph = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(graph.nodes.len(), canned_graph().nodes.len());
+
+        let resp = test::call_service(&mut app, request("stable", Some(&stable_etag))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_not_modified() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let uri = "/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.25";
+
+        let ok = metrics::V1_GRAPH_RESPONSES
+            .with_label_values(&["2xx"])
+            .get();
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers().get(header::ETAG).unwrap().clone();
+        assert!(
+            metrics::V1_GRAPH_RESPONSES
+                .with_label_values(&["2xx"])
+                .get()
+                > ok
+        );
+
+        let not_modified = metrics::V1_GRAPH_NOT_MODIFIED.get();
+        let redirects = metrics::V1_GRAPH_RESPONSES
+            .with_label_values(&["3xx"])
+            .get();
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), &etag);
+        assert!(test::read_body(resp).await.is_empty());
+        assert!(metrics::V1_GRAPH_NOT_MODIFIED.get() > not_modified);
+        assert!(
+            metrics::V1_GRAPH_RESPONSES
+                .with_label_values(&["3xx"])
+                .get()
+                > redirects
+        );
+
+        // Errors are counted by status class too.
+        let client_errors = metrics::V1_GRAPH_RESPONSES
+            .with_label_values(&["4xx"])
+            .get();
+        let req = test::TestRequest::get()
+            .uri("/v1/graph?basearch=x86_64")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            metrics::V1_GRAPH_RESPONSES
+                .with_label_values(&["4xx"])
+                .get()
+                > client_errors
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_formats() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let base = "/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.25";
+        let get = |query: &str, accept: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!("{}{}", base, query));
+            if let Some(accept) = accept {
+                req = req.header(header::ACCEPT, accept);
+            }
+            req.to_request()
+        };
+
+        // Default Cincinnati graph format.
+        let resp = test::call_service(&mut app, get("", None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        let fields: Vec<&String> = body.as_object().unwrap().keys().collect();
+        assert_eq!(fields, vec!["edges", "nodes"]);
+        assert_eq!(body["edges"], serde_json::json!([[0, 1], [0, 2]]));
+
+        // Legacy array format, from the query or the `Accept` profile.
+        let legacy_accept = Some("application/json; profile=legacy");
+        for (query, accept) in &[("&format=legacy", None), ("", legacy_accept)] {
+            let resp = test::call_service(&mut app, get(query, *accept)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            let releases = body.as_array().unwrap();
+            let next: Vec<(&str, &serde_json::Value)> = releases
+                .iter()
+                .map(|r| (r["version"].as_str().unwrap(), &r["next"]))
+                .collect();
+            assert_eq!(
+                next,
+                vec![
+                    ("35.1.0", &serde_json::json!(["35.2.0", "35.3.0"])),
+                    ("35.2.0", &serde_json::json!([])),
+                    ("35.3.0", &serde_json::json!([])),
+                ]
+            );
+            assert!(releases[0]["payload"].is_string());
+            assert!(releases[0]["metadata"].is_object());
+        }
+
+        // The query parameter takes precedence.
+        let resp = test::call_service(&mut app, get("&format=graph", legacy_accept)).await;
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert!(body.is_object());
+
+        // Graph schemas: v1 for v1-only clients, the highest one supported
+        // for v2-capable clients.
+        let v1_accept = Some("application/json; profile=graph-v1");
+        let resp = test::call_service(&mut app, get("", v1_accept)).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["edges"], serde_json::json!([[0, 1], [0, 2]]));
+        let v2_accept =
+            Some("application/json; profile=graph-v1, application/json; profile=graph-v2");
+        let resp = test::call_service(&mut app, get("", v2_accept)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            format::GRAPH_V2_CONTENT_TYPE
+        );
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["schema_version"], 2);
+        assert_eq!(body["nodes"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            body["edges"],
+            serde_json::json!([
+                {"from": "35.1.0", "to": "35.2.0"},
+                {"from": "35.1.0", "to": "35.3.0"},
+            ])
+        );
+
+        for query in &["&format=xml", "&format=legacy&limit=1"] {
+            let resp = test::call_service(&mut app, get(query, None)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_pagination() {
+        let upstream = canned_upstream();
+        let base = "basearch=x86_64&stream=stable&rollout_wariness=0.25";
+
+        // Without pagination parameters, the full graph is returned as is.
+        let (status, body) = query_graph(&upstream, base).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("pagination").is_none());
+        assert_eq!(body["nodes"].as_array().unwrap().len(), 3);
+
+        let pages = vec![
+            ("offset=0&limit=2", vec!["35.1.0", "35.2.0"], 1, Some(2)),
+            ("offset=2&limit=2", vec!["35.3.0"], 0, None),
+            ("offset=1", vec!["35.2.0", "35.3.0"], 0, None),
+            ("limit=1", vec!["35.1.0"], 0, Some(1)),
+            ("offset=3&limit=1", vec![], 0, None),
+        ];
+        for (params, versions, edges, next) in pages {
+            let query = format!("{}&{}", base, params);
+            let (status, body) = query_graph(&upstream, &query).await;
+            assert_eq!(status, StatusCode::OK, "{}", params);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let nodes: Vec<&str> = body["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|n| n["version"].as_str().unwrap())
+                .collect();
+            assert_eq!(nodes, versions, "{}", params);
+            assert_eq!(body["edges"].as_array().unwrap().len(), edges, "{}", params);
+            assert_eq!(body["pagination"]["total"], 3, "{}", params);
+            assert_eq!(body["pagination"]["next"], serde_json::json!(next));
+        }
+
+        for params in &["limit=0", "offset=-1", "limit=many"] {
+            let query = format!("{}&{}", base, params);
+            let (status, _) = query_graph(&upstream, &query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", params);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_warmup() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.warmup.reject_requests = true;
+
+        let state = AppState::new(&settings).unwrap();
+        let readiness = state.readiness.clone();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph))
+                .route("/readyz", web::get().to(pe_serve_readyz)),
+        )
+        .await;
+        let graph_req = || {
+            test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request()
+        };
+        let readyz_req = || test::TestRequest::get().uri("/readyz").to_request();
+
+        // Another request is already fetching, this one is turned away.
+        let probe = readiness.admit().unwrap();
+        let resp = test::call_service(&mut app, graph_req()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "5");
+        let resp = test::call_service(&mut app, readyz_req()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(probe);
+
+        // The first successful fetch ends the warm-up.
+        let resp = test::call_service(&mut app, graph_req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&mut app, readyz_req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _probe = readiness.admit().unwrap();
+        let resp = test::call_service(&mut app, graph_req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_readyz_all_scopes() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.warmup.criterion = config::ReadinessCriterion::All;
+        let scope = |stream: &str| graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        settings.scope_allowlist.scopes = Some(
+            vec![scope("stable"), scope("testing")]
+                .into_iter()
+                .collect(),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph))
+                .route("/readyz", web::get().to(pe_serve_readyz)),
+        )
+        .await;
+        let graph_req = |stream: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/v1/graph?basearch=x86_64&stream={}", stream))
+                .to_request()
+        };
+        let readyz_req = || test::TestRequest::get().uri("/readyz").to_request();
+
+        let resp = test::call_service(&mut app, graph_req("stable")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&mut app, readyz_req()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            test::read_body(resp).await,
+            "warming up\nstable/x86_64: ready\ntesting/x86_64: warming up"
+        );
+
+        let resp = test::call_service(&mut app, graph_req("testing")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&mut app, readyz_req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            test::read_body(resp).await,
+            "ready\nstable/x86_64: ready\ntesting/x86_64: ready"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_blocked_versions() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.policy.blocked_versions = vec!["35.3.0".to_string(), "35.9.0".to_string()]
+            .into_iter()
+            .collect();
+        let query = |current_version: Option<&str>| GraphQuery {
+            current_version: current_version.map(String::from),
+            ..scoped_query("stable", "x86_64", Some("0.0"))
+        };
+        let versions = |graph: &graph::Graph| -> Vec<String> {
+            graph.nodes.iter().map(|n| n.version.clone()).collect()
+        };
+
+        let state = AppState::new(&settings).unwrap();
+        let processed = pipeline::pe_process_graph(&state, &query(None))
+            .await
+            .unwrap();
+        assert!(processed.applied_policies.contains(&"block_versions"));
+        assert_eq!(versions(&processed.graph), vec!["35.1.0", "35.2.0"]);
+        assert_eq!(processed.graph.edges, vec![(0, 1)]);
+
+        // Clients on a blocked release keep it, without updates towards it.
+        let processed = pipeline::pe_process_graph(&state, &query(Some("35.3.0")))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&processed.graph),
+            vec!["35.1.0", "35.2.0", "35.3.0"]
+        );
+        assert_eq!(processed.graph.edges, vec![(0, 1)]);
+
+        // The active blocklist is served on the debug endpoint.
+        let mut app = test::init_service(App::new().data(state).route(
+            "/debug/blocked-versions",
+            web::get().to(pe_serve_debug_blocked_versions),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/debug/blocked-versions")
+            .to_request();
+        let body = test::read_body(test::call_service(&mut app, req).await).await;
+        let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            listing,
+            serde_json::json!({"blocked_versions": ["35.3.0", "35.9.0"]})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_debug_graph_dot() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let state = AppState::new(&settings).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/debug/graph.dot", web::get().to(pe_serve_debug_graph_dot)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/debug/graph.dot?basearch=x86_64&stream=stable&rollout_wariness=0")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            debug::DOT_CONTENT_TYPE
+        );
+        let dot = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(dot.starts_with("digraph \"stable/x86_64\" {\n"));
+        assert!(dot.contains("  n0 [label=\"35.1.0\"];\n"));
+        // The dead-end release has no outgoing edges.
+        assert!(dot.contains("  n0 -> n2;\n"));
+        assert!(!dot.contains("n1 ->"));
+
+        let req = test::TestRequest::get()
+            .uri("/debug/graph.dot?stream=stable")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_cache_expiry() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.status_header = true;
+        settings.cache.ttl = Duration::from_secs(300);
+
+        let clock = Arc::new(clock::MockClock::at(chrono::Utc::now()));
+        let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        for (elapsed, expected) in &[(0, "MISS"), (299, "HIT"), (1, "MISS"), (0, "HIT")] {
+            clock.advance(Duration::from_secs(*elapsed));
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_min_fetch_interval() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.status_header = true;
+        settings.cache.ttl = Duration::from_secs(30);
+        settings.cache.min_fetch_interval = Some(Duration::from_secs(120));
+
+        let clock = Arc::new(clock::MockClock::at(chrono::Utc::now()));
+        let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let suppressed = metrics::UPSTREAM_FETCHES_SUPPRESSED.get();
+        // Expired graphs are served stale until the interval has elapsed.
+        let requests = &[
+            (0, "stable", "MISS"),
+            (31, "stable", "STALE"),
+            (0, "testing", "MISS"),
+            (88, "stable", "STALE"),
+            (1, "stable", "MISS"),
+            (0, "stable", "HIT"),
+        ];
+        for (elapsed, stream, expected) in requests {
+            clock.advance(Duration::from_secs(*elapsed));
+            let req = test::TestRequest::get()
+                .uri(&format!("/v1/graph?basearch=x86_64&stream={}", stream))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+        }
+        assert_eq!(metrics::UPSTREAM_FETCHES_SUPPRESSED.get(), suppressed + 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_stale_while_revalidate() {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_millis(100));
+        let mut settings = settings_for(&upstream);
+        settings.cache.status_header = true;
+        settings.cache.ttl = Duration::from_secs(30);
+        settings.cache.stale_while_revalidate = Some(Duration::from_secs(60));
+
+        let clock = Arc::new(clock::MockClock::at(chrono::Utc::now()));
+        let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+        let cache = state.cache.clone();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+        let stale_served = metrics::CACHE_STALE_WHILE_REVALIDATE.get();
+        // Within the window, stale graphs are served right away, while a
+        // single refresh runs in the background. Past it, graphs are
+        // refreshed on the request path.
+        let requests = &[
+            (0, "MISS", false),
+            (31, "STALE", false),
+            (0, "STALE", true),
+            (0, "HIT", false),
+            (90, "MISS", false),
+        ];
+        for (elapsed, expected, wait_refresh) in requests {
+            clock.advance(Duration::from_secs(*elapsed));
+            let fetched = cache.get(&scope).map(|entry| entry.fetched);
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+            if *wait_refresh {
+                let mut refreshed = false;
+                for _ in 0..50 {
+                    actix_rt::time::delay_for(Duration::from_millis(20)).await;
+                    if cache.get(&scope).map(|entry| entry.fetched) > fetched {
+                        refreshed = true;
+                        break;
+                    }
+                }
+                assert!(refreshed);
+            }
+        }
+        assert!(metrics::CACHE_STALE_WHILE_REVALIDATE.get() >= stale_served + 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_cache_bypass() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.status_header = true;
+        settings.cache.ttl = Duration::from_secs(300);
+
+        for debug in &[false, true] {
+            settings.debug.enabled = *debug;
+            let state = AppState::new(&settings).unwrap();
+            let mut app = test::init_service(
+                App::new()
+                    .data(state)
+                    .route("/v1/graph", web::get().to(pe_serve_graph)),
+            )
+            .await;
+            let bypassed = if *debug { "MISS" } else { "HIT" };
+            let requests = &[
+                ("", None, "MISS"),
+                ("", None, "HIT"),
+                ("", Some("no-cache"), bypassed),
+                ("&fresh=true", Some("max-age=0"), bypassed),
+                ("", Some("max-age=0"), "HIT"),
+            ];
+            for (extra, cache_control, expected) in requests {
+                let mut req = test::TestRequest::get()
+                    .uri(&format!("/v1/graph?basearch=x86_64&stream=stable{}", extra));
+                if let Some(value) = cache_control {
+                    req = req.header(header::CACHE_CONTROL, *value);
+                }
+                let resp = test::call_service(&mut app, req.to_request()).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_generated() {
+        use chrono::TimeZone;
+
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.ttl = Duration::from_secs(300);
+        settings.policy_metadata = true;
+
+        let clock = Arc::new(clock::MockClock::at(
+            chrono::Utc.timestamp(1_600_000_000, 0),
+        ));
+        let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        // Cached graphs keep reporting the time they were fetched.
+        for (elapsed, expected) in &[
+            (0, "2020-09-13T12:26:40+00:00"),
+            (60, "2020-09-13T12:26:40+00:00"),
+            (300, "2020-09-13T12:32:40+00:00"),
+        ] {
+            clock.advance(Duration::from_secs(*elapsed));
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(GENERATED_HEADER).unwrap(), expected);
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            assert_eq!(body["policy_metadata"]["graph_generated"], *expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_last_successful_fetch_timestamp() {
+        use chrono::TimeZone;
+
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.cache.ttl = Duration::from_secs(300);
+
+        let clock = Arc::new(clock::MockClock::at(
+            chrono::Utc.timestamp(1_600_000_000, 0),
+        ));
+        let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state)
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let last_success = metrics::UPSTREAM_LAST_SUCCESS.with_label_values(&["last-fetch"]);
+
+        // Only updated on actual upstream fetches, not on cache hits.
+        for (elapsed, expected) in &[
+            (0, 1_600_000_000),
+            (100, 1_600_000_000),
+            (300, 1_600_000_400),
+        ] {
+            clock.advance(Duration::from_secs(*elapsed));
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=last-fetch")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(last_success.get(), *expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_flush_cache() {
+        use std::io::Write;
+
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        token_file.write_all(b"s3cret").unwrap();
+        let settings = settings::ServiceSettings {
+            admin: Some(settings::AdminSettings {
+                token_path: token_file.path().to_path_buf(),
+            }),
+            ..Default::default()
+        };
+        let state = AppState::new(&settings).unwrap();
+        let scope = |basearch: &str, stream: &str| graph::GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        for (basearch, stream) in &[
+            ("x86_64", "stable"),
+            ("aarch64", "stable"),
+            ("x86_64", "testing"),
+        ] {
+            state.cache.insert(scope(basearch, stream), canned_graph());
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(state.clone())
+                .route("/admin/flush-cache", web::post().to(pe_admin_flush_cache)),
+        )
+        .await;
+
+        let flushes = metrics::ADMIN_CACHE_FLUSHES.get();
+        for token in &[None, Some("Bearer wrong")] {
+            let mut req = test::TestRequest::post().uri("/admin/flush-cache");
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, *token);
+            }
+            let resp = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(metrics::ADMIN_CACHE_FLUSHES.get(), flushes);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/flush-cache?stream=stable")
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .to_request();
+        let flushed: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(flushed["flushed"], 2);
+        assert!(state.cache.get(&scope("x86_64", "stable")).is_none());
+        assert!(state.cache.get(&scope("aarch64", "stable")).is_none());
+        assert!(state.cache.get(&scope("x86_64", "testing")).is_some());
+        assert_eq!(metrics::ADMIN_CACHE_FLUSHES.get(), flushes + 1);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/flush-cache?channel=stable")
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_signed() {
+        use ring::signature::{UnparsedPublicKey, ED25519};
+
+        let upstream = canned_upstream();
+        let signer = signing::GraphSigner::from_pem(&signing::tests::generate_pem()).unwrap();
+        let mut settings = settings_for(&upstream);
+        settings.signer = Some(Arc::new(signer));
+
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph))
+                .route("/v1/signing-key", web::get().to(pe_serve_signing_key)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/v1/signing-key").to_request();
+        let key: serde_json::Value = test::read_response_json(&mut app, req).await;
+        let public_key = base64::decode(key["public_key"].as_str().unwrap()).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/v1/graph?basearch=x86_64&stream=stable")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let signature = resp.headers().get(SIGNATURE_HEADER).unwrap().clone();
+        let signature = base64::decode(signature.to_str().unwrap()).unwrap();
+        let body = test::read_body(resp).await;
+        let verifier = UnparsedPublicKey::new(&ED25519, &public_key);
+        assert!(verifier.verify(&body, &signature).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_zstd() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.compression.enabled = true;
+        settings.compression.zstd_level = Some(3);
+
+        let state = web::Data::new(AppState::new(&settings).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(middleware::Compress::default())
+                .app_data(state.clone())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let request = |accept: &str| {
+            test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.1")
+                .header(header::ACCEPT_ENCODING, accept)
+                .to_request()
+        };
+        let etag = |headers: &header::HeaderMap| {
+            headers
+                .get(header::ETAG)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let resp = test::call_service(&mut app, request("gzip, zstd")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "zstd"
+        );
+        let zstd_etag = etag(resp.headers());
+        assert!(zstd_etag.ends_with("-zstd\""));
+        let encoded = test::read_body(resp).await;
+        let decoded = zstd::decode_all(encoded.as_ref()).unwrap();
+        let graph: graph::Graph = serde_json::from_slice(&decoded).unwrap();
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2)]);
+
+        // Other encodings are left to the middleware, under a weak tag.
+        let resp = test::call_service(&mut app, request("gzip")).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        let gzip_etag = etag(resp.headers());
+        assert!(gzip_etag.starts_with("W/"));
+        assert_ne!(gzip_etag.trim_start_matches("W/"), zstd_etag);
+
+        // Response sizes are tracked per stream, before HTTP-level compression.
+        let sizes = |compressed: &str| {
+            state
+                .response_sizes
+                .with_label_values(&["stable", compressed])
+        };
+        assert_eq!(sizes("true").get_sample_count(), 1);
+        assert_eq!(sizes("true").get_sample_sum(), encoded.len() as f64);
+        assert_eq!(sizes("false").get_sample_count(), 1);
+        assert!(sizes("false").get_sample_sum() > encoded.len() as f64);
+
+        // Validators only match the encoding they were issued for.
+        let revalidate = |accept: &str, tag: &str| {
+            test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0.1")
+                .header(header::ACCEPT_ENCODING, accept)
+                .header(header::IF_NONE_MATCH, tag)
+                .to_request()
+        };
+        let cases = vec![
+            ("zstd", zstd_etag.clone(), StatusCode::NOT_MODIFIED),
+            ("zstd", gzip_etag.clone(), StatusCode::OK),
+            ("gzip", gzip_etag.clone(), StatusCode::NOT_MODIFIED),
+            ("gzip", zstd_etag.clone(), StatusCode::OK),
+        ];
+        for (accept, tag, status) in cases {
+            let resp = test::call_service(&mut app, revalidate(accept, &tag)).await;
+            assert_eq!(resp.status(), status, "{} with {}", accept, tag);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_upstream_error() {
+        let upstream = mock_upstream(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::new(),
+            Duration::from_secs(0),
+        );
+        let (status, body) = query_graph(&upstream, "basearch=x86_64&stream=stable").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(envelope["kind"], "upstream_unavailable");
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_upstream_timeout() {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_secs(5));
+        let (status, _) = query_graph(&upstream, "basearch=x86_64&stream=stable").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_self_test() {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        let good = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let body = serde_json::to_string(&graph::Graph::default()).unwrap();
+        let bad = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let mut settings = settings_for(&good);
+        settings.upstream.stream_endpoints.insert(
+            "testing".to_string(),
+            settings::UpstreamEndpoint::new(reqwest::Url::parse(&bad.url("/v1/graph")).unwrap()),
+        );
+        let scope = |stream: &str| graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        settings.scope_allowlist.scopes = Some(
+            vec![scope("stable"), scope("testing")]
+                .into_iter()
+                .collect(),
+        );
+        let state = AppState::new(&settings).unwrap();
+        let opts = cli::SelfTestOptions {
+            streams: vec![],
+            basearches: vec![],
+        };
+
+        let mut out = Vec::new();
+        let err = pe_self_test(&state, &opts, &mut out).await.unwrap_err();
+        assert_eq!(err.to_string(), "self-test failed for 1 of 2 scopes");
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "PASS basearch='x86_64', stream='stable': 3 nodes, 3 edges",
+                "FAIL basearch='x86_64', stream='testing': empty graph",
+            ]
+        );
+
+        // Only the requested scopes are checked.
+        let opts = cli::SelfTestOptions {
+            streams: vec!["stable".to_string()],
+            basearches: vec![],
+        };
+        let mut out = Vec::new();
+        pe_self_test(&state, &opts, &mut out).await.unwrap();
+        assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), 1);
+    }
+
+    #[test]
+    fn test_self_test_scopes() {
+        let scopes = |allowed: &Option<HashSet<graph::GraphScope>>,
+                      upstream: &settings::UpstreamSettings,
+                      streams: &[&str],
+                      basearches: &[&str]| {
+            let opts = cli::SelfTestOptions {
+                streams: streams.iter().map(|s| s.to_string()).collect(),
+                basearches: basearches.iter().map(|s| s.to_string()).collect(),
+            };
+            self_test_scopes(allowed, upstream, &opts)
+                .into_iter()
+                .map(|scope| format!("{}/{}", scope.stream, scope.basearch))
+                .collect::<Vec<_>>()
+        };
+        let mut upstream = settings::UpstreamSettings::default();
+
+        assert_eq!(scopes(&None, &upstream, &[], &[]).len(), 6);
+        assert_eq!(
+            scopes(&None, &upstream, &["stable"], &["s390x"]),
+            vec!["stable/s390x"]
+        );
+
+        // Streams with a dedicated endpoint replace the usual ones.
+        let endpoint = settings::UpstreamEndpoint::new(
+            reqwest::Url::parse("https://example.com/v1/graph").unwrap(),
+        );
+        upstream
+            .stream_endpoints
+            .insert("rawhide".to_string(), endpoint);
+        assert_eq!(
+            scopes(&None, &upstream, &[], &[]),
+            vec!["rawhide/aarch64", "rawhide/x86_64"]
+        );
+
+        // Allowed scopes take precedence, restricted to requested ones.
+        let allowed: HashSet<_> = vec![
+            ("stable", "x86_64"),
+            ("stable", "aarch64"),
+            ("next", "x86_64"),
+        ]
+        .into_iter()
+        .map(|(stream, basearch)| graph::GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        })
+        .collect();
+        let allowed = Some(allowed);
+        assert_eq!(
+            scopes(&allowed, &upstream, &[], &[]),
+            vec!["stable/aarch64", "next/x86_64", "stable/x86_64"]
+        );
+        assert_eq!(
+            scopes(&allowed, &upstream, &[], &["x86_64"]),
+            vec!["next/x86_64", "stable/x86_64"]
+        );
+        assert!(scopes(&allowed, &upstream, &["testing"], &[]).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_processing_deadline() {
+        let body = serde_json::to_string(&canned_graph()).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_millis(300));
+        let mut settings = settings_for(&upstream);
+        settings.max_processing = Duration::from_secs(5);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let request = |hint: &str| {
+            test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .header(MAX_PROCESSING_HEADER, hint)
+                .to_request()
+        };
+
+        let resp = test::call_service(&mut app, request("50")).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "processing_timeout");
+
+        let resp = test::call_service(&mut app, request("2000")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&mut app, request("soon")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_response_delay() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.debug.response_delay = Some(Duration::from_millis(300));
+
+        // The delay only applies with debugging aids enabled.
+        for enabled in &[false, true] {
+            settings.debug.enabled = *enabled;
+            let mut app = test::init_service(
+                App::new()
+                    .data(AppState::new(&settings).unwrap())
+                    .route("/v1/graph", web::get().to(pe_serve_graph)),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable")
+                .to_request();
+            let start = std::time::Instant::now();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let delayed = start.elapsed() >= Duration::from_millis(300);
+            assert_eq!(delayed, *enabled);
+        }
+    }
+
+    #[test]
+    fn test_processing_deadline_capped() {
+        let max = Duration::from_secs(1);
+        let req = test::TestRequest::default().to_http_request();
+        assert_eq!(processing_deadline(&req, max).unwrap(), max);
+
+        let req = test::TestRequest::default()
+            .header(MAX_PROCESSING_HEADER, "60000")
+            .to_http_request();
+        assert_eq!(processing_deadline(&req, max).unwrap(), max);
+
+        let req = test::TestRequest::default()
+            .header(MAX_PROCESSING_HEADER, "250")
+            .to_http_request();
+        assert_eq!(
+            processing_deadline(&req, max).unwrap(),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_malformed_upstream() {
+        let upstream = mock_upstream(
+            StatusCode::OK,
+            "{not a graph".to_string(),
+            Duration::from_secs(0),
+        );
+        let (status, _) = query_graph(&upstream, "basearch=x86_64&stream=stable").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_invalid_query() {
+        let upstream = mock_upstream(StatusCode::OK, String::new(), Duration::from_secs(0));
+        let (status, body) = query_graph(&upstream, "basearch=x86_64").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(envelope["kind"], "invalid_query");
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_malformed_query() {
+        let upstream = mock_upstream(StatusCode::OK, String::new(), Duration::from_secs(0));
+        for (query, expected) in &[
+            (
+                "basearch=x86_64&stream=stable&rollout_wariness=0.5&rollout_wariness=0.9",
+                "invalid query: Query deserialize error: duplicate field `rollout_wariness`",
+            ),
+            (
+                "basearch=x86_64&stream=stable&limit=ten",
+                "invalid query: malformed query parameter 'limit': invalid digit found in string",
+            ),
+            (
+                "basearch=x86_64&stream=stable&fresh=maybe",
+                "invalid query: malformed query parameter 'fresh': provided string was not `true` or `false`",
+            ),
+        ] {
+            let (status, body) = query_graph(&upstream, query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+            let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(envelope["kind"], "invalid_query");
+            assert_eq!(envelope["value"], *expected);
+        }
+
+        // Other extractors fail with the envelope too.
+        async fn handler(_query: web::Query<GraphQuery>) -> HttpResponse {
+            HttpResponse::Ok().finish()
+        }
+        let mut app = test::init_service(
+            App::new()
+                .app_data(query_config())
+                .route("/", web::get().to(handler)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/?offset=-1").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "invalid_query");
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_not_found() {
+        let mut settings = settings::ServiceSettings::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph))
+                .default_service(web::route().to(pe_serve_not_found)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/v2/graph").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "not_found");
+        assert_eq!(
+            envelope["value"],
+            "unknown endpoint; known endpoints: /v1/graph"
+        );
+
+        settings.not_found_hint = false;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .default_service(web::route().to(pe_serve_not_found)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["value"], "unknown endpoint");
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_root() {
+        let mut settings = settings::ServiceSettings::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/", web::get().to(pe_serve_root)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            ROOT_INFO_CACHE_CONTROL
+        );
+        let info: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "service": crate_name!(),
+                "version": crate_version!(),
+                "endpoints": ["/v1/graph"],
+            })
+        );
+
+        settings.root_info = false;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/", web::get().to(pe_serve_root)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_debug_validate() {
+        let mut app = test::init_service(App::new().service(debug_validate_resource())).await;
+        let post = |body: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/debug/validate")
+                .set_payload(body)
+                .to_request()
+        };
+
+        let body = serde_json::to_vec(&canned_graph()).unwrap();
+        let resp = test::call_service(&mut app, post(body)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "valid": true,
+                "nodes": 3,
+                "edges": 3,
+                "errors": [],
+                "deadends": ["35.2.0"],
+            })
+        );
+
+        let resp = test::call_service(&mut app, post(b"not a graph".to_vec())).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let oversize = vec![b' '; debug::MAX_VALIDATE_BODY_SIZE + 1];
+        let resp = test::call_service(&mut app, post(oversize)).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_streams() {
+        let mut settings = settings::ServiceSettings::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/streams", web::get().to(pe_serve_streams)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/v1/streams").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let scope = |basearch: &str, stream: &str| graph::GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        settings.scope_allowlist.scopes = Some(
+            vec![
+                scope("x86_64", "stable"),
+                scope("aarch64", "stable"),
+                scope("x86_64", "next"),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/streams", web::get().to(pe_serve_streams)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/v1/streams").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=300"
+        );
+        let etag = resp.headers().get(header::ETAG).unwrap().clone();
+        let listing: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            listing,
+            serde_json::json!({
+                "streams": {
+                    "next": ["x86_64"],
+                    "stable": ["aarch64", "x86_64"],
+                }
+            })
+        );
+
+        // Revalidation with the current ETag.
+        let req = test::TestRequest::get()
+            .uri("/v1/streams")
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), &etag);
+        let req = test::TestRequest::get()
+            .uri("/v1/streams")
+            .header(header::IF_NONE_MATCH, "\"stale\"")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_streams_graph() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let scope = |basearch: &str, stream: &str| graph::GraphScope {
+            basearch: basearch.to_string(),
+            stream: stream.to_string(),
+        };
+        settings.scope_allowlist.scopes = Some(
+            vec![
+                scope("x86_64", "stable"),
+                scope("aarch64", "stable"),
+                scope("x86_64", "next"),
+                scope("aarch64", "testing"),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/streams/graph", web::get().to(pe_serve_streams_graph)),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let resp = test::call_service(
+            &mut app,
+            get("/v1/streams/graph?basearch=x86_64&rollout_wariness=0.25"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        let streams: Vec<&String> = body.as_object().unwrap().keys().collect();
+        assert_eq!(streams, vec!["next", "stable"]);
+        for stream in &streams {
+            let graph: graph::Graph =
+                serde_json::from_value(body[stream.as_str()].clone()).unwrap();
+            assert_eq!(graph.nodes.len(), 3);
+            assert_eq!(graph.edges, vec![(0, 1), (0, 2)]);
+        }
+
+        for invalid in &[
+            "/v1/streams/graph",
+            "/v1/streams/graph?basearch=s390x",
+            "/v1/streams/graph?basearch=x86_64&stream=stable",
+            "/v1/streams/graph?basearch=x86_64&limit=1",
+        ] {
+            let resp = test::call_service(&mut app, get(invalid)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", invalid);
+        }
+
+        settings.max_combined_graph_bytes = 64;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/streams/graph", web::get().to(pe_serve_streams_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, get("/v1/streams/graph?basearch=aarch64")).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Combined requests go through the same admission as single-stream
+        // ones, and their tiered responses vary with the client token.
+        settings.max_combined_graph_bytes =
+            settings::ServiceSettings::default().max_combined_graph_bytes;
+        settings.max_graph_requests = 1;
+        settings.require_user_agent = true;
+        let token_file = tempfile::NamedTempFile::new().unwrap();
+        settings.tiers = Some(settings::TiersSettings {
+            token_path: token_file.path().to_path_buf(),
+            anonymous_streams: None,
+        });
+        let state = AppState::new(&settings).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state.clone())
+                .route("/v1/streams/graph", web::get().to(pe_serve_streams_graph)),
+        )
+        .await;
+        let with_user_agent = || {
+            test::TestRequest::get()
+                .uri("/v1/streams/graph?basearch=x86_64")
+                .header(header::USER_AGENT, "test")
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, get("/v1/streams/graph?basearch=x86_64")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let incoming = metrics::V1_GRAPH_INCOMING_REQS.get();
+        let resp = test::call_service(&mut app, with_user_agent()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(metrics::V1_GRAPH_INCOMING_REQS.get() > incoming);
+        let vary: Vec<_> = resp.headers().get_all(header::VARY).collect();
+        assert!(vary.iter().any(|value| *value == "Authorization"));
+        let slot = state.graph_limiter.admit("stable").await;
+        assert!(slot.is_some());
+        let resp = test::call_service(&mut app, with_user_agent()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(slot);
+        settings.tiers = None;
+        settings.require_user_agent = false;
+
+        // Without an allowlist, there are no configured streams to combine.
+        settings.scope_allowlist.scopes = None;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/streams/graph", web::get().to(pe_serve_streams_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, get("/v1/streams/graph?basearch=x86_64")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_graph_query_arch_alias() {
+        let query = web::Query::<GraphQuery>::from_query("basearch=x86_64&stream=stable").unwrap();
+        assert_eq!(query.basearch.as_deref(), Some("x86_64"));
+
+        let alias = web::Query::<GraphQuery>::from_query("arch=aarch64&stream=stable").unwrap();
+        assert_eq!(alias.basearch.as_deref(), Some("aarch64"));
+
+        let conflict = web::Query::<GraphQuery>::from_query("basearch=x86_64&arch=aarch64");
+        assert!(conflict.is_err());
+    }
+
+    #[test]
+    fn test_fair_queue_stream() {
+        assert_eq!(fair_queue_stream(&None, "bogus"), "bogus");
+        let allowed = Some(
+            vec![graph::GraphScope {
+                basearch: "x86_64".to_string(),
+                stream: "stable".to_string(),
+            }]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(fair_queue_stream(&allowed, "stable"), "stable");
+        assert_eq!(fair_queue_stream(&allowed, "bogus"), scopes::OVERFLOW_LABEL);
+        assert_eq!(fair_queue_stream(&allowed, ""), scopes::OVERFLOW_LABEL);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_graph_overloaded() {
+        let settings = settings::ServiceSettings {
+            max_graph_requests: 1,
+            ..Default::default()
+        };
+        let state = AppState::new(&settings).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(state.clone())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+
+        // The only slot is taken by an in-flight request.
+        let slot = state.graph_limiter.admit("stable").await;
+        assert!(slot.is_some());
+        let req = test::TestRequest::get()
+            .uri("/v1/graph?basearch=x86_64&stream=stable")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "overloaded");
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_query_max_length() {
+        let settings = settings::ServiceSettings {
+            max_query_length: 64,
+            ..Default::default()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let request = |uuid_len: usize| {
+            let query = format!("stream=stable&node_uuid={}", "a".repeat(uuid_len));
+            test::TestRequest::get()
+                .uri(&format!("/v1/graph?{}", query))
+                .to_request()
+        };
+
+        // At the limit, the query is accepted (and found invalid).
+        let resp = test::call_service(&mut app, request(64 - 24)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let before = metrics::V1_GRAPH_OVERSIZE_QUERIES.get();
+        let resp = test::call_service(&mut app, request(64 - 23)).await;
+        assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "query_too_long");
+        assert_eq!(metrics::V1_GRAPH_OVERSIZE_QUERIES.get(), before + 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_query_unknown_params() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let request = || {
+            test::TestRequest::get()
+                .uri("/v1/graph?basearch=x86_64&stream=stable&chanel=stable&foo=%20")
+                .to_request()
+        };
+
+        // Lenient (default): ignored, but counted.
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let before = metrics::V1_GRAPH_UNKNOWN_PARAMS.get();
+        let resp = test::call_service(&mut app, request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(metrics::V1_GRAPH_UNKNOWN_PARAMS.get() >= before + 2);
+
+        // Strict: rejected, while known parameters and aliases are accepted.
+        settings.unknown_query_params = config::UnknownQueryParams::Strict;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "invalid_query");
+        let req = test::TestRequest::get()
+            .uri("/v1/graph?arch=x86_64&stream=stable&rollout_wariness=0.5&node_uuid=abc")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_query_node_uuid_validation() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let request = |uuid: &str| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "/v1/graph?basearch=x86_64&stream=stable&node_uuid={}",
+                    uuid
+                ))
+                .to_request()
+        };
+        let valid = "e5a6d0a8-d665-4ccb-b6c2-e6f2cb7b0d1e";
+
+        // Lenient (default): accepted, but counted.
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let before = metrics::V1_GRAPH_MALFORMED_NODE_UUIDS.get();
+        let resp = test::call_service(&mut app, request("not-a-uuid")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(metrics::V1_GRAPH_MALFORMED_NODE_UUIDS.get() > before);
+
+        // Strict: rejected.
+        settings.node_uuid_validation = config::NodeUuidValidation::Strict;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, request("not-a-uuid")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(envelope["kind"], "invalid_query");
+        for uuid in &[valid, "e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e", ""] {
+            let resp = test::call_service(&mut app, request(uuid)).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uuid);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_up_to_date_response() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let request = |current_version: &str| {
+            let uri = format!(
+                "/v1/graph?basearch=x86_64&stream=stable&rollout_wariness=0&current_version={}",
+                current_version
+            );
+            test::TestRequest::get().uri(&uri).to_request()
+        };
+        let graph_of = |body: &[u8]| serde_json::from_slice::<graph::Graph>(body).unwrap();
+
+        // Default: the full graph is served either way.
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, request("35.3.0")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let full = graph_of(&test::read_body(resp).await);
+        assert_eq!(full.nodes.len(), 3);
+
+        for response in &[
+            config::UpToDateResponse::NoContent,
+            config::UpToDateResponse::Minimal,
+        ] {
+            settings.up_to_date_response = *response;
+            let mut app = test::init_service(
+                App::new()
+                    .data(AppState::new(&settings).unwrap())
+                    .route("/v1/graph", web::get().to(pe_serve_graph)),
+            )
+            .await;
+
+            // An update is available: the full graph is served.
+            let resp = test::call_service(&mut app, request("35.1.0")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let graph = graph_of(&test::read_body(resp).await);
+            assert_eq!(graph.nodes.len(), full.nodes.len());
+            assert_eq!(graph.edges, full.edges);
+
+            // Up to date: a lightweight response.
+            let resp = test::call_service(&mut app, request("35.3.0")).await;
+            match response {
+                config::UpToDateResponse::NoContent => {
+                    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+                    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+                    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+                    assert!(test::read_body(resp).await.is_empty());
+
+                    // The answer can be revalidated, and differs from graphs.
+                    let mut revalidate = request("35.3.0");
+                    revalidate
+                        .headers_mut()
+                        .insert(header::IF_NONE_MATCH, etag.clone());
+                    let resp = test::call_service(&mut app, revalidate).await;
+                    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+                    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+                    let mut outdated = request("35.1.0");
+                    outdated.headers_mut().insert(header::IF_NONE_MATCH, etag);
+                    let resp = test::call_service(&mut app, outdated).await;
+                    assert_eq!(resp.status(), StatusCode::OK);
+                }
+                _ => {
+                    assert_eq!(resp.status(), StatusCode::OK);
+                    let graph = graph_of(&test::read_body(resp).await);
+                    assert_eq!(graph.nodes.len(), 1);
+                    assert_eq!(graph.nodes[0].version, "35.3.0");
+                    assert!(graph.edges.is_empty());
+                }
+            }
+
+            // Unknown versions get the full graph.
+            let resp = test::call_service(&mut app, request("34.1.0")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let graph = graph_of(&test::read_body(resp).await);
+            assert_eq!(graph.nodes.len(), full.nodes.len());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_default_basearch() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let request = |query: &str| {
+            let uri = format!("/v1/graph?stream=stable{}", query);
+            test::TestRequest::get().uri(&uri).to_http_request()
+        };
+
+        // The parameter is required by default.
+        let state = web::Data::new(AppState::new(&settings).unwrap());
+        let query = parse_graph_query(&request(""), &state).unwrap();
+        assert_eq!(query.basearch, None);
+        let (status, _) = query_graph(&upstream, "stream=stable").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        settings.default_basearch = Some("aarch64".to_string());
+        let state = web::Data::new(AppState::new(&settings).unwrap());
+        let query = parse_graph_query(&request(""), &state).unwrap();
+        assert_eq!(query.basearch.as_deref(), Some("aarch64"));
+        let processed = pipeline::pe_process_graph(&state, &query).await.unwrap();
+        assert_eq!(processed.scope.basearch, "aarch64");
+
+        // Explicit values take precedence, even empty ones.
+        let query = parse_graph_query(&request("&basearch=x86_64"), &state).unwrap();
+        assert_eq!(query.basearch.as_deref(), Some("x86_64"));
+        let query = parse_graph_query(&request("&basearch="), &state).unwrap();
+        assert_eq!(query.basearch.as_deref(), Some(""));
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_deprecated_stream() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.deprecated_streams.insert("testing".to_string());
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let request = |stream: &str| {
+            let uri = format!("/v1/graph?basearch=x86_64&stream={}", stream);
+            test::TestRequest::get().uri(&uri).to_request()
+        };
+        let deprecated = || {
+            metrics::V1_GRAPH_DEPRECATED_STREAM_REQS
+                .with_label_values(&["testing"])
+                .get()
+        };
+
+        let before = deprecated();
+        let resp = test::call_service(&mut app, request("testing")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::WARNING).unwrap(),
+            "299 - \"stream 'testing' is deprecated\""
+        );
+        assert_eq!(deprecated(), before + 1);
+
+        let resp = test::call_service(&mut app, request("stable")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::WARNING).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_require_user_agent() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let request = |user_agent: Option<&str>| {
+            let req = test::TestRequest::get().uri("/v1/graph?basearch=x86_64&stream=stable");
+            match user_agent {
+                Some(value) => req.header(header::USER_AGENT, value).to_request(),
+                None => req.to_request(),
+            }
+        };
+
+        // Not required by default.
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, request(None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        settings.require_user_agent = true;
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        let before = metrics::V1_GRAPH_MISSING_USER_AGENT.get();
+        for user_agent in &[None, Some(""), Some("  ")] {
+            let resp = test::call_service(&mut app, request(*user_agent)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(metrics::V1_GRAPH_MISSING_USER_AGENT.get(), before + 3);
+        let resp = test::call_service(&mut app, request(Some("zincati/0.0.24"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_query_duplicate_params() {
+        let settings = settings::ServiceSettings::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(AppState::new(&settings).unwrap())
+                .route("/v1/graph", web::get().to(pe_serve_graph)),
+        )
+        .await;
+        for query in &[
+            "basearch=x86_64&stream=stable&stream=testing",
+            "basearch=x86_64&basearch=aarch64&stream=stable",
+            "basearch=x86_64&stream=stable&rollout_wariness=0.1&rollout_wariness=0.9",
+            "basearch=x86_64&stream=stable&stream=stable",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/v1/graph?{}", query))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "query: {}", query);
+            let body = test::read_body(resp).await;
+            assert!(String::from_utf8_lossy(&body).contains("duplicate field"));
         }
     }
 }
@@ -0,0 +1,314 @@
+//! Prometheus metrics of the policy-engine.
+
+use prometheus::{Gauge, GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+
+lazy_static::lazy_static! {
+    pub(crate) static ref V1_GRAPH_INCOMING_REQS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_incoming_requests_total",
+        "Total number of incoming HTTP client request to /v1/graph"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_INCOMING_HEAD_REQS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_incoming_head_requests_total",
+        "Total number of incoming HTTP client HEAD request to /v1/graph"
+    ))
+    .unwrap();
+    pub(crate) static ref UNIQUE_IDS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_unique_uuids_total",
+        "Total number of unique node UUIDs (per-instance Bloom filter)."
+    ))
+    .unwrap();
+    pub(crate) static ref FLEET_UNIQUE_IDS: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_v1_graph_fleet_unique_uuids",
+        "Estimated number of unique node UUIDs across all replicas (shared HyperLogLog)."
+    ))
+    .unwrap();
+    pub(crate) static ref UNIQUE_IDS_BLOOM_BYTES: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_unique_uuids_bloom_filter_bytes",
+        "Memory allocated to the unique node UUIDs Bloom filter, in bytes"
+    ))
+    .unwrap();
+    pub(crate) static ref UNIQUE_IDS_BLOOM_FILL: Gauge = register_gauge!(opts!(
+        "fcos_cincinnati_pe_unique_uuids_bloom_filter_fill_ratio",
+        "Unique node UUIDs recorded in the Bloom filter, as a fraction of its expected maximum population (unreliable counts above 1)"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_OVERSIZE_QUERIES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_oversize_queries_total",
+        "Total number of requests to /v1/graph rejected for an oversize query string"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_UNKNOWN_PARAMS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_unknown_query_params_total",
+        "Total number of unknown query parameters in requests to /v1/graph"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_MALFORMED_NODE_UUIDS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_malformed_node_uuids_total",
+        "Total number of requests to /v1/graph with a node_uuid which is not a well-formed UUID"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_TIER_REQS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_tier_requests_total",
+        "Total number of graph requests with tiered graphs, by client tier",
+        &["tier"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_UP_TO_DATE: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_up_to_date_responses_total",
+        "Total number of lightweight responses to /v1/graph for up-to-date clients",
+        &["response"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_DEPRECATED_STREAM_REQS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_deprecated_stream_requests_total",
+        "Total number of requests to /v1/graph for deprecated streams",
+        &["stream"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_STREAM_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_stream_fallbacks_total",
+        "Total number of graphs served from a fallback stream, for lack of a graph",
+        &["stream", "fallback"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_MISSING_USER_AGENT: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_missing_user_agent_total",
+        "Total number of requests to /v1/graph rejected for lacking a User-Agent"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_INVALID_WARINESS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_invalid_rollout_wariness_total",
+        "Total number of requests to /v1/graph with an unparsable rollout_wariness"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_CONFLICTING_WARINESS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_conflicting_wariness_total",
+        "Total number of requests to /v1/graph with both rollout_wariness and node_uuid"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_SCOPE_REQS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_scope_requests_total",
+        "Total number of valid requests to /v1/graph, per (bounded) scope",
+        &["stream", "basearch"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_SCOPE_VALIDITY: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_scope_validity_total",
+        "Total number of requests to /v1/graph, per stream and basearch validity",
+        &["stream", "basearch"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_CURRENT_VERSIONS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_current_versions_total",
+        "Total number of requests to /v1/graph per client current version (bounded)",
+        &["current_version"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_DISTINCT_SCOPES: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_v1_graph_distinct_scopes",
+        "Number of distinct scopes requested since start (bounded)"
+    ))
+    .unwrap();
+    pub(crate) static ref SERVICE_WORKERS: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_service_workers",
+        "Number of main service workers"
+    ))
+    .unwrap();
+    pub(crate) static ref SERVICE_BUSY_WORKERS: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_service_busy_workers",
+        "Number of main service workers with at least one in-flight request"
+    ))
+    .unwrap();
+    pub(crate) static ref SERVICE_IN_FLIGHT: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_service_in_flight_requests",
+        "Number of in-flight requests to the main service"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_responses_total",
+        "Total number of responses to /v1/graph, per status class",
+        &["status_class"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "fcos_cincinnati_pe_v1_graph_request_duration_seconds",
+        "Duration of /v1/graph requests handling, in seconds, per status class",
+        &["status_class"],
+        prometheus::exponential_buckets(0.00025, 2.0, 16).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_NOT_MODIFIED: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_not_modified_total",
+        "Total number of 304 Not Modified responses to /v1/graph"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_IN_FLIGHT: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_v1_graph_in_flight_requests",
+        "Number of in-flight requests to /v1/graph"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_QUEUED: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_pe_v1_graph_queued_requests",
+        "Number of requests to /v1/graph waiting in the fair queue, per stream",
+        &["stream"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_OVERLOAD_REJECTIONS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_overload_rejections_total",
+        "Total number of requests to /v1/graph rejected over the concurrency limit"
+    ))
+    .unwrap();
+    pub(crate) static ref SCOPE_DISCOVERY_REFRESHES: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_scope_discovery_refreshes_total",
+        "Total number of refreshes of discovered allowed scopes",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_upstream_requests_total",
+        "Total number of requests to upstream endpoints",
+        &["upstream", "result"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_pe_upstream_in_flight_requests",
+        "Number of in-flight requests to upstream endpoints",
+        &["upstream"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_QUEUED: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_pe_upstream_queued_requests",
+        "Number of requests waiting for a slot towards upstream endpoints",
+        &["upstream"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_LAST_SUCCESS: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_pe_last_successful_fetch_timestamp",
+        "UTC timestamp of the last successful upstream graph fetch, per stream",
+        &["stream"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_GRAPH_SHRINKS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_upstream_graph_shrinks_total",
+        "Total number of fetched upstream graphs with unexpectedly fewer nodes than cached ones",
+        &["stream"]
+    )
+    .unwrap();
+    pub(crate) static ref ROLLOUT_PROGRESS: GaugeVec = register_gauge_vec!(
+        "fcos_cincinnati_pe_rollout_progress",
+        "Progress of releases being rolled out, as of the last upstream graph fetch",
+        &["stream", "version"]
+    )
+    .unwrap();
+    pub(crate) static ref UPSTREAM_FETCHES_SUPPRESSED: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_upstream_fetches_suppressed_total",
+        "Total number of upstream graph fetches suppressed by the minimum fetch interval"
+    ))
+    .unwrap();
+    pub(crate) static ref CACHE_STALE_WHILE_REVALIDATE: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_cache_stale_while_revalidate_total",
+        "Total number of graphs served stale while refreshed in the background"
+    ))
+    .unwrap();
+    pub(crate) static ref UPSTREAM_RETRIES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_upstream_retries_total",
+        "Total number of retried requests to upstream"
+    ))
+    .unwrap();
+    pub(crate) static ref UPSTREAM_FETCH_FAILURES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_upstream_fetch_failures_total",
+        "Total number of failed upstream graph fetches, after retries"
+    ))
+    .unwrap();
+    pub(crate) static ref UPSTREAM_SOFT_FAILURES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_upstream_soft_failures_total",
+        "Total number of transient upstream failures tolerated after a grace delay"
+    ))
+    .unwrap();
+    pub(crate) static ref UPSTREAM_RETRY_BUDGET_EXHAUSTED: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_upstream_retry_budget_exhausted_total",
+        "Total number of upstream retries suppressed by the retry budget"
+    ))
+    .unwrap();
+    pub(crate) static ref GRAPH_OVERCONNECTED_NODES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_graph_overconnected_nodes_total",
+        "Total number of upstream nodes trimmed for exceeding the outgoing edges limit"
+    ))
+    .unwrap();
+    pub(crate) static ref ADMIN_CACHE_FLUSHES: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_admin_cache_flushes_total",
+        "Total number of cache flushes requested via the admin endpoint"
+    ))
+    .unwrap();
+    pub(crate) static ref SHARED_CACHE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_shared_cache_requests_total",
+        "Total number of operations on the shared graph cache",
+        &["op", "result"]
+    )
+    .unwrap();
+    pub(crate) static ref CACHE_OLDEST_ENTRY_AGE: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_cache_oldest_entry_age_seconds",
+        "Age of the oldest cached upstream graph, in seconds"
+    ))
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_CACHE_BYPASS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_pe_v1_graph_cache_bypass_total",
+        "Total number of requests to /v1/graph bypassing caches for a fresh graph"
+    ))
+    .unwrap();
+    pub(crate) static ref CACHE_GRAPHS_BYTES: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_cache_graphs_bytes",
+        "Estimated memory used by cached upstream graphs (sum of serialized sizes), in bytes"
+    ))
+    .unwrap();
+    pub(crate) static ref CACHE_REAPED_ENTRIES: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_cache_reaped_entries_total",
+        "Total number of idle in-process cache entries reaped",
+        &["cache"]
+    )
+    .unwrap();
+    pub(crate) static ref THROTTLED_FRACTION: HistogramVec = register_histogram_vec!(
+        "fcos_cincinnati_pe_v1_graph_throttled_nodes_fraction",
+        "Per-request fraction of graph nodes hidden by rollout throttling",
+        &["stream"],
+        prometheus::linear_buckets(0.0, 0.1, 11).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref SHADOW_POLICY_COMPARISONS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_shadow_policy_comparisons_total",
+        "Total number of graph responses compared to the shadow policies outcome",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref POLICY_EVALUATION_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_v1_graph_policy_evaluation_timeouts_total",
+        "Total number of graph requests aborted for exceeding the policy evaluation timeout, by last evaluated policy",
+        &["policy"]
+    )
+    .unwrap();
+    pub(crate) static ref WEBHOOK_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_pe_webhook_requests_total",
+        "Total number of requests to the graph post-processing webhook",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref V1_GRAPH_SERIALIZATION_DURATION: HistogramVec = register_histogram_vec!(
+        "fcos_cincinnati_pe_v1_graph_serialization_duration_seconds",
+        "Duration of graph responses JSON serialization, in seconds",
+        &["outcome"],
+        prometheus::exponential_buckets(0.0001, 2.0, 15).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref ROLLOUT_WINDOW_OPEN: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_pe_rollout_window_open",
+        "Whether rollouts are currently allowed to advance (1) or held (0)"
+    ))
+    .unwrap();
+    // NOTE(lucab): alternatively this could come from the runtime library, see
+    // https://prometheus.io/docs/instrumenting/writing_clientlibs/#process-metrics
+    pub(crate) static ref PROCESS_START_TIME: IntGauge = register_int_gauge!(opts!(
+        "process_start_time_seconds",
+        "Start time of the process since unix epoch in seconds."
+    )).unwrap();
+}
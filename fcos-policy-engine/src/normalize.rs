@@ -0,0 +1,73 @@
+//! Normalization of request paths, before routing.
+//!
+//! Clients sometimes add a trailing slash to endpoints (e.g. `/v1/graph/`),
+//! which would otherwise be routed as a distinct, unknown path. A single
+//! trailing slash is stripped, so both forms reach the same route.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Uri;
+
+/// Strip a trailing slash from the request path, except for the root path.
+pub(crate) fn strip_trailing_slash(req: &mut ServiceRequest) {
+    let head = req.head_mut();
+    let path = head.uri.path();
+    if path.len() <= 1 || !path.ends_with('/') {
+        return;
+    }
+
+    let path = &path[..path.len() - 1];
+    let path_and_query = match head.uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = head.uri.clone().into_parts();
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return,
+    };
+    if let Ok(uri) = Uri::from_parts(parts) {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    async fn echo_query(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(req.query_string().to_string())
+    }
+
+    #[actix_rt::test]
+    async fn test_strip_trailing_slash() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(|mut req, srv| {
+                    strip_trailing_slash(&mut req);
+                    srv.call(req)
+                })
+                .route("/v1/graph", web::get().to(echo_query))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for uri in &["/v1/graph?stream=stable", "/v1/graph/?stream=stable"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(test::read_body(resp).await, "stream=stable");
+        }
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Only a single trailing slash is stripped.
+        let req = test::TestRequest::get().uri("/v1/graph//").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,129 @@
+//! Optional pagination of graph responses.
+//!
+//! A page holds a slice of the graph nodes, in graph order, and the edges
+//! among them. Edges of a page reference nodes by their index within the
+//! page, so each page is a valid graph on its own. Edges to or from nodes
+//! outside of a page are not returned: clients computing update paths must
+//! fetch the whole graph, pagination is only meant for tooling.
+
+use commons::graph::Graph;
+use serde::Serialize;
+
+/// Pagination details of a graph page.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PageInfo {
+    /// Graph index of the first node of this page.
+    pub(crate) offset: u64,
+    /// Number of nodes in this page.
+    pub(crate) count: u64,
+    /// Total number of nodes in the graph.
+    pub(crate) total: u64,
+    /// Offset of the next page, if any.
+    pub(crate) next: Option<u64>,
+}
+
+/// Extract the page of a graph starting at `offset`, with at most `limit`
+/// nodes (all remaining nodes if unset).
+pub(crate) fn paginate(graph: Graph, offset: u64, limit: Option<u64>) -> (Graph, PageInfo) {
+    let total = graph.nodes.len() as u64;
+    let start = offset.min(total);
+    let end = match limit {
+        Some(limit) => start.saturating_add(limit).min(total),
+        None => total,
+    };
+
+    let edges = graph
+        .edges
+        .into_iter()
+        .filter(|(from, to)| (start..end).contains(from) && (start..end).contains(to))
+        .map(|(from, to)| (from - start, to - start))
+        .collect();
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .skip(start as usize)
+        .take((end - start) as usize)
+        .collect();
+
+    let info = PageInfo {
+        offset: start,
+        count: end - start,
+        total,
+        next: if end < total { Some(end) } else { None },
+    };
+    (Graph { nodes, edges }, info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commons::graph::CincinnatiPayload;
+
+    fn graph(size: u64) -> Graph {
+        let nodes = (0..size)
+            .map(|i| CincinnatiPayload {
+                version: format!("35.{}.0", i),
+                metadata: Default::default(),
+                payload: String::new(),
+            })
+            .collect();
+        let edges = (0..size)
+            .flat_map(|i| (i + 1..size).map(move |j| (i, j)))
+            .collect();
+        Graph { nodes, edges }
+    }
+
+    fn versions(graph: &Graph) -> Vec<&str> {
+        graph.nodes.iter().map(|n| n.version.as_str()).collect()
+    }
+
+    #[test]
+    fn test_paginate() {
+        let (page, info) = paginate(graph(5), 1, Some(2));
+        assert_eq!(versions(&page), vec!["35.1.0", "35.2.0"]);
+        assert_eq!(page.edges, vec![(0, 1)]);
+        assert_eq!(
+            info,
+            PageInfo {
+                offset: 1,
+                count: 2,
+                total: 5,
+                next: Some(3),
+            }
+        );
+
+        // Last page, exactly at the end of the graph.
+        let (page, info) = paginate(graph(5), 3, Some(2));
+        assert_eq!(versions(&page), vec!["35.3.0", "35.4.0"]);
+        assert_eq!(info.next, None);
+
+        // Limit beyond the end of the graph.
+        let (page, info) = paginate(graph(5), 4, Some(10));
+        assert_eq!(versions(&page), vec!["35.4.0"]);
+        assert!(page.edges.is_empty());
+        assert_eq!(info.next, None);
+
+        // No limit: all remaining nodes.
+        let (page, info) = paginate(graph(5), 0, None);
+        assert_eq!(page.nodes.len(), 5);
+        assert_eq!(page.edges.len(), 10);
+        assert_eq!(info.count, 5);
+        assert_eq!(info.next, None);
+
+        // Offset at and past the end of the graph.
+        for offset in &[5, 6, u64::MAX] {
+            let (page, info) = paginate(graph(5), *offset, Some(u64::MAX));
+            assert!(page.nodes.is_empty());
+            assert!(page.edges.is_empty());
+            assert_eq!(info.offset, 5);
+            assert_eq!(info.count, 0);
+            assert_eq!(info.next, None);
+        }
+
+        // Empty graph.
+        let (page, info) = paginate(graph(0), 0, Some(1));
+        assert!(page.nodes.is_empty());
+        assert_eq!(info.total, 0);
+        assert_eq!(info.next, None);
+    }
+}
@@ -0,0 +1,1684 @@
+//! Graph request pipeline: getting the upstream graph of a scope (pinned,
+//! cached or fetched), applying policies to it, and building the response.
+
+use crate::wariness::{compute_wariness, windowed_wariness, WarinessSource};
+use crate::{
+    allowlist, cache, compression, config, debug, etag, format, metrics, pagination,
+    pe_admit_graph_request, processing_deadline, scopes, settings, shadow, tiers, utils, AppState,
+    GraphQuery, GENERATED_HEADER, SIGNATURE_HEADER, WARINESS_HEADER,
+};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use commons::errors::PeError;
+use commons::{graph, policy};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Declare the request headers graph responses vary with.
+fn pe_vary_headers(data: &AppState, resp: &mut actix_web::dev::HttpResponseBuilder) {
+    resp.header(header::VARY, "Accept");
+    if data.zstd.is_some() {
+        resp.header(header::VARY, "Accept-Encoding");
+    }
+    if data.tiers.is_some() {
+        resp.header(header::VARY, "Authorization");
+    }
+}
+
+/// Set the headers shared by all graph responses, with or without body.
+fn pe_graph_headers(
+    data: &AppState,
+    processed: &ProcessedGraph,
+    etag: &str,
+    deprecation: Option<&str>,
+    resp: &mut actix_web::dev::HttpResponseBuilder,
+) {
+    resp.header(header::ETAG, etag);
+    pe_vary_headers(data, resp);
+    if let Some(status) = processed.cache_status.filter(|_| data.cache_status_header) {
+        resp.header("X-Cache", status.as_str());
+    }
+    if let Some(generated) = processed.generated {
+        resp.header(GENERATED_HEADER, generated.to_rfc3339());
+    }
+    if data.wariness_header {
+        resp.header(WARINESS_HEADER, processed.wariness.to_string());
+    }
+    if let Some(warning) = deprecation {
+        resp.header(header::WARNING, warning);
+    }
+}
+
+/// Build the response to a graph request.
+pub(crate) async fn pe_graph_response(
+    req: &HttpRequest,
+    data: &web::Data<AppState>,
+) -> Result<HttpResponse, PeError> {
+    let request_start = std::time::Instant::now();
+    let (query, _slot) = pe_admit_graph_request(req, data).await?;
+
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    let format = format::negotiate(query.format.as_deref(), accept)?;
+    if format == format::GraphFormat::Legacy && (query.offset.is_some() || query.limit.is_some()) {
+        return Err(PeError::InvalidQuery(
+            "pagination is not supported with the legacy format".to_string(),
+        ));
+    }
+
+    let _probe = data.readiness.admit().map_err(PeError::WarmingUp)?;
+    let deadline = processing_deadline(req, data.max_processing)?;
+    let processing_start = std::time::Instant::now();
+    let processed = actix_rt::time::timeout(deadline, pe_process_graph(data, &query)).await;
+    let processed = match processed {
+        Ok(res) => res?,
+        Err(_) => {
+            log::warn!("graph request exceeded processing deadline");
+            return Err(PeError::ProcessingTimeout(deadline.as_millis() as u64));
+        }
+    };
+    let processing_duration = processing_start.elapsed();
+    let mut processed = pe_apply_tier(data, req, processed);
+    if processed.cache_status.is_some() {
+        let allowed = data.scope_allowlist.current();
+        data.readiness
+            .mark_ready(&processed.scope, allowed.as_ref().as_ref());
+    }
+    if let Some(delay) = data.response_delay {
+        actix_rt::time::delay_for(delay).await;
+    }
+    let deprecation = deprecation_warning(data, &processed.scope);
+
+    let up_to_date = match (data.up_to_date_response, query.current_version.as_deref()) {
+        (config::UpToDateResponse::Graph, _) | (_, None) => None,
+        (response, Some(version)) => {
+            let unpaginated = query.offset.is_none() && query.limit.is_none();
+            up_to_date_node(&processed.graph, version)
+                .filter(|_| format != format::GraphFormat::Legacy && unpaginated)
+                .map(|node| (response, node))
+        }
+    };
+    let graph = match up_to_date {
+        None => std::mem::take(&mut processed.graph),
+        Some((config::UpToDateResponse::NoContent, node)) => {
+            metrics::V1_GRAPH_UP_TO_DATE
+                .with_label_values(&["no-content"])
+                .inc();
+            // Without a body, the tag covers the version clients are up to.
+            let version = format!("up-to-date:{}", processed.graph.nodes[node].version);
+            let etag = etag::graph_etag(&processed.scope, processed.wariness, version.as_bytes());
+            let mut resp = if etag::if_none_match(req.headers(), &etag) {
+                HttpResponse::NotModified()
+            } else {
+                HttpResponse::NoContent()
+            };
+            pe_graph_headers(data, &processed, &etag, deprecation.as_deref(), &mut resp);
+            return Ok(resp.finish());
+        }
+        Some((_, node)) => {
+            metrics::V1_GRAPH_UP_TO_DATE
+                .with_label_values(&["minimal"])
+                .inc();
+            graph::Graph {
+                nodes: vec![processed.graph.nodes[node].clone()],
+                edges: vec![],
+            }
+        }
+    };
+    let (graph, pagination) = match (query.offset, query.limit) {
+        (None, None) => (graph, None),
+        (_, Some(0)) => {
+            return Err(PeError::InvalidQuery(
+                "page limit must be positive".to_string(),
+            ))
+        }
+        (offset, limit) => {
+            let (page, info) = pagination::paginate(graph, offset.unwrap_or(0), limit);
+            (page, Some(info))
+        }
+    };
+    let policy_metadata = if data.policy_metadata {
+        Some(PolicyMetadata {
+            applied_policies: &processed.applied_policies,
+            rollout_wariness: processed.wariness,
+            graph_generated: processed.generated.map(|generated| generated.to_rfc3339()),
+        })
+    } else {
+        None
+    };
+    let serialization_start = std::time::Instant::now();
+    let edge_rollouts = match data.policy.rollouts {
+        config::RolloutHandling::Throttle => None,
+        config::RolloutHandling::Passthrough => {
+            Some(format::edge_rollouts(&graph, data.clock.now().timestamp()))
+        }
+    };
+    let json = match format {
+        format::GraphFormat::Graph => serde_json::to_string_pretty(&AnnotatedGraph {
+            graph: &graph,
+            edge_rollouts,
+            policy_metadata,
+            pagination,
+        }),
+        format::GraphFormat::GraphV2 => serde_json::to_string_pretty(&AnnotatedGraph {
+            graph: format::graph_v2(&graph),
+            edge_rollouts,
+            policy_metadata,
+            pagination,
+        }),
+        format::GraphFormat::Legacy => {
+            serde_json::to_string_pretty(&format::legacy_releases(&graph))
+        }
+    };
+    let outcome = if json.is_ok() { "success" } else { "failure" };
+    metrics::V1_GRAPH_SERIALIZATION_DURATION
+        .with_label_values(&[outcome])
+        .observe(serialization_start.elapsed().as_secs_f64());
+    let json = json?;
+    let timings = RequestTimings {
+        total: request_start.elapsed(),
+        upstream: processed.upstream_duration,
+        policy: processing_duration
+            .checked_sub(processed.upstream_duration)
+            .unwrap_or_default(),
+        serialize: serialization_start.elapsed(),
+    };
+    if let Some(summary) = slow_request_summary(data.slow_request, &query, &timings) {
+        log::warn!("{}", summary);
+    }
+    let zstd = data.zstd.as_ref().filter(|_| {
+        let accepted = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        compression::prefers_zstd(accepted)
+    });
+    let etag = etag::graph_etag(&processed.scope, processed.wariness, json.as_bytes());
+    let etag = match zstd {
+        Some(_) => etag::encoded_etag(&etag, "zstd"),
+        None => pe_middleware_etag(data, etag),
+    };
+    let not_modified = etag::if_none_match(req.headers(), &etag);
+    let mut resp = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    pe_graph_headers(data, &processed, &etag, deprecation.as_deref(), &mut resp);
+    if not_modified {
+        return Ok(resp.finish());
+    }
+    // HEAD responses carry no body.
+    let scope = &processed.scope;
+    let observe_size = |compressed: bool, size: usize| {
+        if req.method() != actix_web::http::Method::HEAD {
+            let (stream_label, _) = data.scopes.observe(scope);
+            data.response_sizes
+                .with_label_values(&[&stream_label, if compressed { "true" } else { "false" }])
+                .observe(size as f64);
+        }
+    };
+    resp.content_type(match format {
+        format::GraphFormat::GraphV2 => format::GRAPH_V2_CONTENT_TYPE,
+        _ => "application/json",
+    });
+    if let Some(signer) = &data.signer {
+        resp.header(SIGNATURE_HEADER, signer.sign(json.as_bytes()));
+    }
+    if let Some(encoder) = zstd {
+        let encoded = encoder.encode(json.as_bytes())?;
+        observe_size(true, encoded.len());
+        resp.header(header::CONTENT_ENCODING, "zstd");
+        return Ok(resp.body(encoded.as_ref().clone()));
+    }
+    observe_size(false, json.len());
+    Ok(resp.body(json))
+}
+
+/// Entity tag of a response left to the `Compress` middleware, which may
+/// encode it after the fact: with compression enabled, the tag is weak, as
+/// it then covers several content-codings.
+pub(crate) fn pe_middleware_etag(data: &AppState, etag: String) -> String {
+    if data.compress_responses {
+        etag::weak_etag(&etag)
+    } else {
+        etag
+    }
+}
+
+/// `Warning` header value for graph requests of a deprecated stream, if the
+/// requested stream is deprecated, recording the request.
+fn deprecation_warning(data: &AppState, scope: &graph::GraphScope) -> Option<String> {
+    if !data.deprecated_streams.contains(&scope.stream) {
+        return None;
+    }
+    metrics::V1_GRAPH_DEPRECATED_STREAM_REQS
+        .with_label_values(&[&scope.stream])
+        .inc();
+    if data.log_deprecated_streams {
+        info!(
+            "graph request for deprecated stream '{}' (basearch '{}')",
+            scope.stream, scope.basearch
+        );
+    }
+    Some(format!("299 - \"stream '{}' is deprecated\"", scope.stream))
+}
+
+/// Index of the node for a version without outgoing update paths in a graph,
+/// i.e. the node of an up-to-date client.
+fn up_to_date_node(graph: &graph::Graph, version: &str) -> Option<usize> {
+    let index = graph
+        .nodes
+        .iter()
+        .position(|node| node.version == version)?;
+    if graph.edges.iter().any(|(from, _)| *from == index as u64) {
+        return None;
+    }
+    Some(index)
+}
+
+/// Build the response to a combined stream graphs request.
+///
+/// The request takes a single graph request slot, and is recorded as a
+/// single graph request, whatever its number of streams.
+pub(crate) async fn pe_streams_graph_response(
+    req: &HttpRequest,
+    data: &web::Data<AppState>,
+) -> Result<HttpResponse, PeError> {
+    let allowed = data.scope_allowlist.current();
+    let scopes = match allowed.as_ref() {
+        Some(scopes) => scopes,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let (query, _slot) = pe_admit_graph_request(req, data).await?;
+    let unsupported = [
+        ("stream", query.stream.is_some()),
+        ("offset", query.offset.is_some()),
+        ("limit", query.limit.is_some()),
+        ("format", query.format.is_some()),
+    ];
+    if let Some((param, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(PeError::InvalidQuery(format!(
+            "'{}' is not supported on combined stream graphs",
+            param
+        )));
+    }
+    let basearch = match query.basearch.as_deref() {
+        Some(basearch) if !basearch.is_empty() => basearch,
+        _ => return Err(PeError::InvalidQuery("missing basearch".to_string())),
+    };
+    let streams: Vec<String> = allowlist::streams(scopes)
+        .into_iter()
+        .filter(|(_, basearches)| basearches.contains(basearch))
+        .map(|(stream, _)| stream)
+        .collect();
+    if streams.is_empty() {
+        return Err(PeError::InvalidQuery(format!(
+            "basearch not allowed: basearch='{}'",
+            basearch
+        )));
+    }
+
+    let _probe = data.readiness.admit().map_err(PeError::WarmingUp)?;
+    let queries: Vec<GraphQuery> = streams
+        .iter()
+        .map(|stream| GraphQuery {
+            basearch: query.basearch.clone(),
+            stream: Some(stream.clone()),
+            rollout_wariness: query.rollout_wariness.clone(),
+            node_uuid: query.node_uuid.clone(),
+            current_version: query.current_version.clone(),
+            fresh: query.fresh,
+            ..Default::default()
+        })
+        .collect();
+    let processing = futures::future::join_all(
+        queries
+            .iter()
+            .map(|stream_query| pe_process_graph(data, stream_query)),
+    );
+    let processed = match actix_rt::time::timeout(data.max_processing, processing).await {
+        Ok(processed) => processed,
+        Err(_) => {
+            log::warn!("combined stream graphs request exceeded processing deadline");
+            return Err(PeError::ProcessingTimeout(
+                data.max_processing.as_millis() as u64
+            ));
+        }
+    };
+    let mut graphs = BTreeMap::new();
+    for (stream, processed) in streams.iter().zip(processed) {
+        graphs.insert(stream.as_str(), pe_apply_tier(data, req, processed?).graph);
+    }
+
+    let json = serde_json::to_string_pretty(&graphs)?;
+    if json.len() > data.max_combined_graph_bytes {
+        log::warn!(
+            "combined stream graphs for basearch '{}' exceed maximum size ({} bytes)",
+            basearch,
+            json.len()
+        );
+        return Err(PeError::GraphTooLarge);
+    }
+    let mut resp = HttpResponse::Ok();
+    pe_vary_headers(data, &mut resp);
+    Ok(resp.content_type("application/json").body(json))
+}
+
+/// Outcome of processing a graph query.
+#[derive(Debug)]
+pub(crate) struct ProcessedGraph {
+    /// Final client graph.
+    pub(crate) graph: graph::Graph,
+    /// Scope of the graph.
+    pub(crate) scope: graph::GraphScope,
+    /// Cache status, not available for graphs not coming from upstream.
+    pub(crate) cache_status: Option<cache::CacheStatus>,
+    /// Generation time of the upstream graph, if any.
+    pub(crate) generated: Option<chrono::DateTime<chrono::Utc>>,
+    /// Names of the policies applied to the graph, in order.
+    pub(crate) applied_policies: Vec<&'static str>,
+    /// Effective rollout wariness.
+    pub(crate) wariness: f64,
+    /// Time spent getting the upstream graph.
+    pub(crate) upstream_duration: Duration,
+}
+
+/// Graph as returned to clients, with optional policy metadata, rollout
+/// details and pagination details.
+#[derive(Serialize)]
+struct AnnotatedGraph<'a, G> {
+    #[serde(flatten)]
+    graph: G,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edge_rollouts: Option<Vec<format::EdgeRollout<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy_metadata: Option<PolicyMetadata<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pagination: Option<pagination::PageInfo>,
+}
+
+/// Policies which shaped a client graph.
+#[derive(Serialize)]
+struct PolicyMetadata<'a> {
+    applied_policies: &'a [&'static str],
+    rollout_wariness: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    graph_generated: Option<String>,
+}
+
+/// Process a graph query, from upstream graph to the final client graph.
+pub(crate) async fn pe_process_graph(
+    data: &AppState,
+    query: &GraphQuery,
+) -> Result<ProcessedGraph, PeError> {
+    let allowed = data.scope_allowlist.current();
+    let (stream_validity, basearch_validity) =
+        scopes::validity_labels(&allowed, query.stream.as_deref(), query.basearch.as_deref());
+    metrics::V1_GRAPH_SCOPE_VALIDITY
+        .with_label_values(&[stream_validity, basearch_validity])
+        .inc();
+    let scope = match commons::web::validate_scope(
+        query.basearch.clone(),
+        query.stream.clone(),
+        &allowed,
+    ) {
+        Err(e) => {
+            log::error!("graph request with invalid scope: {}", e);
+            return Err(PeError::InvalidQuery(e.to_string()));
+        }
+        Ok(s) => {
+            log::trace!("graph query stream: {:#?}", s);
+            s
+        }
+    };
+    let (stream_label, basearch_label) = data.scopes.observe(&scope);
+    metrics::V1_GRAPH_SCOPE_REQS
+        .with_label_values(&[&stream_label, &basearch_label])
+        .inc();
+    metrics::V1_GRAPH_DISTINCT_SCOPES.set(data.scopes.len() as i64);
+
+    let processed = pe_apply_policies(
+        data,
+        &data.policy,
+        Some(&stream_label),
+        query,
+        scope.clone(),
+    )
+    .await;
+    if let (Some(current_version), Ok(processed)) = (&query.current_version, &processed) {
+        metrics::V1_GRAPH_CURRENT_VERSIONS
+            .with_label_values(&[current_version_label(&processed.graph, current_version)])
+            .inc();
+    }
+    if let Some(shadow) = &data.shadow {
+        pe_compare_shadow(data, shadow, query, scope, &processed).await;
+    }
+    processed
+}
+
+/// Metric label for a client current version: the version itself if it is
+/// a release of the served graph, `other` otherwise, bounding cardinality.
+fn current_version_label<'a>(graph: &graph::Graph, current_version: &'a str) -> &'a str {
+    if graph
+        .nodes
+        .iter()
+        .any(|release| release.version == current_version)
+    {
+        current_version
+    } else {
+        scopes::OVERFLOW_LABEL
+    }
+}
+
+/// Apply a policy chain to the upstream graph of a scope.
+///
+/// Served chains are observed in metrics with the given stream label.
+/// Candidate (shadow) chains, without label, neither use precomputed
+/// graphs nor the webhook.
+async fn pe_apply_policies(
+    data: &AppState,
+    policy: &settings::PolicySettings,
+    stream_label: Option<&str>,
+    query: &GraphQuery,
+    scope: graph::GraphScope,
+) -> Result<ProcessedGraph, PeError> {
+    let candidate = stream_label.is_none();
+    let (wariness, source) = compute_wariness(
+        query,
+        policy.wariness_precision,
+        &policy.wariness_salt,
+        policy.wariness_parsing,
+    )?;
+    match source {
+        _ if candidate => {}
+        WarinessSource::Requested => data.rollout_wariness.observe(wariness),
+        WarinessSource::Computed => data.computed_rollout_wariness.observe(wariness),
+    }
+    let mut budget = PolicyBudget::new(policy.evaluation_timeout);
+    let windowed = windowed_wariness(policy, wariness, data.clock.now());
+    let mut applied_policies = vec![];
+    if windowed != wariness {
+        applied_policies.push("rollout_window");
+    }
+    let wariness = windowed;
+
+    // Clients below the minimum version floor are not offered any update.
+    let floor = policy.version_floor.as_ref().filter(|floor| {
+        query
+            .current_version
+            .as_deref()
+            .map(|current| policy::below_version_floor(current, &floor.min_version))
+            .unwrap_or(false)
+    });
+    let mut cache_status = None;
+    let mut generated = None;
+    let mut upstream_duration = Duration::from_secs(0);
+    let final_graph = match floor.map(|floor| (floor.action, &floor.min_version)) {
+        Some((config::VersionFloorAction::Conflict, min_version)) => {
+            log::debug!("rejecting graph request from client below version floor");
+            return Err(PeError::UnsupportedVersion(format!(
+                "minimum supported version is {}",
+                min_version
+            )));
+        }
+        Some((config::VersionFloorAction::EmptyGraph, _)) => {
+            log::debug!("empty graph for client below version floor");
+            applied_policies.push("version_floor");
+            graph::Graph::default()
+        }
+        None => {
+            let upstream_start = std::time::Instant::now();
+            let fresh = !candidate && query.fresh == Some(true);
+            let (entry, status) = pe_get_graph_with_fallback(data, scope.clone(), fresh).await?;
+            upstream_duration = upstream_start.elapsed();
+            budget.exclude(upstream_duration);
+            cache_status = Some(status);
+            generated = Some(entry.fetched);
+            let cached_graph = if data.injected_deadends.is_empty() {
+                entry.graph
+            } else {
+                applied_policies.push("debug_deadends");
+                debug::inject_deadends(entry.graph, &data.injected_deadends)
+            };
+            let mut filtered_graph = cached_graph;
+            for step in policy.chain(&scope.stream) {
+                filtered_graph = match step {
+                    settings::PolicyStep::ThrottleRollouts => {
+                        let throttled_graph = match policy.rollouts {
+                            config::RolloutHandling::Throttle => {
+                                let graph = pe_throttle_rollouts(
+                                    data,
+                                    filtered_graph,
+                                    stream_label,
+                                    &scope,
+                                    wariness,
+                                );
+                                applied_policies.push("throttle_rollouts");
+                                graph
+                            }
+                            // Clients throttle themselves, from the per-edge
+                            // rollout details.
+                            config::RolloutHandling::Passthrough => {
+                                policy::filter_deadends(filtered_graph)
+                            }
+                        };
+                        applied_policies.push("filter_deadends");
+                        throttled_graph
+                    }
+                    settings::PolicyStep::BlockVersions if !policy.blocked_versions.is_empty() => {
+                        applied_policies.push("block_versions");
+                        policy::block_versions(
+                            filtered_graph,
+                            &policy.blocked_versions,
+                            query.current_version.as_deref(),
+                        )
+                    }
+                    settings::PolicyStep::PreferLatest
+                        if policy.edge_selection == config::EdgeSelection::Latest =>
+                    {
+                        applied_policies.push("prefer_latest");
+                        policy::prefer_latest_edges(filtered_graph)
+                    }
+                    settings::PolicyStep::MaxHops => {
+                        match (policy.max_hops, query.current_version.as_deref()) {
+                            (Some(max_hops), Some(current)) => {
+                                applied_policies.push("max_hops");
+                                policy::limit_hops(filtered_graph, current, max_hops)
+                            }
+                            _ => filtered_graph,
+                        }
+                    }
+                    settings::PolicyStep::PayloadRewrite => match &policy.payload_rewrite {
+                        Some(rewrite) => {
+                            applied_policies.push("payload_rewrite");
+                            policy::rewrite_payload_prefix(
+                                filtered_graph,
+                                &rewrite.from_prefix,
+                                &rewrite.to_prefix,
+                            )
+                        }
+                        None => filtered_graph,
+                    },
+                    _ => filtered_graph,
+                };
+                budget.check(&applied_policies)?;
+            }
+            filtered_graph
+        }
+    };
+
+    budget.check(&applied_policies)?;
+
+    // Custom post-processing, falling back to the built-in policies outcome.
+    let final_graph = match data.webhook.as_ref().filter(|_| !candidate) {
+        Some(webhook) => match budget
+            .run("webhook", webhook.process(&scope, wariness, &final_graph))
+            .await?
+        {
+            Ok(processed) => {
+                metrics::WEBHOOK_REQUESTS
+                    .with_label_values(&["success"])
+                    .inc();
+                applied_policies.push("webhook");
+                processed
+            }
+            Err(e) => {
+                metrics::WEBHOOK_REQUESTS
+                    .with_label_values(&["failure"])
+                    .inc();
+                log::warn!("graph webhook failed, serving unprocessed graph: {}", e);
+                final_graph
+            }
+        },
+        None => final_graph,
+    };
+
+    // Internal-only metadata is stripped last, so that it never reaches clients.
+    let final_graph = match policy.arch_metadata {
+        config::ArchMetadata::All => final_graph,
+        config::ArchMetadata::Requested => {
+            applied_policies.push("arch_metadata");
+            policy::select_arch_metadata(final_graph, &scope.basearch)
+        }
+    };
+    let final_graph = match &policy.metadata_filter {
+        Some(filter) => {
+            applied_policies.push("metadata_filter");
+            policy::filter_metadata(final_graph, |key| filter.keeps(key))
+        }
+        None => final_graph,
+    };
+    budget.check(&applied_policies)?;
+
+    Ok(ProcessedGraph {
+        graph: final_graph,
+        scope,
+        cache_status,
+        generated,
+        applied_policies,
+        wariness,
+        upstream_duration,
+    })
+}
+
+/// Throttle rollouts and filter dead-ends of a graph, reusing precomputed
+/// throttled graphs if enabled.
+fn pe_throttle_rollouts(
+    data: &AppState,
+    graph: graph::Graph,
+    stream_label: Option<&str>,
+    scope: &graph::GraphScope,
+    wariness: f64,
+) -> graph::Graph {
+    // Shadow policies never use precomputed graphs.
+    let throttled = data.throttled.as_ref().filter(|_| stream_label.is_some());
+    let precomputed = throttled.and_then(|throttled| throttled.get(scope, wariness));
+    let (filtered_graph, fraction) = match precomputed {
+        Some(precomputed) => (precomputed.graph, precomputed.throttled_fraction),
+        None => {
+            let nodes_before = reachable_nodes(&graph);
+            let throttled_graph = policy::throttle_rollouts(graph, wariness);
+            let fraction = throttled_fraction(nodes_before, &throttled_graph);
+            let filtered_graph = policy::filter_deadends(throttled_graph);
+            if let Some(throttled) = throttled {
+                throttled.insert(scope.clone(), wariness, filtered_graph.clone(), fraction);
+            }
+            (filtered_graph, fraction)
+        }
+    };
+    if let Some(stream_label) = stream_label {
+        metrics::THROTTLED_FRACTION
+            .with_label_values(&[stream_label])
+            .observe(fraction);
+    }
+    filtered_graph
+}
+
+/// Restrict a processed graph to the tier of the requesting client, if
+/// tiers are enabled.
+fn pe_apply_tier(data: &AppState, req: &HttpRequest, processed: ProcessedGraph) -> ProcessedGraph {
+    let tiers = match &data.tiers {
+        Some(tiers) => tiers,
+        None => return processed,
+    };
+    let tier = tiers.tier(req.headers());
+    metrics::V1_GRAPH_TIER_REQS
+        .with_label_values(&[tier.as_str()])
+        .inc();
+    match tier {
+        tiers::Tier::Authenticated => processed,
+        tiers::Tier::Anonymous => {
+            let mut processed = processed;
+            processed.graph = tiers.anonymous_graph(processed.graph, &processed.scope.stream);
+            processed.applied_policies.push("anonymous_tier");
+            processed
+        }
+    }
+}
+
+/// Time budget of the policies evaluation of a graph request, excluding time
+/// spent getting the upstream graph.
+///
+/// Built-in policies cannot be interrupted, thus the budget is checked after
+/// each of them, while asynchronous ones (i.e. the webhook) are cancelled
+/// once it is exhausted.
+struct PolicyBudget {
+    deadline: Option<std::time::Instant>,
+    timeout: Duration,
+}
+
+impl PolicyBudget {
+    /// Budget starting now, unlimited without a timeout.
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| std::time::Instant::now() + timeout),
+            timeout: timeout.unwrap_or_default(),
+        }
+    }
+
+    /// Extend the deadline by time not spent evaluating policies.
+    fn exclude(&mut self, duration: Duration) {
+        if let Some(deadline) = self.deadline.as_mut() {
+            *deadline += duration;
+        }
+    }
+
+    /// Fail if the budget is exhausted, blaming the last applied policy.
+    fn check(&self, applied_policies: &[&'static str]) -> Result<(), PeError> {
+        match self.deadline {
+            Some(deadline) if std::time::Instant::now() > deadline => {
+                Err(self.exceeded(applied_policies.last().copied().unwrap_or("unknown")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run an asynchronous policy within the remaining budget.
+    async fn run<F: std::future::Future>(
+        &self,
+        policy: &'static str,
+        future: F,
+    ) -> Result<F::Output, PeError> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(future.await),
+        };
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        actix_rt::time::timeout(remaining, future)
+            .await
+            .map_err(|_| self.exceeded(policy))
+    }
+
+    fn exceeded(&self, policy: &'static str) -> PeError {
+        metrics::POLICY_EVALUATION_TIMEOUTS
+            .with_label_values(&[policy])
+            .inc();
+        log::warn!(
+            "graph policies exceeded evaluation timeout of {}ms, at policy '{}'",
+            self.timeout.as_millis(),
+            policy
+        );
+        PeError::Internal(format!("policy '{}' exceeded evaluation timeout", policy))
+    }
+}
+
+/// Process a graph query with the shadow (candidate) policies, and compare
+/// the outcome to the served one.
+///
+/// Upstream failures are not compared, to avoid retrying them once more.
+async fn pe_compare_shadow(
+    data: &AppState,
+    shadow: &settings::ShadowSettings,
+    query: &GraphQuery,
+    scope: graph::GraphScope,
+    served: &Result<ProcessedGraph, PeError>,
+) {
+    let served = shadow::outcome(served);
+    if let Err(status) = served {
+        if status.is_server_error() {
+            return;
+        }
+    }
+    let candidate = pe_apply_policies(data, &shadow.policy, None, query, scope.clone()).await;
+    let divergence = shadow::divergence(served, shadow::outcome(&candidate));
+    let result = if divergence.is_some() {
+        "divergent"
+    } else {
+        "match"
+    };
+    metrics::SHADOW_POLICY_COMPARISONS
+        .with_label_values(&[result])
+        .inc();
+    if let Some(divergence) = divergence {
+        if debug::sample_request(shadow.log_sample_rate) {
+            log::info!(
+                target: debug::SAMPLED_LOG_TARGET,
+                "shadow policies diverge for basearch='{}', stream='{}': {}",
+                scope.basearch,
+                scope.stream,
+                divergence
+            );
+        }
+    }
+}
+
+/// Get the upstream graph for a scope, falling back along the configured
+/// stream fallbacks while a stream has no graph: the upstream failed (with
+/// nothing cached), or the graph has no releases.
+async fn pe_get_graph_with_fallback(
+    data: &AppState,
+    scope: graph::GraphScope,
+    fresh: bool,
+) -> Result<(cache::CachedGraph, cache::CacheStatus), PeError> {
+    let mut scope = scope;
+    loop {
+        let result = pe_get_graph(data, scope.clone(), fresh).await;
+        let has_graph = matches!(&result, Ok((entry, _)) if !entry.graph.nodes.is_empty());
+        // Fallback chains are checked for loops at startup.
+        let fallback = match data.stream_fallbacks.get(&scope.stream) {
+            Some(fallback) if !has_graph => fallback,
+            _ => return result,
+        };
+        log::info!(
+            "no graph for basearch='{}', stream='{}', falling back to stream '{}'",
+            scope.basearch,
+            scope.stream,
+            fallback
+        );
+        metrics::V1_GRAPH_STREAM_FALLBACKS
+            .with_label_values(&[&scope.stream, fallback])
+            .inc();
+        scope = graph::GraphScope {
+            basearch: scope.basearch,
+            stream: fallback.clone(),
+        };
+    }
+}
+
+/// Get the upstream graph for a scope, from pins, cache or upstream.
+///
+/// Upstream graphs carry no generation time, so the returned entry is
+/// stamped with the time it was fetched (or loaded, for pinned graphs).
+///
+/// Fresh requests bypass the caches, which are still populated with the
+/// fetched graph (and used as fallback on upstream failures).
+async fn pe_get_graph(
+    data: &AppState,
+    scope: graph::GraphScope,
+    fresh: bool,
+) -> Result<(cache::CachedGraph, cache::CacheStatus), PeError> {
+    if let Some(pinned) = data.pinned_graphs.get(&scope) {
+        log::debug!(
+            "serving pinned graph: basearch='{}', stream='{}'",
+            scope.basearch,
+            scope.stream
+        );
+        return Ok((pinned.clone(), cache::CacheStatus::Hit));
+    }
+
+    let cached = data.cache.get(&scope);
+    if let Some(entry) = cached.as_ref().filter(|_| !fresh) {
+        if data.cache.is_fresh(entry) {
+            return Ok((entry.clone(), cache::CacheStatus::Hit));
+        }
+        if let Some(revalidator) = &data.revalidator {
+            if revalidator.is_revalidatable(entry) {
+                if revalidator.try_start(&scope) {
+                    pe_revalidate_graph(data.clone(), revalidator.clone(), scope);
+                }
+                metrics::CACHE_STALE_WHILE_REVALIDATE.inc();
+                return Ok((entry.clone(), cache::CacheStatus::Stale));
+            }
+        }
+    }
+
+    pe_refresh_graph(data, scope, cached, fresh).await
+}
+
+/// Refresh a stale cached graph in the background.
+fn pe_revalidate_graph(
+    data: AppState,
+    revalidator: Arc<cache::Revalidator>,
+    scope: graph::GraphScope,
+) {
+    actix_rt::spawn(async move {
+        log::debug!(
+            "revalidating graph: basearch='{}', stream='{}'",
+            scope.basearch,
+            scope.stream
+        );
+        // Failures are logged, the stale graph is kept.
+        let _ = pe_refresh_graph(&data, scope.clone(), None, false).await;
+        revalidator.finish(&scope);
+    });
+}
+
+/// Get the upstream graph for a scope from the shared cache or upstream,
+/// falling back to the given cached entry on upstream failures.
+async fn pe_refresh_graph(
+    data: &AppState,
+    scope: graph::GraphScope,
+    cached: Option<cache::CachedGraph>,
+    fresh: bool,
+) -> Result<(cache::CachedGraph, cache::CacheStatus), PeError> {
+    // Graphs from the shared cache count as fetched by this replica.
+    if let Some(shared) = data.shared_cache.as_ref().filter(|_| !fresh) {
+        if let Some(graph) = shared.get(&scope).await {
+            let (stream_label, _) = data.scopes.observe(&scope);
+            // Shared graphs may come from a replica with nothing to compare
+            // them with.
+            if let Some(kept) = pe_check_shrink(data, &stream_label, &scope, &graph) {
+                return Ok((kept, cache::CacheStatus::Stale));
+            }
+            let now = data.clock.now().timestamp();
+            data.rollouts.update(&stream_label, &scope, &graph, now);
+            let entry = data.cache.insert(scope, graph);
+            return Ok((entry, cache::CacheStatus::Hit));
+        }
+    }
+
+    if let Some(limiter) = &data.fetch_limiter {
+        if !limiter.try_fetch(&scope) {
+            metrics::UPSTREAM_FETCHES_SUPPRESSED.inc();
+            return match cached {
+                Some(entry) if data.cache.is_fresh(&entry) => Ok((entry, cache::CacheStatus::Hit)),
+                Some(stale) => Ok((stale, cache::CacheStatus::Stale)),
+                None => Err(PeError::UpstreamUnavailable(
+                    "upstream fetch suppressed by the minimum fetch interval".to_string(),
+                )),
+            };
+        }
+    }
+
+    let fetched = utils::fetch_graph_with_retries(
+        &data.upstream_client,
+        &data.upstream,
+        &data.retry_budget,
+        &data.upstream_limiter,
+        data.clock.as_ref(),
+        scope.stream.clone(),
+        scope.basearch.clone(),
+    )
+    .await;
+    match (fetched, cached) {
+        (Ok(graph), _) => {
+            // Scopes are validated, bounding labels to the allowlist if any.
+            let (stream_label, _) = data.scopes.observe(&scope);
+            let now = data.clock.now().timestamp();
+            metrics::UPSTREAM_LAST_SUCCESS
+                .with_label_values(&[&stream_label])
+                .set(now);
+            let graph = pe_check_edges(&data.policy, &scope, graph);
+            if let Some(kept) = pe_check_shrink(data, &stream_label, &scope, &graph) {
+                return Ok((kept, cache::CacheStatus::Stale));
+            }
+            data.rollouts.update(&stream_label, &scope, &graph, now);
+            // With the shrink guard, only graphs checked against a cached
+            // one are shared with other replicas.
+            let unchecked =
+                data.upstream.shrink_guard.is_some() && data.cache.get(&scope).is_none();
+            if let Some(shared) = data.shared_cache.as_ref().filter(|_| !unchecked) {
+                shared.insert(&scope, &graph).await;
+            }
+            let entry = data.cache.insert(scope, graph);
+            Ok((entry, cache::CacheStatus::Miss))
+        }
+        (Err(e), Some(stale)) => {
+            log::warn!(
+                "serving stale graph for basearch='{}', stream='{}': {}",
+                scope.basearch,
+                scope.stream,
+                e
+            );
+            Ok((stale, cache::CacheStatus::Stale))
+        }
+        (Err(e), None) => {
+            log::error!(
+                "failed to fetch graph for basearch='{}', stream='{}': {}",
+                scope.basearch,
+                scope.stream,
+                e
+            );
+            Err(PeError::UpstreamUnavailable(e.to_string()))
+        }
+    }
+}
+
+/// Sanity-check a fetched upstream graph, trimming over-connected nodes.
+fn pe_check_edges(
+    policy: &settings::PolicySettings,
+    scope: &graph::GraphScope,
+    graph: graph::Graph,
+) -> graph::Graph {
+    let (graph, trimmed) = policy::limit_outgoing_edges(graph, policy.max_edges_per_node);
+    for index in &trimmed {
+        let version = graph
+            .nodes
+            .get(*index as usize)
+            .map(|node| node.version.as_str())
+            .unwrap_or_default();
+        log::warn!(
+            "trimmed outgoing edges of node '{}' exceeding limit of {}: basearch='{}', stream='{}'",
+            version,
+            policy.max_edges_per_node,
+            scope.basearch,
+            scope.stream
+        );
+    }
+    metrics::GRAPH_OVERCONNECTED_NODES.inc_by(trimmed.len() as u64);
+    graph
+}
+
+/// Compare a fetched upstream graph with the cached one, warning about an
+/// unexpected shrink; returns the cached entry if it must be kept instead.
+///
+/// Kept entries are renewed, so that the upstream is not queried again on
+/// each request while it serves shrunk graphs.
+fn pe_check_shrink(
+    data: &AppState,
+    stream_label: &str,
+    scope: &graph::GraphScope,
+    graph: &graph::Graph,
+) -> Option<cache::CachedGraph> {
+    let guard = data.upstream.shrink_guard.as_ref()?;
+    let previous = data.cache.get(scope)?;
+    let (before, after) = (previous.graph.nodes.len(), graph.nodes.len());
+    if !guard.is_excessive(before, after) {
+        return None;
+    }
+    metrics::UPSTREAM_GRAPH_SHRINKS
+        .with_label_values(&[stream_label])
+        .inc();
+    log::warn!(
+        "upstream graph shrank from {} to {} nodes, {}: basearch='{}', stream='{}'",
+        before,
+        after,
+        if guard.keep_cached {
+            "keeping the cached graph"
+        } else {
+            "replacing the cached graph"
+        },
+        scope.basearch,
+        scope.stream
+    );
+    if guard.keep_cached {
+        data.cache.renew(scope)
+    } else {
+        None
+    }
+}
+
+/// Nodes which are the target of at least one edge.
+fn reachable_nodes(graph: &graph::Graph) -> HashSet<u64> {
+    graph.edges.iter().map(|(_from, to)| *to).collect()
+}
+
+/// Fraction of graph nodes made unreachable by throttling, given the nodes
+/// reachable before throttling.
+fn throttled_fraction(before: HashSet<u64>, throttled: &graph::Graph) -> f64 {
+    if throttled.nodes.is_empty() {
+        return 0.0;
+    }
+    let after = reachable_nodes(throttled);
+    let hidden = before.difference(&after).count();
+    hidden as f64 / throttled.nodes.len() as f64
+}
+
+/// Time breakdown of a graph request.
+struct RequestTimings {
+    total: Duration,
+    upstream: Duration,
+    policy: Duration,
+    serialize: Duration,
+}
+
+/// Summary of a graph request slower than the threshold, for logging.
+///
+/// Only the scope is included: client identifiers (`node_uuid`) are not.
+fn slow_request_summary(
+    threshold: Option<Duration>,
+    query: &GraphQuery,
+    timings: &RequestTimings,
+) -> Option<String> {
+    if timings.total < threshold? {
+        return None;
+    }
+    Some(format!(
+        "slow graph request: basearch='{}', stream='{}', total {}ms (upstream {}ms, policy {}ms, serialize {}ms)",
+        query.basearch.as_deref().unwrap_or_default(),
+        query.stream.as_deref().unwrap_or_default(),
+        timings.total.as_millis(),
+        timings.upstream.as_millis(),
+        timings.policy.as_millis(),
+        timings.serialize.as_millis(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use crate::tests::{
+        canned_graph, canned_upstream, mock_upstream, mock_upstream_sequence, scoped_query,
+        settings_for,
+    };
+    use crate::wariness::round_wariness;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use commons::metadata;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_throttled_fraction() {
+        let input = canned_graph();
+        let before = reachable_nodes(&input);
+        // The rollout node (1 out of 3) is hidden from wary clients.
+        let throttled = policy::throttle_rollouts(input, 0.9);
+        assert!((throttled_fraction(before.clone(), &throttled) - 1.0 / 3.0).abs() < 1e-9);
+
+        let throttled = policy::throttle_rollouts(canned_graph(), 0.1);
+        assert_eq!(throttled_fraction(before, &throttled), 0.0);
+    }
+
+    #[test]
+    fn test_check_edges_overconnected_node() {
+        let policy = settings::PolicySettings {
+            max_edges_per_node: 1,
+            ..Default::default()
+        };
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+
+        let before = metrics::GRAPH_OVERCONNECTED_NODES.get();
+        let graph = pe_check_edges(&policy, &scope, canned_graph());
+        assert_eq!(graph.edges, vec![(0, 1), (1, 2)]);
+        assert!(metrics::GRAPH_OVERCONNECTED_NODES.get() > before);
+    }
+
+    #[test]
+    fn test_slow_request_summary() {
+        let query = GraphQuery {
+            node_uuid: Some("secret-uuid".to_string()),
+            ..scoped_query("stable", "x86_64", None)
+        };
+        let timings = RequestTimings {
+            total: Duration::from_millis(1500),
+            upstream: Duration::from_millis(1200),
+            policy: Duration::from_millis(200),
+            serialize: Duration::from_millis(50),
+        };
+        let threshold = Some(Duration::from_secs(1));
+
+        let summary = slow_request_summary(threshold, &query, &timings).unwrap();
+        assert_eq!(
+            summary,
+            "slow graph request: basearch='x86_64', stream='stable', total 1500ms \
+             (upstream 1200ms, policy 200ms, serialize 50ms)"
+        );
+        assert!(!summary.contains("secret-uuid"));
+
+        assert!(slow_request_summary(None, &query, &timings).is_none());
+        let fast = RequestTimings {
+            total: Duration::from_millis(999),
+            ..timings
+        };
+        assert!(slow_request_summary(threshold, &query, &fast).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_wariness_histograms() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let state = AppState::new(&settings).unwrap();
+        let query = |wariness: Option<&str>| GraphQuery {
+            node_uuid: Some("some-uuid".to_string()),
+            ..scoped_query("stable", "x86_64", wariness)
+        };
+
+        pe_process_graph(&state, &query(Some("0.5"))).await.unwrap();
+        pe_process_graph(&state, &query(None)).await.unwrap();
+        pe_process_graph(&state, &query(Some("invalid")))
+            .await
+            .unwrap();
+        assert_eq!(state.rollout_wariness.get_sample_count(), 1);
+        assert_eq!(state.rollout_wariness.get_sample_sum(), 0.5);
+        assert_eq!(state.computed_rollout_wariness.get_sample_count(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_webhook_fallback() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        // The mock upstream has no such route, thus the webhook fails.
+        settings.policy.webhook = Some(settings::WebhookSettings {
+            url: reqwest::Url::parse(&upstream.url("/hook")).unwrap(),
+            timeout: Duration::from_secs(5),
+        });
+        let state = AppState::new(&settings).unwrap();
+        let query = scoped_query("stable", "x86_64", Some("0.5"));
+
+        let failures = metrics::WEBHOOK_REQUESTS.with_label_values(&["failure"]);
+        let before = failures.get();
+        let processed = pe_process_graph(&state, &query).await.unwrap();
+        assert_eq!(failures.get(), before + 1);
+        let direct = policy::filter_deadends(policy::throttle_rollouts(canned_graph(), 0.5));
+        assert_eq!(processed.graph.edges, direct.edges);
+        assert!(!processed.applied_policies.contains(&"webhook"));
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_evaluation_timeout() {
+        let upstream = canned_upstream();
+        // A deliberately slow webhook policy.
+        let webhook = test::start(|| {
+            App::new().route(
+                "/hook",
+                web::post().to(|graph: web::Json<graph::Graph>| async move {
+                    actix_rt::time::delay_for(Duration::from_secs(3)).await;
+                    Ok::<_, actix_web::Error>(HttpResponse::Ok().json(graph.into_inner()))
+                }),
+            )
+        });
+        let mut settings = settings_for(&upstream);
+        settings.policy.webhook = Some(settings::WebhookSettings {
+            url: reqwest::Url::parse(&webhook.url("/hook")).unwrap(),
+            timeout: Duration::from_secs(10),
+        });
+        settings.policy.evaluation_timeout = Some(Duration::from_millis(200));
+        let state = AppState::new(&settings).unwrap();
+        let query = scoped_query("stable", "x86_64", Some("0.5"));
+
+        let timeouts = metrics::POLICY_EVALUATION_TIMEOUTS.with_label_values(&["webhook"]);
+        let before = timeouts.get();
+        let start = std::time::Instant::now();
+        let err = pe_process_graph(&state, &query).await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(
+            actix_web::ResponseError::status_code(&err),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(err.to_string().contains("'webhook'"), "{}", err);
+        assert_eq!(timeouts.get(), before + 1);
+
+        // Within the budget, policies are unaffected.
+        settings.policy.webhook = None;
+        let state = AppState::new(&settings).unwrap();
+        let processed = pe_process_graph(&state, &query).await.unwrap();
+        assert_eq!(processed.graph.edges, vec![(0, 1), (0, 2)]);
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_stream_chains() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.policy.edge_selection = config::EdgeSelection::Latest;
+        settings.policy.stream_chains.insert(
+            "testing".to_string(),
+            vec![
+                settings::PolicyStep::PreferLatest,
+                settings::PolicyStep::ThrottleRollouts,
+            ],
+        );
+        let state = AppState::new(&settings).unwrap();
+        let query = |stream: &str| scoped_query(stream, "x86_64", Some("1.0"));
+
+        // Throttled first, the newest release still offered is 35.2.0.
+        let stable = pe_process_graph(&state, &query("stable")).await.unwrap();
+        assert_eq!(stable.graph.edges, vec![(0, 1)]);
+        assert_eq!(
+            stable.applied_policies,
+            vec!["throttle_rollouts", "filter_deadends", "prefer_latest"]
+        );
+        // Edges towards the newest release are selected before it is
+        // throttled, leaving none.
+        let testing = pe_process_graph(&state, &query("testing")).await.unwrap();
+        assert!(testing.graph.edges.is_empty());
+        assert_eq!(
+            testing.applied_policies,
+            vec!["prefer_latest", "throttle_rollouts", "filter_deadends"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_metadata_filter() {
+        let mut upstream_graph = canned_graph();
+        for node in upstream_graph.nodes.iter_mut() {
+            node.metadata
+                .insert("org.example.build.host".to_string(), "builder1".to_string());
+        }
+        let body = serde_json::to_string(&upstream_graph).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let mut settings = settings_for(&upstream);
+        settings.policy.metadata_filter = Some(settings::MetadataFilterSettings::Deny(
+            vec!["org.example.build.host".to_string()]
+                .into_iter()
+                .collect(),
+        ));
+        let state = AppState::new(&settings).unwrap();
+        let query = scoped_query("stable", "x86_64", Some("0.0"));
+
+        let processed = pe_process_graph(&state, &query).await.unwrap();
+        assert!(processed.applied_policies.contains(&"metadata_filter"));
+        for node in &processed.graph.nodes {
+            assert!(!node.metadata.contains_key("org.example.build.host"));
+        }
+        let rollout = &processed.graph.nodes[2].metadata;
+        assert_eq!(rollout.get(metadata::ROLLOUT), Some(&"true".to_string()));
+        assert_eq!(rollout.get(metadata::START_VALUE), Some(&"0.5".to_string()));
+        // Policies still saw the complete metadata.
+        let direct = policy::filter_deadends(policy::throttle_rollouts(canned_graph(), 0.0));
+        assert_eq!(processed.graph.edges, direct.edges);
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_stream_fallbacks() {
+        async fn graph_by_stream(query: web::Query<GraphQuery>) -> HttpResponse {
+            match query.stream.as_deref() {
+                Some("broken") => HttpResponse::InternalServerError().finish(),
+                Some("niche") => HttpResponse::Ok().json(graph::Graph::default()),
+                _ => HttpResponse::Ok().json(canned_graph()),
+            }
+        }
+        let upstream =
+            test::start(|| App::new().route("/v1/graph", web::get().to(graph_by_stream)));
+        let mut settings = settings_for(&upstream);
+        settings
+            .stream_fallbacks
+            .insert("broken".to_string(), "niche".to_string());
+        settings
+            .stream_fallbacks
+            .insert("niche".to_string(), "stable".to_string());
+        let state = AppState::new(&settings).unwrap();
+        let query = |stream: &str| scoped_query(stream, "x86_64", Some("0.0"));
+        let fallbacks = |stream: &str, fallback: &str| {
+            metrics::V1_GRAPH_STREAM_FALLBACKS
+                .with_label_values(&[stream, fallback])
+                .get()
+        };
+        let before = (fallbacks("broken", "niche"), fallbacks("niche", "stable"));
+
+        let processed = pe_process_graph(&state, &query("broken")).await.unwrap();
+        assert_eq!(processed.graph.nodes.len(), canned_graph().nodes.len());
+        assert_eq!(fallbacks("broken", "niche"), before.0 + 1);
+        assert_eq!(fallbacks("niche", "stable"), before.1 + 1);
+
+        // Streams with a graph are served as is.
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+        let (entry, _) = pe_get_graph_with_fallback(&state, scope, false)
+            .await
+            .unwrap();
+        assert_eq!(entry.graph.nodes.len(), canned_graph().nodes.len());
+        assert_eq!(fallbacks("niche", "stable"), before.1 + 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_rollout_progress_metrics() {
+        let upstream = canned_upstream();
+        let settings = settings_for(&upstream);
+        let state = AppState::new(&settings).unwrap();
+        let query = scoped_query("rollout-progress", "x86_64", Some("0.0"));
+        pe_process_graph(&state, &query).await.unwrap();
+
+        let progress = metrics::ROLLOUT_PROGRESS
+            .get_metric_with_label_values(&["rollout-progress", "35.3.0"])
+            .unwrap();
+        assert_eq!(progress.get(), 0.5);
+        // Releases not rolling out get no gauge.
+        assert!(metrics::ROLLOUT_PROGRESS
+            .remove_label_values(&["rollout-progress", "35.1.0"])
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_arch_metadata() {
+        let arch_key = |basearch: &str| format!("{}.{}.commit", metadata::ARCH_PREFIX, basearch);
+        let mut upstream_graph = canned_graph();
+        for node in upstream_graph.nodes.iter_mut() {
+            for basearch in &["x86_64", "aarch64", "s390x"] {
+                node.metadata
+                    .insert(arch_key(basearch), basearch.to_string());
+            }
+        }
+        let body = serde_json::to_string(&upstream_graph).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let mut settings = settings_for(&upstream);
+        let query = |basearch: &str| scoped_query("stable", basearch, Some("0.0"));
+
+        // Everything is passed through by default.
+        let state = AppState::new(&settings).unwrap();
+        let processed = pe_process_graph(&state, &query("aarch64")).await.unwrap();
+        assert!(!processed.applied_policies.contains(&"arch_metadata"));
+        for (node, upstream_node) in processed.graph.nodes.iter().zip(&upstream_graph.nodes) {
+            assert_eq!(node.metadata, upstream_node.metadata);
+        }
+
+        settings.policy.arch_metadata = config::ArchMetadata::Requested;
+        let state = AppState::new(&settings).unwrap();
+        let processed = pe_process_graph(&state, &query("aarch64")).await.unwrap();
+        assert!(processed.applied_policies.contains(&"arch_metadata"));
+        for node in &processed.graph.nodes {
+            assert_eq!(
+                node.metadata.get(&arch_key("aarch64")),
+                Some(&"aarch64".to_string())
+            );
+            assert!(!node.metadata.contains_key(&arch_key("x86_64")));
+            assert!(!node.metadata.contains_key(&arch_key("s390x")));
+        }
+        let rollout = &processed.graph.nodes[2].metadata;
+        assert_eq!(rollout.get(metadata::ROLLOUT), Some(&"true".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_max_hops() {
+        let node = |version: &str| graph::CincinnatiPayload {
+            version: version.to_string(),
+            payload: format!("sha256:{}", version),
+            metadata: HashMap::new(),
+        };
+        let upstream_graph = graph::Graph {
+            nodes: vec![
+                node("35.1.0"),
+                node("35.2.0"),
+                node("35.3.0"),
+                node("35.4.0"),
+            ],
+            edges: vec![(0, 1), (1, 2), (2, 3), (1, 3)],
+        };
+        let body = serde_json::to_string(&upstream_graph).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let mut settings = settings_for(&upstream);
+        settings.policy.max_hops = Some(2);
+        let state = AppState::new(&settings).unwrap();
+        let query = |current_version: Option<&str>| GraphQuery {
+            current_version: current_version.map(String::from),
+            ..scoped_query("stable", "x86_64", Some("0.0"))
+        };
+
+        // `35.4.0` is two hops away from `35.1.0`, the longer path through
+        // `35.3.0` is trimmed.
+        let processed = pe_process_graph(&state, &query(Some("35.1.0")))
+            .await
+            .unwrap();
+        assert!(processed.applied_policies.contains(&"max_hops"));
+        assert_eq!(processed.graph.edges, vec![(0, 1), (1, 2), (1, 3)]);
+        let processed = pe_process_graph(&state, &query(Some("35.3.0")))
+            .await
+            .unwrap();
+        assert_eq!(processed.graph.edges, vec![(0, 1), (1, 2), (2, 3), (1, 3)]);
+
+        // Client versions are counted, bounded to graph releases.
+        let versions =
+            |version: &str| metrics::V1_GRAPH_CURRENT_VERSIONS.with_label_values(&[version]);
+        let (before, other_before) = (versions("35.2.0").get(), versions("other").get());
+        pe_process_graph(&state, &query(Some("35.2.0")))
+            .await
+            .unwrap();
+        pe_process_graph(&state, &query(Some("34.9.9")))
+            .await
+            .unwrap();
+        assert_eq!(versions("35.2.0").get(), before + 1);
+        assert!(versions("other").get() > other_before);
+        assert_eq!(versions("34.9.9").get(), 0);
+
+        // Without a client version, the graph is not trimmed.
+        let processed = pe_process_graph(&state, &query(None)).await.unwrap();
+        assert!(!processed.applied_policies.contains(&"max_hops"));
+        assert_eq!(processed.graph.edges.len(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_prefer_latest() {
+        let node = |version: &str, metadata: Vec<(&str, &str)>| graph::CincinnatiPayload {
+            version: version.to_string(),
+            payload: format!("sha256:{}", version),
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let upstream_graph = graph::Graph {
+            nodes: vec![
+                node("35.1.0", vec![]),
+                node("35.2.0", vec![]),
+                node("35.3.0", vec![]),
+                node(
+                    "35.4.0",
+                    vec![(metadata::ROLLOUT, "true"), (metadata::START_VALUE, "0.5")],
+                ),
+            ],
+            edges: vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3)],
+        };
+        let body = serde_json::to_string(&upstream_graph).unwrap();
+        let upstream = mock_upstream(StatusCode::OK, body, Duration::from_secs(0));
+        let mut settings = settings_for(&upstream);
+        let query = |wariness: &str| scoped_query("stable", "x86_64", Some(wariness));
+
+        // All edges are kept by default.
+        let state = AppState::new(&settings).unwrap();
+        let processed = pe_process_graph(&state, &query("0.0")).await.unwrap();
+        assert!(!processed.applied_policies.contains(&"prefer_latest"));
+        assert_eq!(processed.graph.edges, upstream_graph.edges);
+
+        settings.policy.edge_selection = config::EdgeSelection::Latest;
+        let state = AppState::new(&settings).unwrap();
+        let processed = pe_process_graph(&state, &query("0.0")).await.unwrap();
+        assert!(processed.applied_policies.contains(&"prefer_latest"));
+        assert_eq!(processed.graph.edges, vec![(0, 3), (1, 3)]);
+        // Throttled releases are not preferred, the newest offered one is.
+        let processed = pe_process_graph(&state, &query("0.9")).await.unwrap();
+        assert_eq!(processed.graph.edges, vec![(0, 2), (1, 2)]);
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_shadow() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        let query = scoped_query("stable", "x86_64", Some("0.0"));
+        let comparisons = |result: &str| {
+            metrics::SHADOW_POLICY_COMPARISONS
+                .with_label_values(&[result])
+                .get()
+        };
+
+        // Identical candidate policies.
+        settings.shadow = Some(settings::ShadowSettings {
+            policy: settings::PolicySettings::default(),
+            log_sample_rate: 1.0,
+        });
+        let state = AppState::new(&settings).unwrap();
+        let matching = comparisons("match");
+        pe_process_graph(&state, &query).await.unwrap();
+        assert_eq!(comparisons("match"), matching + 1);
+
+        // Candidate policies rewriting payloads, which are not served.
+        let candidate = settings::PolicySettings {
+            payload_rewrite: Some(settings::PayloadRewriteSettings {
+                from_prefix: "sha256:".to_string(),
+                to_prefix: "mirror:".to_string(),
+            }),
+            ..Default::default()
+        };
+        settings.shadow = Some(settings::ShadowSettings {
+            policy: candidate,
+            log_sample_rate: 1.0,
+        });
+        let state = AppState::new(&settings).unwrap();
+        let divergent = comparisons("divergent");
+        let processed = pe_process_graph(&state, &query).await.unwrap();
+        assert_eq!(comparisons("divergent"), divergent + 1);
+        assert!(!processed.applied_policies.contains(&"payload_rewrite"));
+        assert!(processed
+            .graph
+            .nodes
+            .iter()
+            .all(|node| node.payload.starts_with("sha256:")));
+    }
+
+    #[actix_rt::test]
+    async fn test_process_graph_precomputed() {
+        let upstream = canned_upstream();
+        let mut settings = settings_for(&upstream);
+        settings.policy.wariness_precision = 2;
+        settings.policy.precompute = Some(settings::PrecomputeSettings {
+            ttl: Duration::from_secs(3600),
+            max_entries: 1000,
+        });
+        let state = AppState::new(&settings).unwrap();
+        let query = |wariness: &str| scoped_query("stable", "x86_64", Some(wariness));
+
+        // Bucketed results match the direct computation, for values on
+        // both sides of bucket boundaries and of the rollout threshold.
+        for step in 0..=1000 {
+            let wariness = format!("{:.3}", step as f64 / 1000.0);
+            let first = pe_process_graph(&state, &query(&wariness)).await.unwrap();
+            let second = pe_process_graph(&state, &query(&wariness)).await.unwrap();
+            let rounded = round_wariness(step as f64 / 1000.0, 2);
+            let direct =
+                policy::filter_deadends(policy::throttle_rollouts(canned_graph(), rounded));
+            assert_eq!(first.graph.edges, direct.edges, "wariness {}", wariness);
+            assert_eq!(second.graph.edges, direct.edges, "wariness {}", wariness);
+        }
+        let throttled = state.throttled.as_ref().unwrap();
+        assert!(throttled
+            .get(
+                &graph::GraphScope {
+                    basearch: "x86_64".to_string(),
+                    stream: "stable".to_string(),
+                },
+                0.5
+            )
+            .is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_get_graph_shrink_guard() {
+        // The upstream serves the full graph once, then a single release.
+        let full = serde_json::to_string(&canned_graph()).unwrap();
+        let mut shrunk = canned_graph();
+        shrunk.nodes.truncate(1);
+        shrunk.edges.clear();
+        let shrunk = serde_json::to_string(&shrunk).unwrap();
+        let (upstream, fetches) =
+            mock_upstream_sequence(vec![(StatusCode::OK, full), (StatusCode::OK, shrunk)]);
+        let mut settings = settings_for(&upstream);
+        settings.cache.ttl = Duration::from_secs(30);
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "shrink-guard".to_string(),
+        };
+        let shrinks = metrics::UPSTREAM_GRAPH_SHRINKS.with_label_values(&["shrink-guard"]);
+        let fetch_count = || fetches.load(Ordering::SeqCst);
+
+        for keep_cached in &[true, false] {
+            fetches.store(0, Ordering::SeqCst);
+            settings.upstream.shrink_guard = Some(settings::ShrinkGuardSettings {
+                max_shrink_percent: 50,
+                keep_cached: *keep_cached,
+            });
+            let clock = Arc::new(clock::MockClock::at(chrono::Utc::now()));
+            let state = AppState::with_clock(&settings, clock.clone()).unwrap();
+            let (entry, status) = pe_get_graph(&state, scope.clone(), false).await.unwrap();
+            assert_eq!(
+                (entry.graph.nodes.len(), status),
+                (3, cache::CacheStatus::Miss)
+            );
+
+            clock.advance(Duration::from_secs(31));
+            let before = shrinks.get();
+            let (entry, status) = pe_get_graph(&state, scope.clone(), false).await.unwrap();
+            assert_eq!(shrinks.get(), before + 1);
+            assert_eq!(fetch_count(), 2);
+            if *keep_cached {
+                assert_eq!(
+                    (entry.graph.nodes.len(), status),
+                    (3, cache::CacheStatus::Stale)
+                );
+            } else {
+                assert_eq!(
+                    (entry.graph.nodes.len(), status),
+                    (1, cache::CacheStatus::Miss)
+                );
+            }
+            // Kept graphs are renewed, not refetched on every request.
+            let (entry, status) = pe_get_graph(&state, scope.clone(), false).await.unwrap();
+            let expected = if *keep_cached { 3 } else { 1 };
+            assert_eq!(
+                (entry.graph.nodes.len(), status),
+                (expected, cache::CacheStatus::Hit)
+            );
+            assert_eq!(fetch_count(), 2);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Precomputed throttled graphs, per scope and wariness bucket.
+//!
+//! Many clients share the same (rounded) wariness, thus the same throttling
+//! outcome. Throttled and filtered graphs are kept for a short time so that
+//! most requests can reuse them. Buckets are the wariness values at the
+//! configured precision: as client wariness is rounded to it beforehand,
+//! a precomputed graph is exactly the one that would be computed for the
+//! request, up to the (short) entry lifetime.
+
+use crate::clock::Clock;
+use chrono::{DateTime, Utc};
+use commons::graph::{Graph, GraphScope};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Cache of throttled graphs, keyed by scope and wariness bucket.
+#[derive(Debug)]
+pub(crate) struct ThrottledGraphs {
+    precision: u32,
+    ttl: Duration,
+    max_entries: usize,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<HashMap<(GraphScope, u64), ThrottledGraph>>,
+}
+
+/// Throttled and filtered graph, with its throttled nodes fraction.
+#[derive(Clone, Debug)]
+pub(crate) struct ThrottledGraph {
+    pub(crate) graph: Graph,
+    pub(crate) throttled_fraction: f64,
+    computed: DateTime<Utc>,
+    accessed: DateTime<Utc>,
+}
+
+impl ThrottledGraphs {
+    pub(crate) fn new(
+        precision: u32,
+        ttl: Duration,
+        max_entries: usize,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            precision,
+            ttl,
+            max_entries,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bucket of a (rounded) wariness value.
+    fn bucket(&self, wariness: f64) -> u64 {
+        (wariness * 10f64.powi(self.precision as i32)).round() as u64
+    }
+
+    /// Return the precomputed graph for a scope and wariness, if still valid.
+    pub(crate) fn get(&self, scope: &GraphScope, wariness: f64) -> Option<ThrottledGraph> {
+        let key = (scope.clone(), self.bucket(wariness));
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get_mut(&key)?;
+        let now = self.clock.now();
+        let age = now
+            .signed_duration_since(entry.computed)
+            .to_std()
+            .unwrap_or_default();
+        if age >= self.ttl {
+            return None;
+        }
+        entry.accessed = now;
+        Some(entry.clone())
+    }
+
+    /// Store the throttled graph for a scope and wariness.
+    ///
+    /// When full, all entries are dropped: they are short-lived anyway.
+    pub(crate) fn insert(
+        &self,
+        scope: GraphScope,
+        wariness: f64,
+        graph: Graph,
+        throttled_fraction: f64,
+    ) {
+        let key = (scope, self.bucket(wariness));
+        let now = self.clock.now();
+        let entry = ThrottledGraph {
+            graph,
+            throttled_fraction,
+            computed: now,
+            accessed: now,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.clear();
+        }
+        entries.insert(key, entry);
+    }
+
+    /// Drop the precomputed graphs of selected scopes.
+    pub(crate) fn flush(&self, selected: impl Fn(&GraphScope) -> bool) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|(scope, _), _| !selected(scope));
+    }
+
+    /// Drop the precomputed graphs not accessed for at least `max_idle`,
+    /// returning how many were dropped.
+    pub(crate) fn reap_idle(&self, max_idle: Duration) -> usize {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let before = entries.len();
+        entries.retain(|_, entry| {
+            let idle = now
+                .signed_duration_since(entry.accessed)
+                .to_std()
+                .unwrap_or_default();
+            idle < max_idle
+        });
+        before - entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_throttled_graphs() {
+        let scope = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let cache = ThrottledGraphs::new(3, Duration::from_secs(1), 2, clock.clone());
+
+        cache.insert(scope.clone(), 0.25, Graph::default(), 0.5);
+        assert_eq!(cache.get(&scope, 0.25).unwrap().throttled_fraction, 0.5);
+        assert!(cache.get(&scope, 0.251).is_none());
+
+        // Entries expire.
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&scope, 0.25).is_none());
+
+        // Entries are dropped when full.
+        cache.insert(scope.clone(), 0.1, Graph::default(), 0.0);
+        cache.insert(scope.clone(), 0.2, Graph::default(), 0.0);
+        assert!(cache.get(&scope, 0.1).is_none());
+        assert!(cache.get(&scope, 0.2).is_some());
+
+        // Idle entries are reaped, including expired ones.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.reap_idle(Duration::from_secs(5)), 1);
+        assert!(cache.get(&scope, 0.2).is_none());
+    }
+}
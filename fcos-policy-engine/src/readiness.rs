@@ -0,0 +1,232 @@
+//! Service readiness, and admission of graph requests during warm-up.
+//!
+//! The service starts warming up, and becomes ready once a graph has been
+//! successfully obtained or the warm-up period has elapsed. With the `all`
+//! criterion, a graph must have been obtained for each allowed scope
+//! instead (falling back to any scope without an allowlist). Optionally,
+//! while warming up only one graph request at a time is let through to
+//! fetch from upstream; the others are turned away with a `Retry-After`
+//! hint instead of queuing behind a possibly slow upstream.
+
+use crate::clock::Clock;
+use crate::config::ReadinessCriterion;
+use crate::settings::WarmupSettings;
+use chrono::{DateTime, Utc};
+use commons::graph::GraphScope;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Readiness state of the service.
+#[derive(Debug)]
+pub(crate) struct Readiness {
+    settings: WarmupSettings,
+    clock: Arc<dyn Clock>,
+    started: DateTime<Utc>,
+    ready: AtomicBool,
+    probing: AtomicBool,
+    /// Scopes for which a graph has been obtained.
+    served: Mutex<HashSet<GraphScope>>,
+}
+
+/// Marker of the in-flight warm-up request, released on drop.
+#[derive(Debug)]
+pub(crate) struct ProbeGuard<'a> {
+    probing: &'a AtomicBool,
+}
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        self.probing.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Readiness {
+    pub(crate) fn new(settings: &WarmupSettings, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            settings: settings.clone(),
+            started: clock.now(),
+            clock,
+            ready: AtomicBool::new(false),
+            probing: AtomicBool::new(false),
+            served: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether the service is ready, i.e. a graph has been successfully
+    /// obtained or the warm-up period has elapsed.
+    pub(crate) fn is_ready(&self) -> bool {
+        if self.ready.load(Ordering::SeqCst) {
+            return true;
+        }
+        let elapsed = self
+            .clock
+            .now()
+            .signed_duration_since(self.started)
+            .to_std()
+            .unwrap_or_default();
+        if elapsed >= self.settings.timeout {
+            log::info!("warm-up period elapsed, service ready");
+            self.ready.store(true, Ordering::SeqCst);
+            return true;
+        }
+        false
+    }
+
+    /// Mark the service ready right away, e.g. when no graph is served.
+    pub(crate) fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Record that a graph has been successfully obtained for a scope,
+    /// out of the allowed ones (`None` allowing all scopes).
+    pub(crate) fn mark_ready(&self, scope: &GraphScope, allowed: Option<&HashSet<GraphScope>>) {
+        let mut served = self.served.lock().unwrap_or_else(|e| e.into_inner());
+        served.insert(scope.clone());
+        let ready = match (self.settings.criterion, allowed) {
+            (ReadinessCriterion::All, Some(allowed)) => allowed.is_subset(&served),
+            _ => true,
+        };
+        if ready && !self.ready.swap(true, Ordering::SeqCst) {
+            log::info!("graph obtained for all required scopes, service ready");
+        }
+    }
+
+    /// Readiness of each allowed scope, i.e. whether a graph has been
+    /// obtained for it, in stable order.
+    pub(crate) fn scopes_status(&self, allowed: &HashSet<GraphScope>) -> BTreeMap<String, bool> {
+        let served = self.served.lock().unwrap_or_else(|e| e.into_inner());
+        allowed
+            .iter()
+            .map(|scope| {
+                let name = format!("{}/{}", scope.stream, scope.basearch);
+                (name, served.contains(scope))
+            })
+            .collect()
+    }
+
+    /// Decide whether a graph request can proceed.
+    ///
+    /// While warming up with rejections enabled, the single admitted request
+    /// holds a probe guard until it completes; other requests are rejected
+    /// with the retry delay, in seconds.
+    pub(crate) fn admit(&self) -> Result<Option<ProbeGuard<'_>>, u64> {
+        if !self.settings.reject_requests || self.is_ready() {
+            return Ok(None);
+        }
+        match self
+            .probing
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => Ok(Some(ProbeGuard {
+                probing: &self.probing,
+            })),
+            Err(_) => Err(self.settings.retry_after.as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    fn scope(stream: &str) -> GraphScope {
+        GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_readiness_warmup() {
+        let settings = WarmupSettings {
+            timeout: Duration::from_secs(60),
+            reject_requests: true,
+            retry_after: Duration::from_secs(5),
+            criterion: ReadinessCriterion::Any,
+        };
+        let clock = Arc::new(MockClock::at(Utc::now()));
+        let readiness = Readiness::new(&settings, clock.clone());
+        assert!(!readiness.is_ready());
+
+        // A single probe at a time, others are turned away.
+        let probe = readiness.admit().unwrap();
+        assert!(probe.is_some());
+        assert_eq!(readiness.admit().unwrap_err(), 5);
+        drop(probe);
+        assert!(readiness.admit().unwrap().is_some());
+
+        readiness.mark_ready(&scope("stable"), None);
+        assert!(readiness.is_ready());
+        assert!(readiness.admit().unwrap().is_none());
+
+        // Readiness is also reached on warm-up timeout.
+        let readiness = Readiness::new(&settings, clock.clone());
+        clock.advance(Duration::from_secs(59));
+        assert!(!readiness.is_ready());
+        clock.advance(Duration::from_secs(1));
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn test_readiness_all_scopes() {
+        let settings = WarmupSettings {
+            criterion: ReadinessCriterion::All,
+            ..WarmupSettings::default()
+        };
+        let readiness = Readiness::new(&settings, Arc::new(MockClock::at(Utc::now())));
+        let allowed: HashSet<_> = vec![scope("stable"), scope("testing")]
+            .into_iter()
+            .collect();
+
+        readiness.mark_ready(&scope("stable"), Some(&allowed));
+        assert!(!readiness.is_ready());
+        let status: Vec<_> = readiness.scopes_status(&allowed).into_iter().collect();
+        assert_eq!(
+            status,
+            vec![
+                ("stable/x86_64".to_string(), true),
+                ("testing/x86_64".to_string(), false),
+            ]
+        );
+
+        // Graphs for other scopes do not count.
+        readiness.mark_ready(&scope("next"), Some(&allowed));
+        assert!(!readiness.is_ready());
+        readiness.mark_ready(&scope("testing"), Some(&allowed));
+        assert!(readiness.is_ready());
+
+        // Without an allowlist, any scope will do.
+        let readiness = Readiness::new(&settings, Arc::new(MockClock::at(Utc::now())));
+        readiness.mark_ready(&scope("next"), None);
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn test_readiness_no_reject() {
+        let settings = WarmupSettings::default();
+        let readiness = Readiness::new(&settings, Arc::new(MockClock::at(Utc::now())));
+        assert!(!readiness.is_ready());
+        let first = readiness.admit().unwrap();
+        assert!(first.is_none());
+        assert!(readiness.admit().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_readiness_set_ready() {
+        let settings = WarmupSettings {
+            criterion: ReadinessCriterion::All,
+            reject_requests: true,
+            ..WarmupSettings::default()
+        };
+        let readiness = Readiness::new(&settings, Arc::new(MockClock::at(Utc::now())));
+        assert!(!readiness.is_ready());
+        readiness.set_ready();
+        assert!(readiness.is_ready());
+        // Requests are no longer turned away.
+        assert!(readiness.admit().unwrap().is_none());
+        assert!(readiness.admit().unwrap().is_none());
+    }
+}
@@ -0,0 +1,167 @@
+//! Lazily-connected Redis client, shared by Redis-backed features.
+
+use failure::{format_err, Fallible};
+use redis::aio::MultiplexedConnection;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Redis client with a lazily (re-)established multiplexed connection.
+///
+/// Each operation is bounded by a timeout. On errors the connection is
+/// dropped, so that the next operation reconnects.
+pub(crate) struct RedisConnector {
+    client: redis::Client,
+    conn: Mutex<Option<MultiplexedConnection>>,
+    op_timeout: Duration,
+}
+
+impl std::fmt::Debug for RedisConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RedisConnector")
+            .field("op_timeout", &self.op_timeout)
+            .finish()
+    }
+}
+
+impl RedisConnector {
+    /// Build a client for the given URL. No connection is established yet.
+    pub(crate) fn new(url: &str, op_timeout: Duration) -> Fallible<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            client,
+            conn: Mutex::new(None),
+            op_timeout,
+        })
+    }
+
+    /// Run a command, bounded by the operation timeout.
+    pub(crate) async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> Fallible<T> {
+        let res = async {
+            let mut conn = self.connection().await?;
+            self.timed(cmd.query_async(&mut conn)).await
+        }
+        .await;
+        if res.is_err() {
+            self.reset();
+        }
+        res
+    }
+
+    /// Return the current connection, establishing a new one if needed.
+    async fn connection(&self) -> Fallible<MultiplexedConnection> {
+        let existing = self.conn.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some(conn) = existing {
+            return Ok(conn);
+        }
+
+        let conn = self
+            .timed(self.client.get_multiplexed_tokio_connection())
+            .await?;
+        let mut guard = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        *guard = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Drop the current connection.
+    fn reset(&self) {
+        let mut guard = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        *guard = None;
+    }
+
+    async fn timed<T>(
+        &self,
+        op: impl std::future::Future<Output = redis::RedisResult<T>>,
+    ) -> Fallible<T> {
+        match actix_rt::time::timeout(self.op_timeout, op).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(format_err!(
+                "operation timed out after {}ms",
+                self.op_timeout.as_millis()
+            )),
+        }
+    }
+}
+
+/// Minimal in-memory Redis server for tests.
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::collections::{HashMap, HashSet};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// Start a fake server supporting `GET`, `SET`, `PFADD` and `PFCOUNT`
+    /// (the latter as an exact count), returning its URL.
+    pub(crate) fn start() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let store = Arc::new(Mutex::new(Store::default()));
+            for stream in listener.incoming() {
+                let store = Arc::clone(&store);
+                std::thread::spawn(move || serve(stream.unwrap(), &store));
+            }
+        });
+        format!("redis://{}", addr)
+    }
+
+    /// URL of a closed port, where no server is listening.
+    pub(crate) fn unavailable() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("redis://{}", listener.local_addr().unwrap())
+    }
+
+    #[derive(Default)]
+    struct Store {
+        strings: HashMap<String, String>,
+        sets: HashMap<String, HashSet<String>>,
+    }
+
+    fn serve(stream: TcpStream, store: &Mutex<Store>) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut read_line = || {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line.trim_end().to_string()),
+            }
+        };
+        while let Some(header) = read_line() {
+            let count: usize = header.trim_start_matches('*').parse().unwrap();
+            let args: Vec<String> = (0..count)
+                .map(|_| {
+                    read_line().unwrap();
+                    read_line().unwrap()
+                })
+                .collect();
+            let mut store = store.lock().unwrap();
+            let reply = match args[0].as_str() {
+                "GET" => match store.strings.get(&args[1]) {
+                    Some(v) => format!("${}\r\n{}\r\n", v.len(), v),
+                    None => "$-1\r\n".to_string(),
+                },
+                "SET" => {
+                    store.strings.insert(args[1].clone(), args[2].clone());
+                    "+OK\r\n".to_string()
+                }
+                "DEL" => {
+                    let removed = store.strings.remove(&args[1]).is_some();
+                    format!(":{}\r\n", removed as u8)
+                }
+                "PFADD" => {
+                    let set = store.sets.entry(args[1].clone()).or_default();
+                    let before = set.len();
+                    set.extend(args[2..].iter().cloned());
+                    format!(":{}\r\n", (set.len() > before) as u8)
+                }
+                "PFCOUNT" => {
+                    let count = store.sets.get(&args[1]).map(|s| s.len()).unwrap_or(0);
+                    format!(":{}\r\n", count)
+                }
+                _ => "-ERR unsupported\r\n".to_string(),
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+        }
+    }
+}
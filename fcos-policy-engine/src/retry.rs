@@ -0,0 +1,101 @@
+//! Retry budget for upstream requests.
+//!
+//! Retries are only allowed as long as they stay within a fraction of the
+//! total requests observed in the current time window (plus a small fixed
+//! allowance). This prevents retry amplification towards the upstream when
+//! the failure rate is high.
+
+use crate::settings::RetrySettings;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Shared retry budget, accounted over fixed time windows.
+#[derive(Debug)]
+pub(crate) struct RetryBudget {
+    ratio: f64,
+    min_retries: u64,
+    window: Duration,
+    state: Mutex<BudgetWindow>,
+}
+
+/// Accounting for the current budget window.
+#[derive(Debug)]
+struct BudgetWindow {
+    start: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(settings: &RetrySettings) -> Self {
+        Self {
+            ratio: settings.budget_ratio,
+            min_retries: settings.budget_min_retries,
+            window: settings.budget_window,
+            state: Mutex::new(BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    /// Record an incoming (non-retry) request.
+    pub(crate) fn record_request(&self) {
+        let mut state = self.current_window();
+        state.requests = state.requests.saturating_add(1);
+    }
+
+    /// Try to withdraw a retry from the budget, returning whether it is allowed.
+    pub(crate) fn try_retry(&self) -> bool {
+        let mut state = self.current_window();
+        let allowed = (state.requests as f64 * self.ratio) as u64 + self.min_retries;
+        if state.retries >= allowed {
+            return false;
+        }
+        state.retries += 1;
+        true
+    }
+
+    /// Return the accounting for the current window, starting a new one if expired.
+    fn current_window(&self) -> MutexGuard<'_, BudgetWindow> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.start.elapsed() >= self.window {
+            *state = BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_budget() {
+        let settings = RetrySettings {
+            max_retries: 3,
+            budget_ratio: 0.5,
+            budget_min_retries: 1,
+            budget_window: Duration::from_secs(3600),
+            ..RetrySettings::default()
+        };
+        let budget = RetryBudget::new(&settings);
+
+        // Only the fixed allowance is available without requests.
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        // Half of the requests can be retried on top of it.
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+    }
+}
@@ -0,0 +1,123 @@
+//! Time-of-day windows for rollout advancement.
+
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
+use failure::{format_err, Fallible, ResultExt};
+
+/// Daily time window during which rollouts are allowed to advance.
+///
+/// Windows with an end before their start span across midnight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RolloutWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+}
+
+impl RolloutWindow {
+    /// Parse a window from `HH:MM` times and an optional `±HH:MM` UTC offset.
+    pub(crate) fn parse(start: &str, end: &str, utc_offset: Option<&str>) -> Fallible<Self> {
+        let parse_time = |input: &str| {
+            NaiveTime::parse_from_str(input, "%H:%M")
+                .with_context(|_| format!("invalid rollout window time '{}'", input))
+        };
+        let start = parse_time(start)?;
+        let end = parse_time(end)?;
+        let offset = match utc_offset {
+            Some(input) => parse_offset(input)?,
+            None => FixedOffset::east_opt(0).expect("valid UTC offset"),
+        };
+        if start == end {
+            return Err(format_err!("empty rollout window at {}", start));
+        }
+
+        Ok(Self { start, end, offset })
+    }
+
+    /// Whether the given instant falls within this window.
+    pub(crate) fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.offset).time();
+        if self.start < self.end {
+            local >= self.start && local < self.end
+        } else {
+            local >= self.start || local < self.end
+        }
+    }
+}
+
+/// Whether rollouts are allowed to advance at the given instant.
+///
+/// Without any configured window, rollouts always advance.
+pub(crate) fn rollouts_allowed(windows: &[RolloutWindow], now: DateTime<Utc>) -> bool {
+    windows.is_empty() || windows.iter().any(|w| w.contains(now))
+}
+
+/// Parse a `±HH:MM` UTC offset.
+fn parse_offset(input: &str) -> Fallible<FixedOffset> {
+    let invalid = || format_err!("invalid rollout window UTC offset '{}'", input);
+    let (sign, rest) = match input.chars().next() {
+        Some('+') => (1, &input[1..]),
+        Some('-') => (-1, &input[1..]),
+        _ => return Err(invalid()),
+    };
+    let mut parts = rest.splitn(2, ':');
+    let hours: i32 = parts
+        .next()
+        .and_then(|h| h.parse().ok())
+        .ok_or_else(invalid)?;
+    let minutes: i32 = parts
+        .next()
+        .and_then(|m| m.parse().ok())
+        .ok_or_else(invalid)?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 3, 15).and_hms(hour, min, sec)
+    }
+
+    #[test]
+    fn test_window_boundaries() {
+        // Business hours in UTC+02:00, i.e. 07:00-15:00 UTC.
+        let window = RolloutWindow::parse("09:00", "17:00", Some("+02:00")).unwrap();
+        assert!(!window.contains(at(6, 59, 59)));
+        assert!(window.contains(at(7, 0, 0)));
+        assert!(window.contains(at(14, 59, 59)));
+        assert!(!window.contains(at(15, 0, 0)));
+
+        // Nightly maintenance window, across midnight.
+        let window = RolloutWindow::parse("22:00", "02:00", None).unwrap();
+        assert!(window.contains(at(23, 0, 0)));
+        assert!(window.contains(at(1, 59, 59)));
+        assert!(!window.contains(at(2, 0, 0)));
+        assert!(!window.contains(at(21, 59, 59)));
+    }
+
+    #[test]
+    fn test_rollouts_allowed() {
+        assert!(rollouts_allowed(&[], at(3, 0, 0)));
+
+        let windows = vec![
+            RolloutWindow::parse("08:00", "10:00", None).unwrap(),
+            RolloutWindow::parse("14:00", "16:00", None).unwrap(),
+        ];
+        assert!(rollouts_allowed(&windows, at(9, 0, 0)));
+        assert!(!rollouts_allowed(&windows, at(12, 0, 0)));
+        assert!(rollouts_allowed(&windows, at(15, 0, 0)));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(RolloutWindow::parse("9am", "17:00", None).is_err());
+        assert!(RolloutWindow::parse("09:00", "09:00", None).is_err());
+        assert!(RolloutWindow::parse("09:00", "17:00", Some("02:00")).is_err());
+        assert!(RolloutWindow::parse("09:00", "17:00", Some("+25:00")).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Rollout progress metrics.
+//!
+//! The progress of releases being rolled out is exported as gauges labeled
+//! by stream and version, refreshed whenever a graph is fetched. Only
+//! releases currently rolling out in some cached graph have a gauge: labels
+//! of releases which are done (or were pulled) are removed, so that the
+//! number of versions stays bounded by the graphs themselves.
+
+use commons::graph::{Graph, GraphScope};
+use commons::policy;
+use prometheus::GaugeVec;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Tracker of releases being rolled out, per scope.
+#[derive(Debug)]
+pub(crate) struct RolloutTracker {
+    gauge: GaugeVec,
+    /// Stream label and versions rolling out, per scope.
+    rollouts: Mutex<HashMap<GraphScope, (String, HashSet<String>)>>,
+}
+
+impl RolloutTracker {
+    /// Tracker exporting progress to the given `(stream, version)` gauge.
+    pub(crate) fn new(gauge: GaugeVec) -> Self {
+        Self {
+            gauge,
+            rollouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the rollouts of a freshly fetched graph, as of `now`.
+    pub(crate) fn update(&self, stream_label: &str, scope: &GraphScope, graph: &Graph, now: i64) {
+        let mut versions = HashSet::new();
+        for release in &graph.nodes {
+            if let Some(progress) = policy::rollout_progress(release, now) {
+                self.gauge
+                    .with_label_values(&[stream_label, &release.version])
+                    .set(progress);
+                versions.insert(release.version.clone());
+            }
+        }
+
+        let mut rollouts = self.rollouts.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = rollouts.insert(scope.clone(), (stream_label.to_string(), versions));
+        let (previous_label, previous_versions) = match previous {
+            Some(previous) => previous,
+            None => return,
+        };
+        // Other basearches of a stream (or other bucketed streams) may still
+        // be rolling out the same versions.
+        let still_rolling_out: HashSet<&String> = rollouts
+            .values()
+            .filter(|(label, _)| *label == previous_label)
+            .flat_map(|(_, versions)| versions)
+            .collect();
+        for version in &previous_versions {
+            if !still_rolling_out.contains(version) {
+                let _ = self.gauge.remove_label_values(&[&previous_label, version]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commons::graph::CincinnatiPayload;
+    use commons::metadata;
+    use prometheus::core::Collector;
+    use prometheus::Opts;
+
+    fn gauge_values(gauge: &GaugeVec) -> Vec<(String, String, f64)> {
+        let mut values: Vec<_> = gauge
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric().to_vec())
+            .map(|metric| {
+                let labels = metric.get_label();
+                (
+                    labels[0].get_value().to_string(),
+                    labels[1].get_value().to_string(),
+                    metric.get_gauge().get_value(),
+                )
+            })
+            .collect();
+        values.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        values
+    }
+
+    #[test]
+    fn test_rollout_tracker() {
+        let release = |version: &str, start_value: Option<&str>| {
+            let mut entries = HashMap::new();
+            if let Some(value) = start_value {
+                entries.insert(metadata::ROLLOUT.to_string(), "true".to_string());
+                entries.insert(metadata::START_VALUE.to_string(), value.to_string());
+            }
+            CincinnatiPayload {
+                version: version.to_string(),
+                metadata: entries,
+                payload: String::new(),
+            }
+        };
+        let graph = |nodes| Graph {
+            nodes,
+            edges: vec![],
+        };
+        let scope = |basearch: &str| GraphScope {
+            basearch: basearch.to_string(),
+            stream: "stable".to_string(),
+        };
+        let gauge = GaugeVec::new(
+            Opts::new("test_rollout_progress", "test"),
+            &["stream", "version"],
+        )
+        .unwrap();
+        let tracker = RolloutTracker::new(gauge.clone());
+
+        let x86_64 = graph(vec![
+            release("35.1.0", None),
+            release("35.2.0", Some("0.25")),
+            release("35.3.0", Some("0.5")),
+        ]);
+        tracker.update("stable", &scope("x86_64"), &x86_64, 0);
+        let aarch64 = graph(vec![
+            release("35.1.0", None),
+            release("35.3.0", Some("0.5")),
+        ]);
+        tracker.update("stable", &scope("aarch64"), &aarch64, 0);
+        assert_eq!(
+            gauge_values(&gauge),
+            vec![
+                ("stable".to_string(), "35.2.0".to_string(), 0.25),
+                ("stable".to_string(), "35.3.0".to_string(), 0.5),
+            ]
+        );
+
+        // Finished rollouts are dropped, once no basearch has them anymore.
+        let x86_64 = graph(vec![release("35.2.0", None), release("35.3.0", None)]);
+        tracker.update("stable", &scope("x86_64"), &x86_64, 0);
+        assert_eq!(
+            gauge_values(&gauge),
+            vec![("stable".to_string(), "35.3.0".to_string(), 0.5)]
+        );
+        tracker.update("stable", &scope("aarch64"), &graph(vec![]), 0);
+        assert!(gauge_values(&gauge).is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+//! Bounded tracking of requested graph scopes.
+
+use commons::graph::GraphScope;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Label used for scopes beyond the tracking limit, and for scope values
+/// which cannot be classified without an allowlist.
+pub(crate) static OVERFLOW_LABEL: &str = "other";
+
+/// Tracker of distinct (validated) scopes requested by clients.
+///
+/// Scope values are client-controlled, thus only up to `max_scopes` distinct
+/// scopes are tracked; further ones are bucketed as `other`.
+#[derive(Debug)]
+pub(crate) struct ScopeTracker {
+    max_scopes: usize,
+    seen: Mutex<HashSet<GraphScope>>,
+}
+
+impl ScopeTracker {
+    pub(crate) fn new(max_scopes: usize) -> Self {
+        Self {
+            max_scopes,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Record a requested scope, returning its `(stream, basearch)` metric labels.
+    pub(crate) fn observe(&self, scope: &GraphScope) -> (String, String) {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.contains(scope) || seen.len() < self.max_scopes {
+            seen.insert(scope.clone());
+            (scope.stream.clone(), scope.basearch.clone())
+        } else {
+            (OVERFLOW_LABEL.to_string(), OVERFLOW_LABEL.to_string())
+        }
+    }
+
+    /// Number of distinct scopes tracked so far.
+    pub(crate) fn len(&self) -> usize {
+        let seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.len()
+    }
+}
+
+/// Classify requested stream and basearch values against the allowed
+/// scopes, returning their `(stream, basearch)` validity metric labels.
+///
+/// A value is `valid` if some allowed scope has it, and `invalid` if missing,
+/// empty or not allowed. Without an allowlist, non-empty values are `other`.
+pub(crate) fn validity_labels(
+    allowed: &Option<HashSet<GraphScope>>,
+    stream: Option<&str>,
+    basearch: Option<&str>,
+) -> (&'static str, &'static str) {
+    let label = |value: Option<&str>, field: fn(&GraphScope) -> &str| match (value, allowed) {
+        (None, _) | (Some(""), _) => "invalid",
+        (Some(_), None) => OVERFLOW_LABEL,
+        (Some(value), Some(scopes)) if scopes.iter().any(|scope| field(scope) == value) => "valid",
+        (Some(_), Some(_)) => "invalid",
+    };
+    (
+        label(stream, |scope| &scope.stream),
+        label(basearch, |scope| &scope.basearch),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_tracker_overflow() {
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+        let tracker = ScopeTracker::new(2);
+
+        assert_eq!(
+            tracker.observe(&scope("stable")),
+            ("stable".to_string(), "x86_64".to_string())
+        );
+        assert_eq!(tracker.observe(&scope("testing")).0, "testing");
+        assert_eq!(tracker.observe(&scope("tpyo")).0, OVERFLOW_LABEL);
+        // Already tracked scopes keep their own labels.
+        assert_eq!(tracker.observe(&scope("stable")).0, "stable");
+        assert_eq!(tracker.len(), 2);
+    }
+
+    #[test]
+    fn test_validity_labels() {
+        let allowed: HashSet<_> = vec![GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        }]
+        .into_iter()
+        .collect();
+        let allowed = Some(allowed);
+
+        assert_eq!(
+            validity_labels(&allowed, Some("stable"), Some("x86_64")),
+            ("valid", "valid")
+        );
+        assert_eq!(
+            validity_labels(&allowed, Some("stabel"), Some("x86_64")),
+            ("invalid", "valid")
+        );
+        assert_eq!(
+            validity_labels(&allowed, None, Some("")),
+            ("invalid", "invalid")
+        );
+        assert_eq!(
+            validity_labels(&None, Some("stable"), Some("")),
+            (OVERFLOW_LABEL, "invalid")
+        );
+    }
+}
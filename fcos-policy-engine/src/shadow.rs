@@ -0,0 +1,213 @@
+//! Shadow policies, compared to the served ones on real traffic.
+//!
+//! In shadow mode, each graph query is also processed with a candidate set
+//! of policies. Only the outcome of the current policies is served, while
+//! divergences are counted and optionally logged, so that the impact of a
+//! policy change can be assessed before switching to it.
+
+use crate::pipeline::ProcessedGraph;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use commons::errors::PeError;
+use commons::graph::{CincinnatiPayload, Graph};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Maximum number of listed versions (or edges) per kind of difference.
+const MAX_LISTED: usize = 5;
+
+/// Outcome of a policy chain, as compared: a graph or an error status.
+pub(crate) fn outcome(processed: &Result<ProcessedGraph, PeError>) -> Result<&Graph, StatusCode> {
+    match processed {
+        Ok(processed) => Ok(&processed.graph),
+        Err(e) => Err(e.status_code()),
+    }
+}
+
+/// Describe how the candidate outcome diverges from the served one, if at all.
+pub(crate) fn divergence(
+    served: Result<&Graph, StatusCode>,
+    candidate: Result<&Graph, StatusCode>,
+) -> Option<String> {
+    match (served, candidate) {
+        (Ok(served), Ok(candidate)) => {
+            let diff = GraphDiff::new(served, candidate);
+            if diff.is_empty() {
+                None
+            } else {
+                Some(diff.to_string())
+            }
+        }
+        (Err(served), Err(candidate)) if served == candidate => None,
+        (Err(served), Err(candidate)) => Some(format!(
+            "candidate error '{}' instead of error '{}'",
+            candidate, served
+        )),
+        (Ok(_), Err(candidate)) => Some(format!(
+            "candidate error '{}' instead of a graph",
+            candidate
+        )),
+        (Err(served), Ok(_)) => Some(format!("candidate graph instead of error '{}'", served)),
+    }
+}
+
+/// Differences between two graphs, in terms of release versions.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct GraphDiff {
+    added_nodes: Vec<String>,
+    removed_nodes: Vec<String>,
+    /// Releases in both graphs, with a different payload or metadata.
+    changed_nodes: Vec<String>,
+    added_edges: Vec<(String, String)>,
+    removed_edges: Vec<(String, String)>,
+}
+
+impl GraphDiff {
+    fn new(served: &Graph, candidate: &Graph) -> Self {
+        let served_nodes = nodes_by_version(served);
+        let candidate_nodes = nodes_by_version(candidate);
+        let mut diff = Self::default();
+        for (version, node) in &candidate_nodes {
+            match served_nodes.get(version) {
+                None => diff.added_nodes.push(version.to_string()),
+                Some(served) => {
+                    if served.payload != node.payload || served.metadata != node.metadata {
+                        diff.changed_nodes.push(version.to_string());
+                    }
+                }
+            }
+        }
+        for version in served_nodes.keys() {
+            if !candidate_nodes.contains_key(version) {
+                diff.removed_nodes.push(version.to_string());
+            }
+        }
+
+        let served_edges = edges_by_version(served);
+        let candidate_edges = edges_by_version(candidate);
+        diff.added_edges = candidate_edges
+            .difference(&served_edges)
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        diff.removed_edges = served_edges
+            .difference(&candidate_edges)
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let edges = |edges: &[(String, String)]| -> Vec<String> {
+            edges
+                .iter()
+                .map(|(from, to)| format!("{}->{}", from, to))
+                .collect()
+        };
+        let kinds = [
+            ("added nodes", self.added_nodes.clone()),
+            ("removed nodes", self.removed_nodes.clone()),
+            ("changed nodes", self.changed_nodes.clone()),
+            ("added edges", edges(&self.added_edges)),
+            ("removed edges", edges(&self.removed_edges)),
+        ];
+        let mut separator = "";
+        for (kind, entries) in kinds.iter().filter(|(_, entries)| !entries.is_empty()) {
+            write!(
+                f,
+                "{}{} {} [{}",
+                separator,
+                entries.len(),
+                kind,
+                entries[..entries.len().min(MAX_LISTED)].join(", ")
+            )?;
+            if entries.len() > MAX_LISTED {
+                write!(f, ", ...")?;
+            }
+            write!(f, "]")?;
+            separator = "; ";
+        }
+        Ok(())
+    }
+}
+
+/// Releases of a graph, keyed by version.
+fn nodes_by_version(graph: &Graph) -> BTreeMap<&str, &CincinnatiPayload> {
+    graph
+        .nodes
+        .iter()
+        .map(|node| (node.version.as_str(), node))
+        .collect()
+}
+
+/// Edges of a graph, as pairs of release versions.
+fn edges_by_version(graph: &Graph) -> BTreeSet<(&str, &str)> {
+    graph
+        .edges
+        .iter()
+        .filter_map(|(from, to)| {
+            let from = graph.nodes.get(*from as usize)?;
+            let to = graph.nodes.get(*to as usize)?;
+            Some((from.version.as_str(), to.version.as_str()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn graph(versions: &[&str], edges: &[(u64, u64)]) -> Graph {
+        Graph {
+            nodes: versions
+                .iter()
+                .map(|version| CincinnatiPayload {
+                    version: version.to_string(),
+                    metadata: HashMap::new(),
+                    payload: format!("quay.io/fcos:{}", version),
+                })
+                .collect(),
+            edges: edges.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_divergence() {
+        let served = graph(&["1", "2", "3"], &[(0, 1), (1, 2)]);
+        assert_eq!(divergence(Ok(&served), Ok(&served)), None);
+
+        // Same releases and edges, in a different order.
+        let reordered = graph(&["3", "1", "2"], &[(2, 0), (1, 2)]);
+        assert_eq!(divergence(Ok(&served), Ok(&reordered)), None);
+
+        let mut candidate = graph(&["1", "2", "4"], &[(0, 1), (0, 2)]);
+        candidate.nodes[1].payload = "quay.io/other:2".to_string();
+        assert_eq!(
+            divergence(Ok(&served), Ok(&candidate)).unwrap(),
+            "1 added nodes [4]; 1 removed nodes [3]; 1 changed nodes [2]; \
+             1 added edges [1->4]; 1 removed edges [2->3]"
+        );
+
+        let bad_request = StatusCode::BAD_REQUEST;
+        assert_eq!(divergence(Err(bad_request), Err(bad_request)), None);
+        assert!(divergence(Ok(&served), Err(bad_request)).is_some());
+        assert!(divergence(Err(bad_request), Ok(&served)).is_some());
+    }
+
+    #[test]
+    fn test_divergence_truncated() {
+        let versions: Vec<String> = (0..8).map(|v| v.to_string()).collect();
+        let versions: Vec<&str> = versions.iter().map(String::as_str).collect();
+        let candidate = graph(&versions, &[]);
+        assert_eq!(
+            divergence(Ok(&Graph::default()), Ok(&candidate)).unwrap(),
+            "8 added nodes [0, 1, 2, 3, 4, ...]"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Optional graph cache shared across replicas, backed by Redis.
+//!
+//! The shared cache is best-effort: any Redis failure is logged and
+//! bypassed, falling back to the in-process cache and upstream.
+
+use crate::redis_conn::RedisConnector;
+use crate::settings::SharedCacheSettings;
+use commons::graph::{Graph, GraphScope};
+use failure::Fallible;
+use std::time::Duration;
+
+/// Redis-backed cache of upstream graphs, keyed by scope.
+#[derive(Debug)]
+pub(crate) struct SharedCache {
+    redis: RedisConnector,
+    key_prefix: String,
+    ttl: Duration,
+}
+
+impl SharedCache {
+    /// Build a shared cache client. Connection is established lazily.
+    pub(crate) fn new(settings: &SharedCacheSettings, ttl: Duration) -> Fallible<Self> {
+        Ok(Self {
+            redis: RedisConnector::new(&settings.url, settings.op_timeout)?,
+            key_prefix: settings.key_prefix.clone(),
+            ttl,
+        })
+    }
+
+    /// Redis key for a scope.
+    fn key(&self, scope: &GraphScope) -> String {
+        format!("{}:{}:{}", self.key_prefix, scope.stream, scope.basearch)
+    }
+
+    /// Get a graph for a scope, bypassing the shared cache on errors.
+    pub(crate) async fn get(&self, scope: &GraphScope) -> Option<Graph> {
+        let (result, graph) = match self.try_get(scope).await {
+            Ok(Some(graph)) => ("hit", Some(graph)),
+            Ok(None) => ("miss", None),
+            Err(e) => {
+                log::warn!("bypassing shared cache on read: {}", e);
+                ("error", None)
+            }
+        };
+        crate::metrics::SHARED_CACHE_REQUESTS
+            .with_label_values(&["get", result])
+            .inc();
+        graph
+    }
+
+    /// Store a graph for a scope, ignoring (but logging) errors.
+    pub(crate) async fn insert(&self, scope: &GraphScope, graph: &Graph) {
+        let result = match self.try_insert(scope, graph).await {
+            Ok(_) => "success",
+            Err(e) => {
+                log::warn!("bypassing shared cache on write: {}", e);
+                "error"
+            }
+        };
+        crate::metrics::SHARED_CACHE_REQUESTS
+            .with_label_values(&["set", result])
+            .inc();
+    }
+
+    /// Remove the graph of a scope, ignoring (but logging) errors.
+    pub(crate) async fn remove(&self, scope: &GraphScope) {
+        let cmd = redis::cmd("DEL").arg(self.key(scope)).to_owned();
+        let result = match self.redis.query::<()>(&cmd).await {
+            Ok(_) => "success",
+            Err(e) => {
+                log::warn!("failed to remove shared cache entry: {}", e);
+                "error"
+            }
+        };
+        crate::metrics::SHARED_CACHE_REQUESTS
+            .with_label_values(&["del", result])
+            .inc();
+    }
+
+    async fn try_get(&self, scope: &GraphScope) -> Fallible<Option<Graph>> {
+        let cmd = redis::cmd("GET").arg(self.key(scope)).to_owned();
+        let value: Option<String> = self.redis.query(&cmd).await?;
+        match value {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn try_insert(&self, scope: &GraphScope, graph: &Graph) -> Fallible<()> {
+        let json = serde_json::to_string(graph)?;
+        let cmd = redis::cmd("SET")
+            .arg(self.key(scope))
+            .arg(json)
+            .arg("EX")
+            .arg(self.ttl.as_secs().max(1))
+            .to_owned();
+        self.redis.query::<()>(&cmd).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_conn::fake;
+
+    fn settings(url: String) -> SharedCacheSettings {
+        SharedCacheSettings {
+            url,
+            key_prefix: "test".to_string(),
+            op_timeout: Duration::from_millis(500),
+        }
+    }
+
+    fn scope() -> GraphScope {
+        GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_cache_roundtrip() {
+        let cache = SharedCache::new(&settings(fake::start()), Duration::from_secs(30)).unwrap();
+        assert_eq!(cache.key(&scope()), "test:stable:x86_64");
+
+        assert!(cache.get(&scope()).await.is_none());
+        let graph = Graph {
+            nodes: vec![],
+            edges: vec![(0, 1)],
+        };
+        cache.insert(&scope(), &graph).await;
+        let cached = cache.get(&scope()).await.unwrap();
+        assert_eq!(cached.edges, vec![(0, 1)]);
+
+        cache.remove(&scope()).await;
+        assert!(cache.get(&scope()).await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_cache_unavailable() {
+        // Errors are bypassed.
+        let cache =
+            SharedCache::new(&settings(fake::unavailable()), Duration::from_secs(30)).unwrap();
+        cache.insert(&scope(), &Graph::default()).await;
+        assert!(cache.get(&scope()).await.is_none());
+    }
+}
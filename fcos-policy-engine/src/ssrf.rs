@@ -0,0 +1,227 @@
+//! Protection against server-side request forgery via upstream URLs.
+//!
+//! Upstream URLs are operator-configured, but may be templated or supplied
+//! by tenants of a shared control plane. When the guard is enabled, upstream
+//! requests are refused if the upstream host resolves to a loopback,
+//! link-local, unspecified or cloud metadata address (e.g. 169.254.169.254),
+//! unless the host or address is explicitly allowed.
+//!
+//! Hosts are resolved right before each request, and the HTTP client then
+//! resolves them again: this catches misconfigurations and stale templates,
+//! but is not a complete defense against DNS rebinding. Redirects cannot be
+//! resolved before being followed, and are only allowed to IP literal or
+//! explicitly allowed hosts.
+
+use crate::settings::SsrfGuardSettings;
+use failure::{bail, format_err, Fallible};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+
+/// Cloud metadata service addresses outside of the link-local ranges.
+const METADATA_ADDRESSES: [IpAddr; 1] = [
+    // AWS instance metadata service, over IPv6.
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0x0ec2, 0, 0, 0, 0, 0, 0x0254)),
+];
+
+/// Whether an address is internal to the host or its network link, and
+/// must not be reached through upstream URLs by default.
+pub(crate) fn is_internal_address(ip: &IpAddr) -> bool {
+    if METADATA_ADDRESSES.contains(ip) {
+        return true;
+    }
+    match ip {
+        IpAddr::V4(ip) => is_internal_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ipv4_mapped(ip) {
+                return is_internal_ipv4(&mapped);
+            }
+            // Link-local unicast, fe80::/10.
+            let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+            ip.is_loopback() || ip.is_unspecified() || link_local
+        }
+    }
+}
+
+fn is_internal_ipv4(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+/// IPv4 address of an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`).
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::new(
+            (hi >> 8) as u8,
+            hi as u8,
+            (lo >> 8) as u8,
+            lo as u8,
+        )),
+        _ => None,
+    }
+}
+
+/// IP address of a URL host, if it is an IP literal.
+fn literal_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+fn check_ip(url: &reqwest::Url, ip: &IpAddr, guard: &SsrfGuardSettings) -> Fallible<()> {
+    if is_internal_address(ip) && !guard.allowed_ips.contains(ip) {
+        bail!(
+            "refused upstream request to '{}': internal address {}",
+            url,
+            ip
+        );
+    }
+    Ok(())
+}
+
+/// Check an upstream URL without resolving its host: only IP literal hosts
+/// are checked, others are always accepted.
+pub(crate) fn check_literal(url: &reqwest::Url, guard: &SsrfGuardSettings) -> Fallible<()> {
+    if !guard.enabled {
+        return Ok(());
+    }
+    match url.host_str().and_then(literal_ip) {
+        Some(ip) => check_ip(url, &ip, guard),
+        None => Ok(()),
+    }
+}
+
+/// Check the target of an upstream redirect.
+///
+/// Redirects are followed by the HTTP client without resolving their target
+/// first: hosts which are neither IP literals nor explicitly allowed are
+/// refused, as they may resolve to internal addresses.
+pub(crate) fn check_redirect(url: &reqwest::Url, guard: &SsrfGuardSettings) -> Fallible<()> {
+    if !guard.enabled {
+        return Ok(());
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| format_err!("refused upstream redirect to '{}': missing host", url))?;
+    if let Some(ip) = literal_ip(host) {
+        return check_ip(url, &ip, guard);
+    }
+    if !guard.allowed_hosts.contains(&host.to_ascii_lowercase()) {
+        bail!(
+            "refused upstream redirect to '{}': host '{}' not allowed",
+            url,
+            host
+        );
+    }
+    Ok(())
+}
+
+/// Check an upstream URL, resolving its host.
+pub(crate) async fn check_target(url: &reqwest::Url, guard: &SsrfGuardSettings) -> Fallible<()> {
+    if !guard.enabled {
+        return Ok(());
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| format_err!("refused upstream request to '{}': missing host", url))?;
+    if let Some(ip) = literal_ip(host) {
+        return check_ip(url, &ip, guard);
+    }
+    let host = host.to_ascii_lowercase();
+    if guard.allowed_hosts.contains(&host) {
+        return Ok(());
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = actix_web::web::block(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+    })
+    .await
+    .map_err(|e| format_err!("failed to resolve upstream host of '{}': {}", url, e))?;
+    for addr in addrs {
+        check_ip(url, &addr.ip(), guard)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_internal_address() {
+        for internal in &[
+            "127.0.0.1",
+            "127.1.2.3",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00:ec2::254",
+            "::ffff:169.254.169.254",
+            "::ffff:127.0.0.1",
+        ] {
+            let ip: IpAddr = internal.parse().unwrap();
+            assert!(is_internal_address(&ip), "{}", internal);
+        }
+        for external in &["192.0.2.1", "10.0.0.1", "2001:db8::1", "fd00::1", "fec0::1"] {
+            let ip: IpAddr = external.parse().unwrap();
+            assert!(!is_internal_address(&ip), "{}", external);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_check_target() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        let mut guard = SsrfGuardSettings::default();
+
+        // Disabled by default.
+        check_target(&url("http://169.254.169.254/latest"), &guard)
+            .await
+            .unwrap();
+
+        guard.enabled = true;
+        for blocked in &[
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8080/v1/graph",
+            "http://[::1]:8080/v1/graph",
+            "http://[::ffff:a9fe:a9fe]/",
+            "http://localhost:8080/v1/graph",
+        ] {
+            let err = check_target(&url(blocked), &guard).await.unwrap_err();
+            assert!(err.to_string().contains("internal address"), "{}", blocked);
+        }
+        check_target(&url("http://192.0.2.1/v1/graph"), &guard)
+            .await
+            .unwrap();
+        check_literal(&url("http://[::1]:8080/v1/graph"), &guard).unwrap_err();
+        // Hostnames are only checked once resolved.
+        check_literal(&url("http://localhost:8080/v1/graph"), &guard).unwrap();
+        // Redirect targets are not resolved: hostnames must be allowed.
+        for refused in &[
+            "http://localhost:8080/v1/graph",
+            "http://metadata.google.internal/computeMetadata/v1/",
+            "http://example.com/v1/graph",
+            "http://[::1]:8080/v1/graph",
+        ] {
+            check_redirect(&url(refused), &guard).unwrap_err();
+        }
+        check_redirect(&url("http://192.0.2.1/v1/graph"), &guard).unwrap();
+
+        guard.allowed_hosts.insert("localhost".to_string());
+        guard.allowed_ips.insert("127.0.0.1".parse().unwrap());
+        check_target(&url("http://LOCALHOST:8080/v1/graph"), &guard)
+            .await
+            .unwrap();
+        check_target(&url("http://127.0.0.1:8080/v1/graph"), &guard)
+            .await
+            .unwrap();
+        check_literal(&url("http://127.0.0.1:8080/v1/graph"), &guard).unwrap();
+        check_redirect(&url("http://LOCALHOST:8080/v1/graph"), &guard).unwrap();
+        check_redirect(&url("http://example.com/v1/graph"), &guard).unwrap_err();
+        check_target(&url("http://169.254.169.254/"), &guard)
+            .await
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,176 @@
+//! Tiered graphs for anonymous and authenticated clients.
+//!
+//! When enabled, graph requests carrying one of the configured tokens (as
+//! `Authorization: Bearer <token>`) get the complete graph, while anonymous
+//! ones get a conservative subset: releases being rolled out are never
+//! offered to them until fully rolled out, and streams can be restricted.
+//!
+//! Tiers are told apart by token only: TLS client certificates, when
+//! required, are verified for all connections, and requests do not carry
+//! TLS session details.
+
+use crate::settings::TiersSettings;
+use actix_web::http::{header, HeaderMap};
+use commons::graph::Graph;
+use commons::policy;
+use failure::{Fallible, ResultExt};
+use std::collections::HashSet;
+
+/// Tier of a graph request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Tier {
+    Anonymous,
+    Authenticated,
+}
+
+impl Tier {
+    /// Metric label of the tier.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Tier::Anonymous => "anonymous",
+            Tier::Authenticated => "authenticated",
+        }
+    }
+}
+
+/// Tokens unlocking complete graphs, with the anonymous tier restrictions.
+#[derive(Debug)]
+pub(crate) struct Tiers {
+    tokens: Vec<String>,
+    anonymous_streams: Option<HashSet<String>>,
+}
+
+impl Tiers {
+    /// Load the tokens. They are only read at startup: rotating them
+    /// requires a restart.
+    pub(crate) fn new(settings: &TiersSettings) -> Fallible<Self> {
+        let content = std::fs::read_to_string(&settings.token_path).with_context(|_| {
+            format!(
+                "failed to read tier tokens file '{}'",
+                settings.token_path.display()
+            )
+        })?;
+        let tokens = content
+            .lines()
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(Self {
+            tokens,
+            anonymous_streams: settings.anonymous_streams.clone(),
+        })
+    }
+
+    /// Tier of a request, from its headers.
+    pub(crate) fn tier(&self, headers: &HeaderMap) -> Tier {
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let provided = match provided {
+            Some(provided) => provided,
+            None => return Tier::Anonymous,
+        };
+        // Compare with all tokens, not to leak which one matched.
+        let matches = self.tokens.iter().fold(false, |matched, token| {
+            let equal =
+                ring::constant_time::verify_slices_are_equal(provided.as_bytes(), token.as_bytes())
+                    .is_ok();
+            matched | equal
+        });
+        if matches {
+            Tier::Authenticated
+        } else {
+            Tier::Anonymous
+        }
+    }
+
+    /// Graph served to anonymous clients, from the complete one of a stream.
+    pub(crate) fn anonymous_graph(&self, graph: Graph, stream: &str) -> Graph {
+        if let Some(streams) = &self.anonymous_streams {
+            if !streams.contains(stream) {
+                return Graph::default();
+            }
+        }
+        // The most wary clients are only offered fully rolled out releases.
+        policy::filter_deadends(policy::throttle_rollouts(graph, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+    use commons::graph::CincinnatiPayload;
+    use commons::metadata;
+    use std::io::Write;
+
+    #[test]
+    fn test_tier() {
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        token_file.write_all(b"first\n\n  second \n").unwrap();
+        let settings = TiersSettings {
+            token_path: token_file.path().to_path_buf(),
+            anonymous_streams: None,
+        };
+        let tiers = Tiers::new(&settings).unwrap();
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        assert_eq!(tiers.tier(&headers("Bearer first")), Tier::Authenticated);
+        assert_eq!(tiers.tier(&headers("Bearer second")), Tier::Authenticated);
+        assert_eq!(tiers.tier(&headers("Bearer ")), Tier::Anonymous);
+        assert_eq!(tiers.tier(&headers("Bearer third")), Tier::Anonymous);
+        assert_eq!(tiers.tier(&headers("Basic first")), Tier::Anonymous);
+        assert_eq!(tiers.tier(&HeaderMap::new()), Tier::Anonymous);
+    }
+
+    #[test]
+    fn test_anonymous_graph() {
+        let node = |version: &str, metadata: &[(&str, &str)]| CincinnatiPayload {
+            version: version.to_string(),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            payload: String::new(),
+        };
+        let graph = Graph {
+            nodes: vec![
+                node("35.1.0", &[]),
+                node(
+                    "35.2.0",
+                    &[(metadata::ROLLOUT, "true"), (metadata::START_VALUE, "1.0")],
+                ),
+                node(
+                    "35.3.0",
+                    &[(metadata::ROLLOUT, "true"), (metadata::START_VALUE, "0.9")],
+                ),
+            ],
+            edges: vec![(0, 1), (0, 2), (1, 2)],
+        };
+        let token_file = tempfile::NamedTempFile::new().unwrap();
+        let mut settings = TiersSettings {
+            token_path: token_file.path().to_path_buf(),
+            anonymous_streams: None,
+        };
+
+        let tiers = Tiers::new(&settings).unwrap();
+        let anonymous = tiers.anonymous_graph(graph.clone(), "testing");
+        // Only the completed rollout is offered.
+        assert_eq!(anonymous.edges, vec![(0, 1)]);
+
+        settings.anonymous_streams = Some(vec!["stable".to_string()].into_iter().collect());
+        let tiers = Tiers::new(&settings).unwrap();
+        assert_eq!(
+            tiers.anonymous_graph(graph.clone(), "stable").edges,
+            vec![(0, 1)]
+        );
+        let other = tiers.anonymous_graph(graph, "testing");
+        assert!(other.nodes.is_empty() && other.edges.is_empty());
+    }
+}
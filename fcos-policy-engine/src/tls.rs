@@ -0,0 +1,508 @@
+//! TLS termination for the main service, negotiating HTTP/2 via ALPN.
+//!
+//! Only TLS 1.2 and 1.3 are supported, with forward-secret AEAD cipher
+//! suites. Deployments can further restrict both the minimum protocol
+//! version and the accepted cipher suites.
+//!
+//! Client certificates can optionally be required (mutual TLS), signed by
+//! configured CAs. The subject of each authenticated (or rejected) client
+//! certificate is logged when connections are established: requests
+//! themselves do not carry TLS session details.
+
+use crate::config::TlsVersion;
+use failure::{bail, ensure, format_err, Fallible, ResultExt};
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+    DistinguishedNames, NoClientAuth, ProtocolVersion, RootCertStore, ServerConfig,
+    SupportedCipherSuite, TLSError, ALL_CIPHERSUITES,
+};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Server-side TLS configuration, loaded from PEM files.
+#[derive(Clone)]
+pub(crate) struct ServerTls {
+    cert_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    config: ServerConfig,
+}
+
+impl std::fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ServerTls")
+            .field("cert_path", &self.cert_path)
+            .field("client_ca_path", &self.client_ca_path)
+            .finish()
+    }
+}
+
+impl ServerTls {
+    /// Load a certificate chain and private key from PEM files.
+    pub(crate) fn load(cert_path: &Path, key_path: &Path) -> Fallible<Self> {
+        let cert_pem = std::fs::read(cert_path).with_context(|_| {
+            format!("failed to read TLS certificate '{}'", cert_path.display())
+        })?;
+        let key_pem = std::fs::read(key_path)
+            .with_context(|_| format!("failed to read TLS key '{}'", key_path.display()))?;
+        let mut tls = Self::from_pem(&cert_pem, &key_pem)
+            .with_context(|_| format!("invalid TLS certificate '{}'", cert_path.display()))?;
+        tls.cert_path = cert_path.to_path_buf();
+        Ok(tls)
+    }
+
+    /// Build a TLS configuration from a PEM certificate chain and a
+    /// PKCS#8 or RSA PEM private key.
+    pub(crate) fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Fallible<Self> {
+        let certs = pemfile::certs(&mut BufReader::new(cert_pem))
+            .map_err(|_| format_err!("malformed PEM certificate chain"))?;
+        if certs.is_empty() {
+            bail!("no certificates found");
+        }
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(key_pem))
+            .map_err(|_| format_err!("malformed PEM private key"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(key_pem))
+                .map_err(|_| format_err!("malformed PEM private key"))?;
+        }
+        let key = match keys.into_iter().next() {
+            Some(key) => key,
+            None => bail!("no private key found"),
+        };
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(certs, key)?;
+        Ok(Self {
+            cert_path: PathBuf::new(),
+            client_ca_path: None,
+            config,
+        })
+    }
+
+    /// Require client certificates signed by one of the CAs of a PEM file.
+    pub(crate) fn load_client_ca(&mut self, ca_path: &Path) -> Fallible<()> {
+        let ca_pem = std::fs::read(ca_path)
+            .with_context(|_| format!("failed to read TLS client CA '{}'", ca_path.display()))?;
+        self.set_client_ca(&ca_pem)
+            .with_context(|_| format!("invalid TLS client CA '{}'", ca_path.display()))?;
+        self.client_ca_path = Some(ca_path.to_path_buf());
+        Ok(())
+    }
+
+    /// Require client certificates signed by one of the CAs of a PEM bundle.
+    pub(crate) fn set_client_ca(&mut self, ca_pem: &[u8]) -> Fallible<()> {
+        let mut roots = RootCertStore::empty();
+        let (valid, invalid) = roots
+            .add_pem_file(&mut BufReader::new(ca_pem))
+            .map_err(|_| format_err!("malformed PEM CA certificates"))?;
+        ensure!(invalid == 0, "{} invalid CA certificate(s)", invalid);
+        ensure!(valid > 0, "no CA certificates found");
+
+        // The client certificate verifier can only be set on creation.
+        let verifier = Arc::new(LoggingClientVerifier {
+            inner: AllowAnyAuthenticatedClient::new(roots),
+        });
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = Arc::clone(&self.config.cert_resolver);
+        config.versions = self.config.versions.clone();
+        config.ciphersuites = self.config.ciphersuites.clone();
+        self.config = config;
+        Ok(())
+    }
+
+    /// Whether client certificates are required.
+    pub(crate) fn requires_client_certs(&self) -> bool {
+        self.config.get_verifier().client_auth_mandatory()
+    }
+
+    /// Restrict the accepted protocol versions and cipher suites (all
+    /// supported ones if unset). Suites are named as in the TLS registry,
+    /// e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub(crate) fn set_policy(
+        &mut self,
+        min_version: TlsVersion,
+        cipher_suites: Option<&[String]>,
+    ) -> Fallible<()> {
+        let versions = match min_version {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        };
+        let suites: Vec<&'static SupportedCipherSuite> = match cipher_suites {
+            Some(names) => names
+                .iter()
+                .map(|name| cipher_suite(name))
+                .collect::<Fallible<_>>()?,
+            None => ALL_CIPHERSUITES.to_vec(),
+        };
+        ensure!(
+            suites
+                .iter()
+                .any(|suite| versions.iter().any(|v| suite.usable_for_version(*v))),
+            "no configured TLS cipher suite is usable with the enabled protocol versions"
+        );
+        self.config.versions = versions;
+        self.config.ciphersuites = suites;
+        Ok(())
+    }
+
+    /// TLS configuration for the server. HTTP/2 and HTTP/1.1 are both
+    /// offered via ALPN when binding.
+    pub(crate) fn server_config(&self) -> ServerConfig {
+        self.config.clone()
+    }
+}
+
+/// Verifier of client certificates, logging the subject of clients.
+struct LoggingClientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientCertVerifier for LoggingClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+    ) -> Result<ClientCertVerified, TLSError> {
+        let subject = presented_certs
+            .first()
+            .and_then(|cert| cert_subject(&cert.0))
+            .unwrap_or_else(|| "<unknown>".to_string());
+        let verified = self.inner.verify_client_cert(presented_certs);
+        match &verified {
+            Ok(_) => log::info!("TLS client authenticated: {}", subject),
+            Err(e) => log::warn!("TLS client certificate rejected ({}): {}", subject, e),
+        }
+        verified
+    }
+}
+
+/// Subject of a DER certificate, as comma-separated attributes in
+/// certificate order (e.g. `CN=node, O=Example`).
+fn cert_subject(der: &[u8]) -> Option<String> {
+    let (_, cert, _) = der_read(der, 0x30)?;
+    let (_, mut tbs, _) = der_read(cert, 0x30)?;
+    // Optional explicit version, then serial number, signature algorithm,
+    // issuer and validity.
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_read(tbs, 0xa0)?.2;
+    }
+    for tag in &[0x02, 0x30, 0x30, 0x30] {
+        tbs = der_read(tbs, *tag)?.2;
+    }
+    let (_, mut rdns, _) = der_read(tbs, 0x30)?;
+
+    let mut attributes = vec![];
+    while !rdns.is_empty() {
+        let (_, mut rdn, rest) = der_read(rdns, 0x31)?;
+        rdns = rest;
+        while !rdn.is_empty() {
+            let (_, attribute, rest) = der_read(rdn, 0x30)?;
+            rdn = rest;
+            let (_, oid, value) = der_read(attribute, 0x06)?;
+            let (_, value, _) = der_read_any(value)?;
+            attributes.push(format!(
+                "{}={}",
+                attribute_name(oid),
+                String::from_utf8_lossy(value)
+            ));
+        }
+    }
+    Some(attributes.join(", "))
+}
+
+/// Short name of a X.520 attribute type, its dotted OID otherwise.
+fn attribute_name(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        _ => {
+            let mut arcs = vec![];
+            let mut arc = 0u64;
+            for byte in oid {
+                arc = (arc << 7) | u64::from(byte & 0x7f);
+                if byte & 0x80 == 0 {
+                    arcs.push(arc);
+                    arc = 0;
+                }
+            }
+            let mut dotted = match arcs.first() {
+                Some(first) if *first < 80 => format!("{}.{}", first / 40, first % 40),
+                Some(first) => format!("2.{}", first - 80),
+                None => return String::new(),
+            };
+            for arc in &arcs[1..] {
+                dotted.push_str(&format!(".{}", arc));
+            }
+            return dotted;
+        }
+    };
+    name.to_string()
+}
+
+/// Read a DER element with the given tag, returning its tag, content and
+/// the following input.
+fn der_read(input: &[u8], tag: u8) -> Option<(u8, &[u8], &[u8])> {
+    der_read_any(input).filter(|(actual, _, _)| *actual == tag)
+}
+
+/// Read a DER element, returning its tag, content and the following input.
+fn der_read_any(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let (len_bytes, rest) = input.split_at(octets);
+        input = rest;
+        len_bytes
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | usize::from(*byte))
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (content, rest) = input.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// Look up a supported cipher suite by name.
+fn cipher_suite(name: &str) -> Fallible<&'static SupportedCipherSuite> {
+    ALL_CIPHERSUITES
+        .iter()
+        .copied()
+        .find(|suite| format!("{:?}", suite.suite) == name)
+        .ok_or_else(|| format_err!("unsupported TLS cipher suite '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    #[test]
+    fn test_from_pem_invalid() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        assert!(ServerTls::from_pem(cert_pem.as_bytes(), b"").is_err());
+        assert!(ServerTls::from_pem(b"", cert_pem.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_set_policy() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        let mut tls = ServerTls::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+
+        tls.set_policy(TlsVersion::Tls12, None).unwrap();
+        assert_eq!(tls.config.versions.len(), 2);
+        assert_eq!(tls.config.ciphersuites.len(), ALL_CIPHERSUITES.len());
+
+        let tls13 = vec!["TLS13_AES_256_GCM_SHA384".to_string()];
+        tls.set_policy(TlsVersion::Tls13, Some(&tls13)).unwrap();
+        assert_eq!(tls.config.versions, vec![ProtocolVersion::TLSv1_3]);
+        assert_eq!(tls.config.ciphersuites.len(), 1);
+
+        let unknown = vec!["TLS_RSA_WITH_RC4_128_SHA".to_string()];
+        assert!(tls.set_policy(TlsVersion::Tls12, Some(&unknown)).is_err());
+        let tls12 = vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()];
+        assert!(tls.set_policy(TlsVersion::Tls13, Some(&tls12)).is_err());
+    }
+
+    /// Minimal TLS 1.0 ClientHello, offering CBC cipher suites only.
+    fn tls10_client_hello() -> Vec<u8> {
+        let mut hello = vec![0x03, 0x01];
+        hello.extend_from_slice(&[0x42; 32]);
+        // No session ID, TLS_RSA_WITH_AES_128_CBC_SHA and
+        // TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA, null compression.
+        hello.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x2f, 0xc0, 0x13, 0x01, 0x00]);
+        let mut handshake = vec![0x01, 0x00, 0x00, hello.len() as u8];
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01, 0x00, handshake.len() as u8];
+        record.extend(handshake);
+        record
+    }
+
+    #[actix_rt::test]
+    async fn test_reject_tls10() {
+        use std::io::{Read, Write};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        let mut tls = ServerTls::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        tls.set_policy(TlsVersion::default(), None).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(|| {
+            App::new().route("/", web::get().to(|| HttpResponse::Ok().body("ok")))
+        })
+        .listen_rustls(listener, tls.server_config())
+        .unwrap()
+        .run();
+
+        // The server answers with a fatal `protocol_version` alert.
+        let reply = web::block(move || {
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+            stream.write_all(&tls10_client_hello())?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).map(|_| reply)
+        })
+        .await
+        .unwrap();
+        assert_eq!(reply.first(), Some(&0x15));
+        assert_eq!(reply.get(5..7), Some(&[0x02, 0x46][..]));
+
+        server.stop(false).await;
+    }
+
+    /// Certificate for `localhost` with the given common name, a CA if
+    /// `is_ca`.
+    fn cert_with_cn(common_name: &str, is_ca: bool) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, common_name);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::OrganizationName, "Example");
+        if is_ca {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    #[test]
+    fn test_cert_subject() {
+        let cert = cert_with_cn("node-1", false);
+        assert_eq!(
+            cert_subject(&cert.serialize_der().unwrap()).unwrap(),
+            "CN=node-1, O=Example"
+        );
+        assert_eq!(
+            attribute_name(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d]),
+            "1.2.840.113549"
+        );
+        assert!(cert_subject(b"").is_none());
+        assert!(cert_subject(&[0x30, 0x82, 0xff]).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_require_client_certs() {
+        let server_cert =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_pem = server_cert.serialize_pem().unwrap();
+        let mut tls = ServerTls::from_pem(
+            server_pem.as_bytes(),
+            server_cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+        assert!(!tls.requires_client_certs());
+        assert!(tls.set_client_ca(b"").is_err());
+        let ca = cert_with_cn("Example CA", true);
+        tls.set_client_ca(ca.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        tls.set_policy(TlsVersion::default(), None).unwrap();
+        assert!(tls.requires_client_certs());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| {
+            App::new().route("/", web::get().to(|| HttpResponse::Ok().body("ok")))
+        })
+        .listen_rustls(listener, tls.server_config())
+        .unwrap()
+        .run();
+
+        let get = |identity: Option<String>| {
+            let mut builder = reqwest::Client::builder()
+                .use_rustls_tls()
+                .add_root_certificate(
+                    reqwest::Certificate::from_pem(server_pem.as_bytes()).unwrap(),
+                );
+            if let Some(pem) = identity {
+                builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes()).unwrap());
+            }
+            let client = builder.build().unwrap();
+            async move {
+                client
+                    .get(&format!("https://localhost:{}/", port))
+                    .send()
+                    .await
+            }
+        };
+        let identity = |cert: &rcgen::Certificate, signer: Option<&rcgen::Certificate>| {
+            let cert_pem = match signer {
+                Some(signer) => cert.serialize_pem_with_signer(signer).unwrap(),
+                None => cert.serialize_pem().unwrap(),
+            };
+            format!("{}{}", cert_pem, cert.serialize_private_key_pem())
+        };
+
+        let client = cert_with_cn("node-1", false);
+        let resp = get(Some(identity(&client, Some(&ca)))).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+
+        // Untrusted (self-signed) and missing certificates fail handshakes.
+        let untrusted = cert_with_cn("node-2", false);
+        assert!(get(Some(identity(&untrusted, None))).await.is_err());
+        assert!(get(None).await.is_err());
+
+        server.stop(false).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_http2() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        let tls = ServerTls::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| {
+            App::new().route("/", web::get().to(|| HttpResponse::Ok().body("ok")))
+        })
+        .listen_rustls(listener, tls.server_config())
+        .unwrap()
+        .run();
+
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert_pem.as_bytes()).unwrap())
+            .build()
+            .unwrap();
+        let resp = client
+            .get(&format!("https://localhost:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+        assert_eq!(resp.text().await.unwrap(), "ok");
+
+        server.stop(false).await;
+    }
+}
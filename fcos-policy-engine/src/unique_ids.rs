@@ -0,0 +1,422 @@
+//! Unique IDs tracking: sizing of the per-replica Bloom filter, and
+//! optional fleet-wide estimation backed by a Redis HyperLogLog.
+
+use crate::config::UniqueIdsHash;
+use crate::redis_conn::RedisConnector;
+use crate::settings::{ServiceSettings, SharedUniqueIdsSettings};
+use failure::Fallible;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Share of the memory limit the Bloom filter may take, as a divisor.
+const BLOOM_MEMORY_DIVISOR: u64 = 8;
+/// Smallest size a Bloom filter is shrunk to, below which unique IDs
+/// tracking is disabled instead.
+const MIN_BLOOM_SIZE: usize = 64 * 1024;
+/// Memory limits from this value up mean no limit (cgroup v1).
+const UNLIMITED_MEMORY: u64 = 1 << 62;
+/// Files exposing the memory limit, for cgroup v2 and v1.
+const MEMORY_LIMIT_PATHS: &[&str] = &[
+    "/sys/fs/cgroup/memory.max",
+    "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+];
+
+/// Fit the Bloom filter of unique IDs tracking within the memory limit,
+/// shrinking it or disabling tracking (with a warning) if it is too large.
+pub(crate) fn fit_bloom_filter(settings: &mut ServiceSettings) {
+    if !settings.track_unique_ids {
+        return;
+    }
+    let limit = memory_limit();
+    match fit_bloom_size(settings.bloom_size, limit) {
+        Some(size) if size < settings.bloom_size => {
+            log::warn!(
+                "shrinking unique IDs Bloom filter from {} to {} bytes, to fit memory limit of {} bytes",
+                settings.bloom_size,
+                size,
+                limit.unwrap_or_default()
+            );
+            settings.bloom_size = size;
+        }
+        Some(_) => {}
+        None => {
+            log::warn!(
+                "unique IDs tracking disabled, Bloom filter of {} bytes does not fit memory limit of {} bytes",
+                settings.bloom_size,
+                limit.unwrap_or_default()
+            );
+            settings.track_unique_ids = false;
+            settings.shared_unique_ids = None;
+        }
+    }
+}
+
+/// Size of a Bloom filter within the given memory limit, `None` if it
+/// would have to be shrunk below the minimum size.
+fn fit_bloom_size(requested: usize, memory_limit: Option<u64>) -> Option<usize> {
+    let budget = match memory_limit {
+        Some(limit) => (limit / BLOOM_MEMORY_DIVISOR) as usize,
+        None => return Some(requested),
+    };
+    if requested <= budget {
+        Some(requested)
+    } else if budget >= MIN_BLOOM_SIZE {
+        Some(budget)
+    } else {
+        None
+    }
+}
+
+/// Memory limit of the process cgroup, if any.
+fn memory_limit() -> Option<u64> {
+    MEMORY_LIMIT_PATHS
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .find_map(|content| parse_memory_limit(&content))
+}
+
+/// Parse a cgroup memory limit, `None` meaning no limit.
+fn parse_memory_limit(content: &str) -> Option<u64> {
+    content
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|limit| *limit < UNLIMITED_MEMORY)
+}
+
+/// Hash a node UUID to the 64-bit ID tracked for unique IDs counting.
+pub(crate) fn hash_uuid(uuid: &str, algorithm: UniqueIdsHash) -> u64 {
+    match algorithm {
+        UniqueIdsHash::Xxhash64 => {
+            let mut hasher = twox_hash::XxHash64::with_seed(0);
+            hasher.write(uuid.as_bytes());
+            hasher.finish()
+        }
+        UniqueIdsHash::Siphash => {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            uuid.hash(&mut hasher);
+            hasher.finish()
+        }
+    }
+}
+
+/// Whether a node UUID is a well-formed UUID (of any version), either
+/// hyphenated (`8-4-4-4-12` hex digits) or as 32 hex digits.
+pub(crate) fn is_well_formed_uuid(uuid: &str) -> bool {
+    const GROUPS: [usize; 5] = [8, 4, 4, 4, 12];
+    let hex = |group: &str| group.bytes().all(|b| b.is_ascii_hexdigit());
+    if uuid.len() == 32 {
+        return hex(uuid);
+    }
+    let groups: Vec<&str> = uuid.split('-').collect();
+    groups.len() == GROUPS.len()
+        && groups
+            .iter()
+            .zip(GROUPS.iter())
+            .all(|(group, len)| group.len() == *len && hex(group))
+}
+
+/// Per-replica Bloom filter of unique (hashed) node IDs, split into shards
+/// selected by ID.
+///
+/// Each ID always maps to the same shard, so counting IDs new to their shard
+/// counts unique IDs overall, with the accuracy of a single filter of the
+/// same total size.
+///
+/// Beyond its expected population, the false positive rate of the filter
+/// climbs and unique IDs are increasingly undercounted: a warning is logged
+/// once the filter gets close to saturation.
+#[derive(Debug)]
+pub(crate) struct ShardedFilter {
+    shards: Vec<cbloom::Filter>,
+    /// Expected maximum number of IDs, over all shards.
+    capacity: u64,
+    /// Number of IDs recorded so far.
+    insertions: AtomicU64,
+}
+
+impl ShardedFilter {
+    /// Fill ratio from which the filter is considered close to saturation.
+    const SATURATION_WARNING_RATIO: f64 = 0.9;
+
+    /// Build a filter of `size` bytes and `max_population` expected IDs in
+    /// total, evenly split into `shards` shards.
+    pub(crate) fn new(size: usize, max_population: usize, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let population = (max_population / shards).max(1);
+        Self {
+            shards: (0..shards)
+                .map(|_| cbloom::Filter::new(size / shards, population))
+                .collect(),
+            capacity: (population * shards) as u64,
+            insertions: AtomicU64::new(0),
+        }
+    }
+
+    /// Record an ID, returning whether it was (most likely) not seen before.
+    pub(crate) fn insert(&self, id: u64) -> bool {
+        let shard = &self.shards[(id % self.shards.len() as u64) as usize];
+        if shard.maybe_contains(id) {
+            return false;
+        }
+        shard.insert(id);
+        let insertions = self.insertions.fetch_add(1, Ordering::Relaxed) + 1;
+        if insertions == self.warning_threshold() {
+            log::warn!(
+                "unique IDs Bloom filter is close to saturation ({} of {} expected IDs), \
+                 unique counts are becoming unreliable: consider increasing its size",
+                insertions,
+                self.capacity
+            );
+        }
+        true
+    }
+
+    /// Number of recorded IDs, as a fraction of the expected maximum.
+    pub(crate) fn fill_ratio(&self) -> f64 {
+        self.insertions.load(Ordering::Relaxed) as f64 / self.capacity as f64
+    }
+
+    /// Number of recorded IDs from which the saturation warning is logged.
+    fn warning_threshold(&self) -> u64 {
+        ((self.capacity as f64 * Self::SATURATION_WARNING_RATIO).ceil() as u64).max(1)
+    }
+}
+
+/// Sampling of (hashed) node IDs for unique IDs tracking.
+///
+/// IDs are sampled by value, so that each node is either always or never
+/// part of the sample, and unique counts are scaled by the inverse rate.
+#[derive(Debug)]
+pub(crate) struct IdSampler {
+    rate: f64,
+    /// Largest ID in the sample.
+    threshold: u64,
+    /// Number of unique IDs found in the sample so far.
+    sampled_uniques: AtomicU64,
+}
+
+impl IdSampler {
+    pub(crate) fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            threshold: (rate * u64::MAX as f64) as u64,
+            sampled_uniques: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether an ID is part of the sample.
+    pub(crate) fn is_sampled(&self, id: u64) -> bool {
+        self.rate >= 1.0 || id <= self.threshold
+    }
+
+    /// Record a sampled ID not seen before, returning the increase of the
+    /// scaled unique IDs count.
+    pub(crate) fn record_unique(&self) -> u64 {
+        let count = self.sampled_uniques.fetch_add(1, Ordering::Relaxed) + 1;
+        self.scale(count) - self.scale(count - 1)
+    }
+
+    /// Unique IDs count estimated from a count over the sample.
+    pub(crate) fn scale(&self, sampled: u64) -> u64 {
+        (sampled as f64 / self.rate).round() as u64
+    }
+}
+
+/// Fleet-wide estimator of unique node IDs, shared across replicas.
+#[derive(Debug)]
+pub(crate) struct SharedUniqueIds {
+    redis: RedisConnector,
+    key: String,
+}
+
+impl SharedUniqueIds {
+    /// Build an estimator client. Connection is established lazily.
+    pub(crate) fn new(settings: &SharedUniqueIdsSettings) -> Fallible<Self> {
+        Ok(Self {
+            redis: RedisConnector::new(&settings.url, settings.op_timeout)?,
+            key: settings.key.clone(),
+        })
+    }
+
+    /// Record a (hashed) node ID. Errors are logged and ignored.
+    pub(crate) async fn record(&self, client_id: u64) {
+        let cmd = redis::cmd("PFADD")
+            .arg(&self.key)
+            .arg(format!("{:016x}", client_id))
+            .to_owned();
+        if let Err(e) = self.redis.query::<()>(&cmd).await {
+            log::debug!("failed to record unique ID in shared estimator: {}", e);
+        }
+    }
+
+    /// Estimated number of unique node IDs across the fleet.
+    pub(crate) async fn estimate(&self) -> Fallible<u64> {
+        let cmd = redis::cmd("PFCOUNT").arg(&self.key).to_owned();
+        self.redis.query(&cmd).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_conn::fake;
+    use std::time::Duration;
+
+    fn settings(url: String) -> SharedUniqueIdsSettings {
+        SharedUniqueIdsSettings {
+            url,
+            key: "test:uuids".to_string(),
+            op_timeout: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn test_fit_bloom_size() {
+        const MIB: usize = 1024 * 1024;
+        assert_eq!(fit_bloom_size(10 * MIB, None), Some(10 * MIB));
+        assert_eq!(
+            fit_bloom_size(10 * MIB, Some(1024 * MIB as u64)),
+            Some(10 * MIB)
+        );
+        // Shrunk to an eighth of the limit.
+        assert_eq!(
+            fit_bloom_size(10 * MIB, Some(64 * MIB as u64)),
+            Some(8 * MIB)
+        );
+        // Too small to be worth it.
+        assert_eq!(fit_bloom_size(10 * MIB, Some(MIB as u64 / 4)), None);
+        // Explicitly small filters are kept.
+        assert_eq!(fit_bloom_size(1024, Some(MIB as u64 / 4)), Some(1024));
+
+        assert_eq!(parse_memory_limit("max\n"), None);
+        assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_memory_limit("268435456\n"), Some(256 * MIB as u64));
+    }
+
+    #[test]
+    fn test_hash_uuid_stability() {
+        let uuid = "e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e";
+        // Known XXH64 (seed 0) digest, which must never change: it would
+        // reset unique counts, including fleet-wide ones.
+        assert_eq!(
+            hash_uuid(uuid, UniqueIdsHash::Xxhash64),
+            0xabb5_63ca_6501_e3be
+        );
+        assert_eq!(
+            hash_uuid("", UniqueIdsHash::Xxhash64),
+            0xef46_db37_51d8_e999
+        );
+        assert_ne!(
+            hash_uuid(uuid, UniqueIdsHash::Xxhash64),
+            hash_uuid("e5a6d0a8d6654ccbb6c2e6f2cb7b0d1f", UniqueIdsHash::Xxhash64)
+        );
+        assert_eq!(
+            hash_uuid(uuid, UniqueIdsHash::Siphash),
+            hash_uuid(uuid, UniqueIdsHash::Siphash)
+        );
+    }
+
+    #[test]
+    fn test_is_well_formed_uuid() {
+        for valid in &[
+            "e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e",
+            "E5A6D0A8D6654CCBB6C2E6F2CB7B0D1E",
+            "e5a6d0a8-d665-4ccb-b6c2-e6f2cb7b0d1e",
+            "00000000-0000-0000-0000-000000000000",
+            // Any version.
+            "e5a6d0a8-d665-1ccb-b6c2-e6f2cb7b0d1e",
+        ] {
+            assert!(is_well_formed_uuid(valid), "{}", valid);
+        }
+        for malformed in &[
+            "",
+            "some-uuid",
+            "e5a6d0a8d6654ccbb6c2e6f2cb7b0d1",
+            "e5a6d0a8d6654ccbb6c2e6f2cb7b0d1g",
+            "e5a6d0a8-d665-4ccb-b6c2-e6f2cb7b0d1",
+            "e5a6d0a8d665-4ccb-b6c2-e6f2cb7b-0d1e",
+            "{e5a6d0a8-d665-4ccb-b6c2-e6f2cb7b0d1e}",
+            "e5a6d0a8-d665-4ccb-b6c2-e6f2cb7b0d1e-",
+        ] {
+            assert!(!is_well_formed_uuid(malformed), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_sharded_filter() {
+        for shards in &[1, 4] {
+            let filter = ShardedFilter::new(64 * 1024, 1000, *shards);
+            assert_eq!(filter.shards.len(), *shards);
+            let new_ids = (0..1000u64)
+                .chain(0..1000)
+                .filter(|id| filter.insert(id.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+                .count();
+            // Few false positives within the expected population, and no
+            // ID counted twice.
+            assert!(new_ids > 990 && new_ids <= 1000, "{}", new_ids);
+        }
+    }
+
+    #[test]
+    fn test_sharded_filter_saturation() {
+        let filter = ShardedFilter::new(1024, 100, 4);
+        assert_eq!(filter.warning_threshold(), 90);
+        let mut id = 0u64;
+        let mut next_id = || {
+            id += 1;
+            id.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        };
+        for _ in 0..50 {
+            filter.insert(next_id());
+        }
+        let half = filter.fill_ratio();
+        assert!(half > 0.45 && half <= 0.5, "{}", half);
+
+        // Past capacity, more and more new IDs are mistaken for known ones,
+        // and the filter reports being overfilled.
+        let new_ids = (0..10_000).filter(|_| filter.insert(next_id())).count();
+        assert!(new_ids < 5_000, "{}", new_ids);
+        assert!(filter.fill_ratio() > 1.0);
+    }
+
+    #[test]
+    fn test_id_sampler() {
+        let ids: Vec<u64> = (0..10_000)
+            .map(|n| hash_uuid(&format!("uuid-{}", n), UniqueIdsHash::Xxhash64))
+            .collect();
+
+        let full = IdSampler::new(1.0);
+        assert!(ids.iter().all(|id| full.is_sampled(*id)));
+        assert!(full.is_sampled(u64::MAX));
+        assert_eq!(full.record_unique(), 1);
+
+        let sampler = IdSampler::new(0.25);
+        let sampled = ids.iter().filter(|id| sampler.is_sampled(**id)).count();
+        assert!(sampled > 2_300 && sampled < 2_700, "{}", sampled);
+
+        // Scaled increments add up to the scaled count, even for rates whose
+        // inverse is not an integer.
+        let sampler = IdSampler::new(0.3);
+        let total: u64 = (0..10).map(|_| sampler.record_unique()).sum();
+        assert_eq!(total, 33);
+        assert_eq!(sampler.scale(10), 33);
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_unique_ids() {
+        let url = fake::start();
+        // Two replicas sharing the same estimator.
+        let first = SharedUniqueIds::new(&settings(url.clone())).unwrap();
+        let second = SharedUniqueIds::new(&settings(url)).unwrap();
+
+        first.record(1).await;
+        second.record(1).await;
+        second.record(2).await;
+        assert_eq!(first.estimate().await.unwrap(), 2);
+
+        let unavailable = SharedUniqueIds::new(&settings(fake::unavailable())).unwrap();
+        unavailable.record(3).await;
+        assert!(unavailable.estimate().await.is_err());
+    }
+}
@@ -1,25 +1,142 @@
+use crate::clock::Clock;
+use crate::concurrency::UpstreamLimiter;
+use crate::retry::RetryBudget;
+use crate::settings::{
+    RedirectSettings, SsrfGuardSettings, UpstreamAuth, UpstreamEndpoint, UpstreamSettings,
+};
 use commons::graph;
-use failure::{bail, Error, Fallible, SyncFailure};
+use failure::{bail, ensure, Error, Fallible, ResultExt, SyncFailure};
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Method;
+use std::fmt;
 use std::time::Duration;
+use structopt::clap::{crate_name, crate_version};
+
+/// Default User-Agent for requests to upstream.
+static DEFAULT_USER_AGENT: &str = concat!(crate_name!(), "/", crate_version!());
+
+/// Build the HTTP client for requests to upstream.
+///
+/// The client is built once from the upstream settings and shared by all
+/// requests, reusing connections and TLS sessions.
+pub(crate) fn new_client(upstream: &UpstreamSettings) -> Fallible<reqwest::Client> {
+    let mut client_builder = reqwest::ClientBuilder::new()
+        .user_agent(DEFAULT_USER_AGENT)
+        .default_headers(upstream.headers.clone())
+        .timeout(upstream.req_timeout)
+        .redirect(redirect_policy(&upstream.redirects, &upstream.ssrf_guard));
+    if let UpstreamAuth::ClientCert(pem) = &upstream.auth {
+        let identity = reqwest::Identity::from_pem(pem)?;
+        client_builder = client_builder.use_rustls_tls().identity(identity);
+    }
+    Ok(client_builder.build()?)
+}
 
 /// Return a request builder with base URL and parameters set.
-fn new_request(
+pub(crate) fn new_request(
+    client: &reqwest::Client,
     method: reqwest::Method,
     url: reqwest::Url,
-    req_timeout: Duration,
+    upstream: &UpstreamSettings,
 ) -> Fallible<reqwest::RequestBuilder> {
-    let client = reqwest::ClientBuilder::new().timeout(req_timeout).build()?;
-    let builder = client.request(method, url);
+    let mut builder = client.request(method, url);
+    if let UpstreamAuth::BearerToken(path) = &upstream.auth {
+        let token = std::fs::read_to_string(path)
+            .with_context(|_| format!("failed to read bearer token '{}'", path.display()))?;
+        let token = token.trim();
+        ensure!(!token.is_empty(), "empty bearer token '{}'", path.display());
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+        value.set_sensitive(true);
+        builder = builder.header(reqwest::header::AUTHORIZATION, value);
+    }
     Ok(builder)
 }
 
+/// Policy for following upstream redirects.
+///
+/// Redirects beyond the configured limit, or to another host when following
+/// is restricted to the same host, fail the request instead of returning
+/// the redirect response. When the SSRF guard is enabled, redirects to
+/// internal IP literal hosts, or to hostnames which are not explicitly
+/// allowed, are refused as well.
+fn redirect_policy(
+    settings: &RedirectSettings,
+    ssrf_guard: &SsrfGuardSettings,
+) -> reqwest::redirect::Policy {
+    let settings = settings.clone();
+    let ssrf_guard = ssrf_guard.clone();
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > settings.max_redirects {
+            let msg = format!(
+                "too many upstream redirects (maximum {})",
+                settings.max_redirects
+            );
+            return attempt.error(msg);
+        }
+        if settings.same_host_only {
+            let origin = attempt.previous().first().map(|url| url.origin());
+            if origin.as_ref() != Some(&attempt.url().origin()) {
+                let msg = format!(
+                    "refused cross-host upstream redirect to '{}'",
+                    attempt.url()
+                );
+                return attempt.error(msg);
+            }
+        }
+        if let Err(e) = crate::ssrf::check_redirect(attempt.url(), &ssrf_guard) {
+            let msg = e.to_string();
+            return attempt.error(msg);
+        }
+        attempt.follow()
+    })
+}
+
+/// Format headers for logging, redacting sensitive values.
+pub(crate) fn redacted_headers(headers: &HeaderMap) -> String {
+    let entries: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            let val = if value.is_sensitive() {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<non-ascii>")
+            };
+            format!("{}={}", name, val)
+        })
+        .collect();
+    entries.join(", ")
+}
+
+/// Upstream backpressure: `503 Service Unavailable` with a `Retry-After` delay.
+#[derive(Debug)]
+pub(crate) struct RetryAfter(pub(crate) Duration);
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upstream unavailable, retry after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+/// Parse a `Retry-After` header value, either delay seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = date.timestamp().saturating_sub(now.timestamp()).max(0);
+    Some(Duration::from_secs(secs as u64))
+}
+
 /// Fetch the graph from the fcos-graph-builder instance with the query specified.
 pub(crate) async fn fetch_graph_from_gb(
-    upstream_base: reqwest::Url,
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    endpoint: &reqwest::Url,
     stream: String,
     basearch: String,
-    req_timeout: Duration,
 ) -> Result<graph::Graph, Error> {
     if stream.trim().is_empty() {
         bail!("unexpected missing stream");
@@ -30,18 +147,873 @@ pub(crate) async fn fetch_graph_from_gb(
     let query = crate::GraphQuery {
         stream: Some(stream),
         basearch: Some(basearch),
-        rollout_wariness: None,
-        node_uuid: None,
+        ..Default::default()
     };
     // Cannot use `?` directly here otherwise will produce the error:
     //   the trait `std::marker::Sync` is not implemented for `(dyn std::error::Error + std::marker::Send + 'static)`
     // Reference: https://github.com/rust-lang-nursery/failure/issues/284
     let query_str = serde_qs::to_string(&query).map_err(SyncFailure::new)?;
-    let mut target = upstream_base;
+    let mut target = endpoint.clone();
     target.set_query(Some(&query_str));
-    let req = new_request(Method::GET, target, req_timeout)?;
+    crate::ssrf::check_target(&target, &upstream.ssrf_guard).await?;
+    let req = new_request(client, Method::GET, target, upstream)?;
     let resp = req.send().await?;
+    if resp.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, chrono::Utc::now()));
+        if let Some(delay) = retry_after {
+            return Err(RetryAfter(delay).into());
+        }
+    }
     let content = resp.error_for_status()?;
     let json = content.json::<graph::Graph>().await?;
     Ok(json)
 }
+
+/// Fetch the graph from a weighted-random upstream endpoint, failing over
+/// to the other endpoints on errors. Streams with an endpoint override are
+/// only fetched from there.
+async fn fetch_graph_with_failover(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    limiter: &UpstreamLimiter,
+    stream: &str,
+    basearch: &str,
+) -> Result<graph::Graph, Error> {
+    use rand::Rng;
+
+    let endpoints = upstream.endpoints_for(stream);
+    let total_weight = UpstreamEndpoint::total_weight(endpoints);
+    let pick = rand::thread_rng().gen_range(0, total_weight.max(1));
+    let order = UpstreamEndpoint::selection_order(endpoints, pick);
+
+    let mut last_err = None;
+    for index in order {
+        let endpoint = &endpoints[index];
+        let label = endpoint.label();
+        let request = fetch_graph_from_gb(
+            client,
+            upstream,
+            &endpoint.url,
+            stream.to_string(),
+            basearch.to_string(),
+        );
+        let res = limiter.run(&label, request).await;
+        let result = if res.is_ok() { "success" } else { "failure" };
+        crate::metrics::UPSTREAM_REQUESTS
+            .with_label_values(&[&label, result])
+            .inc();
+        match res {
+            Ok(graph) => return Ok(graph),
+            Err(e) => {
+                log::debug!("upstream endpoint '{}' failed: {}", label, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| failure::format_err!("no upstream endpoint configured")))
+}
+
+/// Fetch the graph from the fcos-graph-builder instances, retrying transient
+/// failures within the limits of the retry budget.
+///
+/// Retries are delayed by a capped exponential backoff with jitter, or by
+/// the delay the upstream asked for via `Retry-After` if longer; delays
+/// which would exceed the upstream request timeout (counted from the first
+/// attempt on the given clock) are not waited for, failing instead.
+///
+/// If configured, a transient failure left after all retries is tolerated
+/// once as a soft failure: the fetch is attempted again after a grace delay,
+/// outside of the retry budget, and only counted as failed if that attempt
+/// fails too.
+pub(crate) async fn fetch_graph_with_retries(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    budget: &RetryBudget,
+    limiter: &UpstreamLimiter,
+    clock: &dyn Clock,
+    stream: String,
+    basearch: String,
+) -> Result<graph::Graph, Error> {
+    use rand::Rng;
+
+    budget.record_request();
+
+    let start = clock.now();
+    let elapsed = || (clock.now() - start).to_std().unwrap_or_default();
+    let mut attempt = 0;
+    let mut soft_failed = false;
+    loop {
+        let res = fetch_graph_with_failover(client, upstream, limiter, &stream, &basearch).await;
+        let err = match res {
+            Ok(graph) => {
+                if soft_failed {
+                    crate::metrics::UPSTREAM_SOFT_FAILURES.inc();
+                }
+                return Ok(graph);
+            }
+            Err(e) => e,
+        };
+        if !is_retryable(&err) {
+            crate::metrics::UPSTREAM_FETCH_FAILURES.inc();
+            return Err(err);
+        }
+        if attempt >= upstream.retry.max_retries {
+            let grace = upstream.retry.soft_failure.filter(|grace| {
+                let retry_at = elapsed().checked_add(*grace);
+                !soft_failed
+                    && retry_at
+                        .map(|at| at < upstream.req_timeout)
+                        .unwrap_or(false)
+            });
+            match grace {
+                Some(grace) => {
+                    soft_failed = true;
+                    log::debug!(
+                        "tolerating upstream failure, retrying in {}ms: {}",
+                        grace.as_millis(),
+                        err
+                    );
+                    actix_rt::time::delay_for(grace).await;
+                    continue;
+                }
+                None => {
+                    crate::metrics::UPSTREAM_FETCH_FAILURES.inc();
+                    return Err(err);
+                }
+            }
+        }
+        let backoff = jittered(
+            upstream.retry.backoff(attempt + 1),
+            rand::thread_rng().gen::<f64>(),
+        );
+        let retry_after = err
+            .downcast_ref::<RetryAfter>()
+            .map(|retry_after| retry_after.0);
+        let delay = retry_after.map_or(backoff, |retry_after| retry_after.max(backoff));
+        let retry_at = elapsed().checked_add(delay);
+        if retry_at
+            .map(|at| at >= upstream.req_timeout)
+            .unwrap_or(true)
+        {
+            match retry_after {
+                Some(retry_after) => log::warn!(
+                    "upstream asked to retry after {}s, past the request timeout",
+                    retry_after.as_secs()
+                ),
+                None => log::debug!(
+                    "not retrying upstream request, backoff of {}ms past the request timeout",
+                    delay.as_millis()
+                ),
+            }
+            crate::metrics::UPSTREAM_FETCH_FAILURES.inc();
+            return Err(err);
+        }
+        if !budget.try_retry() {
+            crate::metrics::UPSTREAM_RETRY_BUDGET_EXHAUSTED.inc();
+            crate::metrics::UPSTREAM_FETCH_FAILURES.inc();
+            log::warn!("retry budget exhausted, not retrying upstream request");
+            return Err(err);
+        }
+        attempt += 1;
+        crate::metrics::UPSTREAM_RETRIES.inc();
+        log::debug!("retrying upstream request (attempt {}): {}", attempt, err);
+        if delay > Duration::from_secs(0) {
+            actix_rt::time::delay_for(delay).await;
+        }
+    }
+}
+
+/// Randomly shorten a backoff delay by up to half, given a jitter in
+/// `[0.0, 1.0)`, so that replicas failing together retry apart.
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    delay.mul_f64(1.0 - jitter / 2.0)
+}
+
+/// Whether a failed upstream request is worth retrying.
+fn is_retryable(err: &Error) -> bool {
+    if err.downcast_ref::<RetryAfter>().is_some() {
+        return true;
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) => {
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().map(|s| s.is_server_error()).unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+    use reqwest::header::HeaderName;
+    use std::io::Write;
+    use std::time::Instant;
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_headers() {
+        async fn check_headers(req: HttpRequest) -> HttpResponse {
+            let headers = req.headers();
+            let user_agent = headers.get("user-agent").and_then(|v| v.to_str().ok());
+            let custom = headers.get("x-custom").and_then(|v| v.to_str().ok());
+            if user_agent != Some(DEFAULT_USER_AGENT) || custom != Some("foo") {
+                return HttpResponse::BadRequest().finish();
+            }
+            HttpResponse::Ok().json(graph::Graph::default())
+        }
+        let srv =
+            actix_web::test::start(|| App::new().route("/v1/graph", web::get().to(check_headers)));
+
+        let mut upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            ..UpstreamSettings::default()
+        };
+        upstream.headers.insert(
+            HeaderName::from_static("x-custom"),
+            HeaderValue::from_static("foo"),
+        );
+        let graph = fetch_graph_from_gb(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &upstream.endpoints[0].url,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_ok());
+
+        upstream.headers.clear();
+        let missing = fetch_graph_from_gb(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &upstream.endpoints[0].url,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(missing.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_bearer_token() {
+        async fn check_auth(req: HttpRequest) -> HttpResponse {
+            let auth = req.headers().get("authorization");
+            if auth.and_then(|v| v.to_str().ok()) != Some("Bearer s3cr3t") {
+                return HttpResponse::Unauthorized().finish();
+            }
+            HttpResponse::Ok().json(graph::Graph::default())
+        }
+        let srv =
+            actix_web::test::start(|| App::new().route("/v1/graph", web::get().to(check_auth)));
+
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(token_file, "s3cr3t").unwrap();
+        let upstream = UpstreamSettings {
+            auth: UpstreamAuth::BearerToken(token_file.path().to_path_buf()),
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            ..UpstreamSettings::default()
+        };
+        // The client is shared across requests, the token read on each one.
+        let client = new_client(&upstream).unwrap();
+        let fetch = || {
+            fetch_graph_from_gb(
+                &client,
+                &upstream,
+                &upstream.endpoints[0].url,
+                "stable".to_string(),
+                "x86_64".to_string(),
+            )
+        };
+        assert!(fetch().await.is_ok());
+        assert!(fetch().await.is_ok());
+
+        // Blank tokens are refused, instead of sent.
+        std::fs::write(token_file.path(), " \n").unwrap();
+        let err = fetch().await.unwrap_err();
+        assert!(err.to_string().contains("empty bearer token"), "{}", err);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_retries() {
+        use crate::settings::RetrySettings;
+        use crate::tests::mock_upstream_sequence;
+        use actix_web::http::StatusCode;
+        use std::sync::atomic::Ordering;
+
+        // Fail the first request, then succeed.
+        let empty = serde_json::to_string(&graph::Graph::default()).unwrap();
+        let (srv, hits) = mock_upstream_sequence(vec![
+            (StatusCode::SERVICE_UNAVAILABLE, String::new()),
+            (StatusCode::OK, empty),
+        ]);
+
+        let upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            retry: RetrySettings {
+                max_retries: 1,
+                budget_min_retries: 1,
+                ..RetrySettings::default()
+            },
+            ..UpstreamSettings::default()
+        };
+        let budget = RetryBudget::new(&upstream.retry);
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+        let graph = fetch_graph_with_retries(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &budget,
+            &limiter,
+            &SystemClock,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_ok());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // Budget is now exhausted, the failure is not retried.
+        hits.store(0, Ordering::SeqCst);
+        let graph = fetch_graph_with_retries(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &budget,
+            &limiter,
+            &SystemClock,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_soft_failure() {
+        use crate::settings::RetrySettings;
+        use crate::tests::mock_upstream_sequence;
+        use actix_web::http::StatusCode;
+        use std::sync::atomic::Ordering;
+
+        // Fail the first request, then succeed.
+        let empty = serde_json::to_string(&graph::Graph::default()).unwrap();
+        let (srv, hits) = mock_upstream_sequence(vec![
+            (StatusCode::SERVICE_UNAVAILABLE, String::new()),
+            (StatusCode::OK, empty),
+        ]);
+
+        // Without retries nor grace delay, the failure is final.
+        let mut upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            ..UpstreamSettings::default()
+        };
+        let budget = RetryBudget::new(&upstream.retry);
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+        let failures = crate::metrics::UPSTREAM_FETCH_FAILURES.get();
+        let graph = fetch_graph_with_retries(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &budget,
+            &limiter,
+            &SystemClock,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_err());
+        assert!(crate::metrics::UPSTREAM_FETCH_FAILURES.get() > failures);
+
+        // A single transient failure is tolerated, outside of the retry budget.
+        hits.store(0, Ordering::SeqCst);
+        upstream.retry = RetrySettings {
+            budget_min_retries: 0,
+            budget_ratio: 0.0,
+            soft_failure: Some(Duration::from_millis(10)),
+            ..RetrySettings::default()
+        };
+        let budget = RetryBudget::new(&upstream.retry);
+        let soft_failures = crate::metrics::UPSTREAM_SOFT_FAILURES.get();
+        let graph = fetch_graph_with_retries(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &budget,
+            &limiter,
+            &SystemClock,
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_ok());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(crate::metrics::UPSTREAM_SOFT_FAILURES.get() > soft_failures);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_retry_after() {
+        use crate::settings::RetrySettings;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Ask for a delay on the first request, then succeed.
+        let delay = Arc::new(AtomicUsize::new(1));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (srv_delay, srv_hits) = (Arc::clone(&delay), Arc::clone(&hits));
+        let srv = actix_web::test::start(move || {
+            let (delay, hits) = (Arc::clone(&srv_delay), Arc::clone(&srv_hits));
+            App::new().route(
+                "/v1/graph",
+                web::get().to(move || {
+                    let count = hits.fetch_add(1, Ordering::SeqCst);
+                    let delay = delay.load(Ordering::SeqCst);
+                    async move {
+                        let resp = if count == 0 {
+                            HttpResponse::ServiceUnavailable()
+                                .header("Retry-After", delay.to_string())
+                                .finish()
+                        } else {
+                            HttpResponse::Ok().json(graph::Graph::default())
+                        };
+                        Ok::<_, actix_web::Error>(resp)
+                    }
+                }),
+            )
+        });
+
+        let upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            req_timeout: Duration::from_secs(5),
+            retry: RetrySettings {
+                max_retries: 1,
+                budget_min_retries: 10,
+                ..RetrySettings::default()
+            },
+            ..UpstreamSettings::default()
+        };
+        let budget = RetryBudget::new(&upstream.retry);
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+        let client = new_client(&upstream).unwrap();
+        let fetch = || {
+            fetch_graph_with_retries(
+                &client,
+                &upstream,
+                &budget,
+                &limiter,
+                &SystemClock,
+                "stable".to_string(),
+                "x86_64".to_string(),
+            )
+        };
+
+        let start = Instant::now();
+        assert!(fetch().await.is_ok());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // Delays beyond the request timeout are not waited for.
+        hits.store(0, Ordering::SeqCst);
+        delay.store(3600, Ordering::SeqCst);
+        let start = Instant::now();
+        let err = fetch().await.unwrap_err();
+        assert!(err.downcast_ref::<RetryAfter>().is_some());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_backoff() {
+        use crate::clock::MockClock;
+        use crate::settings::RetrySettings;
+        use std::sync::{Arc, Mutex};
+
+        // Always fail, each request taking a second on the request clock.
+        let clock = Arc::new(MockClock::at(chrono::Utc::now()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (srv_clock, srv_requests) = (Arc::clone(&clock), Arc::clone(&requests));
+        let srv = actix_web::test::start(move || {
+            let (clock, requests) = (Arc::clone(&srv_clock), Arc::clone(&srv_requests));
+            App::new().route(
+                "/v1/graph",
+                web::get().to(move || {
+                    requests.lock().unwrap().push(Instant::now());
+                    clock.advance(Duration::from_secs(1));
+                    async { Ok::<_, actix_web::Error>(HttpResponse::BadGateway().finish()) }
+                }),
+            )
+        });
+
+        let upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&srv.url("/v1/graph")).unwrap(),
+            )],
+            req_timeout: Duration::from_millis(3500),
+            retry: RetrySettings {
+                max_retries: 10,
+                budget_min_retries: 100,
+                backoff_initial: Duration::from_millis(20),
+                backoff_max: Duration::from_millis(80),
+                ..RetrySettings::default()
+            },
+            ..UpstreamSettings::default()
+        };
+        let budget = RetryBudget::new(&upstream.retry);
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+        let retries = crate::metrics::UPSTREAM_RETRIES.get();
+        let graph = fetch_graph_with_retries(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &budget,
+            &limiter,
+            clock.as_ref(),
+            "stable".to_string(),
+            "x86_64".to_string(),
+        )
+        .await;
+        assert!(graph.is_err());
+
+        // Retries stop once the next one would start past the timeout.
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(crate::metrics::UPSTREAM_RETRIES.get() >= retries + 3);
+
+        // Delays double on each retry, shortened by at most half.
+        let gaps: Vec<_> = requests.windows(2).map(|w| w[1] - w[0]).collect();
+        for (gap, min) in gaps.iter().zip(&[10, 20, 40]) {
+            assert!(*gap >= Duration::from_millis(*min), "{:?}", gaps);
+        }
+    }
+
+    #[test]
+    fn test_jittered() {
+        let delay = Duration::from_millis(100);
+        assert_eq!(jittered(delay, 0.0), delay);
+        assert_eq!(jittered(delay, 0.5), Duration::from_millis(75));
+        assert!(jittered(delay, 0.999) > Duration::from_millis(50));
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_redirects() {
+        fn redirect(location: &str) -> HttpResponse {
+            HttpResponse::Found()
+                .header(reqwest::header::LOCATION, location)
+                .finish()
+        }
+        async fn moved() -> HttpResponse {
+            redirect("/moved")
+        }
+        async fn looping() -> HttpResponse {
+            redirect("/loop")
+        }
+        async fn away(req: HttpRequest) -> HttpResponse {
+            let port = req.app_config().local_addr().port();
+            redirect(&format!("http://127.0.0.1:{}/moved", port))
+        }
+        async fn graph() -> HttpResponse {
+            HttpResponse::Ok().json(graph::Graph::default())
+        }
+        let srv = actix_web::test::start(|| {
+            App::new()
+                .route("/v1/graph", web::get().to(moved))
+                .route("/moved", web::get().to(graph))
+                .route("/loop", web::get().to(looping))
+                .route("/away", web::get().to(away))
+        });
+        let fetch = |upstream: UpstreamSettings, path: &str| {
+            let url = reqwest::Url::parse(&srv.url(path)).unwrap();
+            async move {
+                fetch_graph_from_gb(
+                    &new_client(&upstream).unwrap(),
+                    &upstream,
+                    &url,
+                    "stable".to_string(),
+                    "x86_64".to_string(),
+                )
+                .await
+            }
+        };
+
+        // Same-host redirects are followed by default, within limits.
+        assert!(fetch(UpstreamSettings::default(), "/v1/graph")
+            .await
+            .is_ok());
+        let looping = fetch(UpstreamSettings::default(), "/loop").await;
+        assert!(format!("{}", looping.unwrap_err()).contains("too many upstream redirects"));
+        let cross_host = fetch(UpstreamSettings::default(), "/away").await;
+        assert!(format!("{}", cross_host.unwrap_err()).contains("cross-host"));
+
+        let no_redirects = UpstreamSettings {
+            redirects: RedirectSettings {
+                max_redirects: 0,
+                same_host_only: true,
+            },
+            ..UpstreamSettings::default()
+        };
+        assert!(fetch(no_redirects, "/v1/graph").await.is_err());
+
+        let any_host = UpstreamSettings {
+            redirects: RedirectSettings {
+                max_redirects: 1,
+                same_host_only: false,
+            },
+            ..UpstreamSettings::default()
+        };
+        assert!(fetch(any_host, "/away").await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_ssrf_guard() {
+        async fn graph() -> HttpResponse {
+            HttpResponse::Ok().json(graph::Graph::default())
+        }
+        async fn metadata(req: HttpRequest) -> HttpResponse {
+            let port = req.app_config().local_addr().port();
+            HttpResponse::Found()
+                .header(
+                    reqwest::header::LOCATION,
+                    format!("http://127.0.0.1:{}/v1/graph", port),
+                )
+                .finish()
+        }
+        async fn localhost(req: HttpRequest) -> HttpResponse {
+            let port = req.app_config().local_addr().port();
+            HttpResponse::Found()
+                .header(
+                    reqwest::header::LOCATION,
+                    format!("http://localhost:{}/v1/graph", port),
+                )
+                .finish()
+        }
+        let srv = actix_web::test::start(|| {
+            App::new()
+                .route("/v1/graph", web::get().to(graph))
+                .route("/redirect", web::get().to(metadata))
+                .route("/localhost", web::get().to(localhost))
+        });
+        let fetch = |upstream: UpstreamSettings, path: &str| {
+            let url = reqwest::Url::parse(&srv.url(path)).unwrap();
+            async move {
+                fetch_graph_from_gb(
+                    &new_client(&upstream).unwrap(),
+                    &upstream,
+                    &url,
+                    "stable".to_string(),
+                    "x86_64".to_string(),
+                )
+                .await
+            }
+        };
+        let mut upstream = UpstreamSettings::default();
+        upstream.redirects.same_host_only = false;
+        assert!(fetch(upstream.clone(), "/v1/graph").await.is_ok());
+
+        // The test server listens on loopback.
+        upstream.ssrf_guard.enabled = true;
+        let err = fetch(upstream.clone(), "/v1/graph").await.unwrap_err();
+        assert!(err.to_string().contains("internal address"));
+
+        upstream
+            .ssrf_guard
+            .allowed_hosts
+            .insert("localhost".to_string());
+        assert!(fetch(upstream.clone(), "/v1/graph").await.is_ok());
+        // Redirects to internal addresses are refused as well.
+        assert!(fetch(upstream.clone(), "/redirect").await.is_err());
+
+        // Redirect targets are not resolved: hostnames must be allowed.
+        let fetch_direct = |upstream: UpstreamSettings, path: &str| {
+            let port = reqwest::Url::parse(&srv.url(path)).unwrap().port().unwrap();
+            let url = format!("http://127.0.0.1:{}{}", port, path);
+            let url = reqwest::Url::parse(&url).unwrap();
+            async move {
+                fetch_graph_from_gb(
+                    &new_client(&upstream).unwrap(),
+                    &upstream,
+                    &url,
+                    "stable".to_string(),
+                    "x86_64".to_string(),
+                )
+                .await
+            }
+        };
+        upstream.ssrf_guard.allowed_hosts.clear();
+        upstream
+            .ssrf_guard
+            .allowed_ips
+            .insert("127.0.0.1".parse().unwrap());
+        assert!(fetch_direct(upstream.clone(), "/v1/graph").await.is_ok());
+        let err = fetch_direct(upstream.clone(), "/localhost")
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("not allowed"), "{:?}", err);
+        upstream
+            .ssrf_guard
+            .allowed_hosts
+            .insert("localhost".to_string());
+        assert!(fetch_direct(upstream, "/localhost").await.is_ok());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_failover() {
+        let failing = actix_web::test::start(|| {
+            App::new().route(
+                "/v1/graph",
+                web::get().to(|| async {
+                    Ok::<_, actix_web::Error>(HttpResponse::InternalServerError().finish())
+                }),
+            )
+        });
+        let backup = actix_web::test::start(|| {
+            App::new().route(
+                "/v1/graph",
+                web::get().to(|| async {
+                    Ok::<_, actix_web::Error>(HttpResponse::Ok().json(graph::Graph::default()))
+                }),
+            )
+        });
+
+        // The backup endpoint is never selected first, but used on failures.
+        let failing_url = reqwest::Url::parse(&failing.url("/v1/graph")).unwrap();
+        let backup_url = reqwest::Url::parse(&backup.url("/v1/graph")).unwrap();
+        let upstream = UpstreamSettings {
+            endpoints: vec![
+                UpstreamEndpoint::new(failing_url.clone()),
+                UpstreamEndpoint {
+                    url: backup_url.clone(),
+                    weight: 0,
+                },
+            ],
+            ..UpstreamSettings::default()
+        };
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+        let graph = fetch_graph_with_failover(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &limiter,
+            "stable",
+            "x86_64",
+        )
+        .await;
+        assert!(graph.is_ok());
+
+        // Endpoints are labelled by origin, without path nor query.
+        let count = |url: &reqwest::Url, result| {
+            let label = url.origin().ascii_serialization();
+            crate::metrics::UPSTREAM_REQUESTS
+                .with_label_values(&[&label, result])
+                .get()
+        };
+        assert_eq!(count(&failing_url, "failure"), 1);
+        assert_eq!(count(&backup_url, "success"), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_graph_stream_endpoint() {
+        // Each mock upstream replies with a graph with a single distinct node.
+        let mock = |version: &'static str| {
+            actix_web::test::start(move || {
+                App::new().route(
+                    "/v1/graph",
+                    web::get().to(move || {
+                        let graph = graph::Graph {
+                            nodes: vec![graph::CincinnatiPayload {
+                                version: version.to_string(),
+                                metadata: Default::default(),
+                                payload: String::new(),
+                            }],
+                            edges: vec![],
+                        };
+                        async move { Ok::<_, actix_web::Error>(HttpResponse::Ok().json(graph)) }
+                    }),
+                )
+            })
+        };
+        let default_srv = mock("default");
+        let custom_srv = mock("custom");
+
+        let mut upstream = UpstreamSettings {
+            endpoints: vec![UpstreamEndpoint::new(
+                reqwest::Url::parse(&default_srv.url("/v1/graph")).unwrap(),
+            )],
+            ..UpstreamSettings::default()
+        };
+        upstream.stream_endpoints.insert(
+            "custom".to_string(),
+            UpstreamEndpoint::new(reqwest::Url::parse(&custom_srv.url("/v1/graph")).unwrap()),
+        );
+        let limiter = UpstreamLimiter::new(&upstream.concurrency);
+
+        let stable = fetch_graph_with_failover(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &limiter,
+            "stable",
+            "x86_64",
+        )
+        .await
+        .unwrap();
+        assert_eq!(stable.nodes[0].version, "default");
+        let custom = fetch_graph_with_failover(
+            &new_client(&upstream).unwrap(),
+            &upstream,
+            &limiter,
+            "custom",
+            "x86_64",
+        )
+        .await
+        .unwrap();
+        assert_eq!(custom.nodes[0].version, "custom");
+    }
+
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = HeaderMap::new();
+        let mut secret = HeaderValue::from_static("Bearer abc");
+        secret.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, secret);
+        headers.insert(
+            HeaderName::from_static("x-custom"),
+            HeaderValue::from_static("foo"),
+        );
+
+        let out = redacted_headers(&headers);
+        assert!(out.contains("authorization=<redacted>"));
+        assert!(out.contains("x-custom=foo"));
+        assert!(!out.contains("abc"));
+    }
+}
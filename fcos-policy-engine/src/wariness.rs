@@ -0,0 +1,405 @@
+//! Client rollout wariness, requested by clients or derived from their
+//! node UUID.
+
+use crate::config::WarinessParsing;
+use crate::{metrics, settings, GraphQuery};
+use commons::errors::PeError;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Round a wariness value to the given number of decimal places.
+pub(crate) fn round_wariness(wariness: f64, precision: u32) -> f64 {
+    let factor = 10f64.powi(precision as i32);
+    (wariness * factor).round() / factor
+}
+
+/// Origin of a client wariness.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WarinessSource {
+    /// Explicitly requested by the client.
+    Requested,
+    /// Derived from the node UUID.
+    Computed,
+}
+
+/// Interpret a client-provided wariness, before rounding.
+///
+/// Percentages are only accepted in lenient mode, where values in
+/// `(1.0, 100.0]` are scaled down; in strict mode, any value out of
+/// `[0.0, 1.0]` is rejected. Otherwise values are clamped to `[0.0, 1.0]`.
+/// Non-finite values are always rejected, as they cannot be clamped
+/// meaningfully.
+fn interpret_wariness(input: f64, parsing: WarinessParsing) -> Result<f64, PeError> {
+    if !input.is_finite() {
+        return Err(PeError::InvalidQuery(format!(
+            "rollout_wariness must be a finite number, got {}",
+            input
+        )));
+    }
+    let input = match parsing {
+        // Client-provided values are never interpreted when ignored.
+        WarinessParsing::Clamp | WarinessParsing::Ignore => input,
+        WarinessParsing::Lenient if input > 1.0 && input <= 100.0 => input / 100.0,
+        WarinessParsing::Lenient => input,
+        WarinessParsing::Strict if (0.0..=1.0).contains(&input) => input,
+        WarinessParsing::Strict => {
+            return Err(PeError::InvalidQuery(format!(
+                "rollout_wariness must be between 0.0 and 1.0, got {}",
+                input
+            )))
+        }
+    };
+    Ok(input.clamp(0.0, 1.0))
+}
+
+/// Client wariness, from the query or derived from the node UUID, rounded
+/// to the given number of decimal places.
+///
+/// A valid `rollout_wariness` always takes precedence over the `node_uuid`,
+/// which is then only used for unique IDs tracking. The UUID is used if the
+/// requested wariness is missing or unparsable, or always if requested
+/// values are ignored: clients then cannot select themselves into early
+/// rollouts.
+#[allow(clippy::let_and_return)]
+pub(crate) fn compute_wariness(
+    params: &GraphQuery,
+    precision: u32,
+    salt: &str,
+    parsing: WarinessParsing,
+) -> Result<(f64, WarinessSource), PeError> {
+    let requested = params.rollout_wariness.as_deref().unwrap_or_default();
+    match requested.parse::<f64>() {
+        _ if parsing == WarinessParsing::Ignore => {
+            if !requested.is_empty() {
+                log::warn!("ignoring client-provided rollout wariness, using the node UUID");
+            }
+        }
+        Ok(input) => {
+            let wariness = round_wariness(interpret_wariness(input, parsing)?, precision);
+            return Ok((wariness, WarinessSource::Requested));
+        }
+        Err(_) if !requested.is_empty() => {
+            log::debug!("unparsable rollout wariness '{}'", requested);
+            metrics::V1_GRAPH_INVALID_WARINESS.inc();
+        }
+        Err(_) => {}
+    }
+
+    let uuid = params
+        .node_uuid
+        .as_ref()
+        .map(String::from)
+        .unwrap_or_default();
+    let wariness = {
+        // Left limit not included in range.
+        const COMPUTED_MIN: f64 = 0.0 + 0.000_001;
+        const COMPUTED_MAX: f64 = 1.0;
+        let mut hasher = DefaultHasher::new();
+        // An empty salt is not hashed, preserving the unsalted mapping.
+        if !salt.is_empty() {
+            salt.hash(&mut hasher);
+        }
+        uuid.hash(&mut hasher);
+        let digest = hasher.finish();
+        // Scale down.
+        let scaled = (digest as f64) / (u64::MAX as f64);
+        // Round, then clamp within limits.
+        round_wariness(scaled, precision).clamp(COMPUTED_MIN, COMPUTED_MAX)
+    };
+
+    Ok((wariness, WarinessSource::Computed))
+}
+
+/// Whether a query supplies both a rollout wariness and a node UUID, the
+/// former taking precedence (see `compute_wariness`).
+pub(crate) fn has_conflicting_wariness(query: &GraphQuery) -> bool {
+    let supplied = |param: &Option<String>| param.as_deref().map(|v| !v.is_empty()) == Some(true);
+    supplied(&query.rollout_wariness) && supplied(&query.node_uuid)
+}
+
+/// Client wariness, maxed out outside of rollout windows so that no
+/// rollout advances.
+pub(crate) fn windowed_wariness(
+    policy: &settings::PolicySettings,
+    wariness: f64,
+    now: chrono::DateTime<chrono::Utc>,
+) -> f64 {
+    let allowed = crate::rollout_window::rollouts_allowed(&policy.rollout_windows, now);
+    metrics::ROLLOUT_WINDOW_OPEN.set(allowed as i64);
+    if allowed {
+        wariness
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{canned_graph, scoped_query};
+    use commons::policy;
+
+    #[test]
+    fn test_wariness_ignored() {
+        let query = |wariness: Option<&str>| GraphQuery {
+            node_uuid: Some("e5a6d0a8d6654ccbb6c2e6f2cb7b0d1e".to_string()),
+            ..scoped_query("stable", "x86_64", wariness)
+        };
+        let computed = compute_wariness(&query(None), 3, "salt", WarinessParsing::Clamp).unwrap();
+        assert_eq!(computed.1, WarinessSource::Computed);
+        assert!(computed.0 > 0.0);
+
+        // Explicit values (even valid ones) are ignored, for unparsable
+        // and out of range values too.
+        for wariness in &["0", "0.0", "1.0", "50", "-1", "early"] {
+            let ignored =
+                compute_wariness(&query(Some(wariness)), 3, "salt", WarinessParsing::Ignore)
+                    .unwrap();
+            assert_eq!(ignored, computed, "{}", wariness);
+        }
+        let explicit =
+            compute_wariness(&query(Some("0")), 3, "salt", WarinessParsing::Clamp).unwrap();
+        assert_eq!(explicit, (0.0, WarinessSource::Requested));
+    }
+
+    #[test]
+    fn test_wariness_percentage() {
+        let query = |wariness: &str| scoped_query("stable", "x86_64", Some(wariness));
+        let wariness = |value: &str, parsing: WarinessParsing| {
+            compute_wariness(&query(value), 3, "", parsing).map(|(wariness, _)| wariness)
+        };
+
+        // Clamping (the default) treats percentages as out of range.
+        assert_eq!(WarinessParsing::default(), WarinessParsing::Clamp);
+        assert_eq!(wariness("50", WarinessParsing::Clamp).unwrap(), 1.0);
+        assert_eq!(wariness("0.5", WarinessParsing::Clamp).unwrap(), 0.5);
+        assert_eq!(wariness("100", WarinessParsing::Clamp).unwrap(), 1.0);
+
+        // Lenient parsing interprets values in (1.0, 100.0] as percentages.
+        assert_eq!(wariness("50", WarinessParsing::Lenient).unwrap(), 0.5);
+        assert_eq!(wariness("0.5", WarinessParsing::Lenient).unwrap(), 0.5);
+        assert_eq!(wariness("100", WarinessParsing::Lenient).unwrap(), 1.0);
+        assert_eq!(wariness("1", WarinessParsing::Lenient).unwrap(), 1.0);
+        assert_eq!(wariness("250", WarinessParsing::Lenient).unwrap(), 1.0);
+
+        // Strict parsing rejects them.
+        assert!(matches!(
+            wariness("50", WarinessParsing::Strict),
+            Err(PeError::InvalidQuery(_))
+        ));
+        assert_eq!(wariness("0.5", WarinessParsing::Strict).unwrap(), 0.5);
+        assert!(wariness("100", WarinessParsing::Strict).is_err());
+        assert!(wariness("-0.1", WarinessParsing::Strict).is_err());
+        assert_eq!(wariness("1", WarinessParsing::Strict).unwrap(), 1.0);
+
+        // Non-finite values are rejected in all modes, instead of clamped.
+        for parsing in &[
+            WarinessParsing::Clamp,
+            WarinessParsing::Lenient,
+            WarinessParsing::Strict,
+        ] {
+            for value in &["NaN", "inf", "-inf"] {
+                assert!(
+                    matches!(wariness(value, *parsing), Err(PeError::InvalidQuery(_))),
+                    "{} with {:?}",
+                    value,
+                    parsing
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_wariness_precision() {
+        let query = |wariness: Option<&str>, uuid: Option<&str>| GraphQuery {
+            node_uuid: uuid.map(String::from),
+            ..scoped_query("stable", "x86_64", wariness)
+        };
+
+        let precise = query(Some("0.12345678901234567"), None);
+        assert_eq!(
+            compute_wariness(&precise, 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.123
+        );
+        assert_eq!(
+            compute_wariness(&precise, 15, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.123456789012346
+        );
+        assert_eq!(
+            compute_wariness(&precise, 0, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.0
+        );
+
+        // Nearby values are treated alike.
+        let a = query(Some("0.5000000001"), None);
+        let b = query(Some("0.4999999999"), None);
+        assert_eq!(
+            compute_wariness(&a, 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.5
+        );
+        assert_eq!(
+            compute_wariness(&b, 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.5
+        );
+
+        // Bounds are preserved.
+        assert_eq!(
+            compute_wariness(&query(Some("0.9999"), None), 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            1.0
+        );
+        assert_eq!(
+            compute_wariness(&query(Some("0.0004"), None), 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.0
+        );
+        assert_eq!(
+            compute_wariness(&query(Some("0.6"), None), 0, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            1.0
+        );
+        assert_eq!(
+            compute_wariness(&query(Some("-3"), None), 3, "", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            0.0
+        );
+
+        // Unparsable values fall back to computed wariness, and are counted
+        // separately from missing values.
+        let invalid_before = metrics::V1_GRAPH_INVALID_WARINESS.get();
+        let computed = compute_wariness(
+            &query(None, Some("some-uuid")),
+            3,
+            "",
+            WarinessParsing::Clamp,
+        )
+        .unwrap()
+        .0;
+        assert_eq!(metrics::V1_GRAPH_INVALID_WARINESS.get(), invalid_before);
+        let fallback = compute_wariness(
+            &query(Some("0,5"), Some("some-uuid")),
+            3,
+            "",
+            WarinessParsing::Clamp,
+        )
+        .unwrap()
+        .0;
+        assert_eq!(fallback, computed);
+        assert_eq!(metrics::V1_GRAPH_INVALID_WARINESS.get(), invalid_before + 1);
+
+        // Computed wariness is rounded too, but never reaches zero.
+        let computed = compute_wariness(
+            &query(None, Some("some-uuid")),
+            2,
+            "",
+            WarinessParsing::Clamp,
+        )
+        .unwrap()
+        .0;
+        assert_eq!(computed, round_wariness(computed, 2));
+        assert!(
+            compute_wariness(
+                &query(None, Some("some-uuid")),
+                0,
+                "",
+                WarinessParsing::Clamp
+            )
+            .unwrap()
+            .0 > 0.0
+        );
+
+        // Salting reshuffles computed wariness.
+        let uuid = query(None, Some("some-uuid"));
+        let unsalted = compute_wariness(&uuid, 3, "", WarinessParsing::Clamp)
+            .unwrap()
+            .0;
+        let salted = compute_wariness(&uuid, 3, "s3cr3t", WarinessParsing::Clamp)
+            .unwrap()
+            .0;
+        assert_ne!(salted, unsalted);
+        assert_eq!(
+            compute_wariness(&uuid, 3, "s3cr3t", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            salted
+        );
+        assert_ne!(
+            compute_wariness(&uuid, 3, "rotated", WarinessParsing::Clamp)
+                .unwrap()
+                .0,
+            salted
+        );
+        assert_eq!(
+            compute_wariness(
+                &query(Some("0.5"), None),
+                3,
+                "s3cr3t",
+                WarinessParsing::Clamp
+            )
+            .unwrap()
+            .0,
+            0.5
+        );
+
+        // Sources are told apart.
+        let (_, source) =
+            compute_wariness(&query(Some("0.5"), None), 3, "", WarinessParsing::Clamp).unwrap();
+        assert_eq!(source, WarinessSource::Requested);
+        let (_, source) = compute_wariness(
+            &query(None, Some("some-uuid")),
+            3,
+            "",
+            WarinessParsing::Clamp,
+        )
+        .unwrap();
+        assert_eq!(source, WarinessSource::Computed);
+        let (_, source) = compute_wariness(
+            &query(Some("0,5"), Some("some-uuid")),
+            3,
+            "",
+            WarinessParsing::Clamp,
+        )
+        .unwrap();
+        assert_eq!(source, WarinessSource::Computed);
+    }
+
+    #[test]
+    fn test_windowed_wariness() {
+        use chrono::TimeZone;
+
+        let policy = settings::PolicySettings {
+            rollout_windows: vec![crate::rollout_window::RolloutWindow::parse(
+                "09:00", "17:00", None,
+            )
+            .unwrap()],
+            ..Default::default()
+        };
+        let before = chrono::Utc.ymd(2021, 3, 15).and_hms(8, 59, 59);
+        let inside = chrono::Utc.ymd(2021, 3, 15).and_hms(9, 0, 0);
+
+        // Outside the window, the rollout in the canned graph is held.
+        let wariness = windowed_wariness(&policy, 0.1, before);
+        assert_eq!(wariness, 1.0);
+        let graph = policy::throttle_rollouts(canned_graph(), wariness);
+        assert_eq!(graph.edges, vec![(0, 1)]);
+
+        let wariness = windowed_wariness(&policy, 0.1, inside);
+        assert_eq!(wariness, 0.1);
+        let graph = policy::throttle_rollouts(canned_graph(), wariness);
+        assert_eq!(graph.edges, vec![(0, 1), (0, 2), (1, 2)]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Graph post-processing webhook.
+//!
+//! After the built-in policies, the client graph can optionally be handed to
+//! an external service for custom processing. The graph is sent as JSON in a
+//! `POST` request, with `basearch`, `stream` and `rollout_wariness` as query
+//! parameters; a `200 OK` response must carry the (possibly modified) graph,
+//! in the same format. On any failure (connection error, timeout, non-200
+//! status, or invalid graph) the pre-webhook graph is served instead.
+
+use crate::settings::WebhookSettings;
+use commons::graph::{Graph, GraphScope};
+use failure::{bail, Fallible};
+use reqwest::StatusCode;
+
+/// Client for the graph post-processing webhook.
+#[derive(Debug)]
+pub(crate) struct GraphWebhook {
+    client: reqwest::Client,
+    url: reqwest::Url,
+}
+
+impl GraphWebhook {
+    pub(crate) fn new(settings: &WebhookSettings) -> Fallible<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(settings.timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            url: settings.url.clone(),
+        })
+    }
+
+    /// Post-process a client graph, returning the validated webhook graph.
+    pub(crate) async fn process(
+        &self,
+        scope: &GraphScope,
+        wariness: f64,
+        graph: &Graph,
+    ) -> Fallible<Graph> {
+        let wariness = wariness.to_string();
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .query(&[
+                ("basearch", scope.basearch.as_str()),
+                ("stream", scope.stream.as_str()),
+                ("rollout_wariness", wariness.as_str()),
+            ])
+            .json(graph)
+            .send()
+            .await?;
+        if resp.status() != StatusCode::OK {
+            bail!("unexpected webhook response status {}", resp.status());
+        }
+        let processed = resp.json::<Graph>().await?;
+        processed.check_edges()?;
+        Ok(processed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+    use commons::graph::CincinnatiPayload;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn node(version: &str) -> CincinnatiPayload {
+        CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: String::new(),
+        }
+    }
+
+    /// Webhook keeping only the first node, for the requested stream.
+    async fn keep_first(
+        query: web::Query<HashMap<String, String>>,
+        graph: web::Json<Graph>,
+    ) -> HttpResponse {
+        match query.get("stream").map(String::as_str) {
+            Some("stable") => HttpResponse::Ok().json(Graph {
+                nodes: graph.into_inner().nodes.into_iter().take(1).collect(),
+                edges: vec![],
+            }),
+            Some("broken") => HttpResponse::Ok().json(Graph {
+                nodes: vec![],
+                edges: vec![(0, 1)],
+            }),
+            _ => HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_graph_webhook() {
+        let srv = actix_web::test::start(|| App::new().route("/hook", web::post().to(keep_first)));
+        let webhook = GraphWebhook::new(&WebhookSettings {
+            url: reqwest::Url::parse(&srv.url("/hook")).unwrap(),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let graph = Graph {
+            nodes: vec![node("1.0"), node("2.0")],
+            edges: vec![(0, 1)],
+        };
+        let scope = |stream: &str| GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: stream.to_string(),
+        };
+
+        let processed = webhook
+            .process(&scope("stable"), 0.5, &graph)
+            .await
+            .unwrap();
+        assert_eq!(processed.nodes.len(), 1);
+        assert_eq!(processed.nodes[0].version, "1.0");
+        assert!(processed.edges.is_empty());
+
+        // Invalid graphs and error statuses are rejected.
+        assert!(webhook
+            .process(&scope("broken"), 0.5, &graph)
+            .await
+            .is_err());
+        assert!(webhook.process(&scope("next"), 0.5, &graph).await.is_err());
+    }
+}